@@ -0,0 +1,94 @@
+// Registration, unregistration and lookups under churn, comparing the
+// incremental index with rebuilding an `AddressSpaceIndex` on every change.
+// Run with `cargo bench --bench index-churn`.
+
+use std::time::{Duration, Instant};
+
+use stackmap::{
+    generate::{self, GeneratorOptions},
+    index::{AddressSpaceIndex, IncrementalAddressSpaceIndex, Module},
+    LLVMStackMaps,
+};
+
+const MODULES: usize = 200;
+const LOOKUPS: usize = 10_000;
+// Distance between the load addresses of consecutive modules
+const MODULE_SPACING: u64 = 0x100_0000;
+
+fn module(section: &[u8], idx: usize) -> Module<'_> {
+    Module {
+        name: format!("jit-{}", idx),
+        stack_maps: LLVMStackMaps::new(section),
+        load_bias: idx as u64 * MODULE_SPACING,
+    }
+}
+
+fn report(name: &str, elapsed: Duration, count: usize) {
+    println!(
+        "{:<40} {:>10.3} ms total {:>10.3} us each",
+        name,
+        elapsed.as_secs_f64() * 1e3,
+        elapsed.as_secs_f64() * 1e6 / count as f64
+    );
+}
+
+fn main() {
+    let sections: Vec<Vec<u8>> = (0..MODULES)
+        .map(|idx| {
+            generate::generate_section(&GeneratorOptions {
+                seed: idx as u64,
+                ..GeneratorOptions::default()
+            })
+            .unwrap()
+        })
+        .collect();
+    let pcs: Vec<u64> = (0..LOOKUPS as u64)
+        .map(|idx| (idx % MODULES as u64) * MODULE_SPACING + 0x1000 + idx * 8 % 0x4000)
+        .collect();
+
+    // Rebuilding from scratch after every registration
+    let start = Instant::now();
+    for count in 1..=MODULES {
+        let index = AddressSpaceIndex::new(
+            sections[..count]
+                .iter()
+                .enumerate()
+                .map(|(idx, section)| module(section, idx)),
+        )
+        .unwrap();
+        assert!(!index.is_empty());
+    }
+    report("rebuild: register", start.elapsed(), MODULES);
+
+    let mut index = IncrementalAddressSpaceIndex::new();
+    let start = Instant::now();
+    let ids: Vec<_> = sections
+        .iter()
+        .enumerate()
+        .map(|(idx, section)| index.register(module(section, idx)).unwrap())
+        .collect();
+    report("incremental: register", start.elapsed(), MODULES);
+
+    let start = Instant::now();
+    let found: usize = pcs.iter().map(|&pc| index.records_at(pc).len()).sum();
+    report("incremental: lookup", start.elapsed(), LOOKUPS);
+
+    // Unregister and register every other module, with lookups in between
+    let start = Instant::now();
+    for (idx, &id) in ids.iter().enumerate().step_by(2) {
+        index.unregister(id);
+        index.records_at(pcs[idx % LOOKUPS]);
+        index.register(module(&sections[idx], idx)).unwrap();
+    }
+    report("incremental: churn", start.elapsed(), MODULES / 2);
+
+    index.compact();
+    let start = Instant::now();
+    let found_after: usize = pcs.iter().map(|&pc| index.records_at(pc).len()).sum();
+    report(
+        "incremental: lookup after compaction",
+        start.elapsed(),
+        LOOKUPS,
+    );
+    assert_eq!(found, found_after);
+}
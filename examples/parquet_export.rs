@@ -0,0 +1,72 @@
+use std::{env, fs, path::Path, process};
+
+use anyhow::Context;
+use fallible_iterator::FallibleIterator;
+use stackmap::{
+    addresses::{AddressMode, AddressReporting},
+    columnar::ParquetTableWriter,
+    loader::{self, StackMapsSource},
+    LLVMStackMaps,
+};
+
+fn export_binary<W: std::io::Write + Send>(
+    path: &str,
+    address_mode: AddressMode,
+    writer: &mut ParquetTableWriter<W>,
+) -> anyhow::Result<()> {
+    let file_data = fs::read(Path::new(path)).context("Could not read binary file")?;
+    let stack_maps_data = loader::load_stack_maps_data(&file_data, &StackMapsSource::default())
+        .context("Could not load stack maps from object")?;
+    let mut reporting = AddressReporting::new(address_mode);
+    reporting.sections =
+        loader::load_code_sections(&file_data).context("Could not read object sections")?;
+
+    let section = LLVMStackMaps::new(&stack_maps_data);
+    let mut stack_maps_iter = section.stack_maps().enumerate();
+    while let Some((stack_map_idx, stack_map)) = stack_maps_iter.next()? {
+        let mut rows = stack_map.flatten()?;
+        for row in &mut rows {
+            row.report_addresses(&reporting);
+        }
+        writer.write(path, stack_map_idx as u32, &rows)?;
+    }
+
+    Ok(())
+}
+
+// Writes the flattened records of every object given on the command line to a
+// single Parquet file, e.g. to query a corpus of binaries with DuckDB:
+//
+//   parquet-export records.parquet build/*.o
+//
+// Function addresses and PCs are linked addresses, unless reported relative to
+// their section or function with `--address-mode=section|function` first.
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1).peekable();
+    let mut address_mode = AddressMode::Absolute;
+    if let Some(mode) = args
+        .peek()
+        .and_then(|arg| arg.strip_prefix("--address-mode="))
+    {
+        address_mode = mode.parse()?;
+        args.next();
+    }
+    let output_path = match args.next() {
+        Some(output_path) => output_path,
+        None => {
+            eprintln!("usage: parquet-export [--address-mode=<mode>] <output.parquet> <binary>...");
+            process::exit(2);
+        }
+    };
+
+    let output = fs::File::create(&output_path).context("Could not create output file")?;
+    let mut writer = ParquetTableWriter::new(output)?;
+    for path in args {
+        if let Err(error) = export_binary(&path, address_mode, &mut writer) {
+            eprintln!("{}: {:#}", path, error);
+        }
+    }
+    writer.close()?;
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use std::{env, path::Path, process};
+
+use stackmap::differential;
+
+// Compares the stack maps of every object given on the command line with the
+// output of `llvm-readobj --stackmap`, e.g. to run over a corpus of binaries.
+fn main() {
+    let mut failed = false;
+    for path in env::args().skip(1) {
+        match differential::diff_against_readobj(Path::new(&path)) {
+            Ok(mismatches) if mismatches.is_empty() => println!("{}: ok", path),
+            Ok(mismatches) => {
+                failed = true;
+                println!("{}: {} mismatches", path, mismatches.len());
+                for mismatch in mismatches {
+                    println!("  {}", mismatch);
+                }
+            }
+            Err(error) => {
+                failed = true;
+                println!("{}: error: {}", path, error);
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
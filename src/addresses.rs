@@ -0,0 +1,128 @@
+// How the outputs report where functions and records are. Stack maps give the
+// linked address of each function and the offset of each record in its
+// function, which tools want as absolute runtime addresses, as offsets in the
+// code section (stable across load addresses and relinking of other sections),
+// or as offsets in the function (stable across relinking at all).
+
+use alloc::{borrow::ToOwned, collections::BTreeMap, string::String};
+use core::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressMode {
+    // Records at their offset in their function, and functions at their linked
+    // address, which is what the offsets are relative to
+    Function,
+    // Functions and records at their offset from the start of their section
+    Section,
+    // Linked addresses plus the bias, e.g. the load bias of a PIE
+    #[default]
+    Absolute,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAddressModeError {
+    input: String,
+}
+
+impl core::fmt::Display for ParseAddressModeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Unknown address mode: {} (expected function, section or absolute)",
+            self.input
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseAddressModeError {}
+
+impl FromStr for AddressMode {
+    type Err = ParseAddressModeError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "function" => Ok(AddressMode::Function),
+            "section" => Ok(AddressMode::Section),
+            "absolute" => Ok(AddressMode::Absolute),
+            _ => Err(ParseAddressModeError {
+                input: input.to_owned(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressReporting {
+    pub mode: AddressMode,
+    pub bias: u64,
+    // Linked address ranges of the code sections, start to end (excluded).
+    // Addresses outside of all of them are relative to 0 in `Section` mode.
+    pub sections: BTreeMap<u64, u64>,
+}
+
+impl AddressReporting {
+    pub fn new(mode: AddressMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    fn section_start(&self, address: u64) -> u64 {
+        match self.sections.range(..=address).next_back() {
+            Some((&start, &end)) if address < end => start,
+            _ => 0,
+        }
+    }
+
+    /// Reports the function linked at `address`.
+    pub fn function(&self, address: u64) -> u64 {
+        match self.mode {
+            AddressMode::Function => address,
+            AddressMode::Section => address - self.section_start(address),
+            AddressMode::Absolute => address.wrapping_add(self.bias),
+        }
+    }
+
+    /// Reports the record at `instruction_offset` in the function linked at
+    /// `function_address`.
+    pub fn record(&self, function_address: u64, instruction_offset: u64) -> u64 {
+        let pc = function_address.wrapping_add(instruction_offset);
+        match self.mode {
+            AddressMode::Function => instruction_offset,
+            // Relative to the section of the function, which the record
+            // belongs to even at the very end of the section
+            AddressMode::Section => pc.wrapping_sub(self.section_start(function_address)),
+            AddressMode::Absolute => pc.wrapping_add(self.bias),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        let mut reporting = AddressReporting {
+            mode: AddressMode::Absolute,
+            bias: 0x5555_0000_0000,
+            sections: vec![(0x1000, 0x2000)].into_iter().collect(),
+        };
+        assert_eq!(reporting.function(0x1130), 0x5555_0000_1130);
+        assert_eq!(reporting.record(0x1130, 0x20), 0x5555_0000_1150);
+
+        reporting.mode = AddressMode::Section;
+        assert_eq!(reporting.function(0x1130), 0x130);
+        assert_eq!(reporting.record(0x1130, 0x20), 0x150);
+        assert_eq!(reporting.function(0x3000), 0x3000);
+
+        reporting.mode = AddressMode::Function;
+        assert_eq!(reporting.function(0x1130), 0x1130);
+        assert_eq!(reporting.record(0x1130, 0x20), 0x20);
+
+        assert_eq!("section".parse(), Ok(AddressMode::Section));
+        assert!("relative".parse::<AddressMode>().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+// Redaction of what stack maps reveal about the layout of a binary, so that
+// the sections of proprietary binaries can be attached to bug reports. The
+// structure is preserved: counts, stack sizes, instruction offsets, IDs,
+// live-outs and locations other than constants are kept, distinct function
+// addresses stay distinct and in the same order, and equal constants stay
+// equal, inline if they were inline and in the pool otherwise.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    str::FromStr,
+};
+
+use fallible_iterator::FallibleIterator;
+use snafu::Snafu;
+
+use crate::{
+    generate::Rng,
+    owned::{Function, Record, StackMap},
+    transform::{self, Action, Transform},
+    Error, LLVMStackMaps, Location, LocationKind,
+};
+
+// First stripped address, and distance between stripped addresses
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    Keep,
+    // Replaced by placeholders: increasing page addresses, zero for inline
+    // constants and 2^32 onwards for pool constants
+    Strip,
+    // Replaced by random values drawn from the seed
+    Randomize,
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseRedactionError {
+    #[snafu(display("Unknown redaction: {} (expected keep, strip or randomize)", name))]
+    UnknownRedaction { name: String },
+}
+
+impl FromStr for Redaction {
+    type Err = ParseRedactionError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keep" => Ok(Redaction::Keep),
+            "strip" => Ok(Redaction::Strip),
+            "randomize" => Ok(Redaction::Randomize),
+            _ => UnknownRedaction { name }.fail(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnonymizeOptions {
+    pub addresses: Redaction,
+    pub constants: Redaction,
+    pub seed: u64,
+}
+
+impl Default for AnonymizeOptions {
+    fn default() -> Self {
+        Self {
+            addresses: Redaction::Strip,
+            constants: Redaction::Strip,
+            seed: 0,
+        }
+    }
+}
+
+fn is_inline(constant: u64) -> bool {
+    i32::try_from(constant as i64).is_ok()
+}
+
+// Replacements of the function addresses and constants, the same for all the
+// stack maps of a section
+struct Anonymizer {
+    addresses: BTreeMap<u64, u64>,
+    constants: BTreeMap<u64, u64>,
+}
+
+impl Anonymizer {
+    fn new(
+        addresses: &BTreeSet<u64>,
+        constants: &BTreeSet<u64>,
+        options: &AnonymizeOptions,
+    ) -> Self {
+        let mut rng = Rng(options.seed);
+
+        let mut next_address = match options.addresses {
+            Redaction::Randomize => (rng.below(1 << 28) + 1) * PAGE_SIZE,
+            _ => PAGE_SIZE,
+        };
+        let addresses = addresses
+            .iter()
+            .map(|&address| {
+                let replacement = match options.addresses {
+                    Redaction::Keep => address,
+                    Redaction::Strip => {
+                        let replacement = next_address;
+                        next_address += PAGE_SIZE;
+                        replacement
+                    }
+                    Redaction::Randomize => {
+                        let replacement = next_address;
+                        next_address += (rng.below(16) + 1) * PAGE_SIZE;
+                        replacement
+                    }
+                };
+                (address, replacement)
+            })
+            .collect();
+
+        // Pool constants must not be merged, so their replacements are unique
+        let mut used = BTreeSet::new();
+        let constants = constants
+            .iter()
+            .map(|&constant| {
+                let replacement = match (options.constants, is_inline(constant)) {
+                    (Redaction::Keep, _) => constant,
+                    (Redaction::Strip, true) => 0,
+                    (Redaction::Strip, false) => (1 << 32) + used.len() as u64,
+                    (Redaction::Randomize, true) => rng.next() as i32 as u64,
+                    (Redaction::Randomize, false) => loop {
+                        let replacement = (rng.next() | 1 << 62) & !(1 << 63);
+                        if !used.contains(&replacement) {
+                            break replacement;
+                        }
+                    },
+                };
+                if !is_inline(constant) {
+                    used.insert(replacement);
+                }
+                (constant, replacement)
+            })
+            .collect();
+
+        Self {
+            addresses,
+            constants,
+        }
+    }
+
+    fn from_stack_maps<'a, F: 'a, R: 'a>(
+        stack_maps: impl IntoIterator<Item = &'a StackMap<F, R>>,
+        options: &AnonymizeOptions,
+    ) -> Self {
+        let mut addresses = BTreeSet::new();
+        let mut constants = BTreeSet::new();
+        for stack_map in stack_maps {
+            constants.extend(stack_map.constants.iter().copied());
+            for function in &stack_map.functions {
+                addresses.insert(function.address);
+                for record in &function.records {
+                    for location in &record.locations {
+                        if let LocationKind::Constant(constant) = *location.kind() {
+                            constants.insert(constant);
+                        }
+                    }
+                }
+            }
+        }
+        Self::new(&addresses, &constants, options)
+    }
+
+    fn apply<F, R>(&mut self, stack_map: &mut StackMap<F, R>) {
+        for constant in &mut stack_map.constants {
+            *constant = self.constants[&*constant];
+        }
+        transform::apply(stack_map, self);
+    }
+}
+
+impl<F, R> Transform<F, R> for Anonymizer {
+    fn function(&mut self, function: &mut Function<F, R>) -> Action {
+        function.address = self.addresses[&function.address];
+        Action::Keep
+    }
+
+    fn location(&mut self, _record: &Record<R>, location: &mut Location) -> Action {
+        if let LocationKind::Constant(constant) = *location.kind() {
+            let size = location.size() as u16;
+            *location = Location::new(LocationKind::Constant(self.constants[&constant]), size);
+        }
+        Action::Keep
+    }
+}
+
+/// Redacts the function addresses and constants of `stack_map` in place.
+pub fn anonymize<F, R>(stack_map: &mut StackMap<F, R>, options: &AnonymizeOptions) {
+    let mut anonymizer = Anonymizer::from_stack_maps(std::iter::once(&*stack_map), options);
+    anonymizer.apply(stack_map);
+}
+
+/// Redacts all the stack maps of `section` consistently, so that a function
+/// found in several of them gets the same address in all, and serializes the
+/// results back to back.
+pub fn anonymize_section(
+    section: &LLVMStackMaps,
+    options: &AnonymizeOptions,
+) -> Result<Vec<u8>, Error> {
+    let mut stack_maps: Vec<StackMap> = section
+        .stack_maps()
+        .map(|stack_map| StackMap::from_parsed(&stack_map))
+        .collect()?;
+    let mut anonymizer = Anonymizer::from_stack_maps(&stack_maps, options);
+
+    let mut output = Vec::new();
+    for stack_map in &mut stack_maps {
+        anonymizer.apply(stack_map);
+        stack_map.write_to(&mut output)?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    fn anonymized(options: &AnonymizeOptions) -> StackMap {
+        let output =
+            anonymize_section(&LLVMStackMaps::new(test_data::TWO_FUNCTIONS), options).unwrap();
+        assert_eq!(output.len(), test_data::TWO_FUNCTIONS.len());
+        let section = LLVMStackMaps::new(&output);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        StackMap::from_parsed(&stack_map).unwrap()
+    }
+
+    fn constants(stack_map: &StackMap) -> Vec<u64> {
+        stack_map.functions[0].records[0]
+            .locations
+            .iter()
+            .filter_map(|location| location.constant())
+            .map(|constant| constant.as_u64())
+            .collect()
+    }
+
+    #[test]
+    fn strip() {
+        let original = {
+            let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+            let stack_map = section.stack_maps().next().unwrap().unwrap();
+            StackMap::<(), ()>::from_parsed(&stack_map).unwrap()
+        };
+        let stack_map = anonymized(&AnonymizeOptions::default());
+
+        let addresses: Vec<_> = stack_map.functions.iter().map(|f| f.address).collect();
+        assert_eq!(addresses, [0x1000, 0x2000]);
+        assert_eq!(stack_map.constants, [1 << 32]);
+        assert_eq!(constants(&stack_map), [0, 1 << 32]);
+        for (function, original) in stack_map.functions.iter().zip(&original.functions) {
+            assert_eq!(function.stack_size, original.stack_size);
+            assert_eq!(function.records.len(), original.records.len());
+        }
+
+        let kept = anonymized(&AnonymizeOptions {
+            addresses: Redaction::Keep,
+            constants: Redaction::Keep,
+            seed: 0,
+        });
+        assert_eq!(kept, original);
+    }
+
+    #[test]
+    fn randomize() {
+        let options = AnonymizeOptions {
+            addresses: Redaction::Randomize,
+            constants: Redaction::Randomize,
+            seed: 42,
+        };
+        let stack_map = anonymized(&options);
+        assert_eq!(anonymized(&options), stack_map);
+
+        let (first, second) = (&stack_map.functions[0], &stack_map.functions[1]);
+        assert!(first.address < second.address);
+        assert_eq!(first.address % PAGE_SIZE, 0);
+        assert_ne!(first.address, 0x1130);
+
+        let constants = constants(&stack_map);
+        assert!(is_inline(constants[0]));
+        assert!(!is_inline(constants[1]));
+        assert_ne!(constants[1], 1234567890123);
+        assert_eq!(stack_map.constants, [constants[1]]);
+    }
+}
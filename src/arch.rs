@@ -0,0 +1,221 @@
+// Architecture-specific interpretation of live-outs. Live-outs name a DWARF
+// register and how many of its bytes are live, which may be fewer than the
+// register holds, e.g. a 4-byte live-out of an x86-64 general purpose register
+// is its `eax`-like sub-register. Trampolines still save and restore whole
+// registers, so both the sub-register and the full register are given.
+
+use alloc::{format, string::String};
+
+use crate::{DwarfRegNum, LiveOut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    AArch64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveRegister {
+    // The (sub)register holding the live bytes, e.g. `eax` or `w0`
+    pub name: String,
+    // The full register it belongs to, e.g. `rax` or `x0`
+    pub register: String,
+    pub size: u8,
+    pub register_size: u8,
+}
+
+impl LiveRegister {
+    pub fn is_partial(&self) -> bool {
+        self.size < self.register_size
+    }
+}
+
+const X86_64_GPRS: [&str; 16] = [
+    "ax", "dx", "cx", "bx", "si", "di", "bp", "sp", "r8", "r9", "r10", "r11", "r12", "r13", "r14",
+    "r15",
+];
+
+// Names of the sub-registers of the x86-64 general purpose registers, which
+// follow two patterns: the legacy registers and R8-R15
+fn x86_64_gpr(index: usize, size: u8) -> Option<String> {
+    let base = X86_64_GPRS[index];
+    let name = if index < 8 {
+        match size {
+            1 if index < 4 => format!("{}l", &base[..1]),
+            1 => format!("{}l", base),
+            2 => base.into(),
+            4 => format!("e{}", base),
+            8 => format!("r{}", base),
+            _ => return None,
+        }
+    } else {
+        match size {
+            1 => format!("{}b", base),
+            2 => format!("{}w", base),
+            4 => format!("{}d", base),
+            8 => base.into(),
+            _ => return None,
+        }
+    };
+    Some(name)
+}
+
+// XMM0-15 are DWARF registers 17-32, and XMM16-31 of AVX-512 are 67-82. The
+// live bytes of a vector register are named after the smallest of the XMM, YMM
+// and ZMM registers that holds them.
+fn x86_64_vector_index(register: DwarfRegNum) -> Option<u16> {
+    match register {
+        17..=32 => Some(register - 17),
+        67..=82 => Some(register - 67 + 16),
+        _ => None,
+    }
+}
+
+fn x86_64(live_out: &LiveOut) -> Option<LiveRegister> {
+    let register = live_out.dwarf_reg_num();
+    let size = live_out.size() as u8;
+    if register < 16 {
+        return Some(LiveRegister {
+            name: x86_64_gpr(register as usize, size)?,
+            register: x86_64_gpr(register as usize, 8)?,
+            size,
+            register_size: 8,
+        });
+    }
+
+    let index = x86_64_vector_index(register)?;
+    let (prefix, register_size) = match size {
+        1..=16 => ("xmm", 16),
+        17..=32 => ("ymm", 32),
+        33..=64 => ("zmm", 64),
+        _ => return None,
+    };
+    Some(LiveRegister {
+        name: format!("{}{}", prefix, index),
+        register: format!("{}{}", prefix, index),
+        size,
+        register_size,
+    })
+}
+
+// X0-X30 and SP are DWARF registers 0-31, and V0-V31 are 64-95
+fn aarch64(live_out: &LiveOut) -> Option<LiveRegister> {
+    let register = live_out.dwarf_reg_num();
+    let size = live_out.size() as u8;
+    match register {
+        0..=30 => {
+            let prefix = match size {
+                4 => "w",
+                8 => "x",
+                _ => return None,
+            };
+            Some(LiveRegister {
+                name: format!("{}{}", prefix, register),
+                register: format!("x{}", register),
+                size,
+                register_size: 8,
+            })
+        }
+        31 => {
+            let name = match size {
+                4 => "wsp",
+                8 => "sp",
+                _ => return None,
+            };
+            Some(LiveRegister {
+                name: name.into(),
+                register: "sp".into(),
+                size,
+                register_size: 8,
+            })
+        }
+        64..=95 => {
+            let prefix = match size {
+                1 => "b",
+                2 => "h",
+                4 => "s",
+                8 => "d",
+                16 => "q",
+                _ => return None,
+            };
+            Some(LiveRegister {
+                name: format!("{}{}", prefix, register - 64),
+                register: format!("v{}", register - 64),
+                size,
+                register_size: 16,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Arch {
+    /// Maps `live_out` to the (sub)register holding its live bytes, or `None`
+    /// if its register or size is not valid for the architecture.
+    pub fn live_register(self, live_out: &LiveOut) -> Option<LiveRegister> {
+        match self {
+            Arch::X86_64 => x86_64(live_out),
+            Arch::AArch64 => aarch64(live_out),
+        }
+    }
+
+    /// Returns the set of full registers to save for `live_outs`, with the
+    /// bit of each DWARF register number set. Live-outs that are not valid
+    /// for the architecture are left out.
+    pub fn register_mask<'a>(self, live_outs: impl IntoIterator<Item = &'a LiveOut>) -> u128 {
+        live_outs
+            .into_iter()
+            .filter(|live_out| self.live_register(live_out).is_some())
+            .fold(0, |mask, live_out| mask | 1u128 << live_out.dwarf_reg_num())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(arch: Arch, register: DwarfRegNum, size: u8) -> Option<(String, String)> {
+        arch.live_register(&LiveOut::new(register, size))
+            .map(|live| (live.name, live.register))
+    }
+
+    #[test]
+    fn x86_64_registers() {
+        let pair = |name: &str, register: &str| Some((name.into(), register.into()));
+        assert_eq!(names(Arch::X86_64, 0, 4), pair("eax", "rax"));
+        assert_eq!(names(Arch::X86_64, 3, 1), pair("bl", "rbx"));
+        assert_eq!(names(Arch::X86_64, 4, 1), pair("sil", "rsi"));
+        assert_eq!(names(Arch::X86_64, 6, 2), pair("bp", "rbp"));
+        assert_eq!(names(Arch::X86_64, 12, 4), pair("r12d", "r12"));
+        assert_eq!(names(Arch::X86_64, 7, 8), pair("rsp", "rsp"));
+        assert_eq!(names(Arch::X86_64, 17, 16), pair("xmm0", "xmm0"));
+        assert_eq!(names(Arch::X86_64, 18, 32), pair("ymm1", "ymm1"));
+        assert_eq!(names(Arch::X86_64, 67, 64), pair("zmm16", "zmm16"));
+        assert_eq!(names(Arch::X86_64, 0, 3), None);
+        assert_eq!(names(Arch::X86_64, 16, 8), None);
+
+        let live = Arch::X86_64.live_register(&LiveOut::new(0, 4)).unwrap();
+        assert!(live.is_partial());
+        let live_outs = [
+            LiveOut::new(0, 4),
+            LiveOut::new(17, 16),
+            LiveOut::new(16, 8),
+        ];
+        assert_eq!(Arch::X86_64.register_mask(&live_outs), 1 | 1 << 17);
+    }
+
+    #[test]
+    fn aarch64_registers() {
+        let pair = |name: &str, register: &str| Some((name.into(), register.into()));
+        assert_eq!(names(Arch::AArch64, 0, 4), pair("w0", "x0"));
+        assert_eq!(names(Arch::AArch64, 30, 8), pair("x30", "x30"));
+        assert_eq!(names(Arch::AArch64, 31, 8), pair("sp", "sp"));
+        assert_eq!(names(Arch::AArch64, 65, 8), pair("d1", "v1"));
+        assert_eq!(names(Arch::AArch64, 95, 16), pair("q31", "v31"));
+        assert_eq!(names(Arch::AArch64, 64, 3), None);
+        assert_eq!(
+            Arch::AArch64.register_mask(&[LiveOut::new(95, 16)]),
+            1 << 95
+        );
+    }
+}
@@ -0,0 +1,231 @@
+// Stack budgets checked against a baseline stored from a previous build.
+// Functions are keyed by symbol name, since their addresses change between
+// builds, and only the growth of their stack size and the patchpoints that
+// disappeared are violations: new functions and records are fine. Functions
+// found on one side only are listed apart.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{symbols::FunctionSymbols, Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "json",
+    derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)
+)]
+pub struct Baseline {
+    // Stack size of each function, by symbol name or by hexadecimal address
+    // for functions without a symbol
+    pub stack_sizes: BTreeMap<String, u64>,
+    pub patch_point_ids: BTreeSet<u64>,
+}
+
+impl Baseline {
+    /// Collects the stack sizes and patchpoint IDs of `section`. Functions
+    /// sharing a name, such as static functions of different files, get the
+    /// largest of their stack sizes.
+    pub fn new(section: &LLVMStackMaps, symbols: &FunctionSymbols) -> Result<Self, Error> {
+        let mut baseline = Self::default();
+        let mut stack_maps_iter = section.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                let key = match symbols.name(function.address()) {
+                    Some(name) => name.to_owned(),
+                    None => format!("{:#x}", function.address()),
+                };
+                let stack_size = baseline.stack_sizes.entry(key).or_insert(0);
+                *stack_size = (*stack_size).max(function.stack_size() as u64);
+
+                let mut records_iter = function.records();
+                while let Some(record) = records_iter.next()? {
+                    baseline.patch_point_ids.insert(record.patch_point_id());
+                }
+            }
+        }
+        Ok(baseline)
+    }
+
+    /// Compares `current` against the baseline. Stack sizes may grow by
+    /// whichever of the limits allows more, and not at all without limits.
+    pub fn check(&self, current: &Baseline, limits: &GrowthLimits) -> Vec<BaselineViolation> {
+        let mut violations = Vec::new();
+        for (function, &baseline) in &self.stack_sizes {
+            let current = match current.stack_sizes.get(function) {
+                Some(&current) => current,
+                None => continue,
+            };
+            if current.saturating_sub(baseline) > limits.allowed_growth(baseline) {
+                violations.push(BaselineViolation::StackGrowth {
+                    function: function.clone(),
+                    baseline,
+                    current,
+                });
+            }
+        }
+
+        violations.extend(
+            self.patch_point_ids
+                .difference(&current.patch_point_ids)
+                .map(|&id| BaselineViolation::MissingPatchPoint { id }),
+        );
+        violations
+    }
+
+    /// Lists the functions that are only in the baseline or only in
+    /// `current`, which `check` cannot compare.
+    pub fn function_changes(&self, current: &Baseline) -> FunctionChanges {
+        let mut changes = FunctionChanges::default();
+        for function in self.stack_sizes.keys() {
+            if !current.stack_sizes.contains_key(function) {
+                if is_address_key(function) {
+                    changes.unmatched.push(function.clone());
+                } else {
+                    changes.removed.push(function.clone());
+                }
+            }
+        }
+        for function in current.stack_sizes.keys() {
+            if !self.stack_sizes.contains_key(function) {
+                if is_address_key(function) {
+                    changes.unmatched.push(function.clone());
+                } else {
+                    changes.added.push(function.clone());
+                }
+            }
+        }
+        changes
+    }
+}
+
+// Symbol names never start with a digit
+fn is_address_key(function: &str) -> bool {
+    function.starts_with("0x")
+}
+
+// Functions found on one side only. Those without a symbol are keyed by their
+// address, which changes between builds, so they are unmatched rather than
+// added or removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub unmatched: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GrowthLimits {
+    pub max_bytes: Option<u64>,
+    // Relative to the stack size in the baseline
+    pub max_percent: Option<f64>,
+}
+
+impl GrowthLimits {
+    fn allowed_growth(&self, baseline: u64) -> u64 {
+        let percent = self
+            .max_percent
+            .map_or(0, |percent| (baseline as f64 * percent / 100.0) as u64);
+        self.max_bytes.unwrap_or(0).max(percent)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BaselineViolation {
+    StackGrowth {
+        function: String,
+        baseline: u64,
+        current: u64,
+    },
+    // No record has this ID anymore
+    MissingPatchPoint {
+        id: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{symbols::FunctionSymbol, test_data};
+
+    fn baseline(stack_sizes: &[(&str, u64)], patch_point_ids: &[u64]) -> Baseline {
+        Baseline {
+            stack_sizes: stack_sizes
+                .iter()
+                .map(|&(name, size)| (name.to_owned(), size))
+                .collect(),
+            patch_point_ids: patch_point_ids.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn from_section() {
+        let symbols: FunctionSymbols = vec![(
+            0x1130,
+            FunctionSymbol {
+                name: "foo".to_owned(),
+                size: 0x40,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        assert_eq!(
+            Baseline::new(&section, &symbols).unwrap(),
+            baseline(&[("foo", 40), ("0x1170", 8)], &[42, 43, 44])
+        );
+    }
+
+    #[test]
+    fn violations() {
+        let old = baseline(&[("foo", 100), ("bar", 8), ("gone", 8)], &[1, 2]);
+        let new = baseline(&[("foo", 120), ("bar", 8), ("added", 800)], &[2, 3]);
+
+        assert_eq!(
+            old.check(&new, &GrowthLimits::default()),
+            [
+                BaselineViolation::StackGrowth {
+                    function: "foo".to_owned(),
+                    baseline: 100,
+                    current: 120,
+                },
+                BaselineViolation::MissingPatchPoint { id: 1 },
+            ]
+        );
+
+        let limits = GrowthLimits {
+            max_bytes: Some(16),
+            max_percent: Some(20.0),
+        };
+        assert_eq!(
+            old.check(&new, &limits),
+            [BaselineViolation::MissingPatchPoint { id: 1 }]
+        );
+        let limits = GrowthLimits {
+            max_bytes: Some(16),
+            max_percent: None,
+        };
+        assert_eq!(old.check(&new, &limits).len(), 2);
+    }
+
+    #[test]
+    fn function_changes() {
+        let old = baseline(
+            &[("foo", 8), ("gone", 8), ("0x1170", 8), ("0x1200", 8)],
+            &[],
+        );
+        let new = baseline(
+            &[("foo", 8), ("added", 8), ("0x1170", 8), ("0x2200", 8)],
+            &[],
+        );
+        assert_eq!(
+            old.function_changes(&new),
+            FunctionChanges {
+                added: vec!["added".to_owned()],
+                removed: vec!["gone".to_owned()],
+                unmatched: vec!["0x1200".to_owned(), "0x2200".to_owned()],
+            }
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Absolute limits on the stack maps of a build, for build systems enforcing a
+// policy without a baseline to compare against. Unlike the baseline checks,
+// every violation is reported with the function or record it concerns.
+
+use std::collections::BTreeSet;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "json",
+    derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema),
+    serde(default)
+)]
+pub struct BudgetConfig {
+    pub max_stack_size: Option<u64>,
+    pub max_locations_per_record: Option<usize>,
+    // Patchpoint IDs that at least one record must have
+    pub required_ids: BTreeSet<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetViolation {
+    StackSize {
+        function_address: u64,
+        stack_size: u64,
+        limit: u64,
+    },
+    Locations {
+        function_address: u64,
+        instruction_offset: u64,
+        patch_point_id: u64,
+        num_locations: usize,
+        limit: usize,
+    },
+    MissingId {
+        id: u64,
+    },
+}
+
+impl BudgetConfig {
+    /// Checks every function and record of `section` against the limits, in
+    /// section order, followed by the required IDs that were not found.
+    pub fn check(&self, section: &LLVMStackMaps) -> Result<Vec<BudgetViolation>, Error> {
+        let mut violations = Vec::new();
+        let mut missing_ids = self.required_ids.clone();
+
+        let mut stack_maps_iter = section.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                let stack_size = function.stack_size() as u64;
+                if let Some(limit) = self.max_stack_size.filter(|&limit| stack_size > limit) {
+                    violations.push(BudgetViolation::StackSize {
+                        function_address: function.address(),
+                        stack_size,
+                        limit,
+                    });
+                }
+
+                let mut records_iter = function.records();
+                while let Some(record) = records_iter.next()? {
+                    missing_ids.remove(&record.patch_point_id());
+
+                    let num_locations = record.num_locations();
+                    let limit = self
+                        .max_locations_per_record
+                        .filter(|&limit| num_locations > limit);
+                    if let Some(limit) = limit {
+                        violations.push(BudgetViolation::Locations {
+                            function_address: function.address(),
+                            instruction_offset: record.instruction_offset() as u64,
+                            patch_point_id: record.patch_point_id(),
+                            num_locations,
+                            limit,
+                        });
+                    }
+                }
+            }
+        }
+
+        violations.extend(
+            missing_ids
+                .into_iter()
+                .map(|id| BudgetViolation::MissingId { id }),
+        );
+        Ok(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn violations() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        assert!(BudgetConfig::default().check(&section).unwrap().is_empty());
+
+        let config = BudgetConfig {
+            max_stack_size: Some(32),
+            max_locations_per_record: Some(1),
+            required_ids: vec![42, 99].into_iter().collect(),
+        };
+        assert_eq!(
+            config.check(&section).unwrap(),
+            [
+                BudgetViolation::StackSize {
+                    function_address: 0x1130,
+                    stack_size: 40,
+                    limit: 32,
+                },
+                BudgetViolation::Locations {
+                    function_address: 0x1130,
+                    instruction_offset: 0x20,
+                    patch_point_id: 42,
+                    num_locations: 4,
+                    limit: 1,
+                },
+                BudgetViolation::MissingId { id: 99 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+// Experimental synthesis of call frame information from stack maps, so that
+// functions compiled without frame pointers or unwind tables can be unwound
+// through at least at their safepoints. At the return address of a call, the
+// stack pointer is the one of the function body, which puts the CFA at the
+// stack size plus the return address above it.
+//
+// Only the instructions around each record are described, one FDE each: the
+// stack pointer is unknown elsewhere. FDEs refer to the functions by symbol,
+// so the object can be linked with the code it describes, and functions
+// without a symbol or with a dynamically sized frame are left out. Where
+// callee-saved registers were saved is not known either, so they are assumed
+// to be unchanged.
+
+use std::{collections::BTreeMap, convert::TryInto};
+
+use fallible_iterator::FallibleIterator;
+use gimli::{
+    write::{
+        Address, CallFrameInstruction, CommonInformationEntry, DebugFrame, EhFrame, EndianVec,
+        FrameDescriptionEntry, FrameTable, Writer,
+    },
+    Encoding, Format, LittleEndian, Register, SectionId,
+};
+use object::{
+    write::{Object, Relocation, Symbol, SymbolSection},
+    Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationKind, SectionKind,
+    SymbolFlags, SymbolKind, SymbolScope,
+};
+use snafu::{ResultExt, Snafu};
+
+use crate::{classify::RegisterConventions, symbols::FunctionSymbols, Error, LLVMStackMaps};
+
+// Return address column of the x86-64 DWARF register mapping
+pub const X86_64_RETURN_ADDRESS_REGISTER: u16 = 16;
+
+// LLVM records this stack size for functions with variable sized objects
+const DYNAMIC_STACK_SIZE: u64 = u64::MAX;
+
+#[derive(Debug, Snafu)]
+pub enum CfiError {
+    #[snafu(display("Could not encode CFI: {}", source))]
+    GimliError { source: gimli::write::Error },
+    #[snafu(display("Could not write object: {}", source))]
+    ObjectError { source: object::write::Error },
+    #[snafu(display("CFA offset {} of {}+{:#x} does not fit", cfa_offset, symbol, offset))]
+    CfaOffsetOverflow {
+        symbol: String,
+        offset: u64,
+        cfa_offset: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfiFormat {
+    EhFrame,
+    DebugFrame,
+}
+
+impl CfiFormat {
+    pub fn section_name(self) -> &'static str {
+        match self {
+            CfiFormat::EhFrame => ".eh_frame",
+            CfiFormat::DebugFrame => ".debug_frame",
+        }
+    }
+}
+
+// Frame state at the return address of a record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafepointFrame {
+    pub symbol: String,
+    // From the start of the symbol to the return address
+    pub offset: u64,
+    // From the stack pointer to the CFA
+    pub cfa_offset: u64,
+}
+
+/// Collects the frame state at every distinct record address of `section`,
+/// in address order, for the functions that can be described.
+pub fn safepoint_frames(
+    section: &LLVMStackMaps,
+    symbols: &FunctionSymbols,
+    conventions: &RegisterConventions,
+) -> Result<Vec<SafepointFrame>, Error> {
+    let mut frames = BTreeMap::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let stack_size = function.stack_size() as u64;
+            let name = match symbols.name(function.address()) {
+                Some(name) if stack_size != DYNAMIC_STACK_SIZE => name,
+                _ => continue,
+            };
+
+            for offset in function.records_by_offset()?.into_keys() {
+                let pc = function.address().wrapping_add(offset as u64);
+                frames.entry(pc).or_insert_with(|| SafepointFrame {
+                    symbol: name.to_owned(),
+                    offset: offset as u64,
+                    cfa_offset: stack_size + conventions.return_address_size,
+                });
+            }
+        }
+    }
+    Ok(frames.into_values().collect())
+}
+
+// Relocation against either a function symbol, by index in the list of
+// symbols, or the start of the CFI section
+#[derive(Debug, Clone, Copy)]
+enum RelocationTarget {
+    Symbol(usize),
+    Section,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CfiRelocation {
+    offset: usize,
+    size: u8,
+    kind: RelocationKind,
+    target: RelocationTarget,
+    addend: i64,
+}
+
+// Writer leaving relocated values as zeros and recording their relocations
+#[derive(Debug)]
+struct RelocatingWriter {
+    data: EndianVec<LittleEndian>,
+    relocations: Vec<CfiRelocation>,
+}
+
+impl RelocatingWriter {
+    fn new() -> Self {
+        Self {
+            data: EndianVec::new(LittleEndian),
+            relocations: Vec::new(),
+        }
+    }
+
+    fn relocate(
+        &mut self,
+        size: u8,
+        kind: RelocationKind,
+        target: RelocationTarget,
+        addend: i64,
+    ) -> gimli::write::Result<()> {
+        self.relocations.push(CfiRelocation {
+            offset: self.len(),
+            size,
+            kind,
+            target,
+            addend,
+        });
+        self.write_udata(0, size)
+    }
+}
+
+impl Writer for RelocatingWriter {
+    type Endian = LittleEndian;
+
+    fn endian(&self) -> Self::Endian {
+        LittleEndian
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(value) => self.write_udata(value, size),
+            Address::Symbol { symbol, addend } => self.relocate(
+                size,
+                RelocationKind::Absolute,
+                RelocationTarget::Symbol(symbol),
+                addend,
+            ),
+        }
+    }
+
+    fn write_eh_pointer(
+        &mut self,
+        address: Address,
+        eh_pe: gimli::DwEhPe,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        match address {
+            Address::Symbol { symbol, addend } if eh_pe == DW_EH_PE_PCREL_SDATA4 => self.relocate(
+                4,
+                RelocationKind::Relative,
+                RelocationTarget::Symbol(symbol),
+                addend,
+            ),
+            Address::Symbol { .. } => Err(gimli::write::Error::UnsupportedPointerEncoding(eh_pe)),
+            Address::Constant(_) => self.write_address(address, size),
+        }
+    }
+
+    fn write_offset(
+        &mut self,
+        value: usize,
+        _section: SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        // Offsets only ever point into the section being written
+        self.relocate(
+            size,
+            RelocationKind::Absolute,
+            RelocationTarget::Section,
+            value as i64,
+        )
+    }
+}
+
+const DW_EH_PE_PCREL_SDATA4: gimli::DwEhPe =
+    gimli::DwEhPe(gimli::DW_EH_PE_pcrel.0 | gimli::DW_EH_PE_sdata4.0);
+
+/// Builds an x86-64 ELF relocatable object with a CFI section in `format`,
+/// describing `frames`. The function symbols are left undefined, to be
+/// resolved when linking the object with the code.
+pub fn write_object(
+    frames: &[SafepointFrame],
+    conventions: &RegisterConventions,
+    format: CfiFormat,
+) -> Result<Vec<u8>, CfiError> {
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: match format {
+            CfiFormat::EhFrame => 1,
+            CfiFormat::DebugFrame => 4,
+        },
+        address_size: 8,
+    };
+    let return_address_size = conventions.return_address_size as i32;
+    let mut cie = CommonInformationEntry::new(
+        encoding,
+        1,
+        -(return_address_size as i8),
+        Register(X86_64_RETURN_ADDRESS_REGISTER),
+    );
+    if format == CfiFormat::EhFrame {
+        cie.fde_address_encoding = DW_EH_PE_PCREL_SDATA4;
+    }
+    cie.add_instruction(CallFrameInstruction::Offset(
+        Register(X86_64_RETURN_ADDRESS_REGISTER),
+        -return_address_size,
+    ));
+
+    let mut table = FrameTable::default();
+    let cie_id = table.add_cie(cie);
+    let mut symbol_names: Vec<&str> = Vec::new();
+    for frame in frames {
+        let cfa_offset =
+            frame
+                .cfa_offset
+                .try_into()
+                .ok()
+                .ok_or_else(|| CfiError::CfaOffsetOverflow {
+                    symbol: frame.symbol.clone(),
+                    offset: frame.offset,
+                    cfa_offset: frame.cfa_offset,
+                })?;
+        let symbol = match symbol_names.iter().position(|&name| name == frame.symbol) {
+            Some(symbol) => symbol,
+            None => {
+                symbol_names.push(&frame.symbol);
+                symbol_names.len() - 1
+            }
+        };
+
+        // Unwinders look up the byte before a return address, which is part
+        // of the call, so the range covers both
+        let mut fde = FrameDescriptionEntry::new(
+            Address::Symbol {
+                symbol,
+                addend: frame.offset as i64 - 1,
+            },
+            2,
+        );
+        fde.add_instruction(
+            0,
+            CallFrameInstruction::Cfa(Register(conventions.stack_pointer), cfa_offset),
+        );
+        table.add_fde(cie_id, fde);
+    }
+
+    let mut writer = RelocatingWriter::new();
+    match format {
+        CfiFormat::EhFrame => {
+            let mut eh_frame = EhFrame(writer);
+            table.write_eh_frame(&mut eh_frame).context(GimliError)?;
+            writer = eh_frame.0;
+        }
+        CfiFormat::DebugFrame => {
+            let mut debug_frame = DebugFrame(writer);
+            table
+                .write_debug_frame(&mut debug_frame)
+                .context(GimliError)?;
+            writer = debug_frame.0;
+        }
+    }
+
+    let mut object = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+    let section_kind = match format {
+        CfiFormat::EhFrame => SectionKind::ReadOnlyData,
+        CfiFormat::DebugFrame => SectionKind::Debug,
+    };
+    let section_id = object.add_section(
+        Vec::new(),
+        format.section_name().as_bytes().to_vec(),
+        section_kind,
+    );
+    object.append_section_data(section_id, writer.data.slice(), 8);
+
+    let symbol_ids: Vec<_> = symbol_names
+        .iter()
+        .map(|name| {
+            object.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 0,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Unknown,
+                weak: false,
+                section: SymbolSection::Undefined,
+                flags: SymbolFlags::None,
+            })
+        })
+        .collect();
+    for relocation in writer.relocations {
+        let symbol = match relocation.target {
+            RelocationTarget::Symbol(symbol) => symbol_ids[symbol],
+            RelocationTarget::Section => object.section_symbol(section_id),
+        };
+        object
+            .add_relocation(
+                section_id,
+                Relocation {
+                    offset: relocation.offset as u64,
+                    size: relocation.size * 8,
+                    kind: relocation.kind,
+                    encoding: RelocationEncoding::Generic,
+                    symbol,
+                    addend: relocation.addend,
+                },
+            )
+            .context(ObjectError)?;
+    }
+
+    object.write().context(ObjectError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{symbols::FunctionSymbol, test_data};
+    use object::{Object as _, ObjectSection, ObjectSymbol};
+
+    #[test]
+    fn object_with_cfi() {
+        let symbols: FunctionSymbols = vec![(
+            0x1130,
+            FunctionSymbol {
+                name: "foo".to_owned(),
+                size: 0x40,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let frames = safepoint_frames(&section, &symbols, &RegisterConventions::X86_64).unwrap();
+        // `bar` has no symbol
+        assert_eq!(
+            frames,
+            [
+                SafepointFrame {
+                    symbol: "foo".to_owned(),
+                    offset: 0x20,
+                    cfa_offset: 48,
+                },
+                SafepointFrame {
+                    symbol: "foo".to_owned(),
+                    offset: 0x2b,
+                    cfa_offset: 48,
+                },
+            ]
+        );
+
+        for &(format, num_relocations) in &[(CfiFormat::EhFrame, 2), (CfiFormat::DebugFrame, 4)] {
+            let data = write_object(&frames, &RegisterConventions::X86_64, format).unwrap();
+            let object = object::File::parse(&data).unwrap();
+            let cfi = object.section_by_name(format.section_name()).unwrap();
+            assert_eq!(cfi.relocations().count(), num_relocations);
+            assert!(object.symbols().any(|symbol| symbol.name() == Ok("foo")));
+        }
+    }
+}
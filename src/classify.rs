@@ -0,0 +1,202 @@
+// Heuristic classification of frame locations by what they most likely point
+// to. Stack maps only record a register and an offset, so the frame layout is
+// reconstructed from the stack size of the function and the conventions of the
+// target: from the top of the frame down, incoming stack arguments, the return
+// address, the callee-saved registers, and the locals.
+
+use std::collections::BTreeMap;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{arch::Arch, owned, DwarfRegNum, Error, LLVMStackMaps, Location, LocationKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
+pub enum SlotKind {
+    // A value spilled by the register allocator, referred to indirectly
+    SpillSlot,
+    // A stack object whose address is the value, referred to directly
+    Alloca,
+    CalleeSavedSlot,
+    // Above the return address, in the frame of the caller
+    ArgumentArea,
+}
+
+impl SlotKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SlotKind::SpillSlot => "spill slot",
+            SlotKind::Alloca => "alloca",
+            SlotKind::CalleeSavedSlot => "callee-saved slot",
+            SlotKind::ArgumentArea => "argument area",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterConventions {
+    pub stack_pointer: DwarfRegNum,
+    pub frame_pointer: DwarfRegNum,
+    // Offset from the frame pointer to the top of the frame, which is where
+    // the stack pointer plus the stack size points
+    pub frame_pointer_offset: u64,
+    pub return_address_size: u64,
+    // Size of the area at the top of the frame where callee-saved registers
+    // are saved, the frame pointer included, assuming all of them are
+    pub callee_saved_area: u64,
+}
+
+impl RegisterConventions {
+    // System V on x86-64: RBP and RBX, R12-R15 are pushed right after the
+    // return address, and RBP points at its own saved value.
+    pub const X86_64: Self = Self {
+        stack_pointer: 7,
+        frame_pointer: 6,
+        frame_pointer_offset: 8,
+        return_address_size: 8,
+        callee_saved_area: 48,
+    };
+
+    /// Returns the conventions of `arch`, if its frames have a fixed layout.
+    pub fn for_arch(arch: Arch) -> Option<Self> {
+        match arch {
+            Arch::X86_64 => Some(Self::X86_64),
+            // Where the frame record and the callee-saved registers go depends
+            // on the function
+            Arch::AArch64 => None,
+        }
+    }
+}
+
+/// Guesses what the memory referred to by `location` is, given the stack
+/// size of its function. Only direct and indirect locations relative to the
+/// stack or frame pointer can be classified, and neither can offsets below
+/// the frame or at the return address. Since the callee-saved area is
+/// assumed to be full, spill slots near the top of frames that save fewer
+/// registers are reported as callee-saved slots.
+pub fn classify(
+    location: &Location,
+    stack_size: u64,
+    conventions: &RegisterConventions,
+) -> Option<SlotKind> {
+    let (register, offset, direct) = match *location.kind() {
+        LocationKind::Direct { register, offset } => (register, offset as i64, true),
+        LocationKind::Indirect { register, offset } => (register, offset as i64, false),
+        LocationKind::Register(_) | LocationKind::Constant(_) => return None,
+    };
+
+    // Offset from the top of the frame
+    let offset = if register == conventions.stack_pointer {
+        offset - stack_size as i64
+    } else if register == conventions.frame_pointer {
+        offset - conventions.frame_pointer_offset as i64
+    } else {
+        return None;
+    };
+
+    if offset >= conventions.return_address_size as i64 {
+        Some(SlotKind::ArgumentArea)
+    } else if offset >= 0 || offset < -(stack_size as i64) {
+        None
+    } else if direct {
+        // Registers are never saved in stack objects, so the callee-saved area
+        // does not apply
+        Some(SlotKind::Alloca)
+    } else if offset >= -(conventions.callee_saved_area as i64) {
+        Some(SlotKind::CalleeSavedSlot)
+    } else {
+        Some(SlotKind::SpillSlot)
+    }
+}
+
+/// Attaches the kind of each location to the records of `stack_map`, in the
+/// order of their locations.
+pub fn classify_stack_map<F, R>(
+    stack_map: owned::StackMap<F, R>,
+    conventions: &RegisterConventions,
+) -> owned::StackMap<F, Vec<Option<SlotKind>>> {
+    stack_map.map_record_metadata(|function, record| {
+        record
+            .locations
+            .iter()
+            .map(|location| classify(location, function.stack_size, conventions))
+            .collect()
+    })
+}
+
+/// Counts the locations of each kind in the whole section, leaving out the
+/// ones that cannot be classified.
+pub fn count_slot_kinds(
+    section: &LLVMStackMaps,
+    conventions: &RegisterConventions,
+) -> Result<BTreeMap<SlotKind, usize>, Error> {
+    let mut counts = BTreeMap::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let mut records_iter = function.records();
+            while let Some(record) = records_iter.next()? {
+                let mut locations_iter = record.locations();
+                while let Some(location) = locations_iter.next()? {
+                    let stack_size = function.stack_size() as u64;
+                    if let Some(kind) = classify(&location, stack_size, conventions) {
+                        *counts.entry(kind).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    fn indirect(register: DwarfRegNum, offset: isize) -> Location {
+        Location::new(LocationKind::Indirect { register, offset }, 8)
+    }
+
+    #[test]
+    fn frame_regions() {
+        let x86_64 = &RegisterConventions::X86_64;
+        let classify = |location: &Location| classify(location, 128, x86_64);
+
+        assert_eq!(classify(&indirect(7, 0)), Some(SlotKind::SpillSlot));
+        assert_eq!(classify(&indirect(7, 120)), Some(SlotKind::CalleeSavedSlot));
+        assert_eq!(classify(&indirect(7, 128)), None);
+        assert_eq!(classify(&indirect(7, 136)), Some(SlotKind::ArgumentArea));
+        assert_eq!(classify(&indirect(7, -8)), None);
+
+        assert_eq!(classify(&indirect(6, -8)), Some(SlotKind::CalleeSavedSlot));
+        assert_eq!(classify(&indirect(6, 16)), Some(SlotKind::ArgumentArea));
+        let alloca = "direct(r6, -64, 8)".parse().unwrap();
+        assert_eq!(classify(&alloca), Some(SlotKind::Alloca));
+
+        assert_eq!(classify(&indirect(3, 0)), None);
+        let register = "register(r7, 8)".parse().unwrap();
+        assert_eq!(classify(&register), None);
+    }
+
+    #[test]
+    fn section_counts() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let counts = count_slot_kinds(&section, &RegisterConventions::X86_64).unwrap();
+        // The only frame location is `RBP - 32` in a 40-byte frame
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [(SlotKind::Alloca, 1)]
+        );
+
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let stack_map = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        let stack_map = classify_stack_map(stack_map, &RegisterConventions::X86_64);
+        assert_eq!(
+            stack_map.functions[0].records[0].metadata,
+            [Some(SlotKind::Alloca), None, None, None]
+        );
+    }
+}
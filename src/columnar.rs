@@ -0,0 +1,149 @@
+// Arrow and Parquet output for the flattened record tables. Rows coming from
+// many stack maps, possibly of different binaries, are written to the same
+// table, with a `source` column telling them apart.
+
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{
+    ArrayRef, Int64Array, RecordBatch, StringArray, UInt16Array, UInt32Array, UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+use snafu::{ResultExt, Snafu};
+
+use crate::flat::FlatRecordRow;
+
+#[derive(Debug, Snafu)]
+pub enum ColumnarError {
+    #[snafu(display("Could not build Arrow batch: {}", source))]
+    Arrow { source: ArrowError },
+    #[snafu(display("Could not write Parquet file: {}", source))]
+    Parquet { source: ParquetError },
+}
+
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("source", DataType::Utf8, false),
+        Field::new("stack_map_index", DataType::UInt32, false),
+        Field::new("function_index", DataType::UInt64, false),
+        Field::new("function_address", DataType::UInt64, false),
+        Field::new("function_stack_size", DataType::UInt64, false),
+        Field::new("record_index", DataType::UInt64, false),
+        Field::new("patch_point_id", DataType::UInt64, false),
+        Field::new("instruction_offset", DataType::UInt32, false),
+        Field::new("pc", DataType::UInt64, false),
+        Field::new("num_locations", DataType::UInt16, false),
+        Field::new("num_live_outs", DataType::UInt16, false),
+        Field::new("location_index", DataType::UInt16, true),
+        Field::new("location_kind", DataType::Utf8, true),
+        Field::new("location_size", DataType::UInt16, true),
+        Field::new("dwarf_reg_num", DataType::UInt16, true),
+        Field::new("offset", DataType::Int64, true),
+        Field::new("constant", DataType::UInt64, true),
+    ]))
+}
+
+/// Converts the rows of one stack map into an Arrow batch following
+/// `schema()`, tagging every row with `source` and `stack_map_index`.
+pub fn record_batch(
+    source: &str,
+    stack_map_index: u32,
+    rows: &[FlatRecordRow],
+) -> Result<RecordBatch, ColumnarError> {
+    fn column<T, A>(rows: &[FlatRecordRow], f: impl Fn(&FlatRecordRow) -> T) -> ArrayRef
+    where
+        A: From<Vec<T>> + arrow_array::Array + 'static,
+    {
+        Arc::new(A::from(rows.iter().map(f).collect::<Vec<_>>()))
+    }
+
+    let columns = vec![
+        Arc::new(StringArray::from(vec![source; rows.len()])) as ArrayRef,
+        Arc::new(UInt32Array::from(vec![stack_map_index; rows.len()])),
+        column::<_, UInt64Array>(rows, |row| row.function_index as u64),
+        column::<_, UInt64Array>(rows, |row| row.function_address),
+        column::<_, UInt64Array>(rows, |row| row.function_stack_size),
+        column::<_, UInt64Array>(rows, |row| row.record_index as u64),
+        column::<_, UInt64Array>(rows, |row| row.patch_point_id),
+        column::<_, UInt32Array>(rows, |row| row.instruction_offset),
+        column::<_, UInt64Array>(rows, |row| row.pc),
+        column::<_, UInt16Array>(rows, |row| row.num_locations),
+        column::<_, UInt16Array>(rows, |row| row.num_live_outs),
+        column::<_, UInt16Array>(rows, |row| row.location_index),
+        column::<_, StringArray>(rows, |row| row.location_kind),
+        column::<_, UInt16Array>(rows, |row| row.location_size),
+        column::<_, UInt16Array>(rows, |row| row.dwarf_reg_num),
+        column::<_, Int64Array>(rows, |row| row.offset),
+        column::<_, UInt64Array>(rows, |row| row.constant),
+    ];
+
+    RecordBatch::try_new(schema(), columns).context(Arrow)
+}
+
+pub struct ParquetTableWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+}
+
+impl<W: Write + Send> ParquetTableWriter<W> {
+    pub fn new(output: W) -> Result<Self, ColumnarError> {
+        let writer = ArrowWriter::try_new(output, schema(), None).context(Parquet)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(
+        &mut self,
+        source: &str,
+        stack_map_index: u32,
+        rows: &[FlatRecordRow],
+    ) -> Result<(), ColumnarError> {
+        let batch = record_batch(source, stack_map_index, rows)?;
+        self.writer.write(&batch).context(Parquet)
+    }
+
+    // Writes the file footer, the output is not a valid Parquet file before
+    // this is called.
+    pub fn close(self) -> Result<W, ColumnarError> {
+        self.writer.into_inner().context(Parquet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LLVMStackMaps};
+    use arrow_array::Array;
+    use fallible_iterator::FallibleIterator;
+
+    #[test]
+    fn batch_columns() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let rows = stack_map.flatten().unwrap();
+        let batch = record_batch("vmlinux", 0, &rows).unwrap();
+
+        assert_eq!(batch.num_rows(), 6);
+        assert_eq!(batch.num_columns(), schema().fields().len());
+        let constants = batch
+            .column_by_name("constant")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(constants.null_count(), 4);
+        assert_eq!(constants.value(3), 1234567890123);
+    }
+
+    #[test]
+    fn parquet_output() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let rows = stack_map.flatten().unwrap();
+
+        let mut writer = ParquetTableWriter::new(Vec::new()).unwrap();
+        writer.write("a", 0, &rows).unwrap();
+        writer.write("b", 0, &rows).unwrap();
+        let output = writer.close().unwrap();
+        assert_eq!(&output[..4], b"PAR1");
+        assert_eq!(&output[output.len() - 4..], b"PAR1");
+    }
+}
@@ -0,0 +1,347 @@
+// A subset of the parser that can run in constant contexts, so that a stack map
+// embedded with `include_bytes!` is turned into static tables at compile time,
+// without allocating at runtime:
+//
+//     const SECTION: &[u8] = include_bytes!("stackmaps.bin");
+//     static FUNCTIONS: [StaticFunction; 2] = const_parser::functions(SECTION);
+//
+// Lookups return `None` for malformed or truncated input, and the functions
+// building whole tables panic instead, which fails the build when evaluated
+// in a constant. Only the stack map at the start of the input is read, use
+// `stack_map_size` to get to the next one.
+
+use crate::{
+    parser::{
+        padding_size, ALIGNMENT_BYTES, CONSTANT_SIZE, HEADER_SIZE, LIVE_OUT_SIZE, LOCATION_SIZE,
+        RECORD_HEADER_SIZE, STACK_SIZE_RECORD_SIZE,
+    },
+    LocationKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticHeader {
+    pub num_functions: u32,
+    pub num_constants: u32,
+    pub num_records: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticFunction {
+    pub address: u64,
+    pub stack_size: u64,
+    pub record_count: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticRecord {
+    pub patch_point_id: u64,
+    pub instruction_offset: u32,
+    pub num_locations: u16,
+    pub num_live_outs: u16,
+    // Offset of the record in the stack map and its size, padding included
+    pub offset: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticLocation {
+    pub kind: LocationKind,
+    pub size: u16,
+}
+
+const fn fits(bytes: &[u8], offset: usize, len: usize) -> bool {
+    match offset.checked_add(len) {
+        Some(end) => end <= bytes.len(),
+        None => false,
+    }
+}
+
+// The callers check that the integers fit in `bytes`
+const fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+const fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+const fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    (u32_at(bytes, offset) as u64) | ((u32_at(bytes, offset + 4) as u64) << 32)
+}
+
+pub const fn header(bytes: &[u8]) -> Option<StaticHeader> {
+    if !fits(bytes, 0, HEADER_SIZE) || bytes[0] != 3 || bytes[1] != 0 || u16_at(bytes, 2) != 0 {
+        return None;
+    }
+
+    Some(StaticHeader {
+        num_functions: u32_at(bytes, 4),
+        num_constants: u32_at(bytes, 8),
+        num_records: u32_at(bytes, 12),
+    })
+}
+
+const fn constants_offset(header: &StaticHeader) -> usize {
+    HEADER_SIZE + header.num_functions as usize * STACK_SIZE_RECORD_SIZE
+}
+
+const fn records_offset(header: &StaticHeader) -> usize {
+    constants_offset(header) + header.num_constants as usize * CONSTANT_SIZE
+}
+
+pub const fn function(bytes: &[u8], idx: usize) -> Option<StaticFunction> {
+    let header = match header(bytes) {
+        Some(header) => header,
+        None => return None,
+    };
+    if idx >= header.num_functions as usize {
+        return None;
+    }
+    let offset = HEADER_SIZE + idx * STACK_SIZE_RECORD_SIZE;
+    if !fits(bytes, offset, STACK_SIZE_RECORD_SIZE) {
+        return None;
+    }
+
+    Some(StaticFunction {
+        address: u64_at(bytes, offset),
+        stack_size: u64_at(bytes, offset + 8),
+        record_count: u64_at(bytes, offset + 16),
+    })
+}
+
+pub const fn constant(bytes: &[u8], idx: usize) -> Option<u64> {
+    let header = match header(bytes) {
+        Some(header) => header,
+        None => return None,
+    };
+    if idx >= header.num_constants as usize {
+        return None;
+    }
+    let offset = constants_offset(&header) + idx * CONSTANT_SIZE;
+    if !fits(bytes, offset, CONSTANT_SIZE) {
+        return None;
+    }
+
+    Some(u64_at(bytes, offset))
+}
+
+// Decodes the record starting at `offset`
+const fn record_at(bytes: &[u8], offset: usize) -> Option<StaticRecord> {
+    if !fits(bytes, offset, RECORD_HEADER_SIZE) {
+        return None;
+    }
+    let num_locations = u16_at(bytes, offset + 14);
+
+    let locations_end = RECORD_HEADER_SIZE + num_locations as usize * LOCATION_SIZE;
+    let live_outs_header = locations_end + padding_size(locations_end, ALIGNMENT_BYTES);
+    if !fits(bytes, offset + live_outs_header, 4) {
+        return None;
+    }
+    let num_live_outs = u16_at(bytes, offset + live_outs_header + 2);
+
+    let live_outs_end = live_outs_header + 4 + num_live_outs as usize * LIVE_OUT_SIZE;
+    let size = live_outs_end + padding_size(live_outs_end, ALIGNMENT_BYTES);
+    if !fits(bytes, offset, size) {
+        return None;
+    }
+
+    Some(StaticRecord {
+        patch_point_id: u64_at(bytes, offset),
+        instruction_offset: u32_at(bytes, offset + 8),
+        num_locations,
+        num_live_outs,
+        offset,
+        size,
+    })
+}
+
+/// Returns the record at `idx` in the stack map, counted across functions.
+/// Records have variable sizes, so all the records before it are decoded.
+pub const fn record(bytes: &[u8], idx: usize) -> Option<StaticRecord> {
+    let header = match header(bytes) {
+        Some(header) => header,
+        None => return None,
+    };
+    if idx >= header.num_records as usize {
+        return None;
+    }
+
+    let mut offset = records_offset(&header);
+    let mut current = 0;
+    loop {
+        let record = match record_at(bytes, offset) {
+            Some(record) => record,
+            None => return None,
+        };
+        if current == idx {
+            return Some(record);
+        }
+        offset += record.size;
+        current += 1;
+    }
+}
+
+pub const fn location(bytes: &[u8], record: &StaticRecord, idx: usize) -> Option<StaticLocation> {
+    if idx >= record.num_locations as usize {
+        return None;
+    }
+    let offset = record.offset + RECORD_HEADER_SIZE + idx * LOCATION_SIZE;
+    if !fits(bytes, offset, LOCATION_SIZE)
+        || bytes[offset + 1] != 0
+        || u16_at(bytes, offset + 6) != 0
+    {
+        return None;
+    }
+
+    let register = u16_at(bytes, offset + 4);
+    let offset_or_small_const = u32_at(bytes, offset + 8) as i32;
+    let kind = match bytes[offset] {
+        1 => LocationKind::Register(register),
+        2 => LocationKind::Direct {
+            register,
+            offset: offset_or_small_const as isize,
+        },
+        3 => LocationKind::Indirect {
+            register,
+            offset: offset_or_small_const as isize,
+        },
+        4 => LocationKind::Constant(offset_or_small_const as u64),
+        5 if offset_or_small_const >= 0 => match constant(bytes, offset_or_small_const as usize) {
+            Some(constant) => LocationKind::Constant(constant),
+            None => return None,
+        },
+        _ => return None,
+    };
+
+    Some(StaticLocation {
+        kind,
+        size: u16_at(bytes, offset + 2),
+    })
+}
+
+/// Returns the size of the stack map at the start of `bytes`, that is the
+/// offset of the next one in a section.
+pub const fn stack_map_size(bytes: &[u8]) -> Option<usize> {
+    let header = match header(bytes) {
+        Some(header) => header,
+        None => return None,
+    };
+
+    let mut offset = records_offset(&header);
+    let mut remaining = header.num_records;
+    while remaining > 0 {
+        offset += match record_at(bytes, offset) {
+            Some(record) => record.size,
+            None => return None,
+        };
+        remaining -= 1;
+    }
+    Some(offset)
+}
+
+/// Decodes the `N` functions of the stack map, panicking if it has another
+/// number of functions or is malformed.
+pub const fn functions<const N: usize>(bytes: &[u8]) -> [StaticFunction; N] {
+    match header(bytes) {
+        Some(header) if header.num_functions as usize == N => {}
+        _ => panic!("the stack map does not have the expected number of functions"),
+    }
+
+    let mut functions = [StaticFunction {
+        address: 0,
+        stack_size: 0,
+        record_count: 0,
+    }; N];
+    let mut idx = 0;
+    while idx < N {
+        functions[idx] = match function(bytes, idx) {
+            Some(function) => function,
+            None => panic!("the stack map is truncated"),
+        };
+        idx += 1;
+    }
+    functions
+}
+
+/// Decodes the `N` records of the stack map, panicking if it has another
+/// number of records or is malformed.
+pub const fn records<const N: usize>(bytes: &[u8]) -> [StaticRecord; N] {
+    let header = match header(bytes) {
+        Some(header) if header.num_records as usize == N => header,
+        _ => panic!("the stack map does not have the expected number of records"),
+    };
+
+    let mut records = [StaticRecord {
+        patch_point_id: 0,
+        instruction_offset: 0,
+        num_locations: 0,
+        num_live_outs: 0,
+        offset: 0,
+        size: 0,
+    }; N];
+    let mut offset = records_offset(&header);
+    let mut idx = 0;
+    while idx < N {
+        records[idx] = match record_at(bytes, offset) {
+            Some(record) => record,
+            None => panic!("the stack map is truncated"),
+        };
+        offset += records[idx].size;
+        idx += 1;
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LLVMStackMaps};
+    use fallible_iterator::FallibleIterator;
+
+    const FUNCTIONS: [StaticFunction; 2] = functions(test_data::TWO_FUNCTIONS);
+    const RECORDS: [StaticRecord; 3] = records(test_data::TWO_FUNCTIONS);
+
+    #[test]
+    fn static_tables() {
+        assert_eq!(
+            FUNCTIONS[0],
+            StaticFunction {
+                address: 0x1130,
+                stack_size: 40,
+                record_count: 2,
+            }
+        );
+        assert_eq!(FUNCTIONS[1].address, 0x1170);
+        let ids: Vec<_> = RECORDS.iter().map(|record| record.patch_point_id).collect();
+        assert_eq!(ids, [42, 43, 44]);
+        assert_eq!(record(test_data::TWO_FUNCTIONS, 2), Some(RECORDS[2]));
+        assert_eq!(record(test_data::TWO_FUNCTIONS, 3), None);
+        assert_eq!(
+            stack_map_size(test_data::TWO_FUNCTIONS),
+            Some(test_data::TWO_FUNCTIONS.len())
+        );
+
+        // Locations match the ones of the regular parser
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let parsed_function = stack_map.functions().next().unwrap().unwrap();
+        let parsed = parsed_function.records().next().unwrap().unwrap();
+        let locations: Vec<_> = parsed.locations().collect().unwrap();
+        assert_eq!(RECORDS[0].num_locations as usize, locations.len());
+        for (idx, parsed) in locations.iter().enumerate() {
+            let location = location(test_data::TWO_FUNCTIONS, &RECORDS[0], idx).unwrap();
+            assert_eq!(&location.kind, parsed.kind());
+            assert_eq!(location.size as usize, parsed.size());
+        }
+        assert_eq!(location(test_data::TWO_FUNCTIONS, &RECORDS[0], 4), None);
+
+        assert_eq!(header(&test_data::TWO_FUNCTIONS[..15]), None);
+        assert_eq!(function(&test_data::TWO_FUNCTIONS[..40], 1), None);
+        assert_eq!(stack_map_size(&test_data::TWO_FUNCTIONS[..200]), None);
+    }
+}
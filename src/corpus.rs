@@ -0,0 +1,312 @@
+// Golden corpus of stack maps, to run this crate as a conformance suite against
+// the stack maps of other toolchains. A corpus is a directory tree of raw stack
+// map sections, e.g. extracted with
+//
+//     objcopy -O binary --only-section=.llvm_stackmaps binary blob
+//
+// each next to the expected output for it, in a file named after the blob
+// with `.json` appended. The expected output is the JSON export of the owned
+// stack maps of the section, without metadata, or `{"error": CODE}` with the
+// code of the error the section must be rejected with.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use fallible_iterator::FallibleIterator;
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+
+use crate::{owned::StackMap, LLVMStackMaps};
+
+pub const EXPECTED_EXTENSION: &str = "json";
+
+#[derive(Debug, Snafu)]
+pub enum CorpusError {
+    #[snafu(display("Could not read {}: {}", path.display(), source))]
+    ReadError { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not write {}: {}", path.display(), source))]
+    WriteError { path: PathBuf, source: io::Error },
+}
+
+type Result<T> = std::result::Result<T, CorpusError>;
+
+/// Returns the output expected for the section `data` in a corpus.
+pub fn expected_output(data: &[u8]) -> Value {
+    let stack_maps: std::result::Result<Vec<_>, _> = LLVMStackMaps::new(data)
+        .stack_maps()
+        .map(|stack_map| StackMap::<(), ()>::from_parsed(&stack_map))
+        .collect();
+    match stack_maps {
+        Ok(stack_maps) => serde_json::to_value(stack_maps).unwrap(),
+        Err(error) => serde_json::json!({ "error": error.code() }),
+    }
+}
+
+pub fn expected_path(blob: &Path) -> PathBuf {
+    let mut path = blob.as_os_str().to_owned();
+    path.push(".");
+    path.push(EXPECTED_EXTENSION);
+    PathBuf::from(path)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    MissingExpected,
+    InvalidExpected {
+        reason: String,
+    },
+    // At the JSON pointer `pointer`, `None` where the value is missing
+    Output {
+        pointer: String,
+        expected: Option<Value>,
+        actual: Option<Value>,
+    },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "nothing".to_owned(),
+        };
+        match self {
+            Mismatch::MissingExpected => write!(f, "no expected output"),
+            Mismatch::InvalidExpected { reason } => {
+                write!(f, "could not parse expected output: {}", reason)
+            }
+            Mismatch::Output {
+                pointer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected {} at \"{}\", got {}",
+                describe(expected),
+                pointer,
+                describe(actual)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusCase {
+    pub blob: PathBuf,
+    pub mismatch: Option<Mismatch>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorpusReport {
+    // In path order
+    pub cases: Vec<CorpusCase>,
+}
+
+impl CorpusReport {
+    pub fn num_passed(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.mismatch.is_none())
+            .count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CorpusCase> {
+        self.cases.iter().filter(|case| case.mismatch.is_some())
+    }
+}
+
+/// Returns the first difference between `expected` and `actual`, as a JSON
+/// pointer below `pointer` and the values there, in document order.
+pub fn first_difference(
+    pointer: &str,
+    expected: &Value,
+    actual: &Value,
+) -> Option<(String, Option<Value>, Option<Value>)> {
+    match (expected, actual) {
+        (Value::Array(expected), Value::Array(actual)) => {
+            for index in 0..expected.len().max(actual.len()) {
+                let pointer = format!("{}/{}", pointer, index);
+                match (expected.get(index), actual.get(index)) {
+                    (Some(expected), Some(actual)) => {
+                        if let Some(difference) = first_difference(&pointer, expected, actual) {
+                            return Some(difference);
+                        }
+                    }
+                    (expected, actual) => {
+                        return Some((pointer, expected.cloned(), actual.cloned()))
+                    }
+                }
+            }
+            None
+        }
+        (Value::Object(expected), Value::Object(actual)) => {
+            let mut keys: Vec<_> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let pointer = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+                match (expected.get(key), actual.get(key)) {
+                    (Some(expected), Some(actual)) => {
+                        if let Some(difference) = first_difference(&pointer, expected, actual) {
+                            return Some(difference);
+                        }
+                    }
+                    (expected, actual) => {
+                        return Some((pointer, expected.cloned(), actual.cloned()))
+                    }
+                }
+            }
+            None
+        }
+        _ if expected == actual => None,
+        _ => Some((
+            pointer.to_owned(),
+            Some(expected.clone()),
+            Some(actual.clone()),
+        )),
+    }
+}
+
+/// Checks the blob at `blob` against its expected output.
+pub fn check_case(blob: &Path) -> Result<CorpusCase> {
+    let data = fs::read(blob).context(ReadError { path: blob })?;
+    let expected_path = expected_path(blob);
+    let mismatch = match fs::read_to_string(&expected_path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Some(Mismatch::MissingExpected),
+        Err(source) => {
+            return Err(CorpusError::ReadError {
+                path: expected_path,
+                source,
+            })
+        }
+        Ok(text) => match serde_json::from_str(&text) {
+            Err(error) => Some(Mismatch::InvalidExpected {
+                reason: error.to_string(),
+            }),
+            Ok(expected) => first_difference("", &expected, &expected_output(&data)).map(
+                |(pointer, expected, actual)| Mismatch::Output {
+                    pointer,
+                    expected,
+                    actual,
+                },
+            ),
+        },
+    };
+    Ok(CorpusCase {
+        blob: blob.to_owned(),
+        mismatch,
+    })
+}
+
+/// Lists the blobs of the corpus in `dir`, in path order. Hidden files and
+/// directories are skipped.
+pub fn corpus_blobs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut blobs = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .context(ReadError { path: dir })?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type().context(ReadError { path: &path })?;
+        if file_type.is_dir() {
+            blobs.extend(corpus_blobs(&path)?);
+        } else if path.extension() != Some(EXPECTED_EXTENSION.as_ref()) {
+            blobs.push(path);
+        }
+    }
+    Ok(blobs)
+}
+
+/// Checks all the blobs of the corpus in `dir`.
+pub fn run_corpus(dir: &Path) -> Result<CorpusReport> {
+    let cases = corpus_blobs(dir)?
+        .iter()
+        .map(|blob| check_case(blob))
+        .collect::<Result<_>>()?;
+    Ok(CorpusReport { cases })
+}
+
+/// Writes the current output of each blob of the corpus in `dir` as its
+/// expected output, and returns the blobs whose expected output changed.
+pub fn bless_corpus(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut blessed = Vec::new();
+    for case in run_corpus(dir)?.cases {
+        if case.mismatch.is_none() {
+            continue;
+        }
+        let data = fs::read(&case.blob).context(ReadError { path: &case.blob })?;
+        let mut text = serde_json::to_string_pretty(&expected_output(&data)).unwrap();
+        text.push('\n');
+        let path = expected_path(&case.blob);
+        fs::write(&path, text).context(WriteError { path })?;
+        blessed.push(case.blob);
+    }
+    Ok(blessed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn differences() {
+        let expected = serde_json::json!([{"a": [1, 2], "b/c": 3}]);
+        assert_eq!(first_difference("", &expected, &expected.clone()), None);
+        assert_eq!(
+            first_difference("", &expected, &serde_json::json!([{"a": [1, 4], "b/c": 5}])),
+            Some(("/0/a/1".to_owned(), Some(2.into()), Some(4.into())))
+        );
+        assert_eq!(
+            first_difference("", &expected, &serde_json::json!([{"a": [1]}])),
+            Some(("/0/a/1".to_owned(), Some(2.into()), None))
+        );
+        assert_eq!(
+            first_difference("", &expected, &serde_json::json!([{"a": [1, 2]}])),
+            Some(("/0/b~1c".to_owned(), Some(3.into()), None))
+        );
+    }
+
+    #[test]
+    fn corpus() {
+        let dir = std::env::temp_dir().join(format!("stackmap-corpus-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("two_functions"), test_data::TWO_FUNCTIONS).unwrap();
+        fs::write(
+            dir.join("nested/truncated"),
+            &test_data::TWO_FUNCTIONS[..20],
+        )
+        .unwrap();
+
+        let report = run_corpus(&dir).unwrap();
+        assert_eq!(report.cases.len(), 2);
+        assert_eq!(report.num_passed(), 0);
+        assert_eq!(report.cases[0].blob, dir.join("nested/truncated"));
+        assert_eq!(report.cases[0].mismatch, Some(Mismatch::MissingExpected));
+
+        assert_eq!(bless_corpus(&dir).unwrap().len(), 2);
+        assert_eq!(run_corpus(&dir).unwrap().num_passed(), 2);
+        let expected = fs::read_to_string(dir.join("nested/truncated.json")).unwrap();
+        assert!(expected.contains("\"error\""));
+
+        // A constant that changed
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data[16 + 2 * 24] ^= 1;
+        fs::write(dir.join("two_functions"), data).unwrap();
+        let report = run_corpus(&dir).unwrap();
+        let failures: Vec<_> = report.failures().collect();
+        assert_eq!(failures.len(), 1);
+        match &failures[0].mismatch {
+            Some(Mismatch::Output { pointer, .. }) => assert_eq!(pointer, "/0/constants/0"),
+            mismatch => panic!("unexpected mismatch: {:?}", mismatch),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+// Estimation of the work a garbage collector does at each safepoint, counted
+// as the pointer-sized locations of its record that have to be scanned. Stack
+// maps do not say which locations hold pointers, so by default every location
+// of the right size that is not a constant is counted.
+
+use std::collections::BTreeMap;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{Error, LLVMStackMaps, Location, LocationKind, Record};
+
+// Tells whether the location at the given index of a record holds a pointer
+pub type PointerFilter<'a> = &'a dyn Fn(&Record, usize) -> bool;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordCost {
+    pub pc: u64,
+    pub patch_point_id: u64,
+    pub pointer_locations: usize,
+}
+
+/// Tells whether `location` may hold a pointer of `pointer_size` bytes.
+pub fn is_pointer_sized(location: &Location, pointer_size: usize) -> bool {
+    location.size() == pointer_size && !matches!(location.kind(), LocationKind::Constant(_))
+}
+
+/// Counts the locations of `pointer_size` bytes in every record, in address
+/// order. When the types of the locations are known, `is_pointer` is given
+/// each record and location index, and only the locations it accepts are
+/// counted.
+pub fn record_costs(
+    section: &LLVMStackMaps,
+    pointer_size: usize,
+    is_pointer: Option<PointerFilter>,
+) -> Result<Vec<RecordCost>, Error> {
+    let counted = |record: &Record, location_idx: usize, location: &Location| {
+        is_pointer_sized(location, pointer_size)
+            && is_pointer.is_none_or(|is_pointer| is_pointer(record, location_idx))
+    };
+
+    let mut costs = Vec::new();
+    let mut safepoints_iter = section.safepoints();
+    while let Some(safepoint) = safepoints_iter.next()? {
+        let record = safepoint.record();
+        let mut pointer_locations = 0;
+        let mut locations_iter = record.locations().enumerate();
+        while let Some((location_idx, location)) = locations_iter.next()? {
+            if counted(record, location_idx, &location) {
+                pointer_locations += 1;
+            }
+        }
+
+        costs.push(RecordCost {
+            pc: safepoint.pc(),
+            patch_point_id: record.patch_point_id(),
+            pointer_locations,
+        });
+    }
+    Ok(costs)
+}
+
+// Distribution of the pointer locations per record
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CostDistribution {
+    pub records: usize,
+    pub total: usize,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: usize,
+    pub p90: usize,
+    pub p99: usize,
+    // Number of records for each count
+    pub histogram: BTreeMap<usize, usize>,
+}
+
+impl CostDistribution {
+    pub fn new(costs: &[RecordCost]) -> Self {
+        let mut counts: Vec<usize> = costs.iter().map(|cost| cost.pointer_locations).collect();
+        if counts.is_empty() {
+            return Self::default();
+        }
+        counts.sort_unstable();
+
+        // Nearest-rank percentiles
+        let percentile = |percent: usize| counts[(counts.len() * percent).div_ceil(100).max(1) - 1];
+        let mut histogram = BTreeMap::new();
+        for &count in &counts {
+            *histogram.entry(count).or_insert(0) += 1;
+        }
+        let total = counts.iter().sum();
+
+        Self {
+            records: counts.len(),
+            total,
+            min: counts[0],
+            max: counts[counts.len() - 1],
+            mean: total as f64 / counts.len() as f64,
+            median: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            histogram,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn costs() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let costs = record_costs(&section, 8, None).unwrap();
+        let counts: Vec<_> = costs
+            .iter()
+            .map(|cost| (cost.patch_point_id, cost.pointer_locations))
+            .collect();
+        // Constants are not counted, registers and stack slots are
+        assert_eq!(counts, [(42, 2), (43, 1), (44, 1)]);
+
+        let only_first = |_: &Record, location_idx: usize| location_idx == 0;
+        let costs = record_costs(&section, 8, Some(&only_first)).unwrap();
+        assert_eq!(costs[0].pointer_locations, 1);
+
+        let distribution = CostDistribution::new(&costs);
+        assert_eq!(distribution.records, 3);
+        assert_eq!(distribution.total, 3);
+        assert_eq!(
+            (distribution.min, distribution.median, distribution.max),
+            (1, 1, 1)
+        );
+        assert_eq!(CostDistribution::new(&[]), CostDistribution::default());
+    }
+
+    #[test]
+    fn percentiles() {
+        let costs: Vec<_> = (1..=10)
+            .map(|pointer_locations| RecordCost {
+                pc: 0,
+                patch_point_id: 0,
+                pointer_locations,
+            })
+            .collect();
+        let distribution = CostDistribution::new(&costs);
+        assert_eq!(distribution.median, 5);
+        assert_eq!(distribution.p90, 9);
+        assert_eq!(distribution.p99, 10);
+        assert_eq!(distribution.mean, 5.5);
+        assert_eq!(distribution.histogram.len(), 10);
+    }
+}
@@ -0,0 +1,130 @@
+// Tracks which parts of a section belong to stack maps. Producers and linkers
+// sometimes leave padding between or after stack maps, or misalign them, and
+// the bytes that no stack map accounts for make these problems visible.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{parser, Error, StackMap};
+
+#[derive(Debug)]
+pub enum GapKind {
+    // Only zero bytes, most likely alignment padding added by the linker
+    ZeroPadding,
+    // The bytes could not be parsed as a stack map, parsing stopped here
+    Unparsed(Error),
+}
+
+#[derive(Debug)]
+pub struct Gap {
+    pub range: Range<usize>,
+    pub kind: GapKind,
+}
+
+#[derive(Debug)]
+pub struct Coverage {
+    pub section_size: usize,
+    pub stack_maps: Vec<Range<usize>>,
+    pub gaps: Vec<Gap>,
+}
+
+impl Coverage {
+    pub fn consumed_bytes(&self) -> usize {
+        self.stack_maps.iter().map(|range| range.len()).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.gaps.is_empty()
+    }
+}
+
+pub(crate) fn stack_map_size(input: &[u8]) -> Result<usize, Error> {
+    let (_, size) = StackMap::parse(input)?;
+    Ok(size)
+}
+
+/// Splits `data` into the byte ranges of its stack maps and the gaps between
+/// them. Runs of zero bytes are skipped up to the next 8-byte boundary, and
+/// parsing stops at the first range that is not a valid stack map.
+pub fn section_coverage(data: &[u8]) -> Coverage {
+    let mut stack_maps = Vec::new();
+    let mut gaps = Vec::new();
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let zeroes = data[offset..]
+            .iter()
+            .position(|&byte| byte != 0)
+            .unwrap_or(data.len() - offset);
+        let padding_end = if offset + zeroes == data.len() {
+            data.len()
+        } else {
+            offset + zeroes - zeroes % parser::ALIGNMENT_BYTES
+        };
+        if padding_end > offset {
+            gaps.push(Gap {
+                range: offset..padding_end,
+                kind: GapKind::ZeroPadding,
+            });
+            offset = padding_end;
+            continue;
+        }
+
+        match stack_map_size(&data[offset..]) {
+            Ok(size) => {
+                stack_maps.push(offset..offset + size);
+                offset += size;
+            }
+            Err(error) => {
+                gaps.push(Gap {
+                    range: offset..data.len(),
+                    kind: GapKind::Unparsed(error),
+                });
+                break;
+            }
+        }
+    }
+
+    Coverage {
+        section_size: data.len(),
+        stack_maps,
+        gaps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn full_coverage() {
+        let coverage = section_coverage(test_data::TWO_FUNCTIONS);
+        assert!(coverage.is_complete());
+        assert_eq!(coverage.stack_maps.len(), 1);
+        assert_eq!(coverage.stack_maps[0], 0..224);
+        assert_eq!(coverage.consumed_bytes(), coverage.section_size);
+    }
+
+    #[test]
+    fn padding_and_garbage() {
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(test_data::TWO_FUNCTIONS);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0x2a, 0, 0, 0]);
+
+        let coverage = section_coverage(&data);
+        assert_eq!(coverage.stack_maps, [0..224, 240..464]);
+        assert_eq!(coverage.consumed_bytes(), 448);
+        assert_eq!(coverage.gaps.len(), 3);
+        assert_eq!(coverage.gaps[0].range, 224..240);
+        assert!(matches!(coverage.gaps[0].kind, GapKind::ZeroPadding));
+        assert_eq!(coverage.gaps[1].range, 464..472);
+        assert!(matches!(coverage.gaps[1].kind, GapKind::ZeroPadding));
+        assert_eq!(coverage.gaps[2].range, 472..476);
+        assert!(matches!(
+            coverage.gaps[2].kind,
+            GapKind::Unparsed(Error::UnsupportedVersion)
+        ));
+    }
+}
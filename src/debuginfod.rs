@@ -0,0 +1,180 @@
+// Fetching of separate debug info by build ID from debuginfod servers, for
+// stripped binaries whose symbols were split off. Servers and the cache are
+// configured like the elfutils client, so files it already downloaded are
+// reused.
+
+use std::{
+    env, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use object::Object;
+use snafu::{ensure, ResultExt, Snafu};
+
+pub const URLS_VARIABLE: &str = "DEBUGINFOD_URLS";
+pub const CACHE_PATH_VARIABLE: &str = "DEBUGINFOD_CACHE_PATH";
+
+#[derive(Debug, Snafu)]
+pub enum DebuginfodError {
+    #[snafu(display("Could not parse object: {}", source))]
+    ObjectError { source: object::Error },
+    #[snafu(display("Object has no build ID"))]
+    NoBuildId,
+    #[snafu(display("No debuginfod server is configured in {}", URLS_VARIABLE))]
+    NoServers,
+    #[snafu(display("Could not write {}: {}", path.display(), source))]
+    CacheError { path: PathBuf, source: io::Error },
+    #[snafu(display("No server has debug info for build ID {}: {}", build_id, reasons.join(", ")))]
+    NotFound {
+        build_id: String,
+        reasons: Vec<String>,
+    },
+}
+
+type Result<T> = std::result::Result<T, DebuginfodError>;
+
+/// Returns the build ID of the object in `file_data`, if it has one.
+pub fn build_id(file_data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    Ok(object
+        .build_id()
+        .context(ObjectError)?
+        .map(|build_id| build_id.to_vec()))
+}
+
+fn hex(build_id: &[u8]) -> String {
+    build_id
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebuginfodClient {
+    // Server URLs, tried in order
+    pub urls: Vec<String>,
+    pub cache_dir: PathBuf,
+    pub timeout: Duration,
+}
+
+impl DebuginfodClient {
+    /// Configures a client from `DEBUGINFOD_URLS`, a space-separated list of
+    /// servers, and `DEBUGINFOD_CACHE_PATH`, falling back to the cache of the
+    /// elfutils client. Returns `None` when no server is configured.
+    pub fn from_env() -> Option<Self> {
+        let urls = parse_urls(&env::var(URLS_VARIABLE).unwrap_or_default());
+        if urls.is_empty() {
+            return None;
+        }
+
+        let cache_dir = match env::var_os(CACHE_PATH_VARIABLE) {
+            Some(path) => PathBuf::from(path),
+            None => env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .unwrap_or_else(env::temp_dir)
+                .join("debuginfod_client"),
+        };
+        Some(Self {
+            urls,
+            cache_dir,
+            timeout: Duration::from_secs(90),
+        })
+    }
+
+    pub fn debuginfo_path(&self, build_id: &[u8]) -> PathBuf {
+        self.cache_dir.join(hex(build_id)).join("debuginfo")
+    }
+
+    /// Fetches the debug info of the object in `file_data` by its build ID.
+    pub fn fetch_debuginfo_for(&self, file_data: &[u8]) -> Result<PathBuf> {
+        let build_id = build_id(file_data)?.ok_or(DebuginfodError::NoBuildId)?;
+        self.fetch_debuginfo(&build_id)
+    }
+
+    /// Returns the path of the debug info file for `build_id` in the cache,
+    /// downloading it from the first server that has it if needed.
+    pub fn fetch_debuginfo(&self, build_id: &[u8]) -> Result<PathBuf> {
+        let path = self.debuginfo_path(build_id);
+        if path.is_file() {
+            return Ok(path);
+        }
+        ensure!(!self.urls.is_empty(), NoServers);
+
+        let build_id = hex(build_id);
+        let agent = ureq::AgentBuilder::new().timeout(self.timeout).build();
+        let mut reasons = Vec::new();
+        for url in &self.urls {
+            let url = format!(
+                "{}/buildid/{}/debuginfo",
+                url.trim_end_matches('/'),
+                build_id
+            );
+            let mut contents = Vec::new();
+            let result = agent
+                .get(&url)
+                .call()
+                .map_err(|error| error.to_string())
+                .and_then(|response| {
+                    response
+                        .into_reader()
+                        .read_to_end(&mut contents)
+                        .map_err(|error| error.to_string())
+                });
+            match result {
+                Ok(_) => {
+                    store(&path, &contents)?;
+                    return Ok(path);
+                }
+                Err(reason) => reasons.push(reason),
+            }
+        }
+        NotFound { build_id, reasons }.fail()
+    }
+}
+
+fn parse_urls(urls: &str) -> Vec<String> {
+    urls.split_whitespace().map(str::to_owned).collect()
+}
+
+// Writes to a temporary file first, so that concurrent readers never see a
+// partial download
+fn store(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).context(CacheError { path: dir })?;
+    let partial = path.with_extension(format!("partial.{}", std::process::id()));
+    fs::write(&partial, contents).context(CacheError { path: &partial })?;
+    fs::rename(&partial, path).context(CacheError { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_debuginfo() {
+        assert_eq!(
+            parse_urls(" https://a.example/  https://b.example "),
+            ["https://a.example/", "https://b.example"]
+        );
+
+        let client = DebuginfodClient {
+            urls: Vec::new(),
+            cache_dir: env::temp_dir().join(format!("stackmap-debuginfod-{}", std::process::id())),
+            timeout: Duration::from_secs(1),
+        };
+        let build_id = [0xde, 0xad, 0x01];
+        let path = client.debuginfo_path(&build_id);
+        assert!(path.ends_with("dead01/debuginfo"));
+        assert!(matches!(
+            client.fetch_debuginfo(&build_id),
+            Err(DebuginfodError::NoServers)
+        ));
+
+        store(&path, b"\x7fELF").unwrap();
+        assert_eq!(client.fetch_debuginfo(&build_id).unwrap(), path);
+        fs::remove_dir_all(&client.cache_dir).unwrap();
+    }
+}
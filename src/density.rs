@@ -0,0 +1,175 @@
+// Distances between consecutive safepoints of each function, which bound how
+// long a thread can run before it reaches one, e.g. when a collector waits for
+// all threads to stop. Records sharing an instruction count as one safepoint,
+// and the entry and end of a function bound its first and last gap.
+
+use std::collections::BTreeSet;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{symbols::FunctionSymbols, Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafepointGap {
+    pub function_address: u64,
+    // The function entry for the first gap of a function, and its end, from
+    // the symbol size, for the last one
+    pub start_pc: u64,
+    pub end_pc: u64,
+    // Instructions from `start_pc` up to `end_pc` excluded, if the
+    // instruction boundaries are known
+    pub instructions: Option<usize>,
+}
+
+impl SafepointGap {
+    pub fn bytes(&self) -> u64 {
+        self.end_pc - self.start_pc
+    }
+
+    pub fn exceeds(&self, threshold: GapThreshold) -> bool {
+        match threshold {
+            GapThreshold::Bytes(max) => self.bytes() > max,
+            GapThreshold::Instructions(max) => self.instructions.is_some_and(|count| count > max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapThreshold {
+    Bytes(u64),
+    // Gaps without an instruction count never exceed it
+    Instructions(usize),
+}
+
+/// Returns the gaps between consecutive safepoints of every function, in
+/// section order, also measured in instructions when `instruction_boundaries`
+/// are given. The gap after the last safepoint is only known for functions
+/// with a symbol.
+pub fn safepoint_gaps(
+    section: &LLVMStackMaps,
+    symbols: &FunctionSymbols,
+    instruction_boundaries: Option<&BTreeSet<u64>>,
+) -> Result<Vec<SafepointGap>, Error> {
+    let mut gaps = Vec::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let address = function.address();
+            let mut pcs = vec![address];
+            pcs.extend(
+                function
+                    .records_by_offset()?
+                    .into_keys()
+                    .map(|offset| address.wrapping_add(offset as u64)),
+            );
+            if let Some(symbol) = symbols.get(address) {
+                pcs.push(address.wrapping_add(symbol.size));
+            }
+
+            gaps.extend(pcs.windows(2).filter(|pair| pair[0] < pair[1]).map(|pair| {
+                SafepointGap {
+                    function_address: address,
+                    start_pc: pair[0],
+                    end_pc: pair[1],
+                    instructions: instruction_boundaries
+                        .map(|boundaries| boundaries.range(pair[0]..pair[1]).count()),
+                }
+            }));
+        }
+    }
+    Ok(gaps)
+}
+
+/// Returns the addresses of the instructions listed in the output of
+/// `objdump -d`. Lines that only continue the bytes of a long instruction are
+/// skipped.
+pub fn instruction_boundaries_from_objdump(text: &str) -> BTreeSet<u64> {
+    text.lines()
+        .filter(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let (address, rest) = line.trim_start().split_once(':')?;
+            let mut fields = rest.split('\t').skip(1);
+            fields.next()?;
+            fields
+                .next()
+                .filter(|mnemonic| !mnemonic.trim().is_empty())?;
+            u64::from_str_radix(address, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{symbols::FunctionSymbol, test_data};
+
+    #[test]
+    fn gaps() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let gaps = safepoint_gaps(&section, &FunctionSymbols::default(), None).unwrap();
+        // Without symbols, the ends of the functions are unknown
+        assert_eq!(
+            gaps,
+            [
+                SafepointGap {
+                    function_address: 0x1130,
+                    start_pc: 0x1130,
+                    end_pc: 0x1150,
+                    instructions: None,
+                },
+                SafepointGap {
+                    function_address: 0x1130,
+                    start_pc: 0x1150,
+                    end_pc: 0x115b,
+                    instructions: None,
+                },
+                SafepointGap {
+                    function_address: 0x1170,
+                    start_pc: 0x1170,
+                    end_pc: 0x1177,
+                    instructions: None,
+                },
+            ]
+        );
+        assert_eq!(gaps[1].bytes(), 11);
+        assert!(gaps[1].exceeds(GapThreshold::Bytes(10)));
+        assert!(!gaps[1].exceeds(GapThreshold::Bytes(11)));
+        assert!(!gaps[1].exceeds(GapThreshold::Instructions(0)));
+
+        let symbols: FunctionSymbols = vec![(
+            0x1130,
+            FunctionSymbol {
+                name: "foo".to_owned(),
+                size: 0x40,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let boundaries = [0x1130, 0x1150, 0x1155, 0x1158, 0x115b, 0x1160]
+            .iter()
+            .copied()
+            .collect();
+        let gaps = safepoint_gaps(&section, &symbols, Some(&boundaries)).unwrap();
+        assert_eq!(gaps.len(), 4);
+        assert_eq!(gaps[1].instructions, Some(3));
+        assert!(gaps[1].exceeds(GapThreshold::Instructions(2)));
+        assert_eq!((gaps[2].start_pc, gaps[2].end_pc), (0x115b, 0x1170));
+        assert_eq!(gaps[2].instructions, Some(2));
+    }
+
+    #[test]
+    fn objdump() {
+        let text = "\
+0000000000001130 <foo>:
+    1130:\t55                   \tpush   %rbp
+    1131:\t48 b8 00 00 00 00 00 \tmovabs $0x0,%rax
+    1138:\t00 00 00 
+    113b:\tc3                   \tret
+";
+        let boundaries: Vec<_> = instruction_boundaries_from_objdump(text)
+            .into_iter()
+            .collect();
+        assert_eq!(boundaries, [0x1130, 0x1131, 0x113b]);
+    }
+}
@@ -0,0 +1,226 @@
+// Warnings describe data that parses but is suspicious. Unlike errors they do
+// not stop processing: they are handed to a sink, which decides whether to
+// collect, print, ignore or escalate them based on their category.
+
+use std::{fmt, str::FromStr};
+
+use snafu::Snafu;
+
+use crate::addresses::AddressReporting;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningCategory {
+    ZeroAddress,
+    DuplicateAddress,
+    OverlappingFunctions,
+    SectionPadding,
+    SkippedBytes,
+}
+
+impl WarningCategory {
+    pub const ALL: &'static [WarningCategory] = &[
+        WarningCategory::ZeroAddress,
+        WarningCategory::DuplicateAddress,
+        WarningCategory::OverlappingFunctions,
+        WarningCategory::SectionPadding,
+        WarningCategory::SkippedBytes,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningCategory::ZeroAddress => "zero-address",
+            WarningCategory::DuplicateAddress => "duplicate-address",
+            WarningCategory::OverlappingFunctions => "overlapping-functions",
+            WarningCategory::SectionPadding => "section-padding",
+            WarningCategory::SkippedBytes => "skipped-bytes",
+        }
+    }
+}
+
+impl fmt::Display for WarningCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseCategoryError {
+    #[snafu(display("Unknown warning category: {}", name))]
+    UnknownCategory { name: String },
+}
+
+impl FromStr for WarningCategory {
+    type Err = ParseCategoryError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        WarningCategory::ALL
+            .iter()
+            .copied()
+            .find(|category| category.name() == name)
+            .ok_or_else(|| ParseCategoryError::UnknownCategory {
+                name: name.to_owned(),
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    ZeroAddress {
+        function_index: usize,
+    },
+    DuplicateAddress {
+        address: u64,
+        first_index: usize,
+        second_index: usize,
+    },
+    // `first` is only known to extend up to `first_end` when its size is
+    // available from the symbol table.
+    Overlap {
+        first: u64,
+        first_end: u64,
+        second: u64,
+    },
+    SectionPadding {
+        start: usize,
+        end: usize,
+    },
+    // A region that was skipped by lenient parsing because of `reason`
+    SkippedBytes {
+        start: usize,
+        end: usize,
+        reason: String,
+    },
+}
+
+impl Warning {
+    /// Returns the offset in the section that the warning is about, if any.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Warning::SectionPadding { start, .. } | Warning::SkippedBytes { start, .. } => {
+                Some(*start)
+            }
+            _ => None,
+        }
+    }
+
+    /// Reports the function addresses of the warning as selected by
+    /// `reporting`, e.g. with the load bias of the binary.
+    pub fn report_addresses(&mut self, reporting: &AddressReporting) {
+        match self {
+            Warning::DuplicateAddress { address, .. } => *address = reporting.function(*address),
+            Warning::Overlap {
+                first,
+                first_end,
+                second,
+            } => {
+                let size = first_end.wrapping_sub(*first);
+                *first = reporting.function(*first);
+                *first_end = first.wrapping_add(size);
+                *second = reporting.function(*second);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn category(&self) -> WarningCategory {
+        match self {
+            Warning::ZeroAddress { .. } => WarningCategory::ZeroAddress,
+            Warning::DuplicateAddress { .. } => WarningCategory::DuplicateAddress,
+            Warning::Overlap { .. } => WarningCategory::OverlappingFunctions,
+            Warning::SectionPadding { .. } => WarningCategory::SectionPadding,
+            Warning::SkippedBytes { .. } => WarningCategory::SkippedBytes,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::ZeroAddress { function_index } => write!(
+                f,
+                "function #{} has address 0, its relocation was probably not applied",
+                function_index
+            ),
+            Warning::DuplicateAddress {
+                address,
+                first_index,
+                second_index,
+            } => write!(
+                f,
+                "functions #{} and #{} share address {:#x}",
+                first_index, second_index, address
+            ),
+            Warning::Overlap {
+                first,
+                first_end,
+                second,
+            } => write!(
+                f,
+                "function at {:#x} overlaps [{:#x}, {:#x})",
+                second, first, first_end
+            ),
+            Warning::SectionPadding { start, end } => write!(
+                f,
+                "{} zero bytes at [{:#x}, {:#x}) do not belong to any stack map",
+                end - start,
+                start,
+                end
+            ),
+            Warning::SkippedBytes { start, end, reason } => write!(
+                f,
+                "skipped {} malformed bytes at [{:#x}, {:#x}): {}",
+                end - start,
+                start,
+                end,
+                reason
+            ),
+        }
+    }
+}
+
+pub trait DiagnosticsSink {
+    fn warning(&mut self, warning: Warning);
+}
+
+impl DiagnosticsSink for Vec<Warning> {
+    fn warning(&mut self, warning: Warning) {
+        self.push(warning);
+    }
+}
+
+// Discards every warning
+impl DiagnosticsSink for () {
+    fn warning(&mut self, _warning: Warning) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn category_names_roundtrip() {
+        for &category in WarningCategory::ALL {
+            assert_eq!(
+                category.name().parse::<WarningCategory>().unwrap(),
+                category
+            );
+        }
+        assert!("no-such-warning".parse::<WarningCategory>().is_err());
+    }
+
+    #[test]
+    fn biased_addresses() {
+        let mut reporting = AddressReporting::new(Default::default());
+        reporting.bias = 0x1000;
+        let mut warning = Warning::Overlap {
+            first: 0x1130,
+            first_end: 0x1180,
+            second: 0x1170,
+        };
+        warning.report_addresses(&reporting);
+        assert_eq!(
+            warning.to_string(),
+            "function at 0x2170 overlaps [0x2130, 0x2180)"
+        );
+    }
+}
@@ -0,0 +1,484 @@
+// Structural comparison of two stack maps. Functions and records are paired
+// by a configurable key, and only the pairs that differ are reported, together
+// with the functions and records that exist on one side only.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    addresses::AddressReporting,
+    owned::{Function, Record, StackMap},
+    symbols::FunctionSymbols,
+    Error, LiveOut, Location,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionMatching {
+    Address,
+    // Functions without a symbol fall back to being matched by address
+    Symbol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordMatching {
+    PatchPointId,
+    InstructionOffset,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiffOptions<'a> {
+    pub functions: FunctionMatching,
+    pub records: RecordMatching,
+    pub old_symbols: Option<&'a FunctionSymbols>,
+    pub new_symbols: Option<&'a FunctionSymbols>,
+}
+
+impl Default for DiffOptions<'_> {
+    fn default() -> Self {
+        Self {
+            functions: FunctionMatching::Address,
+            records: RecordMatching::PatchPointId,
+            old_symbols: None,
+            new_symbols: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocationDiff {
+    Added {
+        index: usize,
+        location: Location,
+    },
+    Removed {
+        index: usize,
+        location: Location,
+    },
+    Changed {
+        index: usize,
+        old: Location,
+        new: Location,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordDiff {
+    Added {
+        patch_point_id: u64,
+        instruction_offset: u32,
+    },
+    Removed {
+        patch_point_id: u64,
+        instruction_offset: u32,
+    },
+    Changed {
+        old_patch_point_id: u64,
+        new_patch_point_id: u64,
+        old_instruction_offset: u32,
+        new_instruction_offset: u32,
+        locations: Vec<LocationDiff>,
+        // Both lists of live-outs, if they differ
+        live_outs: Option<(Vec<LiveOut>, Vec<LiveOut>)>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionDiff {
+    // With the IDs of all the records of the function, in order
+    Added {
+        address: u64,
+        patch_point_ids: Vec<u64>,
+    },
+    Removed {
+        address: u64,
+        patch_point_ids: Vec<u64>,
+    },
+    Changed {
+        old_address: u64,
+        new_address: u64,
+        old_stack_size: u64,
+        new_stack_size: u64,
+        records: Vec<RecordDiff>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    pub functions: Vec<FunctionDiff>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Replaces the function addresses with how `old` and `new` report them,
+    /// for the old and the new stack map respectively.
+    pub fn report_addresses(&mut self, old: &AddressReporting, new: &AddressReporting) {
+        for function in &mut self.functions {
+            match function {
+                FunctionDiff::Added { address, .. } => *address = new.function(*address),
+                FunctionDiff::Removed { address, .. } => *address = old.function(*address),
+                FunctionDiff::Changed {
+                    old_address,
+                    new_address,
+                    ..
+                } => {
+                    *old_address = old.function(*old_address);
+                    *new_address = new.function(*new_address);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum FunctionKey {
+    Address(u64),
+    Symbol(String),
+}
+
+fn function_key(
+    function: &Function,
+    matching: FunctionMatching,
+    symbols: Option<&FunctionSymbols>,
+) -> FunctionKey {
+    let name = symbols.and_then(|symbols| symbols.name(function.address));
+    match (matching, name) {
+        (FunctionMatching::Symbol, Some(name)) => FunctionKey::Symbol(name.to_owned()),
+        _ => FunctionKey::Address(function.address),
+    }
+}
+
+fn record_key(record: &Record, matching: RecordMatching) -> u64 {
+    match matching {
+        RecordMatching::PatchPointId => record.patch_point_id,
+        RecordMatching::InstructionOffset => record.instruction_offset as u64,
+    }
+}
+
+// Pairs the elements of both sides with the same key. Elements sharing a key
+// on the same side are paired in order, and the leftovers are unmatched.
+fn pair_by_key<K: Ord, T>(
+    old: impl IntoIterator<Item = (K, T)>,
+    new: impl IntoIterator<Item = (K, T)>,
+) -> Vec<(Option<T>, Option<T>)> {
+    let mut groups: BTreeMap<K, (Vec<T>, Vec<T>)> = BTreeMap::new();
+    for (key, element) in old {
+        groups.entry(key).or_default().0.push(element);
+    }
+    for (key, element) in new {
+        groups.entry(key).or_default().1.push(element);
+    }
+
+    let mut pairs = Vec::new();
+    for (_, (old_group, new_group)) in groups {
+        let mut old_group = old_group.into_iter();
+        let mut new_group = new_group.into_iter();
+        loop {
+            match (old_group.next(), new_group.next()) {
+                (None, None) => break,
+                pair => pairs.push(pair),
+            }
+        }
+    }
+    pairs
+}
+
+fn diff_locations(old: &[Location], new: &[Location]) -> Vec<LocationDiff> {
+    let mut diffs = Vec::new();
+    for index in 0..old.len().max(new.len()) {
+        match (old.get(index), new.get(index)) {
+            (Some(old), Some(new)) if old != new => diffs.push(LocationDiff::Changed {
+                index,
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            (Some(old), None) => diffs.push(LocationDiff::Removed {
+                index,
+                location: old.clone(),
+            }),
+            (None, Some(new)) => diffs.push(LocationDiff::Added {
+                index,
+                location: new.clone(),
+            }),
+            _ => {}
+        }
+    }
+    diffs
+}
+
+fn diff_records(old: &Function, new: &Function, options: &DiffOptions) -> Vec<RecordDiff> {
+    let mut diffs = Vec::new();
+    let pairs = pair_by_key(
+        old.records
+            .iter()
+            .map(|record| (record_key(record, options.records), record)),
+        new.records
+            .iter()
+            .map(|record| (record_key(record, options.records), record)),
+    );
+
+    for pair in pairs {
+        match pair {
+            (Some(old), Some(new)) => {
+                let locations = diff_locations(&old.locations, &new.locations);
+                let live_outs = if old.live_outs != new.live_outs {
+                    Some((old.live_outs.clone(), new.live_outs.clone()))
+                } else {
+                    None
+                };
+                if old.patch_point_id != new.patch_point_id
+                    || old.instruction_offset != new.instruction_offset
+                    || !locations.is_empty()
+                    || live_outs.is_some()
+                {
+                    diffs.push(RecordDiff::Changed {
+                        old_patch_point_id: old.patch_point_id,
+                        new_patch_point_id: new.patch_point_id,
+                        old_instruction_offset: old.instruction_offset,
+                        new_instruction_offset: new.instruction_offset,
+                        locations,
+                        live_outs,
+                    });
+                }
+            }
+            (Some(old), None) => diffs.push(RecordDiff::Removed {
+                patch_point_id: old.patch_point_id,
+                instruction_offset: old.instruction_offset,
+            }),
+            (None, Some(new)) => diffs.push(RecordDiff::Added {
+                patch_point_id: new.patch_point_id,
+                instruction_offset: new.instruction_offset,
+            }),
+            (None, None) => unreachable!(),
+        }
+    }
+    diffs
+}
+
+fn patch_point_ids(function: &Function) -> Vec<u64> {
+    function
+        .records
+        .iter()
+        .map(|record| record.patch_point_id)
+        .collect()
+}
+
+pub fn diff(old: &crate::StackMap, new: &crate::StackMap) -> Result<DiffReport, Error> {
+    diff_with_options(old, new, &DiffOptions::default())
+}
+
+/// Compares `old` and `new`, pairing functions and records as configured in
+/// `options`. Functions are reported in the order of their keys.
+pub fn diff_with_options(
+    old: &crate::StackMap,
+    new: &crate::StackMap,
+    options: &DiffOptions,
+) -> Result<DiffReport, Error> {
+    let old = StackMap::<(), ()>::from_parsed(old)?;
+    let new = StackMap::<(), ()>::from_parsed(new)?;
+
+    let pairs = pair_by_key(
+        old.functions.iter().map(|function| {
+            let key = function_key(function, options.functions, options.old_symbols);
+            (key, function)
+        }),
+        new.functions.iter().map(|function| {
+            let key = function_key(function, options.functions, options.new_symbols);
+            (key, function)
+        }),
+    );
+
+    let mut functions = Vec::new();
+    for pair in pairs {
+        match pair {
+            (Some(old_function), Some(new_function)) => {
+                let records = diff_records(old_function, new_function, options);
+                if old_function.address != new_function.address
+                    || old_function.stack_size != new_function.stack_size
+                    || !records.is_empty()
+                {
+                    functions.push(FunctionDiff::Changed {
+                        old_address: old_function.address,
+                        new_address: new_function.address,
+                        old_stack_size: old_function.stack_size,
+                        new_stack_size: new_function.stack_size,
+                        records,
+                    });
+                }
+            }
+            (Some(old_function), None) => functions.push(FunctionDiff::Removed {
+                address: old_function.address,
+                patch_point_ids: patch_point_ids(old_function),
+            }),
+            (None, Some(new_function)) => functions.push(FunctionDiff::Added {
+                address: new_function.address,
+                patch_point_ids: patch_point_ids(new_function),
+            }),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    Ok(DiffReport { functions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{addresses::AddressMode, symbols::FunctionSymbol, test_data, LLVMStackMaps};
+    use fallible_iterator::FallibleIterator;
+
+    fn parse(data: &[u8]) -> crate::StackMap<'_> {
+        LLVMStackMaps::new(data)
+            .stack_maps()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn modified() -> Vec<u8> {
+        let mut stack_map =
+            StackMap::<(), ()>::from_parsed(&parse(test_data::TWO_FUNCTIONS)).unwrap();
+        stack_map.functions[0].address = 0x2130;
+        stack_map.functions[1].stack_size = 16;
+        stack_map.functions[1].records[0].locations[0] = "register(r1, 8)".parse().unwrap();
+        stack_map.functions[1].records.push(Record {
+            patch_point_id: 45,
+            instruction_offset: 0x10,
+            locations: Vec::new(),
+            live_outs: Vec::new(),
+            metadata: (),
+        });
+        stack_map.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn identical_stack_maps() {
+        let stack_map = parse(test_data::TWO_FUNCTIONS);
+        assert!(diff(&stack_map, &stack_map).unwrap().is_empty());
+    }
+
+    #[test]
+    fn match_by_address() {
+        let old = parse(test_data::TWO_FUNCTIONS);
+        let new_data = modified();
+        let new = parse(&new_data);
+        let report = diff(&old, &new).unwrap();
+
+        assert_eq!(
+            report.functions,
+            [
+                FunctionDiff::Removed {
+                    address: 0x1130,
+                    patch_point_ids: vec![42, 43],
+                },
+                FunctionDiff::Changed {
+                    old_address: 0x1170,
+                    new_address: 0x1170,
+                    old_stack_size: 8,
+                    new_stack_size: 16,
+                    records: vec![
+                        RecordDiff::Changed {
+                            old_patch_point_id: 44,
+                            new_patch_point_id: 44,
+                            old_instruction_offset: 7,
+                            new_instruction_offset: 7,
+                            locations: vec![LocationDiff::Changed {
+                                index: 0,
+                                old: "register(r0, 8)".parse().unwrap(),
+                                new: "register(r1, 8)".parse().unwrap(),
+                            }],
+                            live_outs: None,
+                        },
+                        RecordDiff::Added {
+                            patch_point_id: 45,
+                            instruction_offset: 0x10,
+                        },
+                    ],
+                },
+                FunctionDiff::Added {
+                    address: 0x2130,
+                    patch_point_ids: vec![42, 43],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reported_addresses() {
+        let old = parse(test_data::TWO_FUNCTIONS);
+        let new_data = modified();
+        let new = parse(&new_data);
+        let mut report = diff(&old, &new).unwrap();
+
+        // The old side in its section, the new side loaded at a bias
+        let old_reporting = AddressReporting {
+            mode: AddressMode::Section,
+            bias: 0,
+            sections: vec![(0x1000, 0x2000)].into_iter().collect(),
+        };
+        let new_reporting = AddressReporting {
+            mode: AddressMode::Absolute,
+            bias: 0x5555_0000_0000,
+            sections: Default::default(),
+        };
+        report.report_addresses(&old_reporting, &new_reporting);
+        let addresses: Vec<_> = report
+            .functions
+            .iter()
+            .map(|function| match *function {
+                FunctionDiff::Removed { address, .. } => (Some(address), None),
+                FunctionDiff::Added { address, .. } => (None, Some(address)),
+                FunctionDiff::Changed {
+                    old_address,
+                    new_address,
+                    ..
+                } => (Some(old_address), Some(new_address)),
+            })
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                (Some(0x130), None),
+                (Some(0x170), Some(0x5555_0000_1170)),
+                (None, Some(0x5555_0000_2130)),
+            ]
+        );
+    }
+
+    #[test]
+    fn match_by_symbol() {
+        let symbol = |name: &str| FunctionSymbol {
+            name: name.to_owned(),
+            size: 0x40,
+        };
+        let old_symbols = vec![(0x1130, symbol("foo")), (0x1170, symbol("bar"))]
+            .into_iter()
+            .collect();
+        let new_symbols = vec![(0x2130, symbol("foo")), (0x1170, symbol("bar"))]
+            .into_iter()
+            .collect();
+        let options = DiffOptions {
+            functions: FunctionMatching::Symbol,
+            old_symbols: Some(&old_symbols),
+            new_symbols: Some(&new_symbols),
+            ..DiffOptions::default()
+        };
+
+        let old = parse(test_data::TWO_FUNCTIONS);
+        let new_data = modified();
+        let new = parse(&new_data);
+        let report = diff_with_options(&old, &new, &options).unwrap();
+
+        assert_eq!(report.functions.len(), 2);
+        assert!(matches!(
+            report.functions[1],
+            FunctionDiff::Changed {
+                old_address: 0x1130,
+                new_address: 0x2130,
+                ref records,
+                ..
+            } if records.is_empty()
+        ));
+    }
+}
@@ -0,0 +1,418 @@
+// Differential testing against `llvm-readobj --stackmap`, LLVM's reference
+// reader. Its output is parsed back into the owned model and compared field by
+// field with what this crate parses from the same object.
+
+use std::{fmt, fs, io, path::Path, process::Command};
+
+use fallible_iterator::FallibleIterator;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::{
+    loader::{self, StackMapsSource},
+    owned::{Function, Record, StackMap},
+    LLVMStackMaps, LiveOut, Location, LocationKind,
+};
+
+#[derive(Debug, Snafu)]
+pub enum DifferentialError {
+    #[snafu(display("Could not run {}: {}", program, source))]
+    ReadobjSpawn { program: String, source: io::Error },
+    #[snafu(display("{} failed: {}", program, stderr))]
+    ReadobjFailed { program: String, stderr: String },
+    #[snafu(display("Unexpected llvm-readobj output at line {}: {}", line_number, line))]
+    MalformedOutput { line_number: usize, line: String },
+    #[snafu(display("Could not read input: {}", source))]
+    ReadInput { source: io::Error },
+    #[snafu(display("Could not load stack maps: {}", source))]
+    Load { source: loader::LoadError },
+    #[snafu(display("Could not parse stack maps: {:?}", source))]
+    Parse { source: crate::Error },
+}
+
+type Result<T> = std::result::Result<T, DifferentialError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: String,
+    pub ours: String,
+    pub reference: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: ours {}, llvm-readobj {}",
+            self.path, self.ours, self.reference
+        )
+    }
+}
+
+// The program can be overridden through `LLVM_READOBJ`, e.g. to pick a
+// specific LLVM version.
+pub fn run_llvm_readobj(object_path: &Path) -> Result<String> {
+    let program = std::env::var("LLVM_READOBJ").unwrap_or_else(|_| "llvm-readobj".to_owned());
+    let output = Command::new(&program)
+        .arg("--stackmap")
+        .arg(object_path)
+        .output()
+        .context(ReadobjSpawn { program: &program })?;
+
+    if !output.status.success() {
+        return ReadobjFailed {
+            program,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .fail();
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn diff_against_readobj(object_path: &Path) -> Result<Vec<Mismatch>> {
+    let reference = parse_readobj_output(&run_llvm_readobj(object_path)?)?;
+
+    let file_data = fs::read(object_path).context(ReadInput)?;
+    let data =
+        loader::load_stack_maps_data(&file_data, &StackMapsSource::default()).context(Load)?;
+    let section = LLVMStackMaps::new(&data);
+
+    // llvm-readobj only decodes the first stack map of the section
+    let ours = match section.stack_maps().next().context(Parse)? {
+        Some(stack_map) => Some(StackMap::from_parsed(&stack_map).context(Parse)?),
+        None => None,
+    };
+
+    let mut mismatches = Vec::new();
+    match (ours, reference) {
+        (Some(ours), Some(reference)) => compare_stack_maps(&ours, &reference, &mut mismatches),
+        (ours, reference) => {
+            if ours.is_some() != reference.is_some() {
+                mismatches.push(Mismatch {
+                    path: "stack map".to_owned(),
+                    ours: present(ours.is_some()),
+                    reference: present(reference.is_some()),
+                });
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+fn present(present: bool) -> String {
+    if present { "present" } else { "missing" }.to_owned()
+}
+
+struct Lines<'a> {
+    lines: std::iter::Peekable<std::iter::Enumerate<std::str::Lines<'a>>>,
+}
+
+impl<'a> Lines<'a> {
+    fn next_trimmed(&mut self) -> Result<(usize, &'a str)> {
+        match self.lines.next() {
+            Some((index, line)) => Ok((index + 1, line.trim())),
+            None => MalformedOutput {
+                line_number: 0usize,
+                line: "<end of output>",
+            }
+            .fail(),
+        }
+    }
+
+    // Parses the line after `prefix` with `parse`, reporting a malformed line
+    // when either the prefix or the rest does not match.
+    fn parse<T>(&mut self, prefix: &str, parse: impl FnOnce(&str) -> Option<T>) -> Result<T> {
+        let (line_number, line) = self.next_trimmed()?;
+        line.strip_prefix(prefix)
+            .and_then(parse)
+            .context(MalformedOutput { line_number, line })
+    }
+}
+
+pub fn parse_readobj_output(output: &str) -> Result<Option<StackMap>> {
+    let mut lines = Lines {
+        lines: output.lines().enumerate().peekable(),
+    };
+
+    while let Some((_, line)) = lines.lines.peek() {
+        if line.trim_start().starts_with("LLVM StackMap Version:") {
+            break;
+        }
+        lines.lines.next();
+    }
+    if lines.lines.peek().is_none() {
+        return Ok(None);
+    }
+
+    let version = lines.parse("LLVM StackMap Version: ", |rest| rest.parse().ok())?;
+
+    let num_functions: usize = lines.parse("Num Functions: ", |rest| rest.parse().ok())?;
+    let mut function_headers = Vec::with_capacity(num_functions);
+    for _ in 0..num_functions {
+        let header = lines.parse("Function address: ", |rest| {
+            let (address, rest) = rest.split_once(", stack size: ")?;
+            let (stack_size, record_count) = rest.split_once(", callsite record count: ")?;
+            Some((
+                address.parse::<u64>().ok()?,
+                stack_size.parse::<u64>().ok()?,
+                record_count.parse::<usize>().ok()?,
+            ))
+        })?;
+        function_headers.push(header);
+    }
+
+    let num_constants: usize = lines.parse("Num Constants: ", |rest| rest.parse().ok())?;
+    let mut constants = Vec::with_capacity(num_constants);
+    for _ in 0..num_constants {
+        let constant = lines.parse("#", |rest| rest.split_once(": ")?.1.parse().ok())?;
+        constants.push(constant);
+    }
+
+    let num_records: usize = lines.parse("Num Records: ", |rest| rest.parse().ok())?;
+    let mut records = Vec::with_capacity(num_records);
+    for _ in 0..num_records {
+        records.push(parse_record(&mut lines)?);
+    }
+
+    // Records are listed independently of functions, so they are assigned
+    // to them in order, following the record counts
+    let mut records = records.into_iter();
+    let functions = function_headers
+        .into_iter()
+        .map(|(address, stack_size, record_count)| Function {
+            address,
+            stack_size,
+            records: records.by_ref().take(record_count).collect(),
+            metadata: (),
+        })
+        .collect();
+
+    Ok(Some(StackMap {
+        version,
+        constants,
+        functions,
+    }))
+}
+
+fn parse_record(lines: &mut Lines) -> Result<Record> {
+    let (patch_point_id, instruction_offset) = lines.parse("Record ID: ", |rest| {
+        let (id, offset) = rest.split_once(", instruction offset: ")?;
+        Some((id.parse().ok()?, offset.parse().ok()?))
+    })?;
+
+    let (line_number, line) = lines.next_trimmed()?;
+    let num_locations: usize = line
+        .strip_suffix(" locations:")
+        .and_then(|count| count.parse().ok())
+        .context(MalformedOutput { line_number, line })?;
+    let mut locations = Vec::with_capacity(num_locations);
+    for _ in 0..num_locations {
+        let location = lines.parse("#", |rest| parse_location(rest.split_once(": ")?.1))?;
+        locations.push(location);
+    }
+
+    let (line_number, line) = lines.next_trimmed()?;
+    let live_outs = line
+        .split_once(" live-outs: [")
+        .and_then(|(_, rest)| parse_live_outs(rest.strip_suffix(']')?))
+        .context(MalformedOutput { line_number, line })?;
+
+    Ok(Record {
+        patch_point_id,
+        instruction_offset,
+        locations,
+        live_outs,
+        metadata: (),
+    })
+}
+
+fn parse_register(register: &str) -> Option<u16> {
+    register.strip_prefix("R#")?.parse().ok()
+}
+
+fn parse_register_offset(text: &str) -> Option<(u16, isize)> {
+    let (register, offset) = text.split_once(" + ")?;
+    Some((parse_register(register)?, offset.parse().ok()?))
+}
+
+fn parse_location(text: &str) -> Option<Location> {
+    let (kind, size) = text.rsplit_once(", size: ")?;
+    let size = size.parse().ok()?;
+
+    let kind = if let Some(register) = kind.strip_prefix("Register ") {
+        LocationKind::Register(parse_register(register)?)
+    } else if let Some(rest) = kind.strip_prefix("Direct ") {
+        let (register, offset) = parse_register_offset(rest)?;
+        LocationKind::Direct { register, offset }
+    } else if let Some(rest) = kind.strip_prefix("Indirect [") {
+        let (register, offset) = parse_register_offset(rest.strip_suffix(']')?)?;
+        LocationKind::Indirect { register, offset }
+    } else if let Some(constant) = kind.strip_prefix("ConstantIndex ") {
+        let (_, value) = constant.split_once(" (")?;
+        LocationKind::Constant(value.strip_suffix(')')?.parse().ok()?)
+    } else if let Some(constant) = kind.strip_prefix("Constant ") {
+        // Small constants are printed as unsigned 32-bit values
+        let constant: u32 = constant.parse().ok()?;
+        LocationKind::Constant(constant as i32 as u64)
+    } else {
+        return None;
+    };
+
+    Some(Location::new(kind, size))
+}
+
+fn parse_live_outs(text: &str) -> Option<Vec<LiveOut>> {
+    let mut live_outs = Vec::new();
+    let mut tokens = text.split_whitespace();
+    while let Some(register) = tokens.next() {
+        let size = tokens.next()?.strip_prefix('(')?.strip_suffix("-bytes)")?;
+        live_outs.push(LiveOut::new(parse_register(register)?, size.parse().ok()?));
+    }
+
+    Some(live_outs)
+}
+
+fn check<T: PartialEq + fmt::Debug>(
+    path: impl FnOnce() -> String,
+    ours: T,
+    reference: T,
+    mismatches: &mut Vec<Mismatch>,
+) {
+    if ours != reference {
+        mismatches.push(Mismatch {
+            path: path(),
+            ours: format!("{:?}", ours),
+            reference: format!("{:?}", reference),
+        });
+    }
+}
+
+pub fn compare_stack_maps(ours: &StackMap, reference: &StackMap, mismatches: &mut Vec<Mismatch>) {
+    check(
+        || "version".to_owned(),
+        ours.version,
+        reference.version,
+        mismatches,
+    );
+    check(
+        || "constants".to_owned(),
+        &ours.constants,
+        &reference.constants,
+        mismatches,
+    );
+    check(
+        || "number of functions".to_owned(),
+        ours.functions.len(),
+        reference.functions.len(),
+        mismatches,
+    );
+
+    for (function_idx, (ours, reference)) in
+        ours.functions.iter().zip(&reference.functions).enumerate()
+    {
+        let function_path = || format!("functions[{}]", function_idx);
+        check(
+            || format!("{}.address", function_path()),
+            ours.address,
+            reference.address,
+            mismatches,
+        );
+        check(
+            || format!("{}.stack_size", function_path()),
+            ours.stack_size,
+            reference.stack_size,
+            mismatches,
+        );
+        check(
+            || format!("{}.records.len()", function_path()),
+            ours.records.len(),
+            reference.records.len(),
+            mismatches,
+        );
+
+        for (record_idx, (ours, reference)) in
+            ours.records.iter().zip(&reference.records).enumerate()
+        {
+            let record_path = || format!("{}.records[{}]", function_path(), record_idx);
+            check(
+                || format!("{}.patch_point_id", record_path()),
+                ours.patch_point_id,
+                reference.patch_point_id,
+                mismatches,
+            );
+            check(
+                || format!("{}.instruction_offset", record_path()),
+                ours.instruction_offset,
+                reference.instruction_offset,
+                mismatches,
+            );
+            check(
+                || format!("{}.locations", record_path()),
+                &ours.locations,
+                &reference.locations,
+                mismatches,
+            );
+            check(
+                || format!("{}.live_outs", record_path()),
+                &ours.live_outs,
+                &reference.live_outs,
+                mismatches,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    const READOBJ_OUTPUT: &str = "
+File: t
+Format: elf64-x86-64
+Arch: x86_64
+AddressSize: 64bit
+LoadName: <Not found>
+LLVM StackMap Version: 3
+Num Functions: 2
+  Function address: 4400, stack size: 40, callsite record count: 2
+  Function address: 4464, stack size: 8, callsite record count: 1
+Num Constants: 1
+  #1: 1234567890123
+Num Records: 3
+  Record ID: 42, instruction offset: 32
+    4 locations:
+      #1: Direct R#6 + -32, size: 8
+      #2: Register R#14, size: 8
+      #3: Constant 7, size: 8
+      #4: ConstantIndex #0 (1234567890123), size: 8
+    0 live-outs: [ ]
+  Record ID: 43, instruction offset: 43
+    1 locations:
+      #1: Register R#3, size: 8
+    0 live-outs: [ ]
+  Record ID: 44, instruction offset: 7
+    1 locations:
+      #1: Register R#0, size: 8
+    2 live-outs: [ R#7 (8-bytes) R#17 (16-bytes) ]
+";
+
+    #[test]
+    fn parse_and_compare() {
+        let reference = parse_readobj_output(READOBJ_OUTPUT).unwrap().unwrap();
+        assert_eq!(
+            reference.functions[1].records[0].live_outs,
+            vec![LiveOut::new(7, 8), LiveOut::new(17, 16)]
+        );
+
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let ours = StackMap::from_parsed(&stack_map).unwrap();
+
+        let mut mismatches = Vec::new();
+        compare_stack_maps(&ours, &reference, &mut mismatches);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].path, "functions[1].records[0].live_outs");
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{LiveOut, Location, LocationKind};
+
+// 64-bit FNV-1a, which is simple enough to be kept stable across releases,
+// unlike the hashers in the standard library.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(crate) struct Fingerprinter {
+    state: u64,
+}
+
+impl Fingerprinter {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: FNV_OFFSET_BASIS,
+        }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    // Locations are hashed by meaning rather than by encoding, so a constant
+    // hashes the same whether it is stored inline or in the constants pool.
+    pub(crate) fn write_location(&mut self, location: &Location) {
+        match *location.kind() {
+            LocationKind::Register(register) => {
+                self.write(&[1]);
+                self.write_u64(register as u64);
+            }
+            LocationKind::Direct { register, offset } => {
+                self.write(&[2]);
+                self.write_u64(register as u64);
+                self.write_u64(offset as u64);
+            }
+            LocationKind::Indirect { register, offset } => {
+                self.write(&[3]);
+                self.write_u64(register as u64);
+                self.write_u64(offset as u64);
+            }
+            LocationKind::Constant(constant) => {
+                self.write(&[4]);
+                self.write_u64(constant);
+            }
+        }
+        self.write_u64(location.size() as u64);
+    }
+
+    pub(crate) fn write_live_out(&mut self, live_out: &LiveOut) {
+        self.write_u64(live_out.dwarf_reg_num() as u64);
+        self.write_u64(live_out.size() as u64);
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.state
+    }
+}
@@ -0,0 +1,157 @@
+// A denormalized view of a stack map, meant to be loaded as-is into dataframe
+// libraries. Every row repeats the function and record it belongs to, and
+// only uses scalar fields, with `None` where a field does not apply.
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{addresses::AddressReporting, Error, LocationKind, StackMap};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatRecordRow {
+    pub function_index: usize,
+    pub function_address: u64,
+    pub function_stack_size: u64,
+    pub record_index: usize,
+    pub patch_point_id: u64,
+    pub instruction_offset: u32,
+    pub pc: u64,
+    pub num_locations: u16,
+    pub num_live_outs: u16,
+    // The location fields are all `None` for records without locations, which
+    // still get a row so that they are not lost.
+    pub location_index: Option<u16>,
+    pub location_kind: Option<&'static str>,
+    pub location_size: Option<u16>,
+    pub dwarf_reg_num: Option<u16>,
+    pub offset: Option<i64>,
+    pub constant: Option<u64>,
+}
+
+impl FlatRecordRow {
+    /// Replaces the function address and the PC with how `reporting`
+    /// reports them.
+    pub fn report_addresses(&mut self, reporting: &AddressReporting) {
+        self.pc = reporting.record(self.function_address, self.instruction_offset as u64);
+        self.function_address = reporting.function(self.function_address);
+    }
+}
+
+impl<'input> StackMap<'input> {
+    /// Returns one row per location of every record in the stack map, in the
+    /// order they appear in the section.
+    pub fn flatten(&self) -> Result<Vec<FlatRecordRow>, Error> {
+        let mut rows = Vec::new();
+
+        let mut functions_iter = self.functions().enumerate();
+        while let Some((function_index, function)) = functions_iter.next()? {
+            let mut records_iter = function.records().enumerate();
+            while let Some((record_index, record)) = records_iter.next()? {
+                let instruction_offset = record.instruction_offset() as u32;
+                let row = FlatRecordRow {
+                    function_index,
+                    function_address: function.address(),
+                    function_stack_size: function.stack_size() as u64,
+                    record_index,
+                    patch_point_id: record.patch_point_id(),
+                    instruction_offset,
+                    pc: function.address().wrapping_add(instruction_offset as u64),
+                    num_locations: record.num_locations() as u16,
+                    num_live_outs: record.num_live_outs() as u16,
+                    location_index: None,
+                    location_kind: None,
+                    location_size: None,
+                    dwarf_reg_num: None,
+                    offset: None,
+                    constant: None,
+                };
+
+                if record.num_locations() == 0 {
+                    rows.push(row);
+                    continue;
+                }
+
+                let mut locations_iter = record.locations().enumerate();
+                while let Some((location_index, location)) = locations_iter.next()? {
+                    let (kind, dwarf_reg_num, offset, constant) = match *location.kind() {
+                        LocationKind::Register(register) => {
+                            ("register", Some(register), None, None)
+                        }
+                        LocationKind::Direct { register, offset } => {
+                            ("direct", Some(register), Some(offset as i64), None)
+                        }
+                        LocationKind::Indirect { register, offset } => {
+                            ("indirect", Some(register), Some(offset as i64), None)
+                        }
+                        LocationKind::Constant(value) => ("constant", None, None, Some(value)),
+                    };
+
+                    rows.push(FlatRecordRow {
+                        location_index: Some(location_index as u16),
+                        location_kind: Some(kind),
+                        location_size: Some(location.size() as u16),
+                        dwarf_reg_num,
+                        offset,
+                        constant,
+                        ..row.clone()
+                    });
+                }
+            }
+        }
+
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{addresses::AddressMode, owned, test_data, LLVMStackMaps};
+
+    #[test]
+    fn flatten_rows() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let rows = stack_map.flatten().unwrap();
+        assert_eq!(rows.len(), 6);
+
+        assert_eq!(rows[0].function_address, 0x1130);
+        assert_eq!(rows[0].pc, 0x1150);
+        assert_eq!(rows[0].location_kind, Some("direct"));
+        assert_eq!(rows[0].dwarf_reg_num, Some(6));
+        assert_eq!(rows[0].offset, Some(-32));
+        assert_eq!(rows[3].location_index, Some(3));
+        assert_eq!(rows[3].constant, Some(1234567890123));
+        assert_eq!(rows[5].function_index, 1);
+        assert_eq!(rows[5].patch_point_id, 44);
+
+        let mut row = rows[0].clone();
+        row.report_addresses(&AddressReporting {
+            mode: AddressMode::Section,
+            bias: 0,
+            sections: vec![(0x1000, 0x2000)].into_iter().collect(),
+        });
+        assert_eq!((row.function_address, row.pc), (0x130, 0x150));
+    }
+
+    #[test]
+    fn records_without_locations() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        owned.functions[1].records[0].locations.clear();
+        let data = owned.to_bytes().unwrap();
+
+        let section = LLVMStackMaps::new(&data);
+        let rows = section
+            .stack_maps()
+            .next()
+            .unwrap()
+            .unwrap()
+            .flatten()
+            .unwrap();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[5].patch_point_id, 44);
+        assert_eq!(rows[5].num_locations, 0);
+        assert_eq!(rows[5].location_index, None);
+    }
+}
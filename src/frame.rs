@@ -0,0 +1,183 @@
+// Usage of the frame of a function by its records, to find the bytes that no
+// record refers to. A scanner that only visits the locations of the records
+// never needs to look at those bytes, while a conservative one scans them all.
+
+use std::ops::Range;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{
+    classify::RegisterConventions, Error, Function, LLVMStackMaps, Location, LocationKind,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameUsage {
+    pub address: u64,
+    pub stack_size: u64,
+    // Sorted and disjoint ranges of offsets from the stack pointer, within
+    // `0..stack_size`
+    pub referenced: Vec<Range<u64>>,
+    pub unreferenced: Vec<Range<u64>>,
+}
+
+impl FrameUsage {
+    pub fn unreferenced_bytes(&self) -> u64 {
+        self.unreferenced
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+}
+
+/// Returns the frame bytes referred to by `location`, as offsets from the
+/// stack pointer clamped to the frame of `stack_size` bytes. Frame pointer
+/// offsets are translated using `conventions`, and locations based on other
+/// registers are left out. The size of a direct location is that of the
+/// pointer to the stack object rather than of the object itself, so only the
+/// start of allocas is accounted for.
+pub fn frame_range(
+    location: &Location,
+    stack_size: u64,
+    conventions: &RegisterConventions,
+) -> Option<Range<u64>> {
+    let (register, offset) = match *location.kind() {
+        LocationKind::Direct { register, offset } | LocationKind::Indirect { register, offset } => {
+            (register, offset as i64)
+        }
+        LocationKind::Register(_) | LocationKind::Constant(_) => return None,
+    };
+    let start = if register == conventions.stack_pointer {
+        offset
+    } else if register == conventions.frame_pointer {
+        offset - conventions.frame_pointer_offset as i64 + stack_size as i64
+    } else {
+        return None;
+    };
+
+    let end = start.saturating_add(location.size() as i64);
+    let start = start.clamp(0, stack_size as i64) as u64;
+    let end = end.clamp(0, stack_size as i64) as u64;
+    Some(start..end).filter(|range| range.start < range.end)
+}
+
+impl FrameUsage {
+    /// Merges the `referenced` frame ranges of the function at `address`,
+    /// e.g. as returned by [`frame_range`], in any order.
+    pub fn new(address: u64, stack_size: u64, mut referenced: Vec<Range<u64>>) -> Self {
+        referenced.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(referenced.len());
+        for range in referenced {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        let mut unreferenced = Vec::new();
+        let mut next = 0;
+        for range in &merged {
+            if next < range.start {
+                unreferenced.push(next..range.start);
+            }
+            next = range.end;
+        }
+        if next < stack_size {
+            unreferenced.push(next..stack_size);
+        }
+
+        Self {
+            address,
+            stack_size,
+            referenced: merged,
+            unreferenced,
+        }
+    }
+}
+
+/// Collects the frame bytes referred to by any record of `function`.
+pub fn frame_usage(
+    function: &Function,
+    conventions: &RegisterConventions,
+) -> Result<FrameUsage, Error> {
+    let stack_size = function.stack_size() as u64;
+    let mut referenced = Vec::new();
+
+    let mut records_iter = function.records();
+    while let Some(record) = records_iter.next()? {
+        let mut locations_iter = record.locations();
+        while let Some(location) = locations_iter.next()? {
+            referenced.extend(frame_range(&location, stack_size, conventions));
+        }
+    }
+
+    Ok(FrameUsage::new(function.address(), stack_size, referenced))
+}
+
+/// Computes the usage of the frame of every function in the section.
+pub fn frame_usages(
+    section: &LLVMStackMaps,
+    conventions: &RegisterConventions,
+) -> Result<Vec<FrameUsage>, Error> {
+    let mut usages = Vec::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            usages.push(frame_usage(&function, conventions)?);
+        }
+    }
+    Ok(usages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        owned::{self, Record},
+        Location,
+    };
+
+    fn section(locations: Vec<Location>) -> Vec<u8> {
+        let stack_map = owned::StackMap::<(), ()> {
+            version: 3,
+            constants: Vec::new(),
+            functions: vec![owned::Function {
+                address: 0x1000,
+                stack_size: 64,
+                records: vec![Record {
+                    patch_point_id: 1,
+                    instruction_offset: 4,
+                    locations,
+                    live_outs: Vec::new(),
+                    metadata: (),
+                }],
+                metadata: (),
+            }],
+        };
+        stack_map.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn unreferenced_ranges() {
+        let data = section(
+            [
+                "indirect(r7, 8, 8)",
+                "indirect(r7, 12, 8)",
+                // RBP points 56 bytes above RSP in a 64-byte frame
+                "indirect(r6, -16, 8)",
+                "indirect(r7, 64, 8)",
+                "register(r3, 8)",
+            ]
+            .iter()
+            .map(|location| location.parse().unwrap())
+            .collect(),
+        );
+        let usages =
+            frame_usages(&LLVMStackMaps::new(&data), &RegisterConventions::X86_64).unwrap();
+
+        assert_eq!(usages.len(), 1);
+        assert_eq!(usages[0].referenced, [8..20, 40..48]);
+        assert_eq!(usages[0].unreferenced, [0..8, 20..40, 48..64]);
+        assert_eq!(usages[0].unreferenced_bytes(), 44);
+    }
+}
@@ -0,0 +1,226 @@
+// Generation of synthetic but well-formed sections, for benchmarks and load
+// tests. The output only depends on the options, seed included, so inputs can
+// be reproduced from the command line that generated them.
+
+use std::{num::ParseIntError, str::FromStr};
+
+use snafu::{ResultExt, Snafu};
+
+use crate::{owned, Error, LiveOut, Location, LocationKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    Fixed(u64),
+    // Both bounds are inclusive
+    Uniform { min: u64, max: u64 },
+}
+
+impl Distribution {
+    fn sample(self, rng: &mut Rng) -> u64 {
+        match self {
+            Distribution::Fixed(value) => value,
+            Distribution::Uniform { min, max } => rng.below(max - min + 1) + min,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseDistributionError {
+    #[snafu(display("Invalid bound: {}", source))]
+    InvalidBound { source: ParseIntError },
+    #[snafu(display("Empty range {}..={}", min, max))]
+    EmptyRange { min: u64, max: u64 },
+}
+
+// Either a fixed count, e.g. `4`, or an inclusive range, e.g. `0..=8`
+impl FromStr for Distribution {
+    type Err = ParseDistributionError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src.split_once("..=") {
+            Some((min, max)) => {
+                let min = min.parse().context(InvalidBound)?;
+                let max = max.parse().context(InvalidBound)?;
+                if min > max {
+                    return EmptyRange { min, max }.fail();
+                }
+                Ok(Distribution::Uniform { min, max })
+            }
+            None => Ok(Distribution::Fixed(src.parse().context(InvalidBound)?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    pub seed: u64,
+    pub stack_maps: usize,
+    // Counts per stack map, function and record respectively
+    pub functions: Distribution,
+    pub records: Distribution,
+    pub locations: Distribution,
+    pub live_outs: Distribution,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            stack_maps: 1,
+            functions: Distribution::Fixed(100),
+            records: Distribution::Uniform { min: 0, max: 8 },
+            locations: Distribution::Uniform { min: 0, max: 16 },
+            live_outs: Distribution::Uniform { min: 0, max: 2 },
+        }
+    }
+}
+
+// SplitMix64, good enough for test data and stable across platforms
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, bound), or any value if `bound` is 0 because the range
+    // spans all of u64
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        match bound {
+            0 => self.next(),
+            _ => self.next() % bound,
+        }
+    }
+}
+
+fn location(rng: &mut Rng) -> Location {
+    let register = rng.below(16) as u16;
+    let offset = -(rng.below(64) as isize) * 8;
+    let kind = match rng.below(4) {
+        0 => LocationKind::Register(register),
+        1 => LocationKind::Direct { register, offset },
+        2 => LocationKind::Indirect { register, offset },
+        // Half of the constants are too large to be stored inline
+        _ if rng.below(2) == 0 => LocationKind::Constant(rng.below(1 << 16)),
+        _ => LocationKind::Constant(rng.next() | 1 << 63),
+    };
+    Location::new(kind, 8)
+}
+
+fn function(rng: &mut Rng, address: u64, options: &GeneratorOptions) -> owned::Function {
+    let mut instruction_offset = 0u32;
+    let records = (0..options.records.sample(rng))
+        .map(|_| {
+            instruction_offset += 1 + rng.below(64) as u32;
+            owned::Record {
+                patch_point_id: rng.below(1 << 32),
+                instruction_offset,
+                locations: (0..options.locations.sample(rng))
+                    .map(|_| location(rng))
+                    .collect(),
+                live_outs: (0..options.live_outs.sample(rng))
+                    .map(|_| LiveOut::new(rng.below(16) as u16, 8))
+                    .collect(),
+                metadata: (),
+            }
+        })
+        .collect();
+
+    owned::Function {
+        address,
+        stack_size: rng.below(64) * 8,
+        records,
+        metadata: (),
+    }
+}
+
+/// Generates the stack maps described by `options` in the owned model.
+pub fn generate_stack_maps(options: &GeneratorOptions) -> Vec<owned::StackMap> {
+    let mut rng = Rng(options.seed);
+    let mut address = 0x1000;
+    (0..options.stack_maps)
+        .map(|_| {
+            let functions = (0..options.functions.sample(&mut rng))
+                .map(|_| {
+                    let function = function(&mut rng, address, options);
+                    let code_size = function
+                        .records
+                        .last()
+                        .map_or(0, |record| record.instruction_offset as u64);
+                    address += (code_size + 16) & !15;
+                    function
+                })
+                .collect();
+
+            owned::StackMap {
+                version: 3,
+                constants: Vec::new(),
+                functions,
+            }
+        })
+        .collect()
+}
+
+/// Generates a whole section, with the stack maps one after the other. Fails
+/// only if a count does not fit in its field, e.g. more than 65535 locations.
+pub fn generate_section(options: &GeneratorOptions) -> Result<Vec<u8>, Error> {
+    let mut section = Vec::new();
+    for stack_map in generate_stack_maps(options) {
+        stack_map.write_to(&mut section)?;
+    }
+    Ok(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LLVMStackMaps;
+    use fallible_iterator::FallibleIterator;
+
+    #[test]
+    fn distributions() {
+        assert_eq!("4".parse::<Distribution>().unwrap(), Distribution::Fixed(4));
+        assert_eq!(
+            "0..=8".parse::<Distribution>().unwrap(),
+            Distribution::Uniform { min: 0, max: 8 }
+        );
+        assert!(matches!(
+            "8..=0".parse::<Distribution>(),
+            Err(ParseDistributionError::EmptyRange { .. })
+        ));
+        assert!("0..8".parse::<Distribution>().is_err());
+    }
+
+    #[test]
+    fn generated_section() {
+        let options = GeneratorOptions {
+            seed: 42,
+            stack_maps: 2,
+            functions: Distribution::Fixed(10),
+            records: Distribution::Fixed(3),
+            locations: Distribution::Uniform { min: 1, max: 20 },
+            ..GeneratorOptions::default()
+        };
+        let section = generate_section(&options).unwrap();
+        assert_eq!(section, generate_section(&options).unwrap());
+
+        let section = LLVMStackMaps::new(&section);
+        let mut stack_maps_iter = section.stack_maps();
+        let mut num_stack_maps = 0;
+        while let Some(stack_map) = stack_maps_iter.next().unwrap() {
+            assert_eq!(stack_map.num_functions(), 10);
+            assert_eq!(stack_map.num_records(), 30);
+            let stack_map = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+            assert_eq!(
+                stack_map.functions,
+                generate_stack_maps(&options)[num_stack_maps].functions
+            );
+            num_stack_maps += 1;
+        }
+        assert_eq!(num_stack_maps, 2);
+    }
+}
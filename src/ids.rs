@@ -0,0 +1,230 @@
+// Decoding of patch point IDs that pack several fields, e.g. the ID of the pass
+// that inserted the record in the high bits and the index of the site in the
+// low bits. Each field is a mask over the ID, and its value is shifted down to
+// the lowest set bit of the mask. The first field is the namespace by which
+// records are grouped.
+
+use std::{collections::BTreeMap, num::ParseIntError, str::FromStr};
+
+use fallible_iterator::FallibleIterator;
+use snafu::{ResultExt, Snafu};
+
+use crate::{diff::DiffReport, diff::FunctionDiff, diff::RecordDiff, Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "json",
+    derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)
+)]
+pub struct IdField {
+    pub name: String,
+    pub mask: u64,
+}
+
+impl IdField {
+    pub fn decode(&self, id: u64) -> u64 {
+        (id & self.mask) >> self.mask.trailing_zeros().min(63)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseIdFieldError {
+    #[snafu(display("Expected NAME=MASK, got {}", field))]
+    MissingName { field: String },
+    #[snafu(display("Invalid mask: {}", source))]
+    InvalidMask { source: ParseIntError },
+    #[snafu(display("The mask of {} is empty", name))]
+    EmptyMask { name: String },
+}
+
+// `NAME=MASK`, with the mask in hexadecimal if prefixed with `0x`
+impl FromStr for IdField {
+    type Err = ParseIdFieldError;
+
+    fn from_str(field: &str) -> Result<Self, Self::Err> {
+        let (name, mask) = match field.split_once('=') {
+            Some((name, mask)) if !name.is_empty() => (name, mask),
+            _ => return MissingName { field }.fail(),
+        };
+        let mask = match mask.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => mask.parse(),
+        }
+        .context(InvalidMask)?;
+        if mask == 0 {
+            return EmptyMask { name }.fail();
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            mask,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "json",
+    derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)
+)]
+pub struct IdSchema {
+    pub fields: Vec<IdField>,
+}
+
+impl IdSchema {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the name and value of each field of `id`.
+    pub fn decode(&self, id: u64) -> Vec<(&str, u64)> {
+        self.fields
+            .iter()
+            .map(|field| (field.name.as_str(), field.decode(id)))
+            .collect()
+    }
+
+    /// Formats the fields of `id` as `name=value` pairs.
+    pub fn describe(&self, id: u64) -> String {
+        let fields: Vec<String> = self
+            .decode(id)
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        fields.join(" ")
+    }
+
+    /// Returns the field records are grouped by, the first one.
+    pub fn namespace_field(&self) -> Option<&IdField> {
+        self.fields.first()
+    }
+
+    pub fn namespace(&self, id: u64) -> Option<u64> {
+        self.namespace_field().map(|field| field.decode(id))
+    }
+
+    /// Counts the records of `section` in each namespace. All records are in
+    /// namespace 0 if the schema is empty.
+    pub fn count_records(&self, section: &LLVMStackMaps) -> Result<BTreeMap<u64, usize>, Error> {
+        let mut counts = BTreeMap::new();
+        let mut stack_maps_iter = section.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                let mut records_iter = function.records();
+                while let Some(record) = records_iter.next()? {
+                    let namespace = self.namespace(record.patch_point_id()).unwrap_or(0);
+                    *counts.entry(namespace).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Counts the added, removed and changed records of `report` in each
+    /// namespace, the one of the new ID for changed records. All the records
+    /// of added and removed functions are counted.
+    pub fn count_record_diffs(&self, report: &DiffReport) -> BTreeMap<u64, usize> {
+        let mut counts = BTreeMap::new();
+        let mut count = |id| *counts.entry(self.namespace(id).unwrap_or(0)).or_insert(0) += 1;
+        for function in &report.functions {
+            let records = match function {
+                FunctionDiff::Changed { records, .. } => records,
+                FunctionDiff::Added {
+                    patch_point_ids, ..
+                }
+                | FunctionDiff::Removed {
+                    patch_point_ids, ..
+                } => {
+                    patch_point_ids.iter().for_each(|&id| count(id));
+                    continue;
+                }
+            };
+            for record in records {
+                let id = match *record {
+                    RecordDiff::Added { patch_point_id, .. }
+                    | RecordDiff::Removed { patch_point_id, .. } => patch_point_id,
+                    RecordDiff::Changed {
+                        new_patch_point_id, ..
+                    } => new_patch_point_id,
+                };
+                count(id);
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff, test_data};
+
+    fn pass_and_site() -> IdSchema {
+        IdSchema {
+            fields: vec![
+                "pass=0xff00000000000000".parse().unwrap(),
+                "site=0xffffffff".parse().unwrap(),
+            ],
+        }
+    }
+
+    #[test]
+    fn decode_fields() {
+        let schema = pass_and_site();
+        let id = 0x0300_0000_0000_002a;
+        assert_eq!(schema.decode(id), [("pass", 3), ("site", 42)]);
+        assert_eq!(schema.describe(id), "pass=3 site=42");
+        assert_eq!(schema.namespace(id), Some(3));
+        assert_eq!(IdSchema::default().namespace(id), None);
+
+        let field: IdField = "all=18446744073709551615".parse().unwrap();
+        assert_eq!(field.decode(id), id);
+        assert!(matches!(
+            "=0xff".parse::<IdField>(),
+            Err(ParseIdFieldError::MissingName { .. })
+        ));
+        assert!(matches!(
+            "pass=0".parse::<IdField>(),
+            Err(ParseIdFieldError::EmptyMask { .. })
+        ));
+        assert!("pass=0xzz".parse::<IdField>().is_err());
+    }
+
+    #[test]
+    fn group_records() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        // IDs 42, 43 and 44 are in namespaces 2, 3 and 0 of a 2-bit field
+        let schema = IdSchema {
+            fields: vec!["low=0x3".parse().unwrap()],
+        };
+        let counts = schema.count_records(&section).unwrap();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [(0, 1), (2, 1), (3, 1)]
+        );
+
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        // Patch point ID of the first record, at the start of the records
+        data[16 + 2 * 24 + 8] = 45;
+        let old = section.stack_maps().next().unwrap().unwrap();
+        let new_section = LLVMStackMaps::new(&data);
+        let new = new_section.stack_maps().next().unwrap().unwrap();
+        let report = diff::diff(&old, &new).unwrap();
+        let counts = schema.count_record_diffs(&report);
+        // Record 42 is removed and record 45 is added
+        assert_eq!(counts.into_iter().collect::<Vec<_>>(), [(1, 1), (2, 1)]);
+
+        // Address of the second function, which moves
+        data[16 + 24..16 + 24 + 8].copy_from_slice(&0x2170u64.to_le_bytes());
+        let new_section = LLVMStackMaps::new(&data);
+        let new = new_section.stack_maps().next().unwrap().unwrap();
+        let report = diff::diff(&old, &new).unwrap();
+        let counts = schema.count_record_diffs(&report);
+        // Record 44 is also removed and added again with its function
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [(0, 2), (1, 1), (2, 1)]
+        );
+    }
+}
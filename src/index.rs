@@ -0,0 +1,668 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{symbols::FunctionSymbols, Constant, Error, LLVMStackMaps, Record};
+
+// Maps the absolute address of every instrumented instruction in a section to
+// its records. Several records can share the same address, so lookups return
+// all of them rather than picking one.
+#[derive(Debug, Clone, Default)]
+pub struct PcIndex<'input> {
+    records: BTreeMap<u64, Vec<Record<'input>>>,
+}
+
+impl<'input> PcIndex<'input> {
+    pub fn new(section: &LLVMStackMaps<'input>) -> Result<Self, Error> {
+        let mut records: BTreeMap<u64, Vec<Record<'input>>> = BTreeMap::new();
+
+        let mut stack_maps_iter = section.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                for (offset, group) in function.records_by_offset()? {
+                    let pc = function.address().wrapping_add(offset as u64);
+                    records.entry(pc).or_default().extend(group);
+                }
+            }
+        }
+
+        Ok(Self { records })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records_at(&self, pc: u64) -> &[Record<'input>] {
+        self.records.get(&pc).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[Record<'input>])> {
+        self.records
+            .iter()
+            .map(|(&pc, records)| (pc, records.as_slice()))
+    }
+}
+
+// The stack maps of one binary mapped into a process, e.g. the executable or a
+// shared library. `load_bias` is added to the addresses of the stack maps to
+// get runtime addresses, so it is zero for non-PIE executables.
+#[derive(Debug, Clone)]
+pub struct Module<'input> {
+    pub name: String,
+    pub stack_maps: LLVMStackMaps<'input>,
+    pub load_bias: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub load_bias: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleRecord<'input> {
+    // Index of the module in `AddressSpaceIndex::modules`
+    pub module: usize,
+    pub record: Record<'input>,
+}
+
+// Maps the runtime address of every instrumented instruction of a process
+// image, across all its modules, to its records.
+#[derive(Debug, Clone, Default)]
+pub struct AddressSpaceIndex<'input> {
+    modules: Vec<ModuleInfo>,
+    records: BTreeMap<u64, Vec<ModuleRecord<'input>>>,
+}
+
+impl<'input> AddressSpaceIndex<'input> {
+    pub fn new(modules: impl IntoIterator<Item = Module<'input>>) -> Result<Self, Error> {
+        let mut index = Self::default();
+        for module in modules {
+            let module_idx = index.modules.len();
+            for (pc, records) in PcIndex::new(&module.stack_maps)?.records {
+                index
+                    .records
+                    .entry(pc.wrapping_add(module.load_bias))
+                    .or_default()
+                    .extend(records.into_iter().map(|record| ModuleRecord {
+                        module: module_idx,
+                        record,
+                    }));
+            }
+            index.modules.push(ModuleInfo {
+                name: module.name,
+                load_bias: module.load_bias,
+            });
+        }
+
+        Ok(index)
+    }
+
+    pub fn modules(&self) -> &[ModuleInfo] {
+        &self.modules
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records_at(&self, pc: u64) -> &[ModuleRecord<'input>] {
+        self.records.get(&pc).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[ModuleRecord<'input>])> {
+        self.records
+            .iter()
+            .map(|(&pc, records)| (pc, records.as_slice()))
+    }
+}
+
+pub type ModuleId = usize;
+
+// The records of one module, keyed by runtime address
+#[derive(Debug, Clone)]
+struct ModuleEntry<'input> {
+    id: ModuleId,
+    records: BTreeMap<u64, Vec<ModuleRecord<'input>>>,
+}
+
+impl ModuleEntry<'_> {
+    // Runtime addresses of the first and last records, if any
+    fn pc_range(&self) -> Option<(u64, u64)> {
+        let (&first, _) = self.records.iter().next()?;
+        let (&last, _) = self.records.iter().next_back()?;
+        Some((first, last))
+    }
+}
+
+// An `AddressSpaceIndex` for processes that map and unmap modules while it is
+// in use, e.g. JIT compilers registering their code. Rebuilding the whole index
+// on every change would be too slow, so modules registered since the last
+// compaction keep their own sub-index, which lookups check one by one next to
+// the compacted index, and unregistered modules are only removed from the
+// compacted index when it is compacted again. Compaction happens once
+// `max_pending` modules are pending either way, or on demand.
+#[derive(Debug, Clone)]
+pub struct IncrementalAddressSpaceIndex<'input> {
+    // By module ID, `None` once unregistered. IDs are never reused.
+    modules: Vec<Option<ModuleInfo>>,
+    compacted: BTreeMap<u64, Vec<ModuleRecord<'input>>>,
+    pending: Vec<ModuleEntry<'input>>,
+    // Unregistered modules still in `compacted`, with the range of their
+    // records
+    stale: Vec<(ModuleId, (u64, u64))>,
+    // Ranges of the records of the compacted modules, to find them when they
+    // are unregistered
+    compacted_ranges: BTreeMap<ModuleId, (u64, u64)>,
+    pub max_pending: usize,
+}
+
+impl Default for IncrementalAddressSpaceIndex<'_> {
+    fn default() -> Self {
+        Self {
+            modules: Vec::new(),
+            compacted: BTreeMap::new(),
+            pending: Vec::new(),
+            stale: Vec::new(),
+            compacted_ranges: BTreeMap::new(),
+            max_pending: 16,
+        }
+    }
+}
+
+impl<'input> IncrementalAddressSpaceIndex<'input> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes the records of `module`, which can be looked up right away,
+    /// and returns its ID.
+    pub fn register(&mut self, module: Module<'input>) -> Result<ModuleId, Error> {
+        let id = self.modules.len();
+        let mut records: BTreeMap<u64, Vec<ModuleRecord<'input>>> = BTreeMap::new();
+        for (pc, group) in PcIndex::new(&module.stack_maps)?.records {
+            records
+                .entry(pc.wrapping_add(module.load_bias))
+                .or_default()
+                .extend(
+                    group
+                        .into_iter()
+                        .map(|record| ModuleRecord { module: id, record }),
+                );
+        }
+
+        let info = ModuleInfo {
+            name: module.name,
+            load_bias: module.load_bias,
+        };
+        self.modules.push(Some(info));
+        self.pending.push(ModuleEntry { id, records });
+        self.compact_if_needed();
+        Ok(id)
+    }
+
+    /// Removes the records of module `id` from the lookups, and returns its
+    /// information, or `None` if it is not registered.
+    pub fn unregister(&mut self, id: ModuleId) -> Option<ModuleInfo> {
+        let info = self.modules.get_mut(id)?.take()?;
+        if let Some(range) = self.compacted_ranges.remove(&id) {
+            self.stale.push((id, range));
+        } else {
+            self.pending.retain(|entry| entry.id != id);
+        }
+        self.compact_if_needed();
+        Some(info)
+    }
+
+    pub fn module(&self, id: ModuleId) -> Option<&ModuleInfo> {
+        self.modules.get(id)?.as_ref()
+    }
+
+    fn compact_if_needed(&mut self) {
+        if self.pending.len() + self.stale.len() > self.max_pending {
+            self.compact();
+        }
+    }
+
+    /// Merges the pending modules into the compacted index, and removes the
+    /// unregistered ones from it.
+    pub fn compact(&mut self) {
+        for (id, (first, last)) in self.stale.drain(..) {
+            let mut emptied = Vec::new();
+            for (&pc, records) in self.compacted.range_mut(first..=last) {
+                records.retain(|record| record.module != id);
+                if records.is_empty() {
+                    emptied.push(pc);
+                }
+            }
+            for pc in emptied {
+                self.compacted.remove(&pc);
+            }
+        }
+
+        for entry in self.pending.drain(..) {
+            if let Some(range) = entry.pc_range() {
+                self.compacted_ranges.insert(entry.id, range);
+            }
+            for (pc, records) in entry.records {
+                self.compacted.entry(pc).or_default().extend(records);
+            }
+        }
+    }
+
+    /// Returns the records at runtime address `pc` of all the registered
+    /// modules.
+    pub fn records_at(&self, pc: u64) -> Vec<&ModuleRecord<'input>> {
+        let compacted = self
+            .compacted
+            .get(&pc)
+            .into_iter()
+            .flatten()
+            .filter(|record| self.modules[record.module].is_some());
+        let pending = self
+            .pending
+            .iter()
+            .filter_map(|entry| entry.records.get(&pc))
+            .flatten();
+        compacted.chain(pending).collect()
+    }
+
+    /// Iterates the registered modules with their IDs.
+    pub fn modules(&self) -> impl Iterator<Item = (ModuleId, &ModuleInfo)> {
+        self.modules
+            .iter()
+            .enumerate()
+            .filter_map(|(id, info)| Some((id, info.as_ref()?)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRange {
+    pub start: u64,
+    // Excluded, `u64::MAX` when the size of the last function is unknown
+    pub end: u64,
+    pub stack_size: u64,
+}
+
+// Maps the address range of every function with stack maps to its stack size,
+// so that any PC can be resolved, not only those of records. Ranges end at the
+// size of the function symbol when there is one, or else at the start of the
+// next function or symbol, as functions are usually laid out back to back.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionIndex {
+    functions: BTreeMap<u64, FunctionRange>,
+}
+
+impl FunctionIndex {
+    pub fn new(section: &LLVMStackMaps, symbols: &FunctionSymbols) -> Result<Self, Error> {
+        // Functions sharing an address get the largest of their stack sizes
+        let mut stack_sizes: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut stack_maps_iter = section.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                let stack_size = stack_sizes.entry(function.address()).or_insert(0);
+                *stack_size = (*stack_size).max(function.stack_size() as u64);
+            }
+        }
+
+        let starts: BTreeSet<u64> = stack_sizes
+            .keys()
+            .copied()
+            .chain(symbols.iter().map(|(address, _)| address))
+            .collect();
+        let functions = stack_sizes
+            .into_iter()
+            .map(|(start, stack_size)| {
+                let end = match symbols.get(start) {
+                    Some(symbol) if symbol.size > 0 => start.saturating_add(symbol.size),
+                    _ => starts
+                        .range((Bound::Excluded(start), Bound::Unbounded))
+                        .next()
+                        .copied()
+                        .unwrap_or(u64::MAX),
+                };
+                (
+                    start,
+                    FunctionRange {
+                        start,
+                        end,
+                        stack_size,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(Self { functions })
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    pub fn function_at(&self, pc: u64) -> Option<&FunctionRange> {
+        self.functions
+            .range(..=pc)
+            .next_back()
+            .map(|(_, function)| function)
+            .filter(|function| pc < function.end)
+    }
+
+    pub fn stack_size_at(&self, pc: u64) -> Option<u64> {
+        self.function_at(pc).map(|function| function.stack_size)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FunctionRange> {
+        self.functions.values()
+    }
+}
+
+// A location that uses a constant of the pool of its stack map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantUse {
+    pub function_index: usize,
+    pub record_index: usize,
+    pub location_index: usize,
+    pub pc: u64,
+    pub patch_point_id: u64,
+}
+
+// Maps every entry of the constants pools of a section to the locations that
+// use it, e.g. to find the safepoints of a suspicious constant, or to rewrite
+// the indices of the locations when pools are deduplicated. Pools belong to a
+// stack map, so entries are keyed by stack map index and pool index.
+#[derive(Debug, Clone, Default)]
+pub struct ConstantIndex {
+    constants: Vec<Vec<u64>>,
+    uses: BTreeMap<(usize, u32), Vec<ConstantUse>>,
+}
+
+impl ConstantIndex {
+    pub fn new(section: &LLVMStackMaps) -> Result<Self, Error> {
+        let mut constants = Vec::new();
+        let mut uses: BTreeMap<(usize, u32), Vec<ConstantUse>> = BTreeMap::new();
+
+        let mut stack_maps_iter = section.stack_maps().enumerate();
+        while let Some((stack_map_index, stack_map)) = stack_maps_iter.next()? {
+            constants.push(stack_map.constants().map(Constant::as_u64).collect());
+            let mut functions_iter = stack_map.functions().enumerate();
+            while let Some((function_index, function)) = functions_iter.next()? {
+                let mut records_iter = function.records().enumerate();
+                while let Some((record_index, record)) = records_iter.next()? {
+                    let mut locations_iter = record.locations().enumerate();
+                    while let Some((location_index, location)) = locations_iter.next()? {
+                        if let Some(constant_index) = location.constant_index() {
+                            uses.entry((stack_map_index, constant_index))
+                                .or_default()
+                                .push(ConstantUse {
+                                    function_index,
+                                    record_index,
+                                    location_index,
+                                    pc: function
+                                        .address()
+                                        .wrapping_add(record.instruction_offset() as u64),
+                                    patch_point_id: record.patch_point_id(),
+                                });
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Self { constants, uses })
+    }
+
+    pub fn constant(&self, stack_map_index: usize, constant_index: u32) -> Option<u64> {
+        self.constants
+            .get(stack_map_index)?
+            .get(constant_index as usize)
+            .copied()
+    }
+
+    pub fn uses(&self, stack_map_index: usize, constant_index: u32) -> &[ConstantUse] {
+        self.uses
+            .get(&(stack_map_index, constant_index))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the uses of every pool entry equal to `value`, with the index
+    /// of the stack map of each.
+    pub fn uses_of_value(&self, value: u64) -> Vec<(usize, &ConstantUse)> {
+        self.iter()
+            .filter(|&(_, _, constant, _)| constant == value)
+            .flat_map(|(stack_map_index, _, _, uses)| {
+                uses.iter()
+                    .map(move |constant_use| (stack_map_index, constant_use))
+            })
+            .collect()
+    }
+
+    /// Returns the indices of the pool entries of a stack map that no
+    /// location uses.
+    pub fn unused(&self, stack_map_index: usize) -> Vec<u32> {
+        let num_constants = self.constants.get(stack_map_index).map_or(0, Vec::len);
+        (0..num_constants as u32)
+            .filter(|&constant_index| self.uses(stack_map_index, constant_index).is_empty())
+            .collect()
+    }
+
+    /// Iterates all the pool entries as (stack map index, pool index,
+    /// constant, uses).
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u32, u64, &[ConstantUse])> {
+        self.constants
+            .iter()
+            .enumerate()
+            .flat_map(move |(stack_map_index, constants)| {
+                constants
+                    .iter()
+                    .enumerate()
+                    .map(move |(constant_index, &constant)| {
+                        let constant_index = constant_index as u32;
+                        (
+                            stack_map_index,
+                            constant_index,
+                            constant,
+                            self.uses(stack_map_index, constant_index),
+                        )
+                    })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{owned, symbols::FunctionSymbol, test_data};
+
+    #[test]
+    fn records_sharing_a_pc() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+
+        // Move record 43 onto the same instruction as record 42
+        owned.functions[0].records[1].instruction_offset =
+            owned.functions[0].records[0].instruction_offset;
+        let data = owned.to_bytes().unwrap();
+        let section = LLVMStackMaps::new(&data);
+
+        let function = section
+            .stack_maps()
+            .next()
+            .unwrap()
+            .unwrap()
+            .functions()
+            .next()
+            .unwrap()
+            .unwrap();
+        let groups = function.records_by_offset().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[&0x20].len(), 2);
+
+        let index = PcIndex::new(&section).unwrap();
+        assert_eq!(index.len(), 2);
+        let ids: Vec<_> = index
+            .records_at(0x1150)
+            .iter()
+            .map(Record::patch_point_id)
+            .collect();
+        assert_eq!(ids, [42, 43]);
+        assert_eq!(index.records_at(0x1177).len(), 1);
+        assert!(index.records_at(0x1130).is_empty());
+    }
+
+    #[test]
+    fn address_space() {
+        let module = |name: &str, load_bias| Module {
+            name: name.to_owned(),
+            stack_maps: LLVMStackMaps::new(test_data::TWO_FUNCTIONS),
+            load_bias,
+        };
+        let index = AddressSpaceIndex::new(vec![
+            module("a.out", 0x5555_5555_4000),
+            module("libfoo.so", 0x7fff_f7dc_0000),
+        ])
+        .unwrap();
+
+        assert_eq!(index.len(), 6);
+        assert_eq!(index.modules()[1].name, "libfoo.so");
+        let records = index.records_at(0x7fff_f7dc_1177);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].module, 1);
+        assert_eq!(records[0].record.patch_point_id(), 44);
+        assert_eq!(index.records_at(0x5555_5555_5150)[0].module, 0);
+        assert!(index.records_at(0x1150).is_empty());
+    }
+
+    #[test]
+    fn incremental_address_space() {
+        let module = |load_bias: u64| Module {
+            name: format!("jit-{:x}", load_bias),
+            stack_maps: LLVMStackMaps::new(test_data::TWO_FUNCTIONS),
+            load_bias,
+        };
+        let ids_at = |index: &IncrementalAddressSpaceIndex, pc| {
+            let mut ids: Vec<ModuleId> = index.records_at(pc).iter().map(|r| r.module).collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        let mut index = IncrementalAddressSpaceIndex::new();
+        index.max_pending = 2;
+        let first = index.register(module(0)).unwrap();
+        let second = index.register(module(0x10000)).unwrap();
+        // Shares its addresses with the first module
+        let third = index.register(module(0)).unwrap();
+        assert_eq!(index.pending.len(), 0);
+        assert_eq!(ids_at(&index, 0x1150), [first, third]);
+        assert_eq!(ids_at(&index, 0x11177), [second]);
+
+        let fourth = index.register(module(0x20000)).unwrap();
+        assert_eq!(index.pending.len(), 1);
+        assert_eq!(ids_at(&index, 0x21150), [fourth]);
+
+        // Unregistered modules disappear before and after compaction
+        assert_eq!(index.unregister(first).unwrap().load_bias, 0);
+        assert_eq!(index.unregister(fourth).unwrap().name, "jit-20000");
+        assert!(index.unregister(fourth).is_none());
+        assert_eq!(ids_at(&index, 0x1150), [third]);
+        assert!(ids_at(&index, 0x21150).is_empty());
+        index.compact();
+        assert_eq!(ids_at(&index, 0x1150), [third]);
+        assert_eq!(index.compacted.len(), 6);
+        assert_eq!(
+            index.modules().map(|(id, _)| id).collect::<Vec<_>>(),
+            [second, third]
+        );
+    }
+
+    #[test]
+    fn function_ranges() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+
+        // Without symbols, `foo` ends where `bar` starts and `bar` never ends
+        let index = FunctionIndex::new(&section, &FunctionSymbols::default()).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.stack_size_at(0x1130), Some(40));
+        assert_eq!(index.stack_size_at(0x116f), Some(40));
+        assert_eq!(index.stack_size_at(0x1170), Some(8));
+        assert_eq!(index.stack_size_at(0xffff_0000), Some(8));
+        assert_eq!(index.function_at(0x112f), None);
+
+        let symbols: FunctionSymbols = vec![
+            (
+                0x1130,
+                FunctionSymbol {
+                    name: "foo".to_owned(),
+                    size: 0x30,
+                },
+            ),
+            (
+                0x1200,
+                FunctionSymbol {
+                    name: "baz".to_owned(),
+                    size: 0x10,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let index = FunctionIndex::new(&section, &symbols).unwrap();
+        assert_eq!(index.stack_size_at(0x115f), Some(40));
+        assert_eq!(index.stack_size_at(0x1160), None);
+        assert_eq!(
+            index.function_at(0x1177),
+            Some(&FunctionRange {
+                start: 0x1170,
+                end: 0x1200,
+                stack_size: 8,
+            })
+        );
+        assert_eq!(index.function_at(0x1200), None);
+    }
+
+    #[test]
+    fn constant_uses() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let index = ConstantIndex::new(&section).unwrap();
+        assert_eq!(index.constant(0, 0), Some(1234567890123));
+        assert_eq!(index.constant(0, 1), None);
+        let uses = [ConstantUse {
+            function_index: 0,
+            record_index: 0,
+            location_index: 3,
+            pc: 0x1150,
+            patch_point_id: 42,
+        }];
+        assert_eq!(index.uses(0, 0), uses);
+        assert_eq!(index.uses_of_value(1234567890123), [(0, &uses[0])]);
+        // Inline constants are not in the pool
+        assert!(index.uses_of_value(7).is_empty());
+        assert!(index.unused(0).is_empty());
+
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        owned.constants.push(1 << 40);
+        let data = owned.to_bytes().unwrap();
+        let index = ConstantIndex::new(&LLVMStackMaps::new(&data)).unwrap();
+        assert_eq!(index.unused(0), [1]);
+        assert_eq!(index.iter().count(), 2);
+    }
+}
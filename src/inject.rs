@@ -0,0 +1,428 @@
+// Writing of a stack maps section into an ELF file, editing the file in place
+// rather than relinking it: the contents of existing sections keep their file
+// offsets and addresses.
+//
+// An existing section is overwritten when the new contents fit in it. Only
+// sections that are not loaded can grow, by moving their contents to the end
+// of the file. A missing section is added at the end of the file, along with a
+// new section name table and section header table. Sections added to linked
+// binaries are not loaded, as that would need a new segment, but those added
+// to relocatable objects are placed in memory by the linker as usual.
+//
+// No relocations are written. In relocatable objects, the function addresses
+// of stack maps are relocations against the code, so an existing
+// `.llvm_stackmaps` always has some and cannot be replaced, and the addresses
+// of an added section are linked as they are, i.e. they stay relative to the
+// sections of the object rather than becoming the final addresses.
+
+use std::{convert::TryInto, ops::Range};
+
+use object::{
+    elf,
+    read::elf::{FileHeader, SectionHeader},
+    Bytes, Endianness, FileKind,
+};
+use snafu::{ensure, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum InjectError {
+    #[snafu(display("Could not parse object: {}", source))]
+    ObjectError { source: object::Error },
+    #[snafu(display("Sections can only be injected in ELF objects"))]
+    NotElf,
+    #[snafu(display(
+        "Objects with more than {} sections are not supported",
+        elf::SHN_LORESERVE
+    ))]
+    TooManySections,
+    #[snafu(display(
+        "{} is loaded and {} bytes do not fit in its {} bytes",
+        name,
+        size,
+        capacity
+    ))]
+    DoesNotFit {
+        name: String,
+        size: u64,
+        capacity: u64,
+    },
+    #[snafu(display("{} has relocations, which would not apply to new contents", name))]
+    RelocatedSection { name: String },
+    #[snafu(display("{} has no contents within the file", name))]
+    NoContents { name: String },
+}
+
+type Result<T> = std::result::Result<T, InjectError>;
+
+const SECTION_ALIGNMENT: u64 = 8;
+
+// The parts of an ELF file that injection reads, independent of its class
+#[derive(Debug)]
+struct ElfLayout {
+    is_64: bool,
+    little_endian: bool,
+    relocatable: bool,
+    section_header_offset: u64,
+    section_names_idx: usize,
+    sections: Vec<SectionLayout>,
+}
+
+#[derive(Debug)]
+struct SectionLayout {
+    name: Vec<u8>,
+    sh_type: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    info: u32,
+}
+
+impl ElfLayout {
+    fn parse<Elf: FileHeader<Endian = Endianness>>(file_data: &[u8]) -> Result<Self> {
+        let header = Elf::parse(Bytes(file_data)).context(ObjectError)?;
+        let endian = header.endian().context(ObjectError)?;
+        // Counts past the reserved range are stored in the first section
+        // header, which is not worth supporting
+        ensure!(
+            header.e_shnum(endian) != 0 && header.e_shstrndx(endian) != elf::SHN_XINDEX,
+            TooManySections
+        );
+
+        let section_headers = header
+            .section_headers(endian, Bytes(file_data))
+            .context(ObjectError)?;
+        let section_names = header
+            .section_strings(endian, Bytes(file_data), section_headers)
+            .context(ObjectError)?;
+        let sections = section_headers
+            .iter()
+            .map(|section| {
+                Ok(SectionLayout {
+                    name: section
+                        .name(endian, section_names)
+                        .context(ObjectError)?
+                        .to_vec(),
+                    sh_type: section.sh_type(endian),
+                    flags: section.sh_flags(endian).into(),
+                    offset: section.sh_offset(endian).into(),
+                    size: section.sh_size(endian).into(),
+                    info: section.sh_info(endian),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            is_64: header.is_type_64(),
+            little_endian: endian == Endianness::Little,
+            relocatable: header.e_type(endian) == elf::ET_REL,
+            section_header_offset: header.e_shoff(endian).into(),
+            section_names_idx: header.e_shstrndx(endian).into(),
+            sections,
+        })
+    }
+
+    fn section_header_size(&self) -> u64 {
+        if self.is_64 {
+            64
+        } else {
+            40
+        }
+    }
+
+    fn word_size(&self) -> usize {
+        if self.is_64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    // Offsets of the fields of a section header, relative to its start
+    fn sh_offset_field(&self) -> usize {
+        if self.is_64 {
+            24
+        } else {
+            16
+        }
+    }
+
+    fn sh_size_field(&self) -> usize {
+        self.sh_offset_field() + self.word_size()
+    }
+
+    fn write_int(&self, file_data: &mut [u8], offset: usize, value: u64, size: usize) {
+        let bytes = if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        };
+        let value_bytes = if self.little_endian {
+            &bytes[..size]
+        } else {
+            &bytes[8 - size..]
+        };
+        file_data[offset..offset + size].copy_from_slice(value_bytes);
+    }
+
+    fn write_word(&self, file_data: &mut [u8], offset: usize, value: u64) {
+        self.write_int(file_data, offset, value, self.word_size());
+    }
+
+    fn section_header_at(&self, idx: usize) -> usize {
+        (self.section_header_offset + idx as u64 * self.section_header_size()) as usize
+    }
+
+    // Points the header of section `idx` at new contents
+    fn move_section(&self, file_data: &mut [u8], idx: usize, offset: u64, size: u64) {
+        let header = self.section_header_at(idx);
+        self.write_word(file_data, header + self.sh_offset_field(), offset);
+        self.write_word(file_data, header + self.sh_size_field(), size);
+    }
+
+    // Range of the contents of section `idx` in the file
+    fn contents_range(&self, idx: usize, file_len: usize) -> Result<Range<usize>> {
+        let section = &self.sections[idx];
+        let end = section.offset.checked_add(section.size);
+        ensure!(
+            section.sh_type != elf::SHT_NOBITS && end.is_some_and(|end| end <= file_len as u64),
+            NoContents {
+                name: String::from_utf8_lossy(&section.name),
+            }
+        );
+        Ok(section.offset as usize..section.offset as usize + section.size as usize)
+    }
+
+    fn is_relocated(&self, idx: usize) -> bool {
+        self.sections.iter().any(|section| {
+            (section.sh_type == elf::SHT_RELA || section.sh_type == elf::SHT_REL)
+                && section.info as usize == idx
+        })
+    }
+
+    // Header of a new section of `size` bytes at `offset`, named by the
+    // string at `name_offset` in the section names
+    fn new_section_header(&self, name_offset: u64, offset: u64, size: u64) -> Vec<u8> {
+        let mut header = vec![0; self.section_header_size() as usize];
+        let flags = if self.relocatable {
+            u64::from(elf::SHF_ALLOC)
+        } else {
+            0
+        };
+        self.write_int(&mut header, 0, name_offset, 4);
+        self.write_int(&mut header, 4, elf::SHT_PROGBITS.into(), 4);
+        self.write_word(&mut header, 8, flags);
+        self.write_word(&mut header, self.sh_offset_field(), offset);
+        self.write_word(&mut header, self.sh_size_field(), size);
+        // sh_addralign follows sh_link and sh_info
+        let alignment_field = self.sh_size_field() + self.word_size() + 8;
+        self.write_word(&mut header, alignment_field, SECTION_ALIGNMENT);
+        header
+    }
+}
+
+fn append_aligned(file_data: &mut Vec<u8>, contents: &[u8]) -> u64 {
+    let padding =
+        (SECTION_ALIGNMENT - file_data.len() as u64 % SECTION_ALIGNMENT) % SECTION_ALIGNMENT;
+    file_data.resize(file_data.len() + padding as usize, 0);
+    let offset = file_data.len() as u64;
+    file_data.extend_from_slice(contents);
+    offset
+}
+
+/// Returns a copy of the ELF object in `file_data` where the section `name`
+/// has `contents`, creating the section if needed.
+pub fn inject_section(file_data: &[u8], name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+    let layout = match FileKind::parse(file_data).context(ObjectError)? {
+        FileKind::Elf32 => ElfLayout::parse::<elf::FileHeader32<Endianness>>(file_data)?,
+        FileKind::Elf64 => ElfLayout::parse::<elf::FileHeader64<Endianness>>(file_data)?,
+        _ => return NotElf.fail(),
+    };
+    let mut output = file_data.to_vec();
+    let size = contents.len() as u64;
+
+    let existing = layout
+        .sections
+        .iter()
+        .position(|section| section.name == name.as_bytes());
+    if let Some(idx) = existing {
+        ensure!(!layout.is_relocated(idx), RelocatedSection { name });
+        let range = layout.contents_range(idx, file_data.len())?;
+        let section = &layout.sections[idx];
+        if size <= section.size {
+            let start = range.start;
+            output[start..start + contents.len()].copy_from_slice(contents);
+            output[start + contents.len()..range.end]
+                .iter_mut()
+                .for_each(|byte| *byte = 0);
+            layout.move_section(&mut output, idx, section.offset, size);
+        } else {
+            ensure!(
+                section.flags & u64::from(elf::SHF_ALLOC) == 0,
+                DoesNotFit {
+                    name,
+                    size,
+                    capacity: section.size,
+                }
+            );
+            let offset = append_aligned(&mut output, contents);
+            layout.move_section(&mut output, idx, offset, size);
+        }
+        return Ok(output);
+    }
+
+    ensure!(
+        layout.sections.len() < elf::SHN_LORESERVE as usize,
+        TooManySections
+    );
+
+    // New section names, with the name of the new section at the end
+    let names = layout.contents_range(layout.section_names_idx, file_data.len())?;
+    let mut section_names = file_data[names].to_vec();
+    let name_offset = section_names.len() as u64;
+    section_names.extend_from_slice(name.as_bytes());
+    section_names.push(0);
+
+    let contents_offset = append_aligned(&mut output, contents);
+    let names_offset = append_aligned(&mut output, &section_names);
+    layout.move_section(
+        &mut output,
+        layout.section_names_idx,
+        names_offset,
+        section_names.len() as u64,
+    );
+
+    // New section header table, with the header of the new section at the end
+    let table_start = layout.section_header_at(0);
+    let table_end = layout.section_header_at(layout.sections.len());
+    let mut section_headers = output[table_start..table_end].to_vec();
+    section_headers.extend(layout.new_section_header(name_offset, contents_offset, size));
+    let table_offset = append_aligned(&mut output, &section_headers);
+
+    let (shoff_field, shnum_field) = if layout.is_64 {
+        (0x28, 0x3c)
+    } else {
+        (0x20, 0x30)
+    };
+    layout.write_word(&mut output, shoff_field, table_offset);
+    let num_sections = (layout.sections.len() + 1).try_into().unwrap();
+    layout.write_int(&mut output, shnum_field, num_sections, 2);
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        loader::{self, StackMapsSource},
+        test_data,
+    };
+
+    // ELF64 executable with a null section, the section names and an
+    // allocated `.data` section of 16 bytes
+    fn executable() -> Vec<u8> {
+        let names = b"\0.shstrtab\0.data\0";
+        let mut file = vec![0; 64];
+        file[..4].copy_from_slice(b"\x7fELF");
+        file[4] = elf::ELFCLASS64;
+        file[5] = elf::ELFDATA2LSB;
+        file[6] = elf::EV_CURRENT;
+        file[16..18].copy_from_slice(&elf::ET_EXEC.to_le_bytes());
+        file[18..20].copy_from_slice(&elf::EM_X86_64.to_le_bytes());
+        file[20..24].copy_from_slice(&u32::from(elf::EV_CURRENT).to_le_bytes());
+        file[52..54].copy_from_slice(&64u16.to_le_bytes());
+        file[58..60].copy_from_slice(&64u16.to_le_bytes());
+        file[60..62].copy_from_slice(&3u16.to_le_bytes());
+        file[62..64].copy_from_slice(&1u16.to_le_bytes());
+
+        let names_offset = file.len() as u64;
+        file.extend_from_slice(names);
+        let data_offset = append_aligned(&mut file, &[0xaa; 16]);
+        let section_header = |name: u32, sh_type: u32, flags: u64, offset: u64, size: u64| {
+            let mut header = Vec::new();
+            header.extend_from_slice(&name.to_le_bytes());
+            header.extend_from_slice(&sh_type.to_le_bytes());
+            header.extend_from_slice(&flags.to_le_bytes());
+            header.extend_from_slice(&0u64.to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&size.to_le_bytes());
+            header.extend_from_slice(&[0; 8]);
+            header.extend_from_slice(&1u64.to_le_bytes());
+            header.extend_from_slice(&0u64.to_le_bytes());
+            header
+        };
+        let mut headers = vec![0; 64];
+        headers.extend(section_header(1, elf::SHT_STRTAB, 0, names_offset, 17));
+        headers.extend(section_header(
+            11,
+            elf::SHT_PROGBITS,
+            elf::SHF_ALLOC.into(),
+            data_offset,
+            16,
+        ));
+        let headers_offset = append_aligned(&mut file, &headers);
+        file[40..48].copy_from_slice(&headers_offset.to_le_bytes());
+        file
+    }
+
+    fn section_data(file_data: &[u8], name: &str) -> Vec<u8> {
+        loader::load_stack_maps_data(file_data, &StackMapsSource::Section(name.to_owned()))
+            .unwrap()
+            .into_owned()
+    }
+
+    #[test]
+    fn new_section() {
+        let file =
+            inject_section(&executable(), ".llvm_stackmaps", test_data::TWO_FUNCTIONS).unwrap();
+        assert_eq!(
+            loader::load_stack_maps_data(&file, &StackMapsSource::default()).unwrap(),
+            test_data::TWO_FUNCTIONS
+        );
+        assert_eq!(section_data(&file, ".data"), [0xaa; 16]);
+
+        // Replacing it with a larger section moves it again, as it is not
+        // loaded
+        let mut larger = test_data::TWO_FUNCTIONS.to_vec();
+        larger.extend_from_slice(test_data::TWO_FUNCTIONS);
+        let file = inject_section(&file, ".llvm_stackmaps", &larger).unwrap();
+        assert_eq!(section_data(&file, ".llvm_stackmaps"), larger);
+    }
+
+    #[test]
+    fn replaced_section() {
+        let file = inject_section(&executable(), ".data", &[1, 2, 3]).unwrap();
+        assert_eq!(section_data(&file, ".data"), [1, 2, 3]);
+        assert_eq!(file.len(), executable().len());
+
+        assert!(matches!(
+            inject_section(&executable(), ".data", &[0; 17]),
+            Err(InjectError::DoesNotFit { capacity: 16, .. })
+        ));
+        assert!(matches!(
+            inject_section(test_data::TWO_FUNCTIONS, ".data", &[]),
+            Err(InjectError::ObjectError { .. }) | Err(InjectError::NotElf)
+        ));
+    }
+
+    #[test]
+    fn missing_contents() {
+        // Header of `.data`, the third section
+        let executable = executable();
+        let header = u64::from_le_bytes(executable[40..48].try_into().unwrap()) as usize + 2 * 64;
+
+        let mut past_end = executable.clone();
+        past_end[header + 24..header + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            inject_section(&past_end, ".data", &[1, 2, 3]),
+            Err(InjectError::NoContents { .. })
+        ));
+
+        let mut nobits = executable;
+        nobits[header + 4..header + 8].copy_from_slice(&elf::SHT_NOBITS.to_le_bytes());
+        assert!(matches!(
+            inject_section(&nobits, ".data", &[1, 2, 3]),
+            Err(InjectError::NoContents { .. })
+        ));
+    }
+}
@@ -1,30 +1,279 @@
+#![forbid(unsafe_code)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod addresses;
+#[cfg(feature = "std")]
+pub mod anonymize;
+pub mod arch;
+#[cfg(feature = "std")]
+pub mod baseline;
+#[cfg(feature = "std")]
+pub mod budget;
+#[cfg(feature = "cfi")]
+pub mod cfi;
+#[cfg(feature = "std")]
+pub mod classify;
+#[cfg(feature = "columnar")]
+pub mod columnar;
+pub mod const_parser;
+#[cfg(feature = "json")]
+pub mod corpus;
+#[cfg(feature = "std")]
+pub mod cost;
+pub mod coverage;
+#[cfg(feature = "debuginfod")]
+pub mod debuginfod;
+#[cfg(feature = "std")]
+pub mod density;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "differential")]
+pub mod differential;
+mod fingerprint;
+#[cfg(feature = "std")]
+pub mod flat;
+#[cfg(feature = "std")]
+pub mod frame;
+#[cfg(feature = "std")]
+pub mod generate;
+#[cfg(feature = "std")]
+pub mod ids;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "object")]
+pub mod inject;
+#[cfg(feature = "live")]
+pub mod live;
+#[cfg(feature = "object")]
+pub mod loader;
+#[cfg(feature = "std")]
+pub mod map;
+#[cfg(feature = "std")]
+pub mod minimize;
+pub mod overlay;
+pub mod owned;
 mod parser;
+#[cfg(feature = "std")]
+pub mod patch;
+#[cfg(feature = "object")]
+pub mod process;
+pub mod raw;
+#[cfg(feature = "std")]
+pub mod report;
+pub mod roundtrip;
+#[cfg(feature = "std")]
+pub mod samples;
+#[cfg(feature = "std")]
+pub mod sancov;
+#[cfg(feature = "std")]
+pub mod symbols;
+pub mod syntax;
+#[cfg(feature = "std")]
+pub mod transform;
+#[cfg(feature = "std")]
+pub mod validate;
+pub mod view;
+mod writer;
 
-use std::mem;
-
+#[cfg(test)]
+mod test_data;
+
+use alloc::{
+    collections::{BTreeMap, BinaryHeap},
+    format,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    cmp::{Ordering, Reverse},
+    convert::TryInto,
+    fmt,
+    ops::Range,
+};
 use fallible_iterator::FallibleIterator;
+use fingerprint::Fingerprinter;
 use nom::Finish;
-use snafu::Snafu;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    // Once at least one stack map was parsed, accept only zero bytes after the
+    // last one, and report anything else as trailing data at its offset rather
+    // than as a malformed stack map.
+    pub strict_eof: bool,
+    // Skip the regions that cannot be parsed as a stack map, up to the next
+    // 8-byte aligned offset where one can, instead of failing. The skipped
+    // regions are reported by `StackMapsIter::skipped`.
+    pub lenient: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct LLVMStackMaps<'input> {
     section_data: &'input [u8],
+    options: ParseOptions,
 }
 
 impl<'input> LLVMStackMaps<'input> {
     pub fn new(section_data: &'input [u8]) -> Self {
-        Self { section_data }
+        Self::with_options(section_data, ParseOptions::default())
+    }
+
+    pub fn with_options(section_data: &'input [u8], options: ParseOptions) -> Self {
+        Self {
+            section_data,
+            options,
+        }
+    }
+
+    /// Returns the offsets within the section of `bytes`, which must be a
+    /// subslice of the section data such as the one returned by
+    /// `Record::raw_bytes`.
+    pub fn byte_range(&self, bytes: &[u8]) -> Option<Range<usize>> {
+        let section_start = self.section_data.as_ptr() as usize;
+        let start = (bytes.as_ptr() as usize).checked_sub(section_start)?;
+        let end = start.checked_add(bytes.len())?;
+        if end > self.section_data.len() {
+            return None;
+        }
+
+        Some(start..end)
     }
 
     pub fn stack_maps(&self) -> StackMapsIter<'input> {
         StackMapsIter {
             data: self.section_data,
+            section_size: self.section_data.len(),
+            options: self.options,
+            pending_records: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Cheaply checks whether the section starts with a supported stack map:
+    /// the header and counts are checked, and the functions, constants and
+    /// records must fit in the section, but the records are not parsed.
+    pub fn probe(&self) -> Result<'input, ()> {
+        parser::probe_stack_map(self.section_data)
+            .finish()
+            .map_err(|error| error.located(self.section_data, 0))?;
+        Ok(())
+    }
+
+    /// Counts the contents of the whole section in a single pass, without
+    /// decoding locations and live-outs.
+    pub fn summary(&self) -> Result<'input, Summary> {
+        self.summary_with(&mut ())
+    }
+
+    /// Counts the contents of the whole section like `summary`, passing every
+    /// record, function and stack map to `visitor` on the way.
+    pub fn summary_with<V: SummaryVisitor<'input> + ?Sized>(
+        &self,
+        visitor: &mut V,
+    ) -> Result<'input, Summary> {
+        let mut summary = Summary {
+            bytes: self.section_data.len(),
+            ..Summary::default()
+        };
+        let mut stack_maps_iter = self.stack_maps();
+        while let Some(stack_map) = stack_maps_iter.next()? {
+            summary.stack_maps += 1;
+            summary.constants += stack_map.num_constants();
+
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                summary.functions += 1;
+
+                let mut records_iter = function.records();
+                while let Some(record) = records_iter.next()? {
+                    summary.records += 1;
+                    summary.locations += record.num_locations();
+                    summary.live_outs += record.num_live_outs();
+                    visitor.record(&function, &record)?;
+                }
+                visitor.function(&function)?;
+            }
+            visitor.stack_map(&stack_map)?;
+        }
+        Ok(summary)
+    }
+
+    /// Parses the whole section, returning the regions skipped in lenient
+    /// mode. Without it, the first malformed region is returned as an error.
+    pub fn skipped_regions(&self) -> Result<'input, Vec<SkippedRegion<'input>>> {
+        let mut stack_maps_iter = self.stack_maps();
+        while stack_maps_iter.next()?.is_some() {}
+        Ok(stack_maps_iter.skipped)
+    }
+
+    /// Iterates the records of all the stack maps in ascending order of
+    /// their absolute address. Records at the same address are ordered by
+    /// stack map, function and position within the function.
+    pub fn safepoints(&self) -> SafepointsIter<'input> {
+        SafepointsIter {
+            stack_maps: Some(self.stack_maps()),
+            pending: BinaryHeap::new(),
         }
     }
 }
 
 pub struct StackMapsIter<'input> {
     data: &'input [u8],
+    section_size: usize,
+    options: ParseOptions,
+    // The records of the last stack map are only skipped when the next one is
+    // requested, so that taking the first stack map does not scan its records.
+    pending_records: u32,
+    skipped: Vec<SkippedRegion<'input>>,
+}
+
+impl<'input> StackMapsIter<'input> {
+    /// Returns the regions skipped so far in lenient mode.
+    pub fn skipped(&self) -> &[SkippedRegion<'input>] {
+        &self.skipped
+    }
+
+    // Moves past zero padding and malformed regions, so that the remaining
+    // data is either empty or starts with a whole valid stack map.
+    fn skip_malformed(&mut self) {
+        while !self.data.is_empty() {
+            let zeroes = self
+                .data
+                .iter()
+                .position(|&byte| byte != 0)
+                .unwrap_or(self.data.len());
+            if zeroes == self.data.len() {
+                self.data = &[];
+                return;
+            }
+            let padding = zeroes - zeroes % parser::ALIGNMENT_BYTES;
+            if padding > 0 {
+                self.data = &self.data[padding..];
+                continue;
+            }
+
+            let offset = self.section_size - self.data.len();
+            let reason = match coverage::stack_map_size(self.data) {
+                Ok(_) => return,
+                // Located in the region rather than in the section
+                Err(reason) => reason.map_offset(|region_offset| offset + region_offset),
+            };
+            let length = (parser::ALIGNMENT_BYTES..self.data.len())
+                .step_by(parser::ALIGNMENT_BYTES)
+                .find(|&start| coverage::stack_map_size(&self.data[start..]).is_ok())
+                .unwrap_or(self.data.len());
+            self.skipped.push(SkippedRegion {
+                offset,
+                bytes: &self.data[..length],
+                reason,
+            });
+            self.data = &self.data[length..];
+        }
+    }
 }
 
 impl<'input> FallibleIterator for StackMapsIter<'input> {
@@ -32,33 +281,284 @@ impl<'input> FallibleIterator for StackMapsIter<'input> {
     type Error = Error;
 
     fn next(&mut self) -> Result<'input, Option<Self::Item>> {
+        if self.pending_records > 0 {
+            let records_offset = self.section_size - self.data.len();
+            let result = parser::skip_records(self.data, self.pending_records)
+                .finish()
+                .map_err(|error| error.located(self.data, records_offset));
+            self.pending_records = 0;
+            self.data = match result {
+                Ok((rest, _)) => rest,
+                // The records of the last stack map are cut short, so what is
+                // left of them is trailing data
+                Err(_) if self.options.strict_eof => {
+                    return Err(Error::TrailingData {
+                        offset: records_offset,
+                    })
+                }
+                Err(error) => return Err(error),
+            };
+        }
+
+        if self.options.lenient {
+            self.skip_malformed();
+        }
         if self.data.is_empty() {
             return Ok(None);
         }
 
-        match parser::parse_stack_map(self.data).finish() {
-            Ok((rest, next_stack_map)) => {
+        let offset = self.section_size - self.data.len();
+        let after_last_map = self.options.strict_eof && offset > 0;
+        if after_last_map && self.data.iter().all(|&byte| byte == 0) {
+            self.data = &[];
+            return Ok(None);
+        }
+        // Too short to be another stack map, so not worth parsing as one
+        if after_last_map && self.data.len() < parser::HEADER_SIZE {
+            return Err(Error::TrailingData { offset });
+        }
+
+        match parser::parse_stack_map(self.data)
+            .finish()
+            .map_err(|error| error.located(self.data, offset))
+        {
+            Ok((rest, mut next_stack_map)) => {
                 self.data = rest;
+                self.pending_records = next_stack_map.num_records;
+                next_stack_map.offset = offset;
                 Ok(Some(next_stack_map))
             }
+            Err(_) if after_last_map => Err(Error::TrailingData { offset }),
             Err(error) => Err(error),
         }
     }
 }
 
+#[derive(Debug)]
+pub struct SkippedRegion<'input> {
+    offset: usize,
+    bytes: &'input [u8],
+    reason: Error,
+}
+
+impl<'input> SkippedRegion<'input> {
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.bytes.len()
+    }
+
+    pub fn bytes(&self) -> &'input [u8] {
+        self.bytes
+    }
+
+    /// Returns why parsing failed at the start of the region.
+    pub fn reason(&self) -> &Error {
+        &self.reason
+    }
+
+    /// Dumps the region as lines of 16 bytes, each prefixed with its offset
+    /// in the section.
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::new();
+        for (line_idx, line) in self.bytes.chunks(16).enumerate() {
+            let bytes: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            dump.push_str(&format!(
+                "{:08x}  {}\n",
+                self.offset + line_idx * 16,
+                bytes.join(" ")
+            ));
+        }
+        dump
+    }
+}
+
+/// Callbacks of `LLVMStackMaps::summary_with`, to compute more statistics in
+/// the same pass. Each element is visited after everything it contains: a
+/// function after its records and a stack map after its functions.
+pub trait SummaryVisitor<'input> {
+    fn record(
+        &mut self,
+        _function: &Function<'input>,
+        _record: &Record<'input>,
+    ) -> Result<'input, ()> {
+        Ok(())
+    }
+
+    fn function(&mut self, _function: &Function<'input>) -> Result<'input, ()> {
+        Ok(())
+    }
+
+    fn stack_map(&mut self, _stack_map: &StackMap<'input>) -> Result<'input, ()> {
+        Ok(())
+    }
+}
+
+impl<'input> SummaryVisitor<'input> for () {}
+
+// Totals over all the stack maps of a section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub stack_maps: usize,
+    pub functions: usize,
+    pub records: usize,
+    pub locations: usize,
+    pub live_outs: usize,
+    pub constants: usize,
+    // Size of the section, padding included
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Safepoint<'input> {
+    stack_map_index: usize,
+    function_index: usize,
+    function_address: u64,
+    record: Record<'input>,
+}
+
+impl<'input> Safepoint<'input> {
+    pub fn stack_map_index(&self) -> usize {
+        self.stack_map_index
+    }
+
+    pub fn function_index(&self) -> usize {
+        self.function_index
+    }
+
+    pub fn function_address(&self) -> u64 {
+        self.function_address
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.function_address
+            .wrapping_add(self.record.instruction_offset() as u64)
+    }
+
+    pub fn record(&self) -> &Record<'input> {
+        &self.record
+    }
+
+    pub fn into_record(self) -> Record<'input> {
+        self.record
+    }
+}
+
+enum PendingItem<'input> {
+    // A function whose records were not parsed yet, which cannot have any at
+    // an address lower than its own
+    Function(Function<'input>),
+    Record(Record<'input>),
+}
+
+// Ordered by (address, stack map, function, record), where functions come
+// before their own records
+struct PendingSafepoint<'input> {
+    key: (u64, usize, usize, usize),
+    item: PendingItem<'input>,
+}
+
+impl PartialEq for PendingSafepoint<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for PendingSafepoint<'_> {}
+
+impl PartialOrd for PendingSafepoint<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingSafepoint<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+pub struct SafepointsIter<'input> {
+    // Taken on the first call to `next`, which queues every function
+    stack_maps: Option<StackMapsIter<'input>>,
+    pending: BinaryHeap<Reverse<PendingSafepoint<'input>>>,
+}
+
+impl<'input> FallibleIterator for SafepointsIter<'input> {
+    type Item = Safepoint<'input>;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<'input, Option<Self::Item>> {
+        if let Some(stack_maps) = self.stack_maps.take() {
+            let mut stack_maps_iter = stack_maps.enumerate();
+            while let Some((stack_map_index, stack_map)) = stack_maps_iter.next()? {
+                let mut functions_iter = stack_map.functions().enumerate();
+                while let Some((function_index, function)) = functions_iter.next()? {
+                    self.pending.push(Reverse(PendingSafepoint {
+                        key: (function.address(), stack_map_index, function_index, 0),
+                        item: PendingItem::Function(function),
+                    }));
+                }
+            }
+        }
+
+        while let Some(Reverse(pending)) = self.pending.pop() {
+            let (address, stack_map_index, function_index, _) = pending.key;
+            match pending.item {
+                PendingItem::Function(function) => {
+                    let mut records_iter = function.records().enumerate();
+                    while let Some((record_index, record)) = records_iter.next()? {
+                        let pc = address.wrapping_add(record.instruction_offset() as u64);
+                        self.pending.push(Reverse(PendingSafepoint {
+                            key: (pc, stack_map_index, function_index, record_index + 1),
+                            item: PendingItem::Record(record),
+                        }));
+                    }
+                }
+                PendingItem::Record(record) => {
+                    let function_address = address.wrapping_sub(record.instruction_offset() as u64);
+                    return Ok(Some(Safepoint {
+                        stack_map_index,
+                        function_index,
+                        function_address,
+                        record,
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
 pub type StackMapVersion = u8;
 
 #[derive(Debug, Clone)]
 pub struct StackMap<'input> {
+    // Offset of the stack map in the section, or in the input it was parsed
+    // from on its own, which the offsets of its records and locations follow
+    offset: usize,
     version: StackMapVersion,
     num_functions: u32,
+    num_records: u32,
 
     functions: &'input [u8],
-    constants: &'input [u64],
-    record_slices: Vec<&'input [u8]>, // Records have variable length, so they cannot be lazily parsed
+    constants: &'input [u8],
+    records: &'input [u8], // Records have variable length, so they are sliced while iterating functions
 }
 
 impl<'input> StackMap<'input> {
+    /// Parses the stack map at the start of `bytes`, returning it with the
+    /// number of bytes it spans, records included. The bytes after it are
+    /// ignored, so callers can frame consecutive stack maps themselves.
+    pub fn parse(bytes: &'input [u8]) -> Result<'input, (StackMap<'input>, usize)> {
+        let (rest, stack_map) = parser::parse_stack_map(bytes)
+            .finish()
+            .map_err(|error| error.located(bytes, 0))?;
+        let (rest, _) = parser::skip_records(rest, stack_map.num_records)
+            .finish()
+            .map_err(|error| error.located(bytes, 0))?;
+        Ok((stack_map, bytes.len() - rest.len()))
+    }
+
     pub fn version(&self) -> StackMapVersion {
         self.version
     }
@@ -67,49 +567,172 @@ impl<'input> StackMap<'input> {
         self.num_functions as usize
     }
 
+    pub fn num_records(&self) -> usize {
+        self.num_records as usize
+    }
+
+    pub fn num_constants(&self) -> usize {
+        self.constants.len() / parser::CONSTANT_SIZE
+    }
+
+    pub fn constants(&self) -> ConstantsIter<'input> {
+        ConstantsIter {
+            chunks: self.constants.chunks_exact(parser::CONSTANT_SIZE),
+        }
+    }
+
     pub fn functions(&self) -> FunctionsIter<'input> {
         FunctionsIter {
             data: self.functions,
-            record_slices: self.record_slices.clone(),
+            records: self.records,
+            num_records: self.num_records,
+            records_offset: self.offset
+                + parser::HEADER_SIZE
+                + self.functions.len()
+                + self.constants.len(),
+            record_table: None,
+            next_record: 0,
             remaining_functions: self.num_functions as usize,
             constants: self.constants,
         }
     }
+
+    /// Function addresses and instruction offsets are left out, so that the
+    /// fingerprint survives code moving around between builds.
+    pub fn fingerprint(&self) -> Result<'input, u64> {
+        let mut fingerprinter = Fingerprinter::new();
+        fingerprinter.write(&[self.version]);
+        fingerprinter.write_u64(self.num_functions as u64);
+
+        let mut functions_iter = self.functions();
+        while let Some(function) = functions_iter.next()? {
+            fingerprinter.write_u64(function.stack_size);
+            fingerprinter.write_u64(function.num_records() as u64);
+
+            let mut records_iter = function.records();
+            while let Some(record) = records_iter.next()? {
+                fingerprinter.write_u64(record.fingerprint()?);
+            }
+        }
+
+        Ok(fingerprinter.finish())
+    }
+
+    pub fn function_headers(&self) -> FunctionHeadersIter<'input> {
+        FunctionHeadersIter {
+            data: self.functions,
+            offset: self.offset + parser::HEADER_SIZE,
+            remaining_functions: self.num_functions as usize,
+        }
+    }
 }
 
+// The records of a stack map, each with its offset in the section
+type RecordTable<'input> = Arc<[(usize, &'input [u8])]>;
+
 pub struct FunctionsIter<'input> {
     data: &'input [u8],
-    record_slices: Vec<&'input [u8]>,
-    constants: &'input [u64],
+    records: &'input [u8],
+    records_offset: usize,
+    num_records: u32,
+    // All the records of the stack map with their offsets, sliced on the first
+    // call to `next` and shared by the functions, each of which owns a range
+    // of it
+    record_table: Option<RecordTable<'input>>,
+    next_record: usize,
+    constants: &'input [u8],
     remaining_functions: usize,
 }
 
+impl<'input> FunctionsIter<'input> {
+    fn record_table(&mut self) -> Result<'input, RecordTable<'input>> {
+        if let Some(record_table) = &self.record_table {
+            return Ok(record_table.clone());
+        }
+
+        let (_, records) = parser::slice_records(self.records, self.num_records as u64)
+            .finish()
+            .map_err(|error| error.located(self.records, self.records_offset))?;
+        let mut offset = self.records_offset;
+        let record_table: RecordTable<'input> = records
+            .into_iter()
+            .map(|record_slice| {
+                offset += record_slice.len();
+                (offset - record_slice.len(), record_slice)
+            })
+            .collect();
+        self.record_table = Some(record_table.clone());
+        Ok(record_table)
+    }
+}
+
 impl<'input> FallibleIterator for FunctionsIter<'input> {
     type Item = Function<'input>;
     type Error = Error;
 
     fn next(&mut self) -> Result<'input, Option<Self::Item>> {
+        let record_table = self.record_table()?;
+        let remaining_records = (record_table.len() - self.next_record) as u64;
         if self.data.is_empty() {
             // The functions should contain all the records
-            if self.record_slices.is_empty() {
+            if remaining_records == 0 {
                 return Ok(None);
             } else {
-                return FunctionRecordMismatch.fail();
+                return Err(Error::FunctionRecordMismatch);
             }
         }
 
-        match parser::parse_function((
-            self.data,
-            mem::take(&mut self.record_slices),
-            self.constants,
-        ))
-        .finish()
+        // The function headers are followed by the constants, then the records
+        let offset = self.records_offset - self.constants.len() - self.data.len();
+        let (rest_data, header) = parser::parse_function_header(self.data)
+            .finish()
+            .map_err(|error| error.located(self.data, offset))?;
+        if header.record_count > remaining_records {
+            return Err(Error::FunctionRecordMismatch);
+        }
+
+        let first_record = self.next_record;
+        self.data = rest_data;
+        self.next_record += header.record_count as usize;
+        self.remaining_functions -= 1;
+        Ok(Some(Function {
+            address: header.address,
+            stack_size: header.stack_size,
+            record_table,
+            records: first_record..self.next_record,
+            constants: self.constants,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_functions, Some(self.remaining_functions))
+    }
+}
+
+pub struct FunctionHeadersIter<'input> {
+    data: &'input [u8],
+    offset: usize,
+    remaining_functions: usize,
+}
+
+impl<'input> FallibleIterator for FunctionHeadersIter<'input> {
+    type Item = FunctionHeader;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<'input, Option<Self::Item>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        match parser::parse_function_header(self.data)
+            .finish()
+            .map_err(|error| error.located(self.data, self.offset))
         {
-            Ok(((rest_data, rest_record_slices, _), next_function)) => {
-                self.data = rest_data;
-                self.record_slices = rest_record_slices;
+            Ok((rest, next_header)) => {
+                self.offset += self.data.len() - rest.len();
+                self.data = rest;
                 self.remaining_functions -= 1;
-                Ok(Some(next_function))
+                Ok(Some(next_header))
             }
             Err(error) => Err(error),
         }
@@ -120,13 +743,35 @@ impl<'input> FallibleIterator for FunctionsIter<'input> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionHeader {
+    address: u64,
+    stack_size: u64,
+    record_count: u64,
+}
+
+impl FunctionHeader {
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack_size as usize
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.record_count as usize
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Function<'input> {
     address: u64,
     stack_size: u64,
 
-    records: Vec<&'input [u8]>,
-    constants: &'input [u64],
+    record_table: RecordTable<'input>,
+    records: Range<usize>,
+    constants: &'input [u8],
 }
 
 impl<'input> Function<'input> {
@@ -144,16 +789,95 @@ impl<'input> Function<'input> {
 
     pub fn records<'me>(&'me self) -> RecordsIter<'me, 'input> {
         RecordsIter {
-            records_iter: self.records.iter(),
+            records_iter: self.record_table[self.records.clone()].iter(),
             remaining_records: self.records.len(),
             constants: self.constants,
         }
     }
+
+    // Reads only the offset of each record, without parsing the rest
+    fn record_slices(&self) -> &[(usize, &'input [u8])] {
+        &self.record_table[self.records.clone()]
+    }
+
+    fn parse_record(
+        &self,
+        &(offset, record_slice): &(usize, &'input [u8]),
+    ) -> Result<'input, Record<'input>> {
+        let (_, mut record) = parser::parse_record((record_slice, self.constants))
+            .finish()
+            .map_err(|error| error.located(record_slice, offset))?;
+        record.offset = offset;
+        Ok(record)
+    }
+
+    // Index of the first record at an instruction offset not below
+    // `instruction_offset`, reading only the offset of the records looked at
+    fn partition_records(&self, instruction_offset: u32) -> usize {
+        self.record_slices()
+            .partition_point(|&(_, record_slice)| record_offset(record_slice) < instruction_offset)
+    }
+
+    /// Returns the first record at `instruction_offset`. Records are looked up
+    /// by binary search, since LLVM emits them in code order, and only the
+    /// record found is parsed.
+    pub fn record_at_offset(
+        &self,
+        instruction_offset: u32,
+    ) -> Result<'input, Option<Record<'input>>> {
+        self.record_slices()
+            .get(self.partition_records(instruction_offset))
+            .filter(|(_, record_slice)| record_offset(record_slice) == instruction_offset)
+            .map(|record_slice| self.parse_record(record_slice))
+            .transpose()
+    }
+
+    /// Returns the record with the highest offset not above
+    /// `instruction_offset`, or the first of them if there are several.
+    pub fn record_at_or_below_offset(
+        &self,
+        instruction_offset: u32,
+    ) -> Result<'input, Option<Record<'input>>> {
+        let records = self.record_slices();
+        let above = match instruction_offset.checked_add(1) {
+            Some(next_offset) => self.partition_records(next_offset),
+            None => records.len(),
+        };
+        match above.checked_sub(1) {
+            Some(nearest) => {
+                let first = self.partition_records(record_offset(records[nearest].1));
+                self.parse_record(&records[first]).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// LLVM can emit several records at the same instruction offset, e.g. for
+    /// a statepoint and a patchpoint, so each offset maps to all of them in
+    /// the order they appear in the stack map.
+    pub fn records_by_offset(&self) -> Result<'input, BTreeMap<usize, Vec<Record<'input>>>> {
+        let mut groups: BTreeMap<usize, Vec<Record<'input>>> = BTreeMap::new();
+        let mut records_iter = self.records();
+        while let Some(record) = records_iter.next()? {
+            groups
+                .entry(record.instruction_offset())
+                .or_default()
+                .push(record);
+        }
+
+        Ok(groups)
+    }
+}
+
+// Instruction offset of the unparsed record at the start of `record_slice`
+fn record_offset(record_slice: &[u8]) -> u32 {
+    let field = &record_slice[parser::RECORD_OFFSET_FIELD..parser::RECORD_OFFSET_FIELD + 4];
+    u32::from_le_bytes(field.try_into().unwrap())
 }
 
 pub struct RecordsIter<'function, 'input> {
-    records_iter: std::slice::Iter<'function, &'input [u8]>,
-    constants: &'input [u64],
+    records_iter: core::slice::Iter<'function, (usize, &'input [u8])>,
+    constants: &'input [u8],
     remaining_records: usize,
 }
 
@@ -162,15 +886,19 @@ impl<'function, 'input> FallibleIterator for RecordsIter<'function, 'input> {
     type Error = Error;
 
     fn next(&mut self) -> Result<'input, Option<Self::Item>> {
-        let record_slice = match self.records_iter.next() {
-            Some(record_slice) => record_slice,
+        let &(offset, record_slice) = match self.records_iter.next() {
+            Some(record) => record,
             None => return Ok(None),
         };
 
-        match parser::parse_record((record_slice, self.constants)).finish() {
-            Ok(((rest, _), next_record)) => {
+        match parser::parse_record((record_slice, self.constants))
+            .finish()
+            .map_err(|error| error.located(record_slice, offset))
+        {
+            Ok(((rest, _), mut next_record)) => {
                 assert!(rest.is_empty()); // This record slice has already been parsed
                 self.remaining_records -= 1;
+                next_record.offset = offset;
                 Ok(Some(next_record))
             }
             Err(error) => Err(error),
@@ -184,6 +912,9 @@ impl<'function, 'input> FallibleIterator for RecordsIter<'function, 'input> {
 
 #[derive(Debug, Clone)]
 pub struct Record<'input> {
+    raw: &'input [u8],
+    // In the section, like the offset of the stack map
+    offset: usize,
     patch_point_id: u64,
     instruction_offset: u32,
     num_locations: u16,
@@ -191,10 +922,21 @@ pub struct Record<'input> {
 
     locations: &'input [u8],
     live_outs: &'input [u8],
-    constants: &'input [u64],
+    constants: &'input [u8],
 }
 
 impl<'input> Record<'input> {
+    /// Returns the bytes of the record, including its locations, live-outs
+    /// and padding.
+    pub fn raw_bytes(&self) -> &'input [u8] {
+        self.raw
+    }
+
+    /// Returns the range of `raw_bytes` in the section.
+    pub fn byte_range(&self) -> Range<usize> {
+        self.offset..self.offset + self.raw.len()
+    }
+
     pub fn patch_point_id(&self) -> u64 {
         self.patch_point_id
     }
@@ -210,11 +952,16 @@ impl<'input> Record<'input> {
     pub fn locations(&self) -> LocationsIter<'input> {
         LocationsIter {
             data: self.locations,
+            offset: self.offset + parser::RECORD_HEADER_SIZE,
             constants: self.constants,
             remaining_locations: self.num_locations as usize,
         }
     }
 
+    pub fn locations_vec(&self) -> Result<'input, Vec<Location>> {
+        self.locations().into_iter().collect()
+    }
+
     pub fn num_live_outs(&self) -> usize {
         self.num_live_outs as usize
     }
@@ -222,14 +969,42 @@ impl<'input> Record<'input> {
     pub fn live_outs(&self) -> LiveOutsIter<'input> {
         LiveOutsIter {
             data: self.live_outs,
+            // The live-outs are within the raw bytes of the record
+            offset: self.offset + (self.live_outs.as_ptr() as usize - self.raw.as_ptr() as usize),
             remaining_live_outs: self.num_live_outs as usize,
         }
     }
+
+    pub fn live_outs_vec(&self) -> Result<'input, Vec<LiveOut>> {
+        self.live_outs().into_iter().collect()
+    }
+
+    /// The instruction offset is left out, so that records can be correlated
+    /// across builds even when the code around them changes.
+    pub fn fingerprint(&self) -> Result<'input, u64> {
+        let mut fingerprinter = Fingerprinter::new();
+        fingerprinter.write_u64(self.patch_point_id);
+
+        fingerprinter.write_u64(self.num_locations as u64);
+        let mut locations_iter = self.locations();
+        while let Some(location) = locations_iter.next()? {
+            fingerprinter.write_location(&location);
+        }
+
+        fingerprinter.write_u64(self.num_live_outs as u64);
+        let mut live_outs_iter = self.live_outs();
+        while let Some(live_out) = live_outs_iter.next()? {
+            fingerprinter.write_live_out(&live_out);
+        }
+
+        Ok(fingerprinter.finish())
+    }
 }
 
 pub struct LocationsIter<'input> {
     data: &'input [u8],
-    constants: &'input [u64],
+    offset: usize,
+    constants: &'input [u8],
     remaining_locations: usize,
 }
 
@@ -242,10 +1017,17 @@ impl<'input> FallibleIterator for LocationsIter<'input> {
             return Ok(None);
         }
 
-        match parser::parse_location((self.data, self.constants)).finish() {
-            Ok(((rest, _), next_location)) => {
+        match parser::parse_location((self.data, self.constants))
+            .finish()
+            .map_err(|error| error.located(self.data, self.offset))
+        {
+            Ok(((rest, _), mut next_location)) => {
                 self.data = rest;
                 self.remaining_locations -= 1;
+                if let Some(origin) = &mut next_location.origin {
+                    origin.offset = self.offset;
+                }
+                self.offset += parser::LOCATION_SIZE;
                 Ok(Some(next_location))
             }
             Err(error) => Err(error),
@@ -259,6 +1041,7 @@ impl<'input> FallibleIterator for LocationsIter<'input> {
 
 pub struct LiveOutsIter<'input> {
     data: &'input [u8],
+    offset: usize,
     remaining_live_outs: usize,
 }
 
@@ -271,8 +1054,12 @@ impl<'input> FallibleIterator for LiveOutsIter<'input> {
             return Ok(None);
         }
 
-        match parser::parse_live_out(self.data).finish() {
+        match parser::parse_live_out(self.data)
+            .finish()
+            .map_err(|error| error.located(self.data, self.offset))
+        {
             Ok((rest, next_live_out)) => {
+                self.offset += self.data.len() - rest.len();
                 self.data = rest;
                 self.remaining_live_outs -= 1;
                 Ok(Some(next_live_out))
@@ -289,6 +1076,8 @@ impl<'input> FallibleIterator for LiveOutsIter<'input> {
 pub type DwarfRegNum = u16;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
 pub enum LocationKind {
     Register(DwarfRegNum),
     Direct {
@@ -303,12 +1092,54 @@ pub enum LocationKind {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
 pub struct Location {
     kind: LocationKind,
     size: u16,
+    // Only known for parsed locations, and ignored when comparing them
+    #[cfg_attr(feature = "serde", serde(skip))]
+    origin: Option<Origin>,
 }
 
+// Where a parsed location comes from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Origin {
+    // In the section, like the offset of its record
+    pub(crate) offset: usize,
+    pub(crate) constant_index: Option<u32>,
+}
+
+impl PartialEq for Location {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.size == other.size
+    }
+}
+
+impl Eq for Location {}
+
 impl Location {
+    pub fn new(kind: LocationKind, size: u16) -> Self {
+        Self {
+            kind,
+            size,
+            origin: None,
+        }
+    }
+
+    /// Returns the range in the section of the bytes the location was parsed
+    /// from, reserved fields included. Only known for parsed locations.
+    pub fn byte_range(&self) -> Option<Range<usize>> {
+        let offset = self.origin?.offset;
+        Some(offset..offset + parser::LOCATION_SIZE)
+    }
+
+    /// Index in the constants pool of parsed pool constants, which are
+    /// otherwise indistinguishable from inline constants once resolved.
+    pub fn constant_index(&self) -> Option<u32> {
+        self.origin?.constant_index
+    }
+
     pub fn kind(&self) -> &LocationKind {
         &self.kind
     }
@@ -316,15 +1147,225 @@ impl Location {
     pub fn size(&self) -> usize {
         self.size as usize
     }
+
+    pub fn constant(&self) -> Option<Constant> {
+        match self.kind {
+            LocationKind::Constant(value) => Some(Constant(value)),
+            _ => None,
+        }
+    }
+
+    /// Computes `register_value + offset` for frame locations, given the
+    /// value of their register, e.g. the stack or frame pointer of the frame
+    /// at the safepoint. Both kinds add the offset the same way, but the
+    /// result means different things: see `FrameAddress`. Register and
+    /// constant locations do not refer to memory, so they give `None`.
+    pub fn frame_address(&self, register_value: u64) -> Option<FrameAddress> {
+        match self.kind {
+            LocationKind::Direct { offset, .. } => Some(FrameAddress::Direct(
+                register_value.wrapping_add(offset as u64),
+            )),
+            LocationKind::Indirect { offset, .. } => Some(FrameAddress::Indirect(
+                register_value.wrapping_add(offset as u64),
+            )),
+            LocationKind::Register(_) | LocationKind::Constant(_) => None,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+// Where the value of a frame location lives, following the LLVM description
+// of `Direct` (`Reg + Offset`) and `Indirect` (`[Reg + Offset]`) locations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameAddress {
+    // The value is the address itself, which is not loaded from: it is the
+    // address of a stack object such as an alloca, and the stack object is
+    // what a collector has to scan or update.
+    Direct(u64),
+    // The value is stored at this address, e.g. a spill slot. Reading the
+    // value means loading `size` bytes from the slot, and updating it means
+    // writing the slot.
+    Indirect(u64),
+}
+
+impl FrameAddress {
+    pub fn address(self) -> u64 {
+        match self {
+            FrameAddress::Direct(address) | FrameAddress::Indirect(address) => address,
+        }
+    }
+}
+
+// Constants are stored as raw 64-bit values, whose meaning depends on the
+// producer: they are often signed integers, pointers or doubles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Constant(u64);
+
+impl Constant {
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_i64(self) -> i64 {
+        self.0 as i64
+    }
+
+    pub fn as_f64_bits(self) -> f64 {
+        f64::from_bits(self.0)
+    }
+
+    pub fn looks_like_address(self, range: Range<u64>) -> bool {
+        range.contains(&self.0)
+    }
+}
+
+/// Adapts one of the fallible iterators of the parser into a standard iterator
+/// of results, obtained with `into_iter`. The number of items is read from the
+/// stack map, so the adapter implements `ExactSizeIterator` and `collect`
+/// allocates once. To keep that count exact on malformed stack maps, the first
+/// error is repeated for all the items left after it.
+pub struct ExactIter<I: FallibleIterator> {
+    inner: I,
+    remaining: usize,
+    error: Option<I::Error>,
+}
+
+impl<I: FallibleIterator> ExactIter<I> {
+    fn new(inner: I) -> Self {
+        // The iterators of the parser count down from the number of items
+        ExactIter {
+            remaining: inner.size_hint().0,
+            inner,
+            error: None,
+        }
+    }
+}
+
+impl<I: FallibleIterator> Iterator for ExactIter<I>
+where
+    I::Error: Clone,
+{
+    type Item = core::result::Result<I::Item, I::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        if let Some(error) = &self.error {
+            return Some(Err(error.clone()));
+        }
+        match self.inner.next() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.remaining = 0;
+                None
+            }
+            Err(error) => {
+                self.error = Some(error.clone());
+                Some(Err(error))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<I: FallibleIterator> core::iter::FusedIterator for ExactIter<I> where I::Error: Clone {}
+
+impl<'input> ExactSizeIterator for ExactIter<FunctionsIter<'input>> {}
+impl<'input> ExactSizeIterator for ExactIter<FunctionHeadersIter<'input>> {}
+impl<'function, 'input> ExactSizeIterator for ExactIter<RecordsIter<'function, 'input>> {}
+impl<'input> ExactSizeIterator for ExactIter<LocationsIter<'input>> {}
+impl<'input> ExactSizeIterator for ExactIter<LiveOutsIter<'input>> {}
+
+impl<'input> IntoIterator for FunctionsIter<'input> {
+    type Item = Result<'input, Function<'input>>;
+    type IntoIter = ExactIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExactIter::new(self)
+    }
+}
+
+impl<'input> IntoIterator for FunctionHeadersIter<'input> {
+    type Item = Result<'input, FunctionHeader>;
+    type IntoIter = ExactIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExactIter::new(self)
+    }
+}
+
+impl<'function, 'input> IntoIterator for RecordsIter<'function, 'input> {
+    type Item = Result<'input, Record<'input>>;
+    type IntoIter = ExactIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExactIter::new(self)
+    }
+}
+
+impl<'input> IntoIterator for LocationsIter<'input> {
+    type Item = Result<'input, Location>;
+    type IntoIter = ExactIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExactIter::new(self)
+    }
+}
+
+impl<'input> IntoIterator for LiveOutsIter<'input> {
+    type Item = Result<'input, LiveOut>;
+    type IntoIter = ExactIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExactIter::new(self)
+    }
+}
+
+pub struct ConstantsIter<'input> {
+    chunks: core::slice::ChunksExact<'input, u8>,
+}
+
+impl<'input> Iterator for ConstantsIter<'input> {
+    type Item = Constant;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks
+            .next()
+            .map(|bytes| Constant(u64::from_le_bytes(bytes.try_into().unwrap())))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'input> ExactSizeIterator for ConstantsIter<'input> {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
 pub struct LiveOut {
     dwarf_reg_num: DwarfRegNum,
     size: u8,
 }
 
 impl LiveOut {
+    pub fn new(dwarf_reg_num: DwarfRegNum, size: u8) -> Self {
+        Self {
+            dwarf_reg_num,
+            size,
+        }
+    }
+
     pub fn dwarf_reg_num(&self) -> DwarfRegNum {
         self.dwarf_reg_num
     }
@@ -334,12 +1375,16 @@ impl LiveOut {
     }
 }
 
-type Result<'a, T> = std::result::Result<T, Error>;
+type Result<'a, T> = core::result::Result<T, Error>;
 
-#[derive(Debug, Snafu)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
+    // `input` starts with the first bytes of the input that was left, which
+    // is at `offset` in the section. The parser only knows how much input was
+    // left, which is turned into the offset by `located`.
     ParserError {
         input: Vec<u8>,
+        offset: usize,
         kind: nom::error::ErrorKind,
     },
     UnsupportedVersion,
@@ -352,6 +1397,89 @@ pub enum Error {
     InvalidLocationKind {
         invalid_kind: u8,
     },
+    UnencodableCount {
+        count: usize,
+    },
+    UnencodableOffset {
+        offset: i64,
+    },
+    TrailingData {
+        offset: usize,
+    },
+    SizeOverflow {
+        count: u64,
+        element_size: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ParserError { .. } => f.write_str("ParserError"),
+            Error::UnsupportedVersion => f.write_str("UnsupportedVersion"),
+            Error::MalformedHeader => f.write_str("MalformedHeader"),
+            Error::FunctionRecordMismatch => f.write_str("FunctionRecordMismatch"),
+            Error::MalformedReserved => f.write_str("MalformedReserved"),
+            Error::InvalidConstantIndex { .. } => f.write_str("InvalidConstantIndex"),
+            Error::InvalidLocationKind { .. } => f.write_str("InvalidLocationKind"),
+            Error::UnencodableCount { .. } => f.write_str("UnencodableCount"),
+            Error::UnencodableOffset { .. } => f.write_str("UnencodableOffset"),
+            Error::TrailingData { offset } => write!(f, "Trailing data at offset {:#x}", offset),
+            Error::SizeOverflow { .. } => f.write_str("SizeOverflow"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl Error {
+    /// A stable name of the kind of error, for tools that handle errors
+    /// without parsing their messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ParserError { .. } => "parser-error",
+            Error::UnsupportedVersion => "unsupported-version",
+            Error::MalformedHeader => "malformed-header",
+            Error::FunctionRecordMismatch => "function-record-mismatch",
+            Error::MalformedReserved => "malformed-reserved",
+            Error::InvalidConstantIndex { .. } => "invalid-constant-index",
+            Error::InvalidLocationKind { .. } => "invalid-location-kind",
+            Error::UnencodableCount { .. } => "unencodable-count",
+            Error::UnencodableOffset { .. } => "unencodable-offset",
+            Error::TrailingData { .. } => "trailing-data",
+            Error::SizeOverflow { .. } => "size-overflow",
+        }
+    }
+
+    /// Returns the offset in the section at which parsing failed, if known.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Error::TrailingData { offset } | Error::ParserError { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    // Locates a parser error that occurred in `input`, which is at `offset`
+    // in the section
+    pub(crate) fn located(self, input: &[u8], offset: usize) -> Self {
+        self.map_offset(|left| offset + input.len().saturating_sub(left))
+    }
+
+    fn map_offset(self, f: impl FnOnce(usize) -> usize) -> Self {
+        match self {
+            Error::ParserError {
+                input,
+                offset,
+                kind,
+            } => Error::ParserError {
+                input,
+                offset: f(offset),
+                kind,
+            },
+            error => error,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -397,17 +1525,510 @@ mod tests {
 
         let locations: Vec<_> = records[0].locations().collect().unwrap();
         assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0], "direct(r6, -10, 8)".parse().unwrap());
+
+        let live_outs: Vec<_> = records[0].live_outs().collect().unwrap();
+        assert!(live_outs.is_empty());
+    }
+
+    #[test]
+    fn function_headers_skip_records() {
+        // The record data is truncated, so only the function headers can be read
+        let data: &[u8] = &[
+            0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0xc0, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x58, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00,
+        ];
+        let section = LLVMStackMaps::new(data);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+
+        let headers: Vec<_> = stack_map.function_headers().collect().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].address(), 0x11c0);
+        assert_eq!(headers[0].stack_size(), 88);
+        assert_eq!(headers[0].num_records(), 1);
+
+        assert!(stack_map.functions().next().is_err());
+    }
+
+    #[test]
+    fn functions_share_record_table() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let functions: Vec<_> = stack_map.functions().collect().unwrap();
+
+        assert!(Arc::ptr_eq(
+            &functions[0].record_table,
+            &functions[1].record_table
+        ));
+        assert_eq!(functions[0].records, 0..2);
+        assert_eq!(functions[1].records, 2..3);
+        assert_eq!(
+            functions[1]
+                .records()
+                .next()
+                .unwrap()
+                .unwrap()
+                .patch_point_id(),
+            44
+        );
+    }
+
+    #[test]
+    fn fingerprints_ignore_addresses() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+
+        let mut moved = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        moved.functions[0].address += 0x1000;
+        moved.functions[0].records[0].instruction_offset += 4;
+        let moved_data = moved.to_bytes().unwrap();
+        let moved_section = LLVMStackMaps::new(&moved_data);
+        let moved_stack_map = moved_section.stack_maps().next().unwrap().unwrap();
+        assert_eq!(
+            stack_map.fingerprint().unwrap(),
+            moved_stack_map.fingerprint().unwrap()
+        );
+
+        let records: Vec<_> = stack_map
+            .functions()
+            .next()
+            .unwrap()
+            .unwrap()
+            .records()
+            .collect()
+            .unwrap();
+        let moved_records: Vec<_> = moved_stack_map
+            .functions()
+            .next()
+            .unwrap()
+            .unwrap()
+            .records()
+            .collect()
+            .unwrap();
+        assert_eq!(
+            records[0].fingerprint().unwrap(),
+            moved_records[0].fingerprint().unwrap()
+        );
+        assert_ne!(
+            records[0].fingerprint().unwrap(),
+            records[1].fingerprint().unwrap()
+        );
+
+        moved.functions[1].stack_size += 8;
+        let changed_data = moved.to_bytes().unwrap();
+        let changed_section = LLVMStackMaps::new(&changed_data);
+        let changed_stack_map = changed_section.stack_maps().next().unwrap().unwrap();
+        assert_ne!(
+            stack_map.fingerprint().unwrap(),
+            changed_stack_map.fingerprint().unwrap()
+        );
+    }
+
+    #[test]
+    fn typed_constants() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let constants: Vec<_> = stack_map.constants().collect();
+        assert_eq!(stack_map.num_constants(), 1);
+        assert_eq!(constants, vec![Constant::new(1234567890123)]);
+        assert!(constants[0].looks_like_address(0x100_0000_0000..0x200_0000_0000));
+        assert!(!constants[0].looks_like_address(0x1000..0x2000));
+
+        let negative = Constant::new(-2i64 as u64);
+        assert_eq!(negative.as_i64(), -2);
+        assert_eq!(Constant::new(1.5f64.to_bits()).as_f64_bits(), 1.5);
+    }
+
+    #[test]
+    fn strict_eof() {
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        let strict = ParseOptions {
+            strict_eof: true,
+            ..ParseOptions::default()
+        };
+
+        let section = LLVMStackMaps::new(&data);
+        assert!(section.stack_maps().count().is_err());
+        let section = LLVMStackMaps::with_options(&data, strict);
+        assert_eq!(section.stack_maps().count().unwrap(), 1);
+
+        data.extend_from_slice(&[0x2a, 0, 0, 0]);
+        let section = LLVMStackMaps::with_options(&data, strict);
+        assert!(matches!(
+            section.stack_maps().count(),
+            Err(Error::TrailingData { offset: 224 })
+        ));
+
+        // Records cut short, which only fails once they are skipped
+        let data = &test_data::TWO_FUNCTIONS[..200];
+        let section = LLVMStackMaps::with_options(data, strict);
+        let mut stack_maps_iter = section.stack_maps();
+        assert!(stack_maps_iter.next().unwrap().is_some());
+        assert!(matches!(
+            stack_maps_iter.next(),
+            Err(Error::TrailingData { offset: 72 })
+        ));
+    }
+
+    #[test]
+    fn error_offsets() {
+        // A second stack map cut right after its header
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[3, 0, 0, 0, 1, 0]);
+        let error = LLVMStackMaps::new(&data).stack_maps().count().unwrap_err();
+        assert_eq!(error.code(), "parser-error");
+        assert_eq!(error.offset(), Some(228));
+        // Located the same whichever copy of the section is parsed
+        let copy = data.clone();
         assert_eq!(
-            *locations[0].kind(),
-            LocationKind::Direct {
-                register: 6,
-                offset: -10
+            LLVMStackMaps::new(&copy).stack_maps().count().unwrap_err(),
+            error
+        );
+
+        // A second stack map cut within its function records, which are
+        // followed by more zeros than the error keeps
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0; 16]);
+        let error = LLVMStackMaps::new(&data).stack_maps().count().unwrap_err();
+        assert_eq!(error.offset(), Some(240));
+
+        assert_eq!(Error::TrailingData { offset: 8 }.offset(), Some(8));
+        assert_eq!(Error::MalformedHeader.offset(), None);
+    }
+
+    #[test]
+    fn collected_locations() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let record = function.records().next().unwrap().unwrap();
+
+        let locations = record.locations_vec().unwrap();
+        assert_eq!(locations.len(), record.num_locations());
+        assert_eq!(locations[2].kind(), &LocationKind::Constant(7));
+        assert!(record.live_outs_vec().unwrap().is_empty());
+    }
+
+    #[test]
+    fn exact_size_adapters() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+
+        let mut functions = stack_map.functions().into_iter();
+        assert_eq!(functions.len(), 2);
+        let function = functions.next().unwrap().unwrap();
+        assert_eq!(functions.len(), 1);
+        assert_eq!(stack_map.function_headers().into_iter().len(), 2);
+
+        let mut records = function.records().into_iter();
+        assert_eq!(records.len(), 2);
+        let record = records.next().unwrap().unwrap();
+        assert_eq!(records.size_hint(), (1, Some(1)));
+
+        let mut locations = record.locations().into_iter();
+        locations.next().unwrap().unwrap();
+        assert_eq!(locations.len(), 3);
+        assert_eq!(locations.count(), 3);
+        assert_eq!(record.live_outs().into_iter().len(), 0);
+
+        functions.next().unwrap().unwrap();
+        assert_eq!(functions.len(), 0);
+        assert!(functions.next().is_none());
+
+        // The first error is repeated up to the number of locations, here
+        // an invalid kind for the first location of the first record
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data[16 + 2 * 24 + 8 + 16] = 0xff;
+        let section = LLVMStackMaps::new(&data);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let record = function.records().next().unwrap().unwrap();
+        let mut locations = record.locations().into_iter();
+        assert_eq!(locations.len(), 4);
+        assert!(locations.next().unwrap().is_err());
+        assert_eq!(locations.len(), 3);
+        assert_eq!(locations.filter(Result::is_err).count(), 3);
+    }
+
+    #[test]
+    fn records_by_instruction_offset() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+
+        let record = function.record_at_offset(0x2b).unwrap().unwrap();
+        assert_eq!(record.patch_point_id(), 43);
+        assert!(function.record_at_offset(0x2a).unwrap().is_none());
+
+        let nearest = |offset| {
+            function
+                .record_at_or_below_offset(offset)
+                .unwrap()
+                .map(|record| record.patch_point_id())
+        };
+        assert_eq!(nearest(0x1f), None);
+        assert_eq!(nearest(0x20), Some(42));
+        assert_eq!(nearest(0x2a), Some(42));
+        assert_eq!(nearest(0x100), Some(43));
+
+        // Several records at each offset, the first of which is returned
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        let records = &mut owned.functions[0].records;
+        for (idx, instruction_offset) in [0x20, 0x2b, 0x2b, 0x40].iter().enumerate().skip(1) {
+            let mut record = records[0].clone();
+            record.patch_point_id = 100 + idx as u64;
+            record.instruction_offset = *instruction_offset;
+            records.push(record);
+        }
+        records.sort_by_key(|record| record.instruction_offset);
+        let data = owned.to_bytes().unwrap();
+        let section = LLVMStackMaps::new(&data);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let at = |offset| {
+            function
+                .record_at_offset(offset)
+                .unwrap()
+                .map(|record| record.patch_point_id())
+        };
+        let nearest = |offset| {
+            function
+                .record_at_or_below_offset(offset)
+                .unwrap()
+                .map(|record| record.patch_point_id())
+        };
+        assert_eq!(at(0x20), Some(42));
+        assert_eq!(at(0x2b), Some(43));
+        assert_eq!(at(0x40), Some(103));
+        assert_eq!(at(0x41), None);
+        assert_eq!(nearest(0x2a), Some(42));
+        assert_eq!(nearest(0x3f), Some(43));
+        assert_eq!(nearest(u32::MAX), Some(103));
+    }
+
+    #[test]
+    fn frame_addresses() {
+        let spill_slot: Location = "indirect(r7, -16, 8)".parse().unwrap();
+        assert_eq!(
+            spill_slot.frame_address(0x7fff_0040),
+            Some(FrameAddress::Indirect(0x7fff_0030))
+        );
+
+        let alloca: Location = "direct(r7, 8, 8)".parse().unwrap();
+        assert_eq!(
+            alloca.frame_address(0x7fff_0040),
+            Some(FrameAddress::Direct(0x7fff_0048))
+        );
+        assert_eq!(
+            alloca.frame_address(0x7fff_0040).unwrap().address(),
+            0x7fff_0048
+        );
+
+        let register: Location = "register(r3, 8)".parse().unwrap();
+        assert_eq!(register.frame_address(0x7fff_0040), None);
+    }
+
+    #[test]
+    fn section_summary() {
+        let summary = LLVMStackMaps::new(test_data::TWO_FUNCTIONS)
+            .summary()
+            .unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                stack_maps: 1,
+                functions: 2,
+                records: 3,
+                locations: 6,
+                live_outs: 0,
+                constants: 1,
+                bytes: 224,
             }
         );
-        assert_eq!(locations[0].size(), 8);
 
-        let live_outs: Vec<_> = records[0].live_outs().collect().unwrap();
-        assert!(live_outs.is_empty());
+        // The IDs of the records of each function, as visited
+        struct Ids(Vec<Vec<u64>>, Vec<u64>);
+        impl<'input> SummaryVisitor<'input> for Ids {
+            fn record(
+                &mut self,
+                _: &Function<'input>,
+                record: &Record<'input>,
+            ) -> Result<'input, ()> {
+                self.1.push(record.patch_point_id());
+                Ok(())
+            }
+
+            fn function(&mut self, _: &Function<'input>) -> Result<'input, ()> {
+                self.0.push(core::mem::take(&mut self.1));
+                Ok(())
+            }
+        }
+        let mut ids = Ids(Vec::new(), Vec::new());
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        assert_eq!(section.summary_with(&mut ids).unwrap(), summary);
+        assert_eq!(ids.0, [vec![42, 43], vec![44]]);
+    }
+
+    #[test]
+    fn probe() {
+        assert!(LLVMStackMaps::new(test_data::TWO_FUNCTIONS).probe().is_ok());
+        assert!(LLVMStackMaps::new(&test_data::TWO_FUNCTIONS[..100])
+            .probe()
+            .is_err());
+        assert!(matches!(
+            LLVMStackMaps::new(&[2, 0, 0, 0]).probe(),
+            Err(Error::UnsupportedVersion)
+        ));
+        assert!(matches!(
+            LLVMStackMaps::new(&[3, 0, 1, 0]).probe(),
+            Err(Error::MalformedHeader)
+        ));
+        // One record fits in the 24 bytes after the header, but two do not
+        let mut data = vec![3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        data.extend_from_slice(&[0; 24]);
+        assert!(LLVMStackMaps::new(&data).probe().is_ok());
+        data[12] = 2;
+        assert!(LLVMStackMaps::new(&data).probe().is_err());
+    }
+
+    #[test]
+    fn parse_one_stack_map() {
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[0xff; 8]);
+
+        let (stack_map, size) = StackMap::parse(&data).unwrap();
+        assert_eq!(size, test_data::TWO_FUNCTIONS.len());
+        assert_eq!(stack_map.num_functions(), 2);
+        assert_eq!(stack_map.functions().count().unwrap(), 2);
+        assert!(StackMap::parse(&data[..size - 1]).is_err());
+    }
+
+    #[test]
+    fn lenient() {
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[0x2a; 12]);
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(test_data::TWO_FUNCTIONS);
+        data.extend_from_slice(&[0x03, 0, 0, 0, 1]);
+        let lenient = ParseOptions {
+            lenient: true,
+            ..ParseOptions::default()
+        };
+
+        let section = LLVMStackMaps::new(&data);
+        assert!(section.skipped_regions().is_err());
+
+        let section = LLVMStackMaps::with_options(&data, lenient);
+        let mut stack_maps_iter = section.stack_maps();
+        let mut num_stack_maps = 0;
+        while let Some(stack_map) = stack_maps_iter.next().unwrap() {
+            assert_eq!(stack_map.num_records(), 3);
+            num_stack_maps += 1;
+        }
+        assert_eq!(num_stack_maps, 2);
+
+        let skipped = stack_maps_iter.skipped();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].range(), 240..256);
+        assert!(matches!(skipped[0].reason(), Error::MalformedHeader));
+        assert_eq!(
+            skipped[0].hexdump(),
+            "000000f0  2a 2a 2a 2a 2a 2a 2a 2a 2a 2a 2a 2a 00 00 00 00\n"
+        );
+        assert_eq!(skipped[1].range(), 480..485);
+    }
+
+    #[test]
+    fn safepoints_in_address_order() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+
+        // Swap the functions, and record 42 with 43
+        owned.functions.swap(0, 1);
+        owned.functions[1].records.swap(0, 1);
+        let mut data = owned.to_bytes().unwrap();
+        // A second stack map sharing the address of record 44
+        owned.functions.truncate(1);
+        owned.functions[0].records[0].patch_point_id = 45;
+        data.extend(owned.to_bytes().unwrap());
+
+        let section = LLVMStackMaps::new(&data);
+        let safepoints: Vec<_> = section
+            .safepoints()
+            .map(|safepoint| {
+                Ok((
+                    safepoint.pc(),
+                    safepoint.stack_map_index(),
+                    safepoint.function_index(),
+                    safepoint.record().patch_point_id(),
+                ))
+            })
+            .collect()
+            .unwrap();
+        assert_eq!(
+            safepoints,
+            [
+                (0x1150, 0, 1, 42),
+                (0x115b, 0, 1, 43),
+                (0x1177, 0, 0, 44),
+                (0x1177, 1, 0, 45),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().nth(1).unwrap().unwrap();
+        let record = function.records().next().unwrap().unwrap();
+
+        assert_eq!(section.byte_range(record.raw_bytes()), Some(200 - 16..224));
+        assert_eq!(&record.raw_bytes()[..8], &0x2cu64.to_le_bytes());
+        assert_eq!(record.byte_range(), 200 - 16..224);
+
+        let location = record.locations().next().unwrap().unwrap();
+        assert_eq!(location.byte_range(), Some(200..212));
+        assert_eq!(location, Location::new(LocationKind::Register(0), 8));
+        assert_eq!(
+            Location::new(LocationKind::Register(0), 8).byte_range(),
+            None
+        );
+        assert_eq!(section.byte_range(&[0; 4]), None);
+
+        // Offsets are in the section, not in the stack map
+        let data = [test_data::TWO_FUNCTIONS, test_data::TWO_FUNCTIONS].concat();
+        let section = LLVMStackMaps::new(&data);
+        let stack_map = section.stack_maps().nth(1).unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let record = function.record_at_offset(0x20).unwrap().unwrap();
+        assert_eq!(record.byte_range(), 224 + 72..224 + 144);
+        assert_eq!(
+            section.byte_range(record.raw_bytes()),
+            Some(record.byte_range())
+        );
+        let location = record.locations().nth(3).unwrap().unwrap();
+        assert_eq!(location.byte_range(), Some(224 + 124..224 + 136));
+        assert_eq!(location.constant_index(), Some(0));
+    }
+
+    #[test]
+    fn unaligned_constants() {
+        // Shift the section by one byte, so that the constants are misaligned
+        let mut data = vec![0];
+        data.extend_from_slice(test_data::TWO_FUNCTIONS);
+        let section = LLVMStackMaps::new(&data[1..]);
+
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let record = function.records().next().unwrap().unwrap();
+        let locations: Vec<_> = record.locations().collect().unwrap();
+        assert_eq!(*locations[3].kind(), LocationKind::Constant(1234567890123));
     }
 
     #[test]
@@ -458,14 +2079,6 @@ mod tests {
         assert_eq!(record.patch_point_id(), 42);
         assert_eq!(record.instruction_offset(), 15);
 
-        let location = location.unwrap();
-        assert_eq!(
-            *location.kind(),
-            LocationKind::Direct {
-                register: 6,
-                offset: -10
-            }
-        );
-        assert_eq!(location.size(), 8);
+        assert_eq!(location.unwrap(), "direct(r6, -10, 8)".parse().unwrap());
     }
 }
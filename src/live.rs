@@ -0,0 +1,66 @@
+// Annotation of the backtrace of the running thread with the records of its
+// return addresses, e.g. to check in integration tests that a call was
+// compiled as a safepoint. The index has to describe the current process, such
+// as the one built by `ProcessModules::from_pid(std::process::id())`.
+
+use crate::index::{AddressSpaceIndex, ModuleRecord};
+
+#[derive(Debug, Clone)]
+pub struct AnnotatedFrame<'index, 'input> {
+    // Return address of the frame, except for the innermost one
+    pub ip: u64,
+    // Records of the call returning to `ip`, empty if it is not a safepoint
+    pub records: &'index [ModuleRecord<'input>],
+}
+
+/// Looks up each instruction pointer of a backtrace in `index`.
+pub fn annotate_frames<'index, 'input>(
+    index: &'index AddressSpaceIndex<'input>,
+    ips: impl IntoIterator<Item = u64>,
+) -> Vec<AnnotatedFrame<'index, 'input>> {
+    ips.into_iter()
+        .map(|ip| AnnotatedFrame {
+            ip,
+            records: index.records_at(ip),
+        })
+        .collect()
+}
+
+/// Captures the backtrace of the current thread, innermost frame first, and
+/// annotates every frame with its records. Symbols are not resolved, so this
+/// is cheap enough to call at every point of interest.
+pub fn annotate_current_backtrace<'index, 'input>(
+    index: &'index AddressSpaceIndex<'input>,
+) -> Vec<AnnotatedFrame<'index, 'input>> {
+    let mut ips = Vec::new();
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as u64);
+        true
+    });
+    annotate_frames(index, ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{index::Module, test_data, LLVMStackMaps};
+
+    #[test]
+    fn annotated_frames() {
+        let index = AddressSpaceIndex::new(vec![Module {
+            name: "a.out".to_owned(),
+            stack_maps: LLVMStackMaps::new(test_data::TWO_FUNCTIONS),
+            load_bias: 0x5555_5555_4000,
+        }])
+        .unwrap();
+
+        let frames = annotate_frames(&index, vec![0x5555_5555_5160, 0x5555_5555_515b]);
+        assert!(frames[0].records.is_empty());
+        assert_eq!(frames[1].records[0].record.patch_point_id(), 43);
+
+        // None of the frames of the test harness are safepoints
+        let frames = annotate_current_backtrace(&index);
+        assert!(!frames.is_empty());
+        assert!(frames.iter().all(|frame| frame.records.is_empty()));
+    }
+}
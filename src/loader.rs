@@ -0,0 +1,637 @@
+use std::{borrow::Cow, collections::BTreeMap, convert::TryInto};
+
+use object::{
+    elf,
+    read::elf::{FileHeader, ProgramHeader, SectionHeader},
+    Architecture, Bytes, Endianness, FileKind, Object, ObjectSection, ObjectSegment, ObjectSymbol,
+    RelocationKind, RelocationTarget, SectionKind, SymbolKind,
+};
+use snafu::{OptionExt, ResultExt, Snafu};
+
+pub use crate::symbols::{FunctionSymbol, FunctionSymbols};
+use crate::{arch::Arch, sancov::PcTableEntry};
+
+pub const STACK_MAPS_SECTION_NAME: &str = ".llvm_stackmaps";
+pub const SANCOV_PCS_SECTION_NAME: &str = "__sancov_pcs";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackMapsSource {
+    Section(String),
+    Note { name: String, note_type: u32 },
+}
+
+impl Default for StackMapsSource {
+    fn default() -> Self {
+        Self::Section(STACK_MAPS_SECTION_NAME.to_owned())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum LoadError {
+    #[snafu(display("Could not parse object: {}", source))]
+    ObjectError { source: object::Error },
+    #[snafu(display("Could not find {} section in object", name))]
+    SectionNotFound { name: String },
+    #[snafu(display("Could not find note {} with type {:#x} in object", name, note_type))]
+    NoteNotFound { name: String, note_type: u32 },
+    #[snafu(display("Notes are only supported in ELF objects"))]
+    NotElf,
+    #[snafu(display("Unsupported relocation {:?} at offset {:#x}", kind, offset))]
+    UnsupportedRelocation { kind: RelocationKind, offset: u64 },
+    #[snafu(display("Relocation at offset {:#x} out of bounds", offset))]
+    RelocationOutOfBounds { offset: u64 },
+    #[snafu(display("No segment is loaded from file offset {:#x}", offset))]
+    SegmentNotFound { offset: u64 },
+}
+
+type Result<T> = std::result::Result<T, LoadError>;
+
+/// Extracts the stack maps data from the object file contained in `file_data`.
+///
+/// Relocations applying to a stack maps section are resolved, so that function
+/// addresses are filled in for relocatable objects, such as kernel modules. The
+/// resolved addresses are relative to the section containing each function.
+pub fn load_stack_maps_data<'data>(
+    file_data: &'data [u8],
+    source: &StackMapsSource,
+) -> Result<Cow<'data, [u8]>> {
+    match source {
+        StackMapsSource::Section(name) => {
+            let object = object::File::parse(file_data).context(ObjectError)?;
+            let section = object
+                .section_by_name(name)
+                .context(SectionNotFound { name })?;
+            relocated_section_data(&object, &section)
+        }
+        StackMapsSource::Note { name, note_type } => {
+            let desc = match FileKind::parse(file_data).context(ObjectError)? {
+                FileKind::Elf32 => {
+                    find_note::<elf::FileHeader32<Endianness>>(file_data, name, *note_type)?
+                }
+                FileKind::Elf64 => {
+                    find_note::<elf::FileHeader64<Endianness>>(file_data, name, *note_type)?
+                }
+                _ => return NotElf.fail(),
+            };
+            let desc = desc.context(NoteNotFound {
+                name,
+                note_type: *note_type,
+            })?;
+            Ok(Cow::Borrowed(desc))
+        }
+    }
+}
+
+/// Returns the architecture of the object in `file_data`, or `None` if it is
+/// not one whose stack maps can be interpreted.
+pub fn load_arch(file_data: &[u8]) -> Result<Option<Arch>> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    Ok(match object.architecture() {
+        Architecture::X86_64 => Some(Arch::X86_64),
+        Architecture::Aarch64 => Some(Arch::AArch64),
+        _ => None,
+    })
+}
+
+/// Returns the size in bytes of the pointers of the object in `file_data`.
+pub fn load_pointer_size(file_data: &[u8]) -> Result<usize> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    Ok(pointer_size(&object))
+}
+
+fn pointer_size(object: &object::File) -> usize {
+    if object.is_64() {
+        8
+    } else {
+        4
+    }
+}
+
+// Reads a word of 4 or 8 bytes in the byte order of the object
+fn read_word(bytes: &[u8], little_endian: bool) -> u64 {
+    match (bytes.len(), little_endian) {
+        (4, true) => u32::from_le_bytes(bytes.try_into().unwrap()).into(),
+        (4, false) => u32::from_be_bytes(bytes.try_into().unwrap()).into(),
+        (_, true) => u64::from_le_bytes(bytes.try_into().unwrap()),
+        (_, false) => u64::from_be_bytes(bytes.try_into().unwrap()),
+    }
+}
+
+// Writes `value` as a word of 4 or 8 bytes, truncating it to 4
+fn write_word(bytes: &mut [u8], value: u64, little_endian: bool) {
+    match (bytes.len(), little_endian) {
+        (4, true) => bytes.copy_from_slice(&(value as u32).to_le_bytes()),
+        (4, false) => bytes.copy_from_slice(&(value as u32).to_be_bytes()),
+        (_, true) => bytes.copy_from_slice(&value.to_le_bytes()),
+        (_, false) => bytes.copy_from_slice(&value.to_be_bytes()),
+    }
+}
+
+// In relocatable objects, function addresses are offsets into their section,
+// so a function at address 0 is expected there.
+pub fn is_relocatable(file_data: &[u8]) -> Result<bool> {
+    let file_type = match FileKind::parse(file_data).context(ObjectError)? {
+        FileKind::Elf32 => elf_file_type::<elf::FileHeader32<Endianness>>(file_data)?,
+        FileKind::Elf64 => elf_file_type::<elf::FileHeader64<Endianness>>(file_data)?,
+        _ => return Ok(false),
+    };
+
+    Ok(file_type == elf::ET_REL)
+}
+
+fn elf_file_type<Elf: FileHeader<Endian = Endianness>>(file_data: &[u8]) -> Result<u16> {
+    let header = Elf::parse(Bytes(file_data)).context(ObjectError)?;
+    let endian = header.endian().context(ObjectError)?;
+    Ok(header.e_type(endian))
+}
+
+/// Collects the function symbols of the object file contained in `file_data`,
+/// keyed by address. For relocatable objects, addresses are relative to the
+/// section containing each function, matching the resolved stack maps data.
+pub fn load_function_symbols(file_data: &[u8]) -> Result<FunctionSymbols> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+
+    let mut symbols = BTreeMap::new();
+    for symbol in object.symbols() {
+        if symbol.kind() != SymbolKind::Text || !symbol.is_definition() {
+            continue;
+        }
+
+        if let Ok(name) = symbol.name() {
+            symbols
+                .entry(symbol.address())
+                .or_insert_with(|| FunctionSymbol {
+                    name: name.to_owned(),
+                    size: symbol.size(),
+                });
+        }
+    }
+
+    Ok(symbols.into_iter().collect())
+}
+
+/// Reads the PC table emitted by `-fsanitize-coverage=pc-table`, returning the
+/// address and flags of every instrumented basic block. The relocations of the
+/// object are applied, including the relative ones that the dynamic loader
+/// applies to the table of a position-independent executable. Entries left at
+/// 0, e.g. by packed relative relocations, are skipped.
+pub fn load_sancov_pc_table(file_data: &[u8]) -> Result<Vec<PcTableEntry>> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    let section = object
+        .section_by_name(SANCOV_PCS_SECTION_NAME)
+        .context(SectionNotFound {
+            name: SANCOV_PCS_SECTION_NAME,
+        })?;
+    let data = relocated_section_data(&object, &section)?;
+
+    // Each entry is a PC followed by flags, both pointer-sized
+    let pointer_size = pointer_size(&object);
+    let little_endian = object.is_little_endian();
+    let entries = data
+        .chunks_exact(2 * pointer_size)
+        .map(|entry| PcTableEntry {
+            pc: read_word(&entry[..pointer_size], little_endian),
+            flags: read_word(&entry[pointer_size..], little_endian),
+        })
+        .filter(|entry| entry.pc != 0)
+        .collect();
+    Ok(entries)
+}
+
+/// Computes the load bias of the object in `file_data`, given that the page at
+/// `file_offset` in the file is mapped at `address`, e.g. as listed in
+/// `/proc/<pid>/maps`.
+pub fn load_bias(file_data: &[u8], file_offset: u64, address: u64) -> Result<u64> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    let segment = object
+        .segments()
+        .find(|segment| {
+            let (start, size) = segment.file_range();
+            start <= file_offset && file_offset < start + size
+        })
+        .context(SegmentNotFound {
+            offset: file_offset,
+        })?;
+
+    let (segment_offset, _) = segment.file_range();
+    let linked_address = segment.address().wrapping_add(file_offset - segment_offset);
+    Ok(address.wrapping_sub(linked_address))
+}
+
+// Loaded ranges of the file, keyed by their linked address, with their size
+// in the file and their file offset
+#[derive(Debug, Clone, Default)]
+pub struct FileOffsets {
+    ranges: BTreeMap<u64, (u64, u64)>,
+}
+
+impl FileOffsets {
+    /// Returns the offset in the file of the byte linked at `address`, if it
+    /// is loaded from the file.
+    pub fn file_offset(&self, address: u64) -> Option<u64> {
+        let (&start, &(size, offset)) = self.ranges.range(..=address).next_back()?;
+        if address - start < size {
+            Some(offset + (address - start))
+        } else {
+            None
+        }
+    }
+}
+
+/// Maps the linked addresses of the object in `file_data` to offsets in the
+/// file, through its segments, or through its sections for objects without
+/// segments. Addresses in relocatable objects are relative to their section,
+/// so none of them is mapped.
+pub fn load_file_offsets(file_data: &[u8]) -> Result<FileOffsets> {
+    let mut file_offsets = FileOffsets::default();
+    if is_relocatable(file_data)? {
+        return Ok(file_offsets);
+    }
+
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    for segment in object.segments() {
+        let (offset, size) = segment.file_range();
+        if size > 0 {
+            file_offsets
+                .ranges
+                .insert(segment.address(), (size, offset));
+        }
+    }
+    if file_offsets.ranges.is_empty() {
+        for section in object.sections() {
+            if let Some((offset, size)) = section.file_range() {
+                if section.address() != 0 && size > 0 {
+                    file_offsets
+                        .ranges
+                        .insert(section.address(), (size, offset));
+                }
+            }
+        }
+    }
+
+    Ok(file_offsets)
+}
+
+/// Returns the linked address ranges of the code sections of the object in
+/// `file_data`, keyed by start address, with their end address.
+pub fn load_code_sections(file_data: &[u8]) -> Result<BTreeMap<u64, u64>> {
+    let object = object::File::parse(file_data).context(ObjectError)?;
+    Ok(object
+        .sections()
+        .filter(|section| section.kind() == SectionKind::Text && section.size() > 0)
+        .map(|section| {
+            (
+                section.address(),
+                section.address().saturating_add(section.size()),
+            )
+        })
+        .collect())
+}
+
+// Type of the dynamic relocations that add the load bias to their addend
+fn relative_relocation_type(architecture: Architecture) -> Option<u32> {
+    match architecture {
+        Architecture::Aarch64 => Some(elf::R_AARCH64_RELATIVE),
+        Architecture::Arm => Some(elf::R_ARM_RELATIVE),
+        Architecture::I386 => Some(elf::R_386_RELATIVE),
+        Architecture::X86_64 => Some(elf::R_X86_64_RELATIVE),
+        _ => None,
+    }
+}
+
+// Applies the relocations of `section`, and the relative dynamic relocations
+// within it, which are resolved with a load bias of 0
+fn relocated_section_data<'data>(
+    object: &object::File<'data>,
+    section: &object::Section<'data, '_>,
+) -> Result<Cow<'data, [u8]>> {
+    let data = section.data().context(ObjectError)?;
+    let relative_type = relative_relocation_type(object.architecture());
+    let section_start = section.address();
+    let section_end = section_start.saturating_add(section.size());
+    let dynamic_relocations = object
+        .dynamic_relocations()
+        .into_iter()
+        .flatten()
+        .filter(|(address, relocation)| {
+            relative_type.is_some_and(|r_type| relocation.kind() == RelocationKind::Elf(r_type))
+                && section_start <= *address
+                && *address < section_end
+        })
+        .map(|(address, relocation)| (address - section_start, relocation, true));
+    let mut relocations = section
+        .relocations()
+        .map(|(offset, relocation)| (offset, relocation, false))
+        .chain(dynamic_relocations)
+        .peekable();
+    if relocations.peek().is_none() {
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let little_endian = object.is_little_endian();
+    let mut data = data.to_vec();
+    for (offset, relocation, relative) in relocations {
+        let size = match relocation.kind() {
+            _ if relative => pointer_size(object),
+            RelocationKind::Absolute if matches!(relocation.size(), 32 | 64) => {
+                relocation.size() as usize / 8
+            }
+            kind => return UnsupportedRelocation { kind, offset }.fail(),
+        };
+
+        let target_address = match relocation.target() {
+            _ if relative => 0,
+            RelocationTarget::Symbol(index) => object
+                .symbol_by_index(index)
+                .context(ObjectError)?
+                .address(),
+            RelocationTarget::Section(index) => object
+                .section_by_index(index)
+                .context(ObjectError)?
+                .address(),
+            RelocationTarget::Absolute => 0,
+        };
+        let place = data
+            .get_mut(offset as usize..(offset as usize).saturating_add(size))
+            .context(RelocationOutOfBounds { offset })?;
+        let addend = if relocation.has_implicit_addend() {
+            read_word(place, little_endian) as i64
+        } else {
+            relocation.addend()
+        };
+        let value = target_address.wrapping_add(addend as u64);
+        write_word(place, value, little_endian);
+    }
+
+    Ok(Cow::Owned(data))
+}
+
+// Notes are looked up in PT_NOTE segments first and then in SHT_NOTE sections,
+// since relocatable objects have no program headers.
+fn find_note<'data, Elf: FileHeader<Endian = Endianness>>(
+    file_data: &'data [u8],
+    name: &str,
+    note_type: u32,
+) -> Result<Option<&'data [u8]>> {
+    let data = Bytes(file_data);
+    let header = Elf::parse(data).context(ObjectError)?;
+    let endian = header.endian().context(ObjectError)?;
+
+    for segment in header.program_headers(endian, data).context(ObjectError)? {
+        if let Some(notes) = segment.notes(endian, data).context(ObjectError)? {
+            if let Some(desc) = find_in_notes(notes, endian, name, note_type)? {
+                return Ok(Some(desc));
+            }
+        }
+    }
+
+    for section in header.sections(endian, data).context(ObjectError)?.iter() {
+        if let Some(notes) = section.notes(endian, data).context(ObjectError)? {
+            if let Some(desc) = find_in_notes(notes, endian, name, note_type)? {
+                return Ok(Some(desc));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn find_in_notes<'data, Elf: FileHeader<Endian = Endianness>>(
+    mut notes: object::read::elf::NoteIterator<'data, Elf>,
+    endian: Endianness,
+    name: &str,
+    note_type: u32,
+) -> Result<Option<&'data [u8]>> {
+    while let Some(note) = notes.next().context(ObjectError)? {
+        if note.name() == name.as_bytes() && note.n_type(endian) == note_type {
+            return Ok(Some(note.desc()));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestSection {
+        name: &'static str,
+        sh_type: u32,
+        address: u64,
+        link: u32,
+        entsize: u64,
+        data: Vec<u8>,
+    }
+
+    // Words of 4 or 8 bytes in either byte order
+    struct Encoder {
+        is_64: bool,
+        little_endian: bool,
+    }
+
+    impl Encoder {
+        fn int(&self, out: &mut Vec<u8>, value: u64, size: usize) {
+            let mut bytes = vec![0; size];
+            match (size, self.little_endian) {
+                (2, true) => bytes.copy_from_slice(&(value as u16).to_le_bytes()),
+                (2, false) => bytes.copy_from_slice(&(value as u16).to_be_bytes()),
+                _ => write_word(&mut bytes, value, self.little_endian),
+            }
+            out.extend_from_slice(&bytes);
+        }
+
+        fn word(&self, out: &mut Vec<u8>, value: u64) {
+            self.int(out, value, if self.is_64 { 8 } else { 4 });
+        }
+
+        // An object with a null section and the section names, followed by
+        // `sections`
+        fn object(&self, e_type: u16, machine: u16, sections: &[TestSection]) -> Vec<u8> {
+            let mut names = vec![0];
+            let mut name_offsets = Vec::new();
+            for name in [".shstrtab"]
+                .iter()
+                .chain(sections.iter().map(|section| &section.name))
+            {
+                name_offsets.push(names.len() as u64);
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+
+            let (header_size, section_header_size) = if self.is_64 { (64, 64) } else { (52, 40) };
+            let mut file = vec![0; header_size];
+            let mut offsets = vec![file.len() as u64];
+            file.extend_from_slice(&names);
+            for section in sections {
+                while file.len() % 8 != 0 {
+                    file.push(0);
+                }
+                offsets.push(file.len() as u64);
+                file.extend_from_slice(&section.data);
+            }
+            while file.len() % 8 != 0 {
+                file.push(0);
+            }
+            let headers_offset = file.len() as u64;
+
+            let mut headers = vec![0; section_header_size];
+            let mut section_header = |name: u64,
+                                      sh_type: u32,
+                                      address: u64,
+                                      offset: u64,
+                                      size: u64,
+                                      link: u32,
+                                      entsize: u64| {
+                self.int(&mut headers, name, 4);
+                self.int(&mut headers, sh_type.into(), 4);
+                self.word(&mut headers, elf::SHF_ALLOC.into());
+                self.word(&mut headers, address);
+                self.word(&mut headers, offset);
+                self.word(&mut headers, size);
+                self.int(&mut headers, link.into(), 4);
+                self.int(&mut headers, 0, 4);
+                self.word(&mut headers, 1);
+                self.word(&mut headers, entsize);
+            };
+            section_header(
+                name_offsets[0],
+                elf::SHT_STRTAB,
+                0,
+                offsets[0],
+                names.len() as u64,
+                0,
+                0,
+            );
+            for (index, section) in sections.iter().enumerate() {
+                section_header(
+                    name_offsets[index + 1],
+                    section.sh_type,
+                    section.address,
+                    offsets[index + 1],
+                    section.data.len() as u64,
+                    section.link,
+                    section.entsize,
+                );
+            }
+            file.extend_from_slice(&headers);
+
+            let mut header = b"\x7fELF".to_vec();
+            header.push(if self.is_64 {
+                elf::ELFCLASS64
+            } else {
+                elf::ELFCLASS32
+            });
+            header.push(if self.little_endian {
+                elf::ELFDATA2LSB
+            } else {
+                elf::ELFDATA2MSB
+            });
+            header.push(elf::EV_CURRENT);
+            header.resize(16, 0);
+            self.int(&mut header, e_type.into(), 2);
+            self.int(&mut header, machine.into(), 2);
+            self.int(&mut header, elf::EV_CURRENT.into(), 4);
+            self.word(&mut header, 0);
+            self.word(&mut header, 0);
+            self.word(&mut header, headers_offset);
+            self.int(&mut header, 0, 4);
+            self.int(&mut header, header_size as u64, 2);
+            self.int(&mut header, 0, 2);
+            self.int(&mut header, 0, 2);
+            self.int(&mut header, section_header_size as u64, 2);
+            self.int(&mut header, sections.len() as u64 + 2, 2);
+            self.int(&mut header, 1, 2);
+            file[..header_size].copy_from_slice(&header);
+            file
+        }
+
+        fn pc_table(&self, entries: &[(u64, u64)]) -> TestSection {
+            let mut data = Vec::new();
+            for &(pc, flags) in entries {
+                self.word(&mut data, pc);
+                self.word(&mut data, flags);
+            }
+            TestSection {
+                name: SANCOV_PCS_SECTION_NAME,
+                sh_type: elf::SHT_PROGBITS,
+                address: 0x2000,
+                link: 0,
+                entsize: 0,
+                data,
+            }
+        }
+    }
+
+    fn entries(pc_table: &[PcTableEntry]) -> Vec<(u64, u64)> {
+        pc_table
+            .iter()
+            .map(|entry| (entry.pc, entry.flags))
+            .collect()
+    }
+
+    #[test]
+    fn pc_table_byte_orders() {
+        let encoder = Encoder {
+            is_64: false,
+            little_endian: true,
+        };
+        let table = encoder.pc_table(&[(0x1130, 1), (0x1150, 0)]);
+        let file = encoder.object(elf::ET_EXEC, elf::EM_386, &[table]);
+        assert_eq!(load_pointer_size(&file).unwrap(), 4);
+        assert_eq!(
+            entries(&load_sancov_pc_table(&file).unwrap()),
+            [(0x1130, 1), (0x1150, 0)]
+        );
+
+        let encoder = Encoder {
+            is_64: true,
+            little_endian: false,
+        };
+        let table = encoder.pc_table(&[(0x1130, 1), (0, 0)]);
+        let file = encoder.object(elf::ET_EXEC, elf::EM_S390, &[table]);
+        assert_eq!(
+            entries(&load_sancov_pc_table(&file).unwrap()),
+            [(0x1130, 1)]
+        );
+    }
+
+    #[test]
+    fn pc_table_of_pie() {
+        // The PCs are only filled in by relative dynamic relocations
+        let encoder = Encoder {
+            is_64: true,
+            little_endian: true,
+        };
+        let mut relocations = Vec::new();
+        for &(offset, addend) in &[(0x2000, 0x1130), (0x2010, 0x1170)] {
+            encoder.word(&mut relocations, offset);
+            encoder.word(&mut relocations, elf::R_X86_64_RELATIVE.into());
+            encoder.word(&mut relocations, addend);
+        }
+        let sections = [
+            encoder.pc_table(&[(0, 1), (0, 0)]),
+            TestSection {
+                name: ".dynsym",
+                sh_type: elf::SHT_DYNSYM,
+                address: 0,
+                link: 1,
+                entsize: 24,
+                data: vec![0; 24],
+            },
+            TestSection {
+                name: ".rela.dyn",
+                sh_type: elf::SHT_RELA,
+                address: 0,
+                link: 3,
+                entsize: 24,
+                data: relocations,
+            },
+        ];
+        let file = encoder.object(elf::ET_DYN, elf::EM_X86_64, &sections);
+        assert_eq!(
+            entries(&load_sancov_pc_table(&file).unwrap()),
+            [(0x1130, 1), (0x1170, 0)]
+        );
+    }
+}
@@ -1,125 +1,2643 @@
 use anyhow::Context;
+use clap::{Args, CommandFactory, Parser};
+use clap_complete::Shell;
 use fallible_iterator::FallibleIterator;
 use memmap2::Mmap;
-use object::{Object, ObjectSection};
-use stackmap::{Function, LLVMStackMaps, Location, Record, StackMap};
+#[cfg(feature = "cfi")]
+use stackmap::cfi::{self, CfiFormat};
+#[cfg(feature = "debuginfod")]
+use stackmap::debuginfod::DebuginfodClient;
+use stackmap::{
+    addresses::{AddressMode, AddressReporting},
+    anonymize::{self, AnonymizeOptions, Redaction},
+    classify::{self, RegisterConventions, SlotKind},
+    cost::{self, RecordCost},
+    coverage::{self, GapKind},
+    density::{self, GapThreshold},
+    diagnostics::{DiagnosticsSink, Warning, WarningCategory},
+    diff::{
+        self, DiffOptions, DiffReport, FunctionDiff, FunctionMatching, LocationDiff, RecordDiff,
+    },
+    frame::{self, FrameUsage},
+    generate::{self, Distribution, GeneratorOptions},
+    ids::{IdField, IdSchema},
+    inject,
+    loader::{self, FileOffsets, FunctionSymbols, StackMapsSource},
+    map,
+    report::Report,
+    roundtrip,
+    samples::{self, SampleCounts},
+    sancov::{self, Reach},
+    syntax::{HexLocation, LocationFilter},
+    validate, Constant, Function, LLVMStackMaps, Location, ParseOptions, Record, StackMap, Summary,
+    SummaryVisitor,
+};
+#[cfg(feature = "json")]
+use stackmap::{
+    baseline::{Baseline, BaselineViolation, GrowthLimits},
+    budget::{BudgetConfig, BudgetViolation},
+    corpus,
+};
 use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
     fs,
+    io::{self, Write},
+    num::ParseIntError,
+    ops::Range,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
 };
-use structopt::StructOpt;
 
-const STACK_MAPS_SECTION_NAME: &str = ".llvm_stackmaps";
-#[derive(Debug, StructOpt)]
-#[structopt(about = "A cmdline parser for LLVM StackMaps.")]
-struct Opt {
-    #[structopt(help = "Path to the ELF object to parse")]
-    binary_path: PathBuf,
+fn parse_u32(src: &str) -> Result<u32, ParseIntError> {
+    match src.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => src.parse(),
+    }
+}
+
+fn parse_address(src: &str) -> Result<u64, ParseIntError> {
+    match src.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => src.parse(),
+    }
+}
+
+#[derive(Debug, Args)]
+struct InputOpt {
+    #[arg(
+        required = true,
+        num_args = 1..,
+        value_name = "BINARY_PATH",
+        help = "Paths to the ELF objects to parse (vmlinux and kernel modules included)"
+    )]
+    binary_paths: Vec<PathBuf>,
+    #[arg(
+        short = 'j',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Number of binaries to process in parallel"
+    )]
+    jobs: u32,
+    #[arg(
+        long,
+        requires = "note_type",
+        help = "Read the stack maps from the ELF note with this name instead of the section"
+    )]
+    note_name: Option<String>,
+    #[arg(
+        long,
+        requires = "note_name",
+        value_parser = parse_u32,
+        help = "Type of the ELF note containing the stack maps"
+    )]
+    note_type: Option<u32>,
+    #[arg(
+        short = 'W',
+        num_args = 1,
+        value_name = "CATEGORY",
+        help = "Treat warnings of this category as errors (zero-address, duplicate-address, overlapping-functions, section-padding, skipped-bytes)"
+    )]
+    deny: Vec<WarningCategory>,
+    #[arg(
+        short = 'A',
+        num_args = 1,
+        value_name = "CATEGORY",
+        help = "Do not report warnings of this category"
+    )]
+    allow: Vec<WarningCategory>,
+    #[cfg(feature = "debuginfod")]
+    #[arg(
+        long,
+        help = "Fetch the symbols of stripped binaries from the servers in DEBUGINFOD_URLS"
+    )]
+    debuginfod: bool,
+    #[arg(
+        long,
+        conflicts_with = "dec",
+        help = "Print all numbers in hexadecimal, including sizes"
+    )]
+    hex: bool,
+    #[arg(
+        long,
+        help = "Print all numbers in decimal, including addresses and IDs"
+    )]
+    dec: bool,
+    #[arg(
+        long,
+        default_value = "text",
+        value_name = "FORMAT",
+        help = "Print errors and warnings as text, or as one JSON object per line with their code, offset and context"
+    )]
+    error_format: ErrorFormat,
+    #[arg(
+        long,
+        visible_alias = "kaslr-offset",
+        default_value = "0",
+        value_parser = parse_address,
+        help = "Load bias of the binary, e.g. of a PIE or the KASLR slide of a running kernel: added to reported addresses and subtracted from sampled and executed PCs"
+    )]
+    load_bias: u64,
+    #[arg(
+        long,
+        default_value = "absolute",
+        value_name = "MODE",
+        help = "Report functions and records at their absolute address (with the load bias), their section offset, or their function offset"
+    )]
+    address_mode: AddressMode,
+    #[arg(
+        long = "id-field",
+        value_name = "NAME=MASK",
+        help = "Decode this bit-field of the patchpoint IDs, e.g. pass=0xff00000000000000; records are grouped by the first field"
+    )]
+    id_fields: Vec<IdField>,
+    #[cfg(feature = "json")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "id_fields",
+        help = "Read the patchpoint ID fields from a JSON file of the form {\"fields\": [{\"name\": \"pass\", \"mask\": 255}]}"
+    )]
+    id_schema: Option<PathBuf>,
+}
+
+impl InputOpt {
+    fn number_format(&self) -> NumberFormat {
+        match (self.hex, self.dec) {
+            (true, _) => NumberFormat::Hex,
+            (_, true) => NumberFormat::Dec,
+            _ => NumberFormat::Mixed,
+        }
+    }
+
+    fn address_reporting(&self, file_data: &[u8]) -> anyhow::Result<AddressReporting> {
+        // Code sections are also where constants that are code addresses point
+        let mut reporting = AddressReporting::new(self.address_mode);
+        reporting.bias = self.load_bias;
+        reporting.sections =
+            loader::load_code_sections(file_data).context("Could not read object sections")?;
+        Ok(reporting)
+    }
+
+    fn id_schema(&self) -> anyhow::Result<IdSchema> {
+        #[cfg(feature = "json")]
+        if let Some(path) = &self.id_schema {
+            let text = fs::read_to_string(path).context("Could not read ID schema")?;
+            return serde_json::from_str(&text).context("Could not parse ID schema");
+        }
+        Ok(IdSchema {
+            fields: self.id_fields.clone(),
+        })
+    }
+
+    fn warning_policy(&self, binary_path: &Path, err: Box<dyn Write>) -> WarningPolicy {
+        WarningPolicy {
+            deny: self.deny.clone(),
+            allow: self.allow.clone(),
+            stack_map_idx: None,
+            num_errors: 0,
+            err,
+            error_format: self.error_format,
+            binary_path: binary_path.to_owned(),
+            failure_offset: None,
+            addresses: AddressReporting::default(),
+        }
+    }
+
+    fn source(&self) -> StackMapsSource {
+        match (&self.note_name, self.note_type) {
+            (Some(name), Some(note_type)) => StackMapsSource::Note {
+                name: name.clone(),
+                note_type,
+            },
+            _ => StackMapsSource::default(),
+        }
+    }
+}
+
+// Diagnostics are either human-readable lines, or one JSON object per line
+// with a stable `code`, for build automation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!(
+                "Unknown error format: {} (expected text or json)",
+                name
+            )),
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
 }
 
-impl Opt {
-    fn binary_path(&self) -> &Path {
-        &self.binary_path
+// By default, addresses, offsets and IDs are printed in hexadecimal, and
+// sizes and counts in decimal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberFormat {
+    Mixed,
+    Hex,
+    Dec,
+}
+
+impl NumberFormat {
+    // Addresses, instruction offsets and IDs
+    fn address(self, value: u64) -> String {
+        match self {
+            NumberFormat::Dec => value.to_string(),
+            _ => format!("{:#x}", value),
+        }
+    }
+
+    fn size(self, value: u64) -> String {
+        match self {
+            NumberFormat::Hex => format!("{:#x}", value),
+            _ => value.to_string(),
+        }
+    }
+
+    fn count(self, value: usize) -> String {
+        self.size(value as u64)
+    }
+
+    fn ids(self, ids: &[u64]) -> String {
+        let ids: Vec<String> = ids.iter().map(|&id| self.address(id)).collect();
+        ids.join(", ")
     }
 }
 
-fn print_location(location: &Location) {
-    match location.kind() {
-        stackmap::LocationKind::Register(register) => {
-            print!("Register R#{}, ", register);
+#[derive(Debug, Parser)]
+#[command(
+    name = "stackmap-parser",
+    version,
+    about = "A cmdline parser for LLVM StackMaps.",
+    after_help = "Without a command, the arguments are those of dump, e.g. `stackmap-parser BINARY`."
+)]
+enum Command {
+    #[command(about = "Print the contents of the stack maps")]
+    Dump {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            help = "Only print the function table, without parsing any record"
+        )]
+        functions_only: bool,
+        #[arg(
+            long,
+            help = "Print the offset in the binary of each function and record, besides its address"
+        )]
+        file_offsets: bool,
+        #[arg(
+            long = "where",
+            value_name = "FILTER",
+            conflicts_with = "functions_only",
+            help = "Only print the records with a location matching all of these filters: kind=indirect, register=r6, size=8 or location='direct(r6, -32, 8)'"
+        )]
+        filters: Vec<LocationFilter>,
+        #[arg(
+            long,
+            help = "Fail if anything other than zero bytes follows the last stack map"
+        )]
+        strict_eof: bool,
+        #[arg(
+            long,
+            help = "Skip malformed regions of the section instead of failing, and report them"
+        )]
+        lenient: bool,
+    },
+    #[command(about = "Print the number of stack maps, functions, records and their contents")]
+    Summary {
+        #[command(flatten)]
+        input: InputOpt,
+    },
+    #[cfg(feature = "json")]
+    #[command(about = "Export the stack maps as JSON")]
+    Json {
+        #[command(flatten)]
+        input: Option<InputOpt>,
+        #[arg(
+            long,
+            conflicts_with = "binary_paths",
+            help = "Print the JSON Schema of the export instead, without reading any binary"
+        )]
+        emit_schema: bool,
+    },
+    #[cfg(feature = "json")]
+    #[command(
+        about = "Check stack sizes and patchpoints against a baseline from a previous build"
+    )]
+    Check {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            required_unless_present = "budget",
+            help = "JSON baseline to compare against"
+        )]
+        baseline: Option<PathBuf>,
+        #[arg(
+            long,
+            requires = "baseline",
+            conflicts_with = "budget",
+            help = "Write the baseline of this binary instead of checking it"
+        )]
+        update: bool,
+        #[arg(
+            long,
+            help = "JSON budget with absolute limits on stack sizes, locations and required IDs"
+        )]
+        budget: Option<PathBuf>,
+        #[arg(long, help = "Allowed stack size growth per function, in bytes")]
+        max_growth: Option<u64>,
+        #[arg(
+            long,
+            help = "Allowed stack size growth per function, in percent of the baseline"
+        )]
+        max_growth_percent: Option<f64>,
+    },
+    #[command(about = "Compare the stack maps with those of another build of the binary")]
+    Diff {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(long, help = "Build of the binary to compare against")]
+        old: PathBuf,
+        #[arg(
+            long,
+            help = "Match functions by symbol instead of address, e.g. across unrelated builds"
+        )]
+        by_symbol: bool,
+    },
+    #[command(about = "Report the distances between consecutive safepoints of each function")]
+    Density {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            default_value = "256",
+            help = "Flag gaps between safepoints larger than this many bytes"
+        )]
+        max_gap: u64,
+        #[arg(
+            long,
+            requires = "objdump",
+            help = "Flag gaps between safepoints of more than this many instructions instead"
+        )]
+        max_gap_instructions: Option<usize>,
+        #[arg(
+            long,
+            help = "Count the instructions of the gaps from the output of `objdump -d` for the binary"
+        )]
+        objdump: Option<PathBuf>,
+    },
+    #[cfg(feature = "cfi")]
+    #[command(
+        about = "Write an object file with CFI describing the frames at the records (experimental)"
+    )]
+    Cfi {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(short, long, help = "Path of the ELF object to write")]
+        output: PathBuf,
+        #[arg(long, help = "Write a .debug_frame section instead of .eh_frame")]
+        debug_frame: bool,
+    },
+    #[command(about = "Write a report of the functions and records for sharing")]
+    Report {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            required_unless_present = "markdown",
+            help = "Write a self-contained HTML report to this file"
+        )]
+        html: Option<PathBuf>,
+        #[arg(long, help = "Print a summary as Markdown tables")]
+        markdown: bool,
+    },
+    #[command(
+        about = "Write the stack maps with their function addresses and constants redacted, e.g. for bug reports"
+    )]
+    Anonymize {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(short, long, help = "File to write the raw section to")]
+        output: PathBuf,
+        #[arg(
+            long,
+            default_value = "strip",
+            help = "What to do with function addresses: keep, strip or randomize"
+        )]
+        addresses: Redaction,
+        #[arg(
+            long,
+            default_value = "strip",
+            help = "What to do with constants: keep, strip or randomize"
+        )]
+        constants: Redaction,
+        #[arg(long, default_value = "0", help = "Seed of the randomized values")]
+        seed: u64,
+    },
+    #[command(
+        about = "Print a linker-map-like list of the safepoints in address order: address, size, symbol and patchpoint ID"
+    )]
+    Map {
+        #[command(flatten)]
+        input: InputOpt,
+    },
+    #[command(about = "Count profiler samples at and right after the instrumented instructions")]
+    Samples {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            required_unless_present = "counts",
+            conflicts_with = "counts",
+            help = "Read the samples from the output of `perf script`"
+        )]
+        perf_script: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Read the samples from lines of a hexadecimal address and an optional count"
+        )]
+        counts: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value = "1",
+            help = "Size in bytes of the patchpoint shadow starting at each instrumented instruction"
+        )]
+        shadow_size: u64,
+    },
+    #[command(about = "Report which safepoints were reached according to SanitizerCoverage")]
+    Reach {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            required = true,
+            num_args = 1,
+            help = "File of executed PCs, either a .sancov file or hexadecimal PCs one per line"
+        )]
+        executed: Vec<PathBuf>,
+    },
+    #[command(about = "Parse the whole section, check it for suspicious data and report coverage")]
+    Verify {
+        #[command(flatten)]
+        input: InputOpt,
+        #[arg(
+            long,
+            help = "Also check that serializing the parsed stack maps reproduces the section byte for byte"
+        )]
+        roundtrip: bool,
+    },
+    #[command(about = "Write a synthetic stack maps section, e.g. to benchmark parsers")]
+    Generate {
+        #[arg(short, long, help = "File to write the raw section to")]
+        output: PathBuf,
+        #[arg(long, default_value = "0")]
+        seed: u64,
+        #[arg(long, default_value = "1")]
+        stack_maps: usize,
+        #[arg(
+            long,
+            default_value = "100",
+            help = "Functions per stack map, either a count or an inclusive range such as 10..=100"
+        )]
+        functions: Distribution,
+        #[arg(long, default_value = "0..=8", help = "Records per function")]
+        records: Distribution,
+        #[arg(long, default_value = "0..=16", help = "Locations per record")]
+        locations: Distribution,
+        #[arg(long, default_value = "0..=2", help = "Live-outs per record")]
+        live_outs: Distribution,
+    },
+    #[command(about = "Write stack maps into an ELF object, creating or replacing their section")]
+    Inject {
+        #[arg(help = "Path to the ELF object to inject the stack maps into")]
+        binary_path: PathBuf,
+        #[arg(long, help = "File containing the raw stack maps section")]
+        stack_maps: PathBuf,
+        #[arg(short, long, help = "Path of the ELF object to write")]
+        output: PathBuf,
+        #[arg(long, default_value = loader::STACK_MAPS_SECTION_NAME)]
+        section_name: String,
+        #[arg(long, help = "Inject the stack maps even if they cannot be parsed")]
+        force: bool,
+    },
+    #[cfg(feature = "json")]
+    #[command(about = "Check a corpus of raw sections against their expected JSON outputs")]
+    TestCorpus {
+        #[arg(help = "Directory of raw sections, each next to its expected output in NAME.json")]
+        corpus: PathBuf,
+        #[arg(
+            long,
+            help = "Write the current outputs as the expected ones instead of checking"
+        )]
+        bless: bool,
+    },
+    #[command(about = "Print a shell completion script")]
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    #[command(about = "Print a man page in roff format")]
+    Man,
+}
+
+impl Command {
+    fn input(&self) -> Option<&InputOpt> {
+        match self {
+            Command::Dump { input, .. }
+            | Command::Summary { input }
+            | Command::Diff { input, .. }
+            | Command::Density { input, .. }
+            | Command::Report { input, .. }
+            | Command::Anonymize { input, .. }
+            | Command::Map { input, .. }
+            | Command::Samples { input, .. }
+            | Command::Reach { input, .. }
+            | Command::Verify { input, .. } => Some(input),
+            #[cfg(feature = "json")]
+            Command::Json { input, .. } => input.as_ref(),
+            #[cfg(feature = "json")]
+            Command::Check { input, .. } => Some(input),
+            #[cfg(feature = "cfi")]
+            Command::Cfi { input, .. } => Some(input),
+            #[cfg(feature = "json")]
+            Command::TestCorpus { .. } => None,
+            Command::Generate { .. }
+            | Command::Inject { .. }
+            | Command::Completions { .. }
+            | Command::Man => None,
+        }
+    }
+
+    // Option naming a file that belongs to a single binary, which cannot be
+    // used when several binaries are given
+    fn per_binary_file(&self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "json")]
+            Command::Check {
+                baseline: Some(_), ..
+            } => Some("--baseline"),
+            #[cfg(feature = "cfi")]
+            Command::Cfi { .. } => Some("--output"),
+            Command::Report { html: Some(_), .. } => Some("--html"),
+            Command::Anonymize { .. } => Some("--output"),
+            Command::Diff { .. } => Some("--old"),
+            Command::Density {
+                objdump: Some(_), ..
+            } => Some("--objdump"),
+            Command::Samples {
+                perf_script: Some(_),
+                ..
+            } => Some("--perf-script"),
+            Command::Samples { .. } => Some("--counts"),
+            Command::Reach { .. } => Some("--executed"),
+            _ => None,
         }
-        stackmap::LocationKind::Direct { register, offset } => {
-            print!("Direct R#{} + {}, ", register, offset);
+    }
+}
+
+// Prints warnings as they are reported, except for allowed categories, and
+// counts those in denied categories as errors.
+struct WarningPolicy {
+    deny: Vec<WarningCategory>,
+    allow: Vec<WarningCategory>,
+    // Stack map being checked, for warnings that concern a single one
+    stack_map_idx: Option<usize>,
+    num_errors: usize,
+    // Diagnostics of the command, either stderr or a buffer when several
+    // binaries are processed in parallel
+    err: Box<dyn Write>,
+    error_format: ErrorFormat,
+    binary_path: PathBuf,
+    // Offset in the section of the error that stopped the command, if known
+    failure_offset: Option<usize>,
+    // How warnings report function addresses
+    addresses: AddressReporting,
+}
+
+impl WarningPolicy {
+    // Diagnostics are best effort, like `eprintln!`
+    fn report(
+        &mut self,
+        level: &str,
+        code: &str,
+        message: &str,
+        offset: Option<usize>,
+        context: &[String],
+    ) {
+        let _ = match self.error_format {
+            ErrorFormat::Text => match self.stack_map_idx {
+                Some(stack_map_idx) => writeln!(
+                    self.err,
+                    "{}: stack map #{}: {} [{}]",
+                    level, stack_map_idx, message, code
+                ),
+                None => writeln!(self.err, "{}: {} [{}]", level, message, code),
+            },
+            ErrorFormat::Json => {
+                let context: Vec<String> = context.iter().map(|text| json_string(text)).collect();
+                writeln!(
+                    self.err,
+                    "{{\"level\": {}, \"code\": {}, \"message\": {}, \"binary\": {}, \"stack_map\": {}, \"offset\": {}, \"context\": [{}]}}",
+                    json_string(level),
+                    json_string(code),
+                    json_string(message),
+                    json_string(&self.binary_path.display().to_string()),
+                    self.stack_map_idx
+                        .map_or("null".to_owned(), |idx| idx.to_string()),
+                    offset.map_or("null".to_owned(), |offset| offset.to_string()),
+                    context.join(", ")
+                )
+            }
+        };
+    }
+
+    // Diagnostics of checks and commands, which are printed without their
+    // code as text
+    fn plain(&mut self, level: &str, code: &str, message: &str, offset: Option<usize>) {
+        match self.error_format {
+            ErrorFormat::Text => {
+                let _ = writeln!(self.err, "{}: {}", level, message);
+            }
+            ErrorFormat::Json => self.report(level, code, message, offset, &[]),
         }
-        stackmap::LocationKind::Indirect { register, offset } => {
-            print!("Indirect [R#{} + {}], ", register, offset);
+    }
+
+    fn error(&mut self, code: &str, message: &str, offset: Option<usize>) {
+        self.num_errors += 1;
+        self.plain("error", code, message, offset);
+    }
+
+    // Reports an error that stopped the command, as the last diagnostic
+    fn failure(&mut self, error: &anyhow::Error) {
+        let root = error.root_cause();
+        let code = root
+            .downcast_ref::<stackmap::Error>()
+            .map_or("failure", |error| error.code());
+        let context: Vec<String> = error
+            .chain()
+            .take_while(|cause| !std::ptr::eq(*cause, root))
+            .map(|cause| cause.to_string())
+            .collect();
+        self.num_errors += 1;
+        self.stack_map_idx = None;
+        let offset = self.failure_offset;
+        self.report("error", code, &root.to_string(), offset, &context);
+    }
+}
+
+impl DiagnosticsSink for WarningPolicy {
+    fn warning(&mut self, mut warning: Warning) {
+        let category = warning.category();
+        if self.allow.contains(&category) {
+            return;
         }
-        stackmap::LocationKind::Constant(constant) => {
-            print!("Constant {}, ", constant);
+        warning.report_addresses(&self.addresses);
+
+        let level = if self.deny.contains(&category) {
+            self.num_errors += 1;
+            "error"
+        } else {
+            "warning"
+        };
+        self.report(
+            level,
+            category.name(),
+            &warning.to_string(),
+            warning.offset(),
+            &[],
+        );
+    }
+}
+
+// In the syntax of the `--where` filters
+fn format_location(location: &Location, format: NumberFormat) -> String {
+    match format {
+        NumberFormat::Hex => HexLocation(location).to_string(),
+        _ => location.to_string(),
+    }
+}
+
+// Places of the functions and records in the dump: their position as selected
+// by the address mode, and optionally their offset in the binary
+struct AddressMap<'a> {
+    reporting: AddressReporting,
+    file_offsets: Option<&'a FileOffsets>,
+}
+
+impl AddressMap<'_> {
+    // `address` is the linked address, as found in the stack maps
+    fn describe(&self, position: Option<String>, address: u64, format: NumberFormat) -> String {
+        let mut parts: Vec<String> = position.into_iter().collect();
+        if let Some(file_offsets) = self.file_offsets {
+            let file_offset = match file_offsets.file_offset(address) {
+                Some(file_offset) => format.address(file_offset),
+                None => "<not in file>".to_owned(),
+            };
+            parts.push(format!("file offset: {}", file_offset));
         }
+        parts.join(", ")
+    }
+
+    fn describe_function(&self, address: u64, format: NumberFormat) -> String {
+        let label = match self.reporting.mode {
+            AddressMode::Section => "section offset",
+            AddressMode::Function | AddressMode::Absolute => "address",
+        };
+        let position = format.address(self.reporting.function(address));
+        self.describe(Some(format!("{}: {}", label, position)), address, format)
+    }
+
+    // Records are already printed with their offset in their function
+    fn describe_record(
+        &self,
+        function_address: u64,
+        instruction_offset: u64,
+        format: NumberFormat,
+    ) -> String {
+        let position = self.reporting.record(function_address, instruction_offset);
+        let position = match self.reporting.mode {
+            AddressMode::Function => None,
+            AddressMode::Section => Some(format!("section offset: {}", format.address(position))),
+            AddressMode::Absolute => Some(format!("address: {}", format.address(position))),
+        };
+        self.describe(
+            position,
+            function_address.wrapping_add(instruction_offset),
+            format,
+        )
     }
-    println!("size: {}", location.size());
 }
 
-fn print_record(record: &Record) -> anyhow::Result<()> {
-    println!(
-        "    ID: {:#x}, instruction offset: {:#x}",
-        record.patch_point_id(),
-        record.instruction_offset()
+// What `dump` prints, and how
+struct DumpOptions<'a> {
+    addresses: AddressMap<'a>,
+    functions_only: bool,
+    // Only records with a location matching all of them are printed
+    filters: &'a [LocationFilter],
+    // Fields of the IDs to print next to them
+    ids: &'a IdSchema,
+    format: NumberFormat,
+}
+
+fn print_record(
+    out: &mut dyn Write,
+    record: &Record,
+    function_address: u64,
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
+    let format = options.format;
+    let mut id = format.address(record.patch_point_id());
+    if !options.ids.is_empty() {
+        id.push_str(&format!(
+            " ({})",
+            options.ids.describe(record.patch_point_id())
+        ));
+    }
+    let mut header = format!(
+        "ID: {}, instruction offset: {}",
+        id,
+        format.address(record.instruction_offset() as u64)
+    );
+    let place = options.addresses.describe_record(
+        function_address,
+        record.instruction_offset() as u64,
+        format,
     );
+    if !place.is_empty() {
+        header.push_str(", ");
+        header.push_str(&place);
+    }
+    writeln!(out, "    {}", header)?;
 
-    println!("    {} locations:", record.num_locations());
+    writeln!(
+        out,
+        "    {} locations:",
+        format.count(record.num_locations())
+    )?;
     let mut locations_iter = record.locations().enumerate();
     while let Some((location_idx, location)) = locations_iter.next()? {
-        print!("      #{}: ", location_idx);
-        print_location(&location);
+        writeln!(
+            out,
+            "      #{}: {}",
+            location_idx,
+            format_location(&location, format)
+        )?;
     }
 
-    print!("    {} live-outs: [ ", record.num_live_outs());
+    write!(
+        out,
+        "    {} live-outs: [ ",
+        format.count(record.num_live_outs())
+    )?;
     let mut live_outs_iter = record.live_outs();
     while let Some(live_out) = live_outs_iter.next()? {
-        print!("{} ({}-bytes)", live_out.dwarf_reg_num(), live_out.size());
+        write!(
+            out,
+            "{} ({}-bytes)",
+            live_out.dwarf_reg_num(),
+            format.size(live_out.size() as u64)
+        )?;
     }
-    println!("]");
+    writeln!(out, "]")?;
 
     Ok(())
 }
 
-fn print_function(function: &Function) -> anyhow::Result<()> {
-    println!(
-        "  address: {:#x}, stack size: {}",
-        function.address(),
-        function.stack_size(),
-    );
-    println!("  {} records:", function.num_records());
+fn print_function(
+    out: &mut dyn Write,
+    function: &Function,
+    records: &[Record],
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
+    writeln!(
+        out,
+        "  {}, stack size: {}",
+        options
+            .addresses
+            .describe_function(function.address(), options.format),
+        options.format.size(function.stack_size() as u64),
+    )?;
+    let field = match options.ids.namespace_field() {
+        Some(field) => field,
+        None => {
+            writeln!(out, "  {} records:", options.format.count(records.len()))?;
+            for record in records {
+                print_record(out, record, function.address(), options)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let mut namespaces: BTreeMap<u64, Vec<&Record>> = BTreeMap::new();
+    for record in records {
+        let namespace = field.decode(record.patch_point_id());
+        namespaces.entry(namespace).or_default().push(record);
+    }
+    for (namespace, records) in namespaces {
+        writeln!(
+            out,
+            "  {} records with {}={}:",
+            options.format.count(records.len()),
+            field.name,
+            options.format.size(namespace)
+        )?;
+        for record in records {
+            print_record(out, record, function.address(), options)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Functions end where their symbol says, or else at the end of their code
+// section
+fn function_end(address: u64, symbols: &FunctionSymbols, sections: &BTreeMap<u64, u64>) -> u64 {
+    match symbols.get(address) {
+        Some(symbol) => address.saturating_add(symbol.size.max(1)),
+        None => match sections.range(..=address).next_back() {
+            Some((_, &end)) if address < end => end,
+            _ => address.saturating_add(1),
+        },
+    }
+}
 
-    let mut records_iter = function.records();
-    while let Some(record) = records_iter.next()? {
-        print_record(&record)?;
+// Addresses from the first function in the stack map up to the end of the
+// last one, used to flag constants that are probably code pointers
+fn code_range(
+    stack_map: &StackMap,
+    symbols: &FunctionSymbols,
+    sections: &BTreeMap<u64, u64>,
+) -> anyhow::Result<Range<u64>> {
+    let mut range: Option<Range<u64>> = None;
+    let mut headers_iter = stack_map.function_headers();
+    while let Some(header) = headers_iter.next()? {
+        let address = header.address();
+        let end = function_end(address, symbols, sections);
+        range = Some(match range {
+            Some(range) => range.start.min(address)..range.end.max(end),
+            None => address..end,
+        });
+    }
+
+    Ok(range.unwrap_or(0..0))
+}
+
+fn print_constant(
+    out: &mut dyn Write,
+    constant: Constant,
+    code_range: &Range<u64>,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    match format {
+        NumberFormat::Mixed => write!(out, "{:#x} ({})", constant.as_u64(), constant.as_i64())?,
+        NumberFormat::Hex => write!(out, "{:#x}", constant.as_u64())?,
+        NumberFormat::Dec => write!(out, "{}", constant.as_i64())?,
+    }
+    if constant.looks_like_address(code_range.clone()) {
+        write!(out, ", code address")?;
     }
+    writeln!(out)?;
 
     Ok(())
 }
 
-fn print_stack_map(stack_map: &StackMap) -> anyhow::Result<()> {
-    println!("version: {}", stack_map.version(),);
-    println!("{} functions:", stack_map.num_functions());
+fn matches_filters(record: &Record, filters: &[LocationFilter]) -> Result<bool, stackmap::Error> {
+    if filters.is_empty() {
+        return Ok(true);
+    }
+    record
+        .locations()
+        .any(|location| Ok(filters.iter().all(|filter| filter.matches(&location))))
+}
+
+fn print_stack_map(
+    out: &mut dyn Write,
+    stack_map: &StackMap,
+    symbols: &FunctionSymbols,
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
+    let format = options.format;
+    writeln!(out, "version: {}", stack_map.version(),)?;
+
+    let code_range = code_range(stack_map, symbols, &options.addresses.reporting.sections)?;
+    writeln!(
+        out,
+        "{} constants:",
+        format.count(stack_map.num_constants())
+    )?;
+    for (constant_idx, constant) in stack_map.constants().enumerate() {
+        write!(out, "  #{}: ", constant_idx)?;
+        print_constant(out, constant, &code_range, format)?;
+    }
 
+    // Functions without any matching record are left out
+    let mut functions = Vec::new();
     let mut functions_iter = stack_map.functions();
     while let Some(function) = functions_iter.next()? {
-        print_function(&function)?;
+        let records: Vec<Record> = function
+            .records()
+            .filter(|record| matches_filters(record, options.filters))
+            .collect()?;
+        if options.filters.is_empty() || !records.is_empty() {
+            functions.push((function, records));
+        }
+    }
+
+    writeln!(out, "{} functions:", format.count(functions.len()))?;
+    for (function, records) in &functions {
+        print_function(out, function, records, options)?;
     }
 
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
-    let binary_path = opt.binary_path();
+fn print_function_table(
+    out: &mut dyn Write,
+    stack_map: &StackMap,
+    symbols: &FunctionSymbols,
+    addresses: &AddressMap,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    writeln!(out, "version: {}", stack_map.version())?;
+    writeln!(
+        out,
+        "{} functions:",
+        format.count(stack_map.num_functions())
+    )?;
+
+    let mut headers_iter = stack_map.function_headers();
+    while let Some(header) = headers_iter.next()? {
+        writeln!(
+            out,
+            "  {}, symbol: {}, stack size: {}, records: {}",
+            addresses.describe_function(header.address(), format),
+            symbols.name(header.address()).unwrap_or("<unknown>"),
+            format.size(header.stack_size() as u64),
+            format.count(header.num_records()),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn dump(
+    out: &mut dyn Write,
+    llvm_stack_maps: &LLVMStackMaps,
+    symbols: &FunctionSymbols,
+    policy: &mut WarningPolicy,
+    options: &DumpOptions,
+) -> anyhow::Result<()> {
+    let mut stack_maps_iter = llvm_stack_maps.stack_maps();
+    let mut stack_map_idx = 0;
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        policy.stack_map_idx = Some(stack_map_idx);
+        validate::check_functions(&stack_map, symbols, policy)?;
+
+        write!(out, "Stack map #{}: ", stack_map_idx)?;
+        if options.functions_only {
+            print_function_table(out, &stack_map, symbols, &options.addresses, options.format)?;
+        } else {
+            print_stack_map(out, &stack_map, symbols, options)?;
+        }
+        writeln!(out)?;
+        stack_map_idx += 1;
+    }
+
+    policy.stack_map_idx = None;
+    for region in stack_maps_iter.skipped() {
+        let range = region.range();
+        policy.warning(Warning::SkippedBytes {
+            start: range.start,
+            end: range.end,
+            reason: region.reason().to_string(),
+        });
+        if !policy.allow.contains(&WarningCategory::SkippedBytes)
+            && policy.error_format == ErrorFormat::Text
+        {
+            write!(policy.err, "{}", region.hexdump())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_samples(
+    out: &mut dyn Write,
+    llvm_stack_maps: &LLVMStackMaps,
+    samples: &SampleCounts,
+    shadow_size: u64,
+    reporting: &AddressReporting,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let mut pcs = samples::correlate(llvm_stack_maps, samples, shadow_size)?;
+    let at_pcs: u64 = pcs.iter().map(|pc| pc.at_pc).sum();
+    let in_shadows: u64 = pcs.iter().map(|pc| pc.in_shadow).sum();
+
+    pcs.retain(|pc| pc.in_shadow > 0);
+    pcs.sort_by(|a, b| b.in_shadow.cmp(&a.in_shadow).then(a.pc.cmp(&b.pc)));
+    for pc in &pcs {
+        writeln!(
+            out,
+            "{}: {} at PC, {} in shadow, IDs: {}",
+            format.address(
+                reporting.record(pc.function_address, pc.pc.wrapping_sub(pc.function_address))
+            ),
+            format.size(pc.at_pc),
+            format.size(pc.in_shadow),
+            format.ids(&pc.patch_point_ids)
+        )?;
+    }
+
+    let percent = |count: u64| 100.0 * count as f64 / samples.total().max(1) as f64;
+    writeln!(
+        out,
+        "{} samples, {} ({:.2}%) at record PCs, {} ({:.2}%) in shadows",
+        format.size(samples.total()),
+        format.size(at_pcs),
+        percent(at_pcs),
+        format.size(in_shadows),
+        percent(in_shadows)
+    )?;
+
+    Ok(())
+}
+
+fn reach(
+    out: &mut dyn Write,
+    policy: &mut WarningPolicy,
+    llvm_stack_maps: &LLVMStackMaps,
+    file_data: &[u8],
+    executed_paths: &[PathBuf],
+    // Its bias is the load bias of the executed PCs
+    reporting: &AddressReporting,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let mut executed = Vec::new();
+    for path in executed_paths {
+        let data = fs::read(path)
+            .with_context(|| format!("Could not read executed PCs from {}", path.display()))?;
+        let pcs = sancov::read_executed_pcs(&data)
+            .with_context(|| format!("Could not parse executed PCs from {}", path.display()))?;
+        executed.extend(pcs.into_iter().map(|pc| pc.wrapping_sub(reporting.bias)));
+    }
+
+    let pc_table = match loader::load_sancov_pc_table(file_data) {
+        Ok(pc_table) => {
+            if pc_table.is_empty() {
+                // E.g. filled in through relocations that are not applied,
+                // such as the packed relative relocations of a PIE
+                policy.plain(
+                    "warning",
+                    "unrelocated-sancov-pc-table",
+                    "SanCov PC table present but without relocated entries, whether safepoints were reached is unknown",
+                    None,
+                );
+            }
+            pc_table
+        }
+        Err(loader::LoadError::SectionNotFound { .. }) => {
+            policy.plain(
+                "warning",
+                "no-sancov-pc-table",
+                "no SanCov PC table, whether safepoints were reached is unknown",
+                None,
+            );
+            Vec::new()
+        }
+        Err(error) => return Err(error).context("Could not read SanCov PC table"),
+    };
+    if !pc_table.is_empty() && pc_table.iter().all(|entry| entry.is_function_entry()) {
+        policy.plain(
+            "warning",
+            "sancov-function-coverage",
+            "SanCov PC table only has function entries, only safepoints of functions never entered are known",
+            None,
+        );
+    }
+
+    let safepoints = sancov::safepoint_reach(llvm_stack_maps, &pc_table, &executed)?;
+    let count = |reach| {
+        format.count(
+            safepoints
+                .iter()
+                .filter(|safepoint| safepoint.reach == reach)
+                .count(),
+        )
+    };
+
+    for safepoint in &safepoints {
+        writeln!(
+            out,
+            "{}: {}, IDs: {}",
+            format.address(reporting.record(
+                safepoint.function_address,
+                safepoint.pc.wrapping_sub(safepoint.function_address)
+            )),
+            match safepoint.reach {
+                Reach::Reached => "reached",
+                Reach::NotReached => "not reached",
+                Reach::Unknown => "unknown",
+            },
+            format.ids(&safepoint.patch_point_ids)
+        )?;
+    }
+    writeln!(
+        out,
+        "{} of {} safepoints reached, {} unknown",
+        count(Reach::Reached),
+        format.count(safepoints.len()),
+        count(Reach::Unknown)
+    )?;
+
+    Ok(())
+}
+
+// Everything the summary reports
+#[derive(Default)]
+struct SummaryCounts {
+    summary: Summary,
+    code_addresses: usize,
+    records_by_namespace: BTreeMap<u64, usize>,
+    // Only with the register conventions of the architecture
+    slot_kinds: Option<BTreeMap<SlotKind, usize>>,
+    frame_usages: Option<Vec<FrameUsage>>,
+    costs: Vec<RecordCost>,
+}
+
+impl SummaryCounts {
+    // Adds the counts of another binary, whose frame layouts are only
+    // included if it has some
+    fn add(&mut self, other: SummaryCounts) {
+        let summary = &mut self.summary;
+        summary.stack_maps += other.summary.stack_maps;
+        summary.functions += other.summary.functions;
+        summary.records += other.summary.records;
+        summary.locations += other.summary.locations;
+        summary.live_outs += other.summary.live_outs;
+        summary.constants += other.summary.constants;
+        summary.bytes += other.summary.bytes;
+        self.code_addresses += other.code_addresses;
+        for (namespace, count) in other.records_by_namespace {
+            *self.records_by_namespace.entry(namespace).or_insert(0) += count;
+        }
+        if let Some(slot_kinds) = other.slot_kinds {
+            let total = self.slot_kinds.get_or_insert_with(BTreeMap::new);
+            for (kind, count) in slot_kinds {
+                *total.entry(kind).or_insert(0) += count;
+            }
+        }
+        if let Some(frame_usages) = other.frame_usages {
+            self.frame_usages
+                .get_or_insert_with(Vec::new)
+                .extend(frame_usages);
+        }
+        self.costs.extend(other.costs);
+    }
+}
+
+// Counts what the summary reports beyond the library summary, in its pass
+struct SummaryPass<'a> {
+    symbols: &'a FunctionSymbols,
+    sections: &'a BTreeMap<u64, u64>,
+    ids: &'a IdSchema,
+    conventions: Option<&'a RegisterConventions>,
+    pointer_size: usize,
+    counts: SummaryCounts,
+    slot_kinds: BTreeMap<SlotKind, usize>,
+    frame_usages: Vec<FrameUsage>,
+    // Of the current stack map and function
+    code_range: Option<Range<u64>>,
+    referenced: Vec<Range<u64>>,
+}
+
+impl<'input> SummaryVisitor<'input> for SummaryPass<'_> {
+    fn record(
+        &mut self,
+        function: &Function<'input>,
+        record: &Record<'input>,
+    ) -> Result<(), stackmap::Error> {
+        if self.ids.namespace_field().is_some() {
+            let namespace = self.ids.namespace(record.patch_point_id()).unwrap_or(0);
+            *self
+                .counts
+                .records_by_namespace
+                .entry(namespace)
+                .or_insert(0) += 1;
+        }
+
+        let stack_size = function.stack_size() as u64;
+        let mut pointer_locations = 0;
+        let mut locations_iter = record.locations();
+        while let Some(location) = locations_iter.next()? {
+            if cost::is_pointer_sized(&location, self.pointer_size) {
+                pointer_locations += 1;
+            }
+            if let Some(conventions) = self.conventions {
+                if let Some(kind) = classify::classify(&location, stack_size, conventions) {
+                    *self.slot_kinds.entry(kind).or_insert(0) += 1;
+                }
+                self.referenced
+                    .extend(frame::frame_range(&location, stack_size, conventions));
+            }
+        }
+        self.counts.costs.push(RecordCost {
+            pc: function
+                .address()
+                .wrapping_add(record.instruction_offset() as u64),
+            patch_point_id: record.patch_point_id(),
+            pointer_locations,
+        });
+        Ok(())
+    }
+
+    fn function(&mut self, function: &Function<'input>) -> Result<(), stackmap::Error> {
+        let address = function.address();
+        let end = function_end(address, self.symbols, self.sections);
+        self.code_range = Some(match self.code_range.take() {
+            Some(range) => range.start.min(address)..range.end.max(end),
+            None => address..end,
+        });
+        self.frame_usages.push(FrameUsage::new(
+            address,
+            function.stack_size() as u64,
+            std::mem::take(&mut self.referenced),
+        ));
+        Ok(())
+    }
 
-    let binary_file = fs::File::open(&binary_path).context("Could not open binary file")?;
+    fn stack_map(&mut self, stack_map: &StackMap<'input>) -> Result<(), stackmap::Error> {
+        let code_range = self.code_range.take().unwrap_or(0..0);
+        self.counts.code_addresses += stack_map
+            .constants()
+            .filter(|constant| constant.looks_like_address(code_range.clone()))
+            .count();
+        Ok(())
+    }
+}
+
+// Counts everything the summary reports in a single pass over the section
+fn summarize(
+    data: &[u8],
+    symbols: &FunctionSymbols,
+    sections: &BTreeMap<u64, u64>,
+    ids: &IdSchema,
+    conventions: Option<&RegisterConventions>,
+    pointer_size: usize,
+) -> Result<SummaryCounts, stackmap::Error> {
+    let mut pass = SummaryPass {
+        symbols,
+        sections,
+        ids,
+        conventions,
+        pointer_size,
+        counts: SummaryCounts::default(),
+        slot_kinds: BTreeMap::new(),
+        frame_usages: Vec::new(),
+        code_range: None,
+        referenced: Vec::new(),
+    };
+    let summary = LLVMStackMaps::new(data).summary_with(&mut pass)?;
+
+    let mut counts = pass.counts;
+    counts.summary = summary;
+    if conventions.is_some() {
+        counts.slot_kinds = Some(pass.slot_kinds);
+        counts.frame_usages = Some(pass.frame_usages);
+    }
+    Ok(counts)
+}
+
+fn print_summary(
+    out: &mut dyn Write,
+    counts: &SummaryCounts,
+    ids: &IdSchema,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let summary = &counts.summary;
+    writeln!(out, "Stack maps: {}", format.count(summary.stack_maps))?;
+    writeln!(out, "Functions: {}", format.count(summary.functions))?;
+    writeln!(out, "Records: {}", format.count(summary.records))?;
+    writeln!(out, "Locations: {}", format.count(summary.locations))?;
+    writeln!(out, "Live-outs: {}", format.count(summary.live_outs))?;
+    writeln!(out, "Constants: {}", format.count(summary.constants))?;
+    writeln!(
+        out,
+        "Constants that look like code addresses: {}",
+        format.count(counts.code_addresses)
+    )?;
+    writeln!(out, "Bytes: {}", format.size(summary.bytes as u64))?;
+
+    if let Some(field) = ids.namespace_field() {
+        writeln!(out, "Records by {}:", field.name)?;
+        for (&namespace, &count) in &counts.records_by_namespace {
+            writeln!(
+                out,
+                "  {}={}: {}",
+                field.name,
+                format.size(namespace),
+                format.count(count)
+            )?;
+        }
+    }
+
+    if let Some(slot_kinds) = &counts.slot_kinds {
+        writeln!(out, "Frame locations by likely kind (x86-64):")?;
+        for (kind, &count) in slot_kinds {
+            writeln!(out, "  {}: {}", kind.name(), format.count(count))?;
+        }
+    }
+
+    let costs = cost::CostDistribution::new(&counts.costs);
+    writeln!(
+        out,
+        "Pointer-sized locations per record: min {}, median {}, p90 {}, p99 {}, max {}, mean {:.1}",
+        format.count(costs.min),
+        format.count(costs.median),
+        format.count(costs.p90),
+        format.count(costs.p99),
+        format.count(costs.max),
+        costs.mean
+    )?;
+
+    if let Some(usages) = &counts.frame_usages {
+        writeln!(
+            out,
+            "Frame bytes never referenced by a record: {} of {}",
+            format.size(usages.iter().map(|usage| usage.unreferenced_bytes()).sum()),
+            format.size(usages.iter().map(|usage| usage.stack_size).sum())
+        )?;
+    }
+
+    Ok(())
+}
+
+fn verify_stack_map(stack_map: &StackMap) -> anyhow::Result<usize> {
+    let mut num_records = 0;
+    let mut functions_iter = stack_map.functions();
+    while let Some(function) = functions_iter.next()? {
+        let mut records_iter = function.records();
+        while let Some(record) = records_iter.next()? {
+            record.locations().for_each(|_| Ok(()))?;
+            record.live_outs().for_each(|_| Ok(()))?;
+            num_records += 1;
+        }
+    }
+
+    Ok(num_records)
+}
+
+fn verify_roundtrip(
+    out: &mut dyn Write,
+    data: &[u8],
+    policy: &mut WarningPolicy,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let divergence = match roundtrip::check_roundtrip(data) {
+        Ok(divergence) => divergence,
+        Err(error) => {
+            let offset = error.offset();
+            policy.error(
+                error.code(),
+                &format!(
+                    "round trip failed: stack maps cannot be serialized: {}",
+                    error
+                ),
+                offset,
+            );
+            return Ok(());
+        }
+    };
+    let divergence = match divergence {
+        Some(divergence) => divergence,
+        None => {
+            writeln!(out, "Round trip: identical")?;
+            return Ok(());
+        }
+    };
+
+    let byte =
+        |byte: Option<u8>| byte.map_or("end of data".to_owned(), |byte| format!("{:#04x}", byte));
+    policy.error(
+        "roundtrip-divergence",
+        &format!(
+            "round trip diverges at offset {}: original {}, serialized {}",
+            format.address(divergence.offset as u64),
+            byte(divergence.original),
+            byte(divergence.serialized)
+        ),
+        Some(divergence.offset),
+    );
+    Ok(())
+}
+
+fn verify(
+    out: &mut dyn Write,
+    data: &[u8],
+    symbols: &FunctionSymbols,
+    policy: &mut WarningPolicy,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let coverage = coverage::section_coverage(data);
+
+    for (stack_map_idx, range) in coverage.stack_maps.iter().enumerate() {
+        let llvm_stack_maps = LLVMStackMaps::new(&data[range.clone()]);
+        let stack_map = llvm_stack_maps
+            .stack_maps()
+            .next()?
+            .context("Stack map disappeared while verifying it")?;
+
+        policy.stack_map_idx = Some(stack_map_idx);
+        validate::check_functions(&stack_map, symbols, policy)?;
+        let num_records = verify_stack_map(&stack_map)
+            .with_context(|| format!("Stack map #{} is malformed", stack_map_idx))?;
+        writeln!(
+            out,
+            "Stack map #{} at [{}, {}): {} functions, {} records",
+            stack_map_idx,
+            format.address(range.start as u64),
+            format.address(range.end as u64),
+            format.count(stack_map.num_functions()),
+            format.count(num_records)
+        )?;
+    }
+
+    policy.stack_map_idx = None;
+    writeln!(
+        out,
+        "Coverage: {} of {} bytes",
+        format.size(coverage.consumed_bytes() as u64),
+        format.size(coverage.section_size as u64)
+    )?;
+    for gap in coverage.gaps {
+        match gap.kind {
+            GapKind::ZeroPadding => policy.warning(Warning::SectionPadding {
+                start: gap.range.start,
+                end: gap.range.end,
+            }),
+            GapKind::Unparsed(error) => {
+                let code = error.code();
+                policy.error(
+                    code,
+                    &format!(
+                        "{} bytes at [{}, {}) could not be parsed: {:?}",
+                        format.size(gap.range.len() as u64),
+                        format.address(gap.range.start as u64),
+                        format.address(gap.range.end as u64),
+                        error
+                    ),
+                    Some(gap.range.start),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Record metadata of the JSON export: where the record is, as reported by
+// the address mode, and the slot kind of each of its locations, null for all
+// of them when frame layouts are not known
+#[cfg(feature = "json")]
+#[derive(serde::Serialize, schemars::JsonSchema)]
+struct JsonRecordMetadata {
+    address: u64,
+    slot_kinds: Vec<Option<SlotKind>>,
+}
+
+#[cfg(feature = "json")]
+fn check_baseline(
+    section: &LLVMStackMaps,
+    symbols: &FunctionSymbols,
+    baseline: &Path,
+    update: bool,
+    limits: &GrowthLimits,
+    policy: &mut WarningPolicy,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let current = Baseline::new(section, symbols).context("Could not parse stack maps")?;
+    if update {
+        let mut output = fs::File::create(baseline).context("Could not create baseline")?;
+        serde_json::to_writer_pretty(&mut output, &current).context("Could not write baseline")?;
+        io::Write::write_all(&mut output, b"\n").context("Could not write baseline")?;
+        return Ok(());
+    }
+
+    let text = fs::read_to_string(baseline).context("Could not read baseline")?;
+    let stored: Baseline = serde_json::from_str(&text).context("Could not parse baseline")?;
+    let changes = stored.function_changes(&current);
+    for function in &changes.added {
+        policy.plain(
+            "note",
+            "function-added",
+            &format!("{} is not in the baseline", function),
+            None,
+        );
+    }
+    for function in &changes.removed {
+        policy.plain(
+            "warning",
+            "function-removed",
+            &format!("{} is not in the binary anymore", function),
+            None,
+        );
+    }
+    for function in &changes.unmatched {
+        policy.plain(
+            "warning",
+            "function-unmatched",
+            &format!(
+                "function at {} has no symbol and cannot be compared with the baseline",
+                function
+            ),
+            None,
+        );
+    }
+    for violation in stored.check(&current, limits) {
+        match violation {
+            BaselineViolation::StackGrowth {
+                function,
+                baseline,
+                current,
+            } => policy.error(
+                "stack-growth",
+                &format!(
+                    "stack size of {} grew from {} to {}",
+                    function,
+                    format.size(baseline),
+                    format.size(current)
+                ),
+                None,
+            ),
+            BaselineViolation::MissingPatchPoint { id } => policy.error(
+                "missing-patchpoint",
+                &format!("no record has patchpoint ID {} anymore", format.address(id)),
+                None,
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+// `old -> new`, or a single value if they are the same
+fn change<T: PartialEq>(old: T, new: T, show: impl Fn(T) -> String) -> String {
+    if old == new {
+        show(new)
+    } else {
+        format!("{} -> {}", show(old), show(new))
+    }
+}
+
+fn print_record_diff(
+    out: &mut dyn Write,
+    record: &RecordDiff,
+    ids: &IdSchema,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let id = |id: u64| {
+        if ids.is_empty() {
+            format.address(id)
+        } else {
+            format!("{} ({})", format.address(id), ids.describe(id))
+        }
+    };
+    let offset = |offset: u32| format.address(offset as u64);
+    match record {
+        RecordDiff::Added {
+            patch_point_id,
+            instruction_offset,
+        } => writeln!(
+            out,
+            "  added record ID: {}, instruction offset: {}",
+            id(*patch_point_id),
+            offset(*instruction_offset)
+        )?,
+        RecordDiff::Removed {
+            patch_point_id,
+            instruction_offset,
+        } => writeln!(
+            out,
+            "  removed record ID: {}, instruction offset: {}",
+            id(*patch_point_id),
+            offset(*instruction_offset)
+        )?,
+        RecordDiff::Changed {
+            old_patch_point_id,
+            new_patch_point_id,
+            old_instruction_offset,
+            new_instruction_offset,
+            locations,
+            live_outs,
+        } => {
+            writeln!(
+                out,
+                "  changed record ID: {}, instruction offset: {}",
+                change(*old_patch_point_id, *new_patch_point_id, id),
+                change(*old_instruction_offset, *new_instruction_offset, offset)
+            )?;
+            for location in locations {
+                match location {
+                    LocationDiff::Added { index, location } => writeln!(
+                        out,
+                        "    #{}: added {}",
+                        index,
+                        format_location(location, format)
+                    )?,
+                    LocationDiff::Removed { index, location } => writeln!(
+                        out,
+                        "    #{}: removed {}",
+                        index,
+                        format_location(location, format)
+                    )?,
+                    LocationDiff::Changed { index, old, new } => writeln!(
+                        out,
+                        "    #{}: {} -> {}",
+                        index,
+                        format_location(old, format),
+                        format_location(new, format)
+                    )?,
+                }
+            }
+            if let Some((old, new)) = live_outs {
+                writeln!(
+                    out,
+                    "    live-outs: {} -> {}",
+                    format.count(old.len()),
+                    format.count(new.len())
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the functions of `report` that differ, named with the symbols of
+// their side, before their addresses are rewritten as configured
+fn print_diff(
+    out: &mut dyn Write,
+    mut report: DiffReport,
+    options: &DiffOptions,
+    reporting: (&AddressReporting, &AddressReporting),
+    ids: &IdSchema,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let name = |symbols: Option<&FunctionSymbols>, address: u64| {
+        symbols
+            .and_then(|symbols| symbols.name(address))
+            .unwrap_or("<unknown>")
+            .to_owned()
+    };
+    let names: Vec<String> = report
+        .functions
+        .iter()
+        .map(|function| match *function {
+            FunctionDiff::Added { address, .. } => name(options.new_symbols, address),
+            FunctionDiff::Removed { address, .. } => name(options.old_symbols, address),
+            FunctionDiff::Changed { new_address, .. } => name(options.new_symbols, new_address),
+        })
+        .collect();
+    report.report_addresses(reporting.0, reporting.1);
+
+    for (function, name) in report.functions.iter().zip(names) {
+        match function {
+            FunctionDiff::Added { address, .. } => writeln!(
+                out,
+                "added function {} at {}",
+                name,
+                format.address(*address)
+            )?,
+            FunctionDiff::Removed { address, .. } => writeln!(
+                out,
+                "removed function {} at {}",
+                name,
+                format.address(*address)
+            )?,
+            FunctionDiff::Changed {
+                old_address,
+                new_address,
+                old_stack_size,
+                new_stack_size,
+                records,
+            } => {
+                writeln!(
+                    out,
+                    "changed function {} at {}, stack size: {}",
+                    name,
+                    change(*old_address, *new_address, |address| format
+                        .address(address)),
+                    change(*old_stack_size, *new_stack_size, |size| format.size(size))
+                )?;
+                for record in records {
+                    print_record_diff(out, record, ids, format)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Compares the stack maps of both sections in order
+fn diff_sections(
+    out: &mut dyn Write,
+    old: &LLVMStackMaps,
+    new: &LLVMStackMaps,
+    options: &DiffOptions,
+    reporting: (&AddressReporting, &AddressReporting),
+    ids: &IdSchema,
+    format: NumberFormat,
+) -> anyhow::Result<()> {
+    let old_stack_maps: Vec<StackMap> = old.stack_maps().collect()?;
+    let new_stack_maps: Vec<StackMap> = new.stack_maps().collect()?;
+    if old_stack_maps.len() != new_stack_maps.len() {
+        writeln!(
+            out,
+            "stack maps: {} -> {}",
+            format.count(old_stack_maps.len()),
+            format.count(new_stack_maps.len())
+        )?;
+    }
+
+    let mut num_functions = 0;
+    let mut records_by_namespace = BTreeMap::new();
+    for (old, new) in old_stack_maps.iter().zip(&new_stack_maps) {
+        let report = diff::diff_with_options(old, new, options)?;
+        num_functions += report.functions.len();
+        if ids.namespace_field().is_some() {
+            for (namespace, count) in ids.count_record_diffs(&report) {
+                *records_by_namespace.entry(namespace).or_insert(0) += count;
+            }
+        }
+        print_diff(out, report, options, reporting, ids, format)?;
+    }
+
+    writeln!(out, "{} functions differ", format.count(num_functions))?;
+    if let Some(field) = ids.namespace_field() {
+        writeln!(out, "Records that differ by {}:", field.name)?;
+        for (namespace, count) in records_by_namespace {
+            writeln!(
+                out,
+                "  {}={}: {}",
+                field.name,
+                format.size(namespace),
+                format.count(count)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "debuginfod")]
+fn debuginfod_symbols(file_data: &[u8]) -> anyhow::Result<FunctionSymbols> {
+    let client = DebuginfodClient::from_env().context("DEBUGINFOD_URLS is not set")?;
+    let path = client
+        .fetch_debuginfo_for(file_data)
+        .context("Could not fetch debug info")?;
+    let debuginfo = fs::read(path).context("Could not read debug info")?;
+    loader::load_function_symbols(&debuginfo).context("Could not read debug info symbols")
+}
+
+// Commands that do not read any binary: synthetic sections are generated from
+// scratch, corpora are made of raw sections, and completions and man pages
+// describe the CLI itself.
+fn run_without_input(command: &Command) -> anyhow::Result<()> {
+    let mut cli = Command::command();
+    match command {
+        Command::Generate {
+            output,
+            seed,
+            stack_maps,
+            functions,
+            records,
+            locations,
+            live_outs,
+        } => {
+            let section = generate::generate_section(&GeneratorOptions {
+                seed: *seed,
+                stack_maps: *stack_maps,
+                functions: *functions,
+                records: *records,
+                locations: *locations,
+                live_outs: *live_outs,
+            })
+            .context("Could not generate stack maps")?;
+            fs::write(output, section).context("Could not write section")?;
+        }
+        Command::Inject {
+            binary_path,
+            stack_maps,
+            output,
+            section_name,
+            force,
+        } => {
+            let section = fs::read(stack_maps).context("Could not read stack maps")?;
+            if !force {
+                // Parse everything, down to the locations and live-outs
+                let mut stack_maps_iter = LLVMStackMaps::new(&section).stack_maps();
+                while let Some(stack_map) = stack_maps_iter
+                    .next()
+                    .context("Could not parse stack maps")?
+                {
+                    verify_stack_map(&stack_map).context("Could not parse stack maps")?;
+                }
+            }
+            let binary = fs::read(binary_path).context("Could not read binary file")?;
+            let injected = inject::inject_section(&binary, section_name, &section)
+                .context("Could not inject stack maps")?;
+            if loader::is_relocatable(&binary).context("Could not parse object file")? {
+                eprintln!(
+                    "warning: no relocations are written, so the function addresses of the stack maps are not relocated when linking"
+                );
+            }
+            fs::write(output, injected).context("Could not write binary file")?;
+            // Keep the output executable, like objcopy does
+            let permissions = fs::metadata(binary_path)
+                .context("Could not read binary file")?
+                .permissions();
+            fs::set_permissions(output, permissions).context("Could not write binary file")?;
+        }
+        #[cfg(feature = "json")]
+        Command::TestCorpus { corpus, bless } => {
+            if *bless {
+                for blob in corpus::bless_corpus(corpus).context("Could not bless corpus")? {
+                    println!("Blessed {}", blob.display());
+                }
+                return Ok(());
+            }
+
+            let report = corpus::run_corpus(corpus).context("Could not run corpus")?;
+            for case in report.failures() {
+                println!(
+                    "FAIL {}: {}",
+                    case.blob.display(),
+                    case.mismatch.as_ref().unwrap()
+                );
+            }
+            println!(
+                "{} of {} cases passed",
+                report.num_passed(),
+                report.cases.len()
+            );
+            if report.num_passed() < report.cases.len() {
+                anyhow::bail!("{} cases failed", report.cases.len() - report.num_passed());
+            }
+        }
+        Command::Completions { shell } => {
+            let name = cli.get_name().to_owned();
+            clap_complete::generate(*shell, &mut cli, name, &mut io::stdout());
+        }
+        Command::Man => clap_mangen::Man::new(cli)
+            .render(&mut io::stdout())
+            .context("Could not write man page")?,
+        #[cfg(feature = "json")]
+        Command::Json { .. } => {
+            let schema = stackmap::owned::json_schema::<(), JsonRecordMetadata>();
+            serde_json::to_writer_pretty(io::stdout().lock(), &schema)
+                .context("Could not write JSON Schema")?;
+            println!();
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+// Runs `command` on a single binary, writing its results to `out` and its
+// diagnostics to `err`, and returns the number of errors it reported.
+fn run(
+    command: &Command,
+    input: &InputOpt,
+    binary_path: &Path,
+    out: &mut dyn Write,
+    err: Box<dyn Write>,
+    counts: &mut Option<SummaryCounts>,
+) -> anyhow::Result<usize> {
+    let mut policy = input.warning_policy(binary_path, err);
+    match run_binary(command, input, binary_path, out, &mut policy, counts) {
+        Ok(()) => Ok(policy.num_errors),
+        // Errors that stop the command are diagnostics like any other then
+        Err(error) if input.error_format == ErrorFormat::Json => {
+            policy.failure(&error);
+            Ok(policy.num_errors)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+fn run_binary(
+    command: &Command,
+    input: &InputOpt,
+    binary_path: &Path,
+    out: &mut dyn Write,
+    policy: &mut WarningPolicy,
+    counts: &mut Option<SummaryCounts>,
+) -> anyhow::Result<()> {
+    let binary_file = fs::File::open(binary_path).context("Could not open binary file")?;
     let file_map = unsafe { Mmap::map(&binary_file).context("Could not map binary file")? };
-    let object = object::File::parse(&file_map).context("Could not parse input file as object")?;
-
-    let stack_maps_section = object
-        .section_by_name(STACK_MAPS_SECTION_NAME)
-        .with_context(|| {
-            format!(
-                "Could not find {} section in object",
-                STACK_MAPS_SECTION_NAME
+    let stack_maps_data = loader::load_stack_maps_data(&file_map, &input.source())
+        .context("Could not load stack maps from object")?;
+
+    let result = run_command(
+        command,
+        input,
+        &file_map,
+        &stack_maps_data,
+        out,
+        policy,
+        counts,
+    );
+    if let Err(error) = &result {
+        policy.failure_offset = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<stackmap::Error>())
+            .and_then(stackmap::Error::offset);
+    }
+    result
+}
+
+fn run_command(
+    command: &Command,
+    input: &InputOpt,
+    file_map: &[u8],
+    stack_maps_data: &[u8],
+    out: &mut dyn Write,
+    policy: &mut WarningPolicy,
+    // Set by `summary`, for the total of several binaries
+    counts: &mut Option<SummaryCounts>,
+) -> anyhow::Result<()> {
+    let relocatable = loader::is_relocatable(file_map).context("Could not parse object file")?;
+    let symbols =
+        loader::load_function_symbols(file_map).context("Could not read object symbols")?;
+    #[cfg(feature = "debuginfod")]
+    let symbols = if symbols.is_empty() && input.debuginfod {
+        debuginfod_symbols(file_map)?
+    } else {
+        symbols
+    };
+
+    let format = input.number_format();
+    let ids = input.id_schema()?;
+    let reporting = input.address_reporting(file_map)?;
+    policy.addresses = reporting.clone();
+    // Frame layouts are only known for some architectures
+    let conventions = loader::load_arch(file_map)
+        .context("Could not parse object file")?
+        .and_then(RegisterConventions::for_arch);
+    // Functions at the start of their section are at address 0 here, which is
+    // only reported if asked for explicitly
+    if relocatable && !input.deny.contains(&WarningCategory::ZeroAddress) {
+        policy.allow.push(WarningCategory::ZeroAddress);
+    }
+
+    match *command {
+        Command::Dump {
+            functions_only,
+            file_offsets,
+            ref filters,
+            strict_eof,
+            lenient,
+            ..
+        } => {
+            let file_offsets = if file_offsets {
+                Some(
+                    loader::load_file_offsets(file_map)
+                        .context("Could not read object segments")?,
+                )
+            } else {
+                None
+            };
+            dump(
+                out,
+                &LLVMStackMaps::with_options(
+                    stack_maps_data,
+                    ParseOptions {
+                        strict_eof,
+                        lenient,
+                    },
+                ),
+                &symbols,
+                policy,
+                &DumpOptions {
+                    addresses: AddressMap {
+                        reporting: reporting.clone(),
+                        file_offsets: file_offsets.as_ref(),
+                    },
+                    functions_only,
+                    filters,
+                    ids: &ids,
+                    format,
+                },
+            )?;
+        }
+        Command::Summary { .. } => {
+            let pointer_size =
+                loader::load_pointer_size(file_map).context("Could not parse object file")?;
+            let summary_counts = summarize(
+                stack_maps_data,
+                &symbols,
+                &reporting.sections,
+                &ids,
+                conventions.as_ref(),
+                pointer_size,
+            )
+            .context("Could not parse stack maps")?;
+            print_summary(out, &summary_counts, &ids, format)?;
+            *counts = Some(summary_counts);
+        }
+        #[cfg(feature = "json")]
+        Command::Json { .. } => {
+            let stack_maps: Vec<_> = LLVMStackMaps::new(stack_maps_data)
+                .stack_maps()
+                .map(|stack_map| {
+                    let stack_map = stackmap::owned::StackMap::<(), ()>::from_parsed(&stack_map)?;
+                    let stack_map = match &conventions {
+                        Some(conventions) => classify::classify_stack_map(stack_map, conventions),
+                        None => stack_map
+                            .map_record_metadata(|_, record| vec![None; record.locations.len()]),
+                    };
+                    // Records are reported from the linked address of their function
+                    let mut stack_map =
+                        stack_map.map_record_metadata(|function, record| JsonRecordMetadata {
+                            address: reporting
+                                .record(function.address, record.instruction_offset as u64),
+                            slot_kinds: record.metadata.clone(),
+                        });
+                    stack_map.report_addresses(&reporting);
+                    Ok(stack_map)
+                })
+                .collect()
+                .context("Could not parse stack maps")?;
+            if input.binary_paths.len() > 1 {
+                let line = serde_json::json!({
+                    "binary": policy.binary_path.display().to_string(),
+                    "stack_maps": stack_maps,
+                });
+                serde_json::to_writer(&mut *out, &line)
+            } else {
+                serde_json::to_writer_pretty(&mut *out, &stack_maps)
+            }
+            .context("Could not write JSON")?;
+            writeln!(out)?;
+        }
+        #[cfg(feature = "json")]
+        Command::Check {
+            ref baseline,
+            update,
+            ref budget,
+            max_growth,
+            max_growth_percent,
+            ..
+        } => {
+            let limits = GrowthLimits {
+                max_bytes: max_growth,
+                max_percent: max_growth_percent,
+            };
+            let section = LLVMStackMaps::new(stack_maps_data);
+            if let Some(baseline) = baseline {
+                check_baseline(
+                    &section, &symbols, baseline, update, &limits, policy, format,
+                )?;
+            }
+            if let Some(budget) = budget {
+                let text = fs::read_to_string(budget).context("Could not read budget")?;
+                let config: BudgetConfig =
+                    serde_json::from_str(&text).context("Could not parse budget")?;
+                let violations = config
+                    .check(&section)
+                    .context("Could not parse stack maps")?;
+                for violation in violations {
+                    match violation {
+                        BudgetViolation::StackSize {
+                            function_address,
+                            stack_size,
+                            limit,
+                        } => policy.error(
+                            "stack-size-budget",
+                            &format!(
+                                "function at {} uses {} bytes of stack, more than {}",
+                                format.address(reporting.function(function_address)),
+                                format.size(stack_size),
+                                format.size(limit)
+                            ),
+                            None,
+                        ),
+                        BudgetViolation::Locations {
+                            function_address,
+                            instruction_offset,
+                            patch_point_id,
+                            num_locations,
+                            limit,
+                        } => policy.error(
+                            "locations-budget",
+                            &format!(
+                                "record {} at {} has {} locations, more than {}",
+                                format.address(patch_point_id),
+                                format.address(
+                                    reporting.record(function_address, instruction_offset)
+                                ),
+                                format.count(num_locations),
+                                format.count(limit)
+                            ),
+                            None,
+                        ),
+                        BudgetViolation::MissingId { id } => policy.error(
+                            "missing-required-id",
+                            &format!(
+                                "no record has the required patchpoint ID {}",
+                                format.address(id)
+                            ),
+                            None,
+                        ),
+                    }
+                }
+            }
+        }
+        Command::Diff {
+            ref old, by_symbol, ..
+        } => {
+            let old_file = fs::File::open(old).context("Could not open old binary file")?;
+            let old_map = unsafe { Mmap::map(&old_file).context("Could not map old binary file")? };
+            let old_data = loader::load_stack_maps_data(&old_map, &input.source())
+                .context("Could not load stack maps from old object")?;
+            let old_symbols = loader::load_function_symbols(&old_map)
+                .context("Could not read old object symbols")?;
+            let old_reporting = input.address_reporting(&old_map)?;
+            let options = DiffOptions {
+                functions: if by_symbol {
+                    FunctionMatching::Symbol
+                } else {
+                    FunctionMatching::Address
+                },
+                old_symbols: Some(&old_symbols),
+                new_symbols: Some(&symbols),
+                ..DiffOptions::default()
+            };
+            diff_sections(
+                out,
+                &LLVMStackMaps::new(&old_data),
+                &LLVMStackMaps::new(stack_maps_data),
+                &options,
+                (&old_reporting, &reporting),
+                &ids,
+                format,
+            )
+            .context("Could not parse stack maps")?;
+        }
+        Command::Density {
+            max_gap,
+            max_gap_instructions,
+            ref objdump,
+            ..
+        } => {
+            let boundaries = match objdump {
+                Some(path) => {
+                    let text = fs::read_to_string(path).context("Could not read objdump output")?;
+                    Some(density::instruction_boundaries_from_objdump(&text))
+                }
+                None => None,
+            };
+            let gaps = density::safepoint_gaps(
+                &LLVMStackMaps::new(stack_maps_data),
+                &symbols,
+                boundaries.as_ref(),
+            )
+            .context("Could not parse stack maps")?;
+            let (threshold, unit) = match max_gap_instructions {
+                Some(max) => (GapThreshold::Instructions(max), "instructions"),
+                None => (GapThreshold::Bytes(max_gap), "bytes"),
+            };
+            let mut num_large = 0;
+            for gap in gaps.iter().filter(|gap| gap.exceeds(threshold)) {
+                num_large += 1;
+                let instructions = match gap.instructions {
+                    Some(count) => format!(" ({} instructions)", format.count(count)),
+                    None => String::new(),
+                };
+                writeln!(
+                    out,
+                    "{} bytes{} between {} and {} in {}",
+                    format.size(gap.bytes()),
+                    instructions,
+                    format.address(reporting.record(
+                        gap.function_address,
+                        gap.start_pc.wrapping_sub(gap.function_address)
+                    )),
+                    format.address(reporting.record(
+                        gap.function_address,
+                        gap.end_pc.wrapping_sub(gap.function_address)
+                    )),
+                    symbols.name(gap.function_address).unwrap_or("<unknown>")
+                )?;
+            }
+            let (max, largest) = match threshold {
+                GapThreshold::Instructions(max) => (
+                    format.count(max),
+                    format.count(
+                        gaps.iter()
+                            .filter_map(|gap| gap.instructions)
+                            .max()
+                            .unwrap_or(0),
+                    ),
+                ),
+                GapThreshold::Bytes(max) => (
+                    format.size(max),
+                    format.size(gaps.iter().map(|gap| gap.bytes()).max().unwrap_or(0)),
+                ),
+            };
+            writeln!(
+                out,
+                "{} of {} gaps larger than {} {}, largest: {} {}",
+                format.count(num_large),
+                format.count(gaps.len()),
+                max,
+                unit,
+                largest,
+                unit
+            )?;
+        }
+        #[cfg(feature = "cfi")]
+        Command::Cfi {
+            ref output,
+            debug_frame,
+            ..
+        } => {
+            let format = if debug_frame {
+                CfiFormat::DebugFrame
+            } else {
+                CfiFormat::EhFrame
+            };
+            // The synthesized CFI is x86-64 specific
+            let conventions =
+                match loader::load_arch(file_map).context("Could not parse object file")? {
+                    Some(stackmap::arch::Arch::X86_64) => RegisterConventions::X86_64,
+                    _ => anyhow::bail!("CFI can only be synthesized for x86-64 objects"),
+                };
+            let frames =
+                cfi::safepoint_frames(&LLVMStackMaps::new(stack_maps_data), &symbols, &conventions)
+                    .context("Could not parse stack maps")?;
+            let object = cfi::write_object(&frames, &conventions, format)
+                .context("Could not synthesize CFI")?;
+            fs::write(output, object).context("Could not write object")?;
+            writeln!(
+                out,
+                "{} safepoints described in {}",
+                frames.len(),
+                format.section_name()
+            )?;
+        }
+        Command::Report {
+            ref html, markdown, ..
+        } => {
+            let mut report = Report::new(&LLVMStackMaps::new(stack_maps_data), &symbols)
+                .context("Could not parse stack maps")?;
+            report.report_addresses(&reporting);
+            let title = policy.binary_path.display().to_string();
+            if let Some(html) = html {
+                let mut output = fs::File::create(html).context("Could not create HTML report")?;
+                report
+                    .write_html(&title, &mut output)
+                    .context("Could not write HTML report")?;
+            }
+            if markdown {
+                report
+                    .write_markdown(&title, &mut &mut *out)
+                    .context("Could not write Markdown report")?;
+            }
+        }
+        Command::Anonymize {
+            ref output,
+            addresses,
+            constants,
+            seed,
+            ..
+        } => {
+            let section = anonymize::anonymize_section(
+                &LLVMStackMaps::new(stack_maps_data),
+                &AnonymizeOptions {
+                    addresses,
+                    constants,
+                    seed,
+                },
             )
-        })?;
-    let stack_maps_section_data = stack_maps_section
-        .data()
-        .with_context(|| format!("Could not get data for {} section", STACK_MAPS_SECTION_NAME))?;
-
-    let llvm_stack_maps = LLVMStackMaps::new(stack_maps_section_data);
-
-    let mut stack_maps_iter = llvm_stack_maps.stack_maps().enumerate();
-    while let Some((stack_map_idx, stack_map)) = stack_maps_iter.next()? {
-        print!("Stack map #{}: ", stack_map_idx);
-        print_stack_map(&stack_map)?;
-        println!();
+            .context("Could not parse stack maps")?;
+            fs::write(output, &section).context("Could not write stack maps")?;
+        }
+        Command::Map { .. } => {
+            let entries =
+                map::safepoint_map(&LLVMStackMaps::new(stack_maps_data), &symbols, &reporting)
+                    .context("Could not parse stack maps")?;
+            map::write_map(out, &entries)?;
+        }
+        Command::Samples {
+            ref perf_script,
+            ref counts,
+            shadow_size,
+            ..
+        } => {
+            let samples = match (perf_script, counts) {
+                (Some(path), _) => {
+                    let text = fs::read_to_string(path).context("Could not read perf script")?;
+                    SampleCounts::from_perf_script(&text)
+                }
+                (None, Some(path)) => {
+                    let text = fs::read_to_string(path).context("Could not read sample counts")?;
+                    SampleCounts::from_address_counts(&text)?
+                }
+                (None, None) => unreachable!(),
+            };
+            print_samples(
+                out,
+                &LLVMStackMaps::new(stack_maps_data),
+                &samples.rebased(input.load_bias),
+                shadow_size,
+                &reporting,
+                format,
+            )?;
+        }
+        Command::Reach { ref executed, .. } => reach(
+            out,
+            policy,
+            &LLVMStackMaps::new(stack_maps_data),
+            file_map,
+            executed,
+            &reporting,
+            format,
+        )?,
+        #[cfg(feature = "json")]
+        Command::TestCorpus { .. } => unreachable!(),
+        Command::Generate { .. }
+        | Command::Inject { .. }
+        | Command::Completions { .. }
+        | Command::Man => unreachable!(),
+        Command::Verify { roundtrip, .. } => {
+            verify(out, stack_maps_data, &symbols, policy, format)?;
+            if roundtrip {
+                verify_roundtrip(out, stack_maps_data, policy, format)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Output of a command run on one of several binaries, printed once all
+// binaries before it are done so that the output does not depend on `--jobs`
+struct JobOutput {
+    out: Vec<u8>,
+    err: Vec<u8>,
+    result: anyhow::Result<usize>,
+    counts: Option<SummaryCounts>,
+}
+
+// Writer appending to a buffer that is shared with the job it belongs to
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn run_job(command: &Command, input: &InputOpt, binary_path: &Path) -> JobOutput {
+    let mut out = Vec::new();
+    let err = SharedBuffer::default();
+    let mut counts = None;
+    let result = run(
+        command,
+        input,
+        binary_path,
+        &mut out,
+        Box::new(err.clone()),
+        &mut counts,
+    );
+    let err = std::mem::take(&mut *err.0.lock().unwrap());
+    JobOutput {
+        out,
+        err,
+        result,
+        counts,
+    }
+}
+
+// Runs `command` on every binary, using up to `--jobs` threads, and prints
+// the output of each binary in the order they were given, followed by the
+// total of their summaries. JSON exports are printed one per line instead,
+// without headers, to stay parseable as JSON Lines.
+fn run_all(command: &Command, input: &InputOpt) -> anyhow::Result<usize> {
+    let paths = &input.binary_paths;
+    if let Some(option) = command.per_binary_file() {
+        anyhow::bail!("{} can only be used with a single binary", option);
+    }
+    #[cfg(feature = "json")]
+    let headers = !matches!(command, Command::Json { .. });
+    #[cfg(not(feature = "json"))]
+    let headers = true;
+    let jobs = (input.jobs as usize).min(paths.len());
+    let next_idx = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let next_idx = &next_idx;
+            scope.spawn(move || loop {
+                let idx = next_idx.fetch_add(1, Ordering::Relaxed);
+                let path = match paths.get(idx) {
+                    Some(path) => path,
+                    None => break,
+                };
+                if sender.send((idx, run_job(command, input, path))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut next_to_print = 0;
+        let mut num_errors = 0;
+        let mut num_failed = 0;
+        let mut total: Option<SummaryCounts> = None;
+        for (idx, output) in receiver {
+            pending.insert(idx, output);
+            while let Some(output) = pending.remove(&next_to_print) {
+                let path = paths[next_to_print].display();
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                if headers {
+                    if next_to_print > 0 {
+                        writeln!(stdout)?;
+                    }
+                    writeln!(stdout, "==> {} <==", path)?;
+                }
+                stdout.write_all(&output.out)?;
+                drop(stdout);
+                io::stderr().write_all(&output.err)?;
+                match output.result {
+                    Ok(errors) => num_errors += errors,
+                    Err(error) => {
+                        num_failed += 1;
+                        eprintln!("error: {}: {:#}", path, error);
+                    }
+                }
+                if let Some(counts) = output.counts {
+                    match &mut total {
+                        Some(total) => total.add(counts),
+                        None => total = Some(counts),
+                    }
+                }
+                next_to_print += 1;
+            }
+        }
+
+        if let Some(total) = &total {
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout)?;
+            writeln!(stdout, "==> total <==")?;
+            print_summary(
+                &mut stdout,
+                total,
+                &input.id_schema()?,
+                input.number_format(),
+            )?;
+        }
+
+        if num_failed > 0 {
+            anyhow::bail!(
+                "{} of {} binaries could not be processed",
+                num_failed,
+                paths.len()
+            );
+        }
+        Ok(num_errors)
+    })
+}
+
+// `stackmap-parser BINARY`, from before there were subcommands, still dumps
+// the stack maps: arguments that do not start with a subcommand are the ones
+// of `dump`.
+fn default_to_dump(mut args: Vec<OsString>) -> Vec<OsString> {
+    let cli = Command::command();
+    let starts_with_subcommand = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) => {
+            matches!(arg, "-h" | "--help" | "-V" | "--version" | "help")
+                || cli.find_subcommand(arg).is_some()
+        }
+        None => true,
+    };
+    if !starts_with_subcommand {
+        args.insert(1, "dump".into());
+    }
+    args
+}
+
+fn main() -> anyhow::Result<()> {
+    let command = Command::parse_from(default_to_dump(env::args_os().collect()));
+    let input = match command.input() {
+        Some(input) => input,
+        None => return run_without_input(&command),
+    };
+
+    let num_errors = match input.binary_paths.as_slice() {
+        [binary_path] => run(
+            &command,
+            input,
+            binary_path,
+            &mut io::stdout().lock(),
+            Box::new(io::stderr()),
+            &mut None,
+        )?,
+        _ => run_all(&command, input)?,
+    };
+    if num_errors > 0 {
+        // Every error was already reported as JSON, and stderr must only
+        // contain JSON
+        if input.error_format == ErrorFormat::Json {
+            std::process::exit(1);
+        }
+        anyhow::bail!("{} errors reported", num_errors);
     }
 
     Ok(())
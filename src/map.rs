@@ -0,0 +1,124 @@
+// A linker-map-like listing of the safepoints, one per line in address order:
+//
+//     0000000000001150 0000000b foo 0x2a
+//
+// with the address, the size, the function symbol (`?` if unknown) and the
+// patch point ID. The size of a safepoint spans up to the next safepoint of its
+// function, or up to the end of the function for the last one if its symbol
+// gives its size. Records sharing an instruction have the same size.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+};
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{addresses::AddressReporting, symbols::FunctionSymbols, Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapEntry {
+    pub address: u64,
+    pub size: u64,
+    pub symbol: Option<String>,
+    pub patch_point_id: u64,
+}
+
+/// Lists the safepoints of `section` in ascending linked address order, with
+/// their addresses as `reporting` reports them.
+pub fn safepoint_map(
+    section: &LLVMStackMaps,
+    symbols: &FunctionSymbols,
+    reporting: &AddressReporting,
+) -> Result<Vec<MapEntry>, Error> {
+    let safepoints: Vec<_> = section.safepoints().iterator().collect::<Result<_, _>>()?;
+
+    let mut function_pcs: BTreeMap<(usize, usize), BTreeSet<u64>> = BTreeMap::new();
+    for safepoint in &safepoints {
+        function_pcs
+            .entry((safepoint.stack_map_index(), safepoint.function_index()))
+            .or_default()
+            .insert(safepoint.pc());
+    }
+
+    let entries = safepoints
+        .iter()
+        .map(|safepoint| {
+            let pc = safepoint.pc();
+            let symbol = symbols.get(safepoint.function_address());
+            let pcs = &function_pcs[&(safepoint.stack_map_index(), safepoint.function_index())];
+            let end = match pcs.range(pc.saturating_add(1)..).next() {
+                Some(&next) => next,
+                None => symbol.map_or(pc, |symbol| {
+                    safepoint.function_address().wrapping_add(symbol.size)
+                }),
+            };
+            MapEntry {
+                address: reporting.record(
+                    safepoint.function_address(),
+                    safepoint.record().instruction_offset() as u64,
+                ),
+                size: end.saturating_sub(pc),
+                symbol: symbol.map(|symbol| symbol.name.clone()),
+                patch_point_id: safepoint.record().patch_point_id(),
+            }
+        })
+        .collect();
+    Ok(entries)
+}
+
+pub fn write_map(out: &mut dyn Write, entries: &[MapEntry]) -> io::Result<()> {
+    for entry in entries {
+        writeln!(
+            out,
+            "{:016x} {:08x} {} {:#x}",
+            entry.address,
+            entry.size,
+            entry.symbol.as_deref().unwrap_or("?"),
+            entry.patch_point_id
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{addresses::AddressMode, symbols::FunctionSymbol, test_data};
+
+    #[test]
+    fn map_lines() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let symbols: FunctionSymbols = vec![(
+            0x1130,
+            FunctionSymbol {
+                name: "foo".into(),
+                size: 0x40,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let entries = safepoint_map(&section, &symbols, &AddressReporting::default()).unwrap();
+        let mut out = Vec::new();
+        write_map(&mut out, &entries).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0000000000001150 0000000b foo 0x2a\n\
+             000000000000115b 00000015 foo 0x2b\n\
+             0000000000001177 00000000 ? 0x2c\n"
+        );
+
+        let reporting = AddressReporting {
+            bias: 0x1000,
+            ..AddressReporting::default()
+        };
+        let entries = safepoint_map(&section, &FunctionSymbols::default(), &reporting).unwrap();
+        assert_eq!(entries[0].address, 0x2150);
+        assert_eq!(entries[1].size, 0);
+
+        let reporting = AddressReporting::new(AddressMode::Function);
+        let entries = safepoint_map(&section, &symbols, &reporting).unwrap();
+        assert_eq!(entries[1].address, 0x2b);
+        assert_eq!(entries[2].address, 7);
+    }
+}
@@ -0,0 +1,276 @@
+// Delta debugging for sections that fail to parse. The section is split into
+// stack maps, functions and records using only the counts and sizes in the
+// data, which does not require the records themselves to be valid. Elements
+// are then removed for as long as the input stays interesting, fixing up the
+// counts so that the result is still structurally consistent.
+
+use std::{convert::TryInto, mem};
+
+use fallible_iterator::FallibleIterator;
+use nom::Finish;
+
+use crate::{
+    parser::{self, CONSTANT_SIZE, STACK_SIZE_RECORD_SIZE},
+    Error, LLVMStackMaps,
+};
+
+const HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+struct RawFunction<'a> {
+    // Address and stack size, the record count is recomputed when encoding
+    address_and_stack_size: &'a [u8],
+    records: Vec<&'a [u8]>,
+}
+
+#[derive(Debug, Clone)]
+struct RawStackMap<'a> {
+    version_and_reserved: &'a [u8],
+    constants: &'a [u8],
+    constants_count: &'a [u8],
+    functions: Vec<RawFunction<'a>>,
+}
+
+#[derive(Debug, Clone)]
+struct RawSection<'a> {
+    stack_maps: Vec<RawStackMap<'a>>,
+    // Whatever could not be split into stack maps is kept untouched
+    trailing: &'a [u8],
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn split_stack_map(input: &[u8]) -> Option<(RawStackMap<'_>, &[u8])> {
+    if input.len() < HEADER_SIZE {
+        return None;
+    }
+    let (header, rest) = input.split_at(HEADER_SIZE);
+    let num_functions = read_u32(&header[4..]) as usize;
+    let num_constants = read_u32(&header[8..]) as usize;
+    let num_records = read_u32(&header[12..]);
+
+    let functions_size = num_functions.checked_mul(STACK_SIZE_RECORD_SIZE)?;
+    let constants_size = num_constants.checked_mul(CONSTANT_SIZE)?;
+    if rest.len() < functions_size.checked_add(constants_size)? {
+        return None;
+    }
+    let (function_entries, rest) = rest.split_at(functions_size);
+    let (constants, rest) = rest.split_at(constants_size);
+
+    let (rest, mut records) = parser::slice_records(rest, num_records as u64)
+        .finish()
+        .ok()?;
+
+    let mut functions = Vec::with_capacity(num_functions);
+    for entry in function_entries.chunks_exact(STACK_SIZE_RECORD_SIZE) {
+        let record_count = u64::from_le_bytes(entry[16..].try_into().unwrap());
+        if record_count > records.len() as u64 {
+            return None;
+        }
+        let rest_records = records.split_off(record_count as usize);
+        functions.push(RawFunction {
+            address_and_stack_size: &entry[..16],
+            records: mem::replace(&mut records, rest_records),
+        });
+    }
+    if !records.is_empty() {
+        return None;
+    }
+
+    Some((
+        RawStackMap {
+            version_and_reserved: &header[..4],
+            constants_count: &header[8..12],
+            constants,
+            functions,
+        },
+        rest,
+    ))
+}
+
+fn split_section(mut input: &[u8]) -> RawSection<'_> {
+    let mut stack_maps = Vec::new();
+    while let Some((stack_map, rest)) = split_stack_map(input) {
+        stack_maps.push(stack_map);
+        input = rest;
+    }
+
+    RawSection {
+        stack_maps,
+        trailing: input,
+    }
+}
+
+fn encode_section(section: &RawSection) -> Vec<u8> {
+    let mut output = Vec::new();
+    for stack_map in &section.stack_maps {
+        let num_records: usize = stack_map
+            .functions
+            .iter()
+            .map(|function| function.records.len())
+            .sum();
+
+        output.extend_from_slice(stack_map.version_and_reserved);
+        output.extend_from_slice(&(stack_map.functions.len() as u32).to_le_bytes());
+        output.extend_from_slice(stack_map.constants_count);
+        output.extend_from_slice(&(num_records as u32).to_le_bytes());
+        for function in &stack_map.functions {
+            output.extend_from_slice(function.address_and_stack_size);
+            output.extend_from_slice(&(function.records.len() as u64).to_le_bytes());
+        }
+        output.extend_from_slice(stack_map.constants);
+        for function in &stack_map.functions {
+            for record in &function.records {
+                output.extend_from_slice(record);
+            }
+        }
+    }
+    output.extend_from_slice(section.trailing);
+
+    output
+}
+
+// Removes chunks of `items` of decreasing size for as long as `is_interesting`
+// holds for what is left.
+fn reduce<T: Clone>(items: &mut Vec<T>, mut is_interesting: impl FnMut(&[T]) -> bool) {
+    let mut chunk_size = (items.len() / 2).max(1);
+    while !items.is_empty() {
+        let mut removed_any = false;
+        let mut start = 0;
+        while start < items.len() {
+            let end = (start + chunk_size).min(items.len());
+            let mut candidate = items[..start].to_vec();
+            candidate.extend_from_slice(&items[end..]);
+
+            if is_interesting(&candidate) {
+                *items = candidate;
+                removed_any = true;
+            } else {
+                start = end;
+            }
+        }
+
+        if !removed_any {
+            if chunk_size == 1 {
+                break;
+            }
+            chunk_size /= 2;
+        }
+    }
+}
+
+// Parses every stack map, function, record, location and live-out in the
+// section, returning the first error.
+fn check_section(data: &[u8]) -> Result<(), Error> {
+    let section = LLVMStackMaps::new(data);
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let mut records_iter = function.records();
+            while let Some(record) = records_iter.next()? {
+                record.locations().for_each(|_| Ok(()))?;
+                record.live_outs().for_each(|_| Ok(()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Shrinks `data` by removing whole stack maps, functions and records for as
+/// long as `is_interesting` returns true for the result. The record counts of
+/// functions and stack maps are updated to match what is left.
+pub fn minimize(data: &[u8], mut is_interesting: impl FnMut(&[u8]) -> bool) -> Vec<u8> {
+    let mut section = split_section(data);
+
+    let mut stack_maps = section.stack_maps.clone();
+    reduce(&mut stack_maps, |candidate| {
+        let candidate = RawSection {
+            stack_maps: candidate.to_vec(),
+            trailing: section.trailing,
+        };
+        is_interesting(&encode_section(&candidate))
+    });
+    section.stack_maps = stack_maps;
+
+    for map_idx in 0..section.stack_maps.len() {
+        let mut functions = section.stack_maps[map_idx].functions.clone();
+        reduce(&mut functions, |candidate| {
+            let mut section = section.clone();
+            section.stack_maps[map_idx].functions = candidate.to_vec();
+            is_interesting(&encode_section(&section))
+        });
+        section.stack_maps[map_idx].functions = functions;
+
+        for function_idx in 0..section.stack_maps[map_idx].functions.len() {
+            let mut records = section.stack_maps[map_idx].functions[function_idx]
+                .records
+                .clone();
+            reduce(&mut records, |candidate| {
+                let mut section = section.clone();
+                section.stack_maps[map_idx].functions[function_idx].records = candidate.to_vec();
+                is_interesting(&encode_section(&section))
+            });
+            section.stack_maps[map_idx].functions[function_idx].records = records;
+        }
+    }
+
+    encode_section(&section)
+}
+
+/// Minimizes a section that fails to parse, keeping inputs that fail with the
+/// same kind of error as the original one. Returns `None` if `data` parses
+/// successfully.
+pub fn minimize_parse_failure(data: &[u8]) -> Option<Vec<u8>> {
+    let original_error = check_section(data).err()?;
+
+    Some(minimize(data, |candidate| match check_section(candidate) {
+        Ok(()) => false,
+        Err(error) => mem::discriminant(&error) == mem::discriminant(&original_error),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn split_and_encode_roundtrip() {
+        let section = split_section(test_data::TWO_FUNCTIONS);
+        assert_eq!(section.stack_maps.len(), 1);
+        assert!(section.trailing.is_empty());
+        assert_eq!(encode_section(&section), test_data::TWO_FUNCTIONS);
+    }
+
+    #[test]
+    fn minimize_invalid_location_kind() {
+        // Corrupt the kind of the only location of the record with ID 0x2c
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data[200] = 0x09;
+        assert!(matches!(
+            check_section(&data),
+            Err(Error::InvalidLocationKind { invalid_kind: 9 })
+        ));
+
+        let minimized = minimize_parse_failure(&data).unwrap();
+        assert!(minimized.len() < data.len());
+        assert!(matches!(
+            check_section(&minimized),
+            Err(Error::InvalidLocationKind { invalid_kind: 9 })
+        ));
+
+        let section = split_section(&minimized);
+        assert_eq!(section.stack_maps.len(), 1);
+        assert_eq!(section.stack_maps[0].functions.len(), 1);
+        assert_eq!(section.stack_maps[0].functions[0].records.len(), 1);
+    }
+
+    #[test]
+    fn minimize_valid_input() {
+        assert!(minimize_parse_failure(test_data::TWO_FUNCTIONS).is_none());
+    }
+}
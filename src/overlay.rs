@@ -0,0 +1,277 @@
+// Staged edits over a parsed stack map, for tools that rewrite stack maps
+// interactively. Edits are recorded next to the untouched parsed stack map
+// instead of being applied to an owned copy, so they are cheap to make and to
+// undo, and the edited stack map can be queried through the view traits at any
+// point. It is only materialized when serialized.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+
+use fallible_iterator::{Enumerate, FallibleIterator};
+
+use crate::{
+    owned,
+    view::{FunctionView, StackMapView},
+    Error, Function, FunctionsIter, Record, RecordsIter, StackMap, StackMapVersion,
+};
+
+pub struct Overlay<'input> {
+    stack_map: StackMap<'input>,
+    // Original function address to new address
+    addresses: BTreeMap<u64, u64>,
+    // Function index and record index within the function
+    removed_records: BTreeSet<(usize, usize)>,
+}
+
+impl<'input> Overlay<'input> {
+    pub fn new(stack_map: StackMap<'input>) -> Self {
+        Self {
+            stack_map,
+            addresses: BTreeMap::new(),
+            removed_records: BTreeSet::new(),
+        }
+    }
+
+    /// The stack map without any edit.
+    pub fn original(&self) -> &StackMap<'input> {
+        &self.stack_map
+    }
+
+    pub fn is_edited(&self) -> bool {
+        !self.addresses.is_empty() || !self.removed_records.is_empty()
+    }
+
+    /// Moves the functions at `old_address` to `new_address`. Remaps replace
+    /// each other and are not chained: `old_address` is the original address.
+    pub fn remap_address(&mut self, old_address: u64, new_address: u64) {
+        if old_address == new_address {
+            self.addresses.remove(&old_address);
+        } else {
+            self.addresses.insert(old_address, new_address);
+        }
+    }
+
+    /// Removes a record, given its function index and its index within the
+    /// function in the original stack map.
+    pub fn remove_record(&mut self, function_index: usize, record_index: usize) {
+        self.removed_records.insert((function_index, record_index));
+    }
+
+    pub fn restore_record(&mut self, function_index: usize, record_index: usize) {
+        self.removed_records.remove(&(function_index, record_index));
+    }
+
+    /// Drops all the staged edits.
+    pub fn reset(&mut self) {
+        self.addresses.clear();
+        self.removed_records.clear();
+    }
+
+    fn address(&self, original: u64) -> u64 {
+        self.addresses.get(&original).copied().unwrap_or(original)
+    }
+
+    fn is_removed(&self, function_index: usize, record_index: usize) -> bool {
+        self.removed_records
+            .contains(&(function_index, record_index))
+    }
+
+    /// Applies the staged edits to an owned copy of the stack map. Constants
+    /// are kept even if only removed records used them.
+    pub fn to_owned_stack_map(&self) -> Result<owned::StackMap, Error> {
+        let mut stack_map = owned::StackMap::from_parsed(&self.stack_map)?;
+        for (function_index, function) in stack_map.functions.iter_mut().enumerate() {
+            function.address = self.address(function.address);
+            let mut record_index = 0;
+            function.records.retain(|_| {
+                record_index += 1;
+                !self.is_removed(function_index, record_index - 1)
+            });
+        }
+        Ok(stack_map)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.to_owned_stack_map()?.to_bytes()
+    }
+}
+
+pub struct OverlayFunction<'me, 'input> {
+    overlay: &'me Overlay<'input>,
+    index: usize,
+    function: Function<'input>,
+}
+
+impl<'me, 'input> OverlayFunction<'me, 'input> {
+    /// The function without any edit.
+    pub fn original(&self) -> &Function<'input> {
+        &self.function
+    }
+}
+
+pub struct OverlayFunctionsIter<'me, 'input> {
+    overlay: &'me Overlay<'input>,
+    functions: Enumerate<FunctionsIter<'input>>,
+}
+
+impl<'me, 'input> FallibleIterator for OverlayFunctionsIter<'me, 'input> {
+    type Item = OverlayFunction<'me, 'input>;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Error> {
+        Ok(self
+            .functions
+            .next()?
+            .map(|(index, function)| OverlayFunction {
+                overlay: self.overlay,
+                index,
+                function,
+            }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.functions.size_hint()
+    }
+}
+
+// Skips the removed records
+pub struct OverlayRecordsIter<'me, 'input> {
+    overlay: &'me Overlay<'input>,
+    function_index: usize,
+    records: Enumerate<RecordsIter<'me, 'input>>,
+}
+
+impl<'me, 'input> FallibleIterator for OverlayRecordsIter<'me, 'input> {
+    type Item = Record<'input>;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Error> {
+        while let Some((index, record)) = self.records.next()? {
+            if !self.overlay.is_removed(self.function_index, index) {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'input> StackMapView for Overlay<'input> {
+    type Function<'me>
+        = OverlayFunction<'me, 'input>
+    where
+        Self: 'me;
+    type Functions<'me>
+        = OverlayFunctionsIter<'me, 'input>
+    where
+        Self: 'me;
+
+    fn version(&self) -> StackMapVersion {
+        self.stack_map.version()
+    }
+
+    fn num_functions(&self) -> usize {
+        self.stack_map.num_functions()
+    }
+
+    fn functions(&self) -> Self::Functions<'_> {
+        OverlayFunctionsIter {
+            overlay: self,
+            functions: self.stack_map.functions().enumerate(),
+        }
+    }
+}
+
+impl<'me, 'input> FunctionView for OverlayFunction<'me, 'input> {
+    type Record<'a>
+        = Record<'input>
+    where
+        Self: 'a;
+    type Records<'a>
+        = OverlayRecordsIter<'a, 'input>
+    where
+        Self: 'a;
+
+    fn address(&self) -> u64 {
+        self.overlay.address(self.function.address())
+    }
+
+    fn stack_size(&self) -> usize {
+        self.function.stack_size()
+    }
+
+    fn num_records(&self) -> usize {
+        let removed = self
+            .overlay
+            .removed_records
+            .range((self.index, 0)..(self.index + 1, 0))
+            .filter(|&&(_, record_index)| record_index < self.function.num_records())
+            .count();
+        self.function.num_records() - removed
+    }
+
+    fn records(&self) -> Self::Records<'_> {
+        OverlayRecordsIter {
+            overlay: self.overlay,
+            function_index: self.index,
+            records: self.function.records().enumerate(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, view::RecordView, LLVMStackMaps};
+
+    fn summary<V: StackMapView>(view: &V) -> Vec<(u64, Vec<u64>)> {
+        let mut functions = Vec::new();
+        let mut functions_iter = view.functions();
+        while let Some(function) = functions_iter.next().unwrap() {
+            let ids: Vec<u64> = function
+                .records()
+                .map(|record| Ok(record.patch_point_id()))
+                .collect()
+                .unwrap();
+            assert_eq!(ids.len(), function.num_records());
+            functions.push((function.address(), ids));
+        }
+        functions
+    }
+
+    #[test]
+    fn staged_edits() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let mut overlay = Overlay::new(section.stack_maps().next().unwrap().unwrap());
+        assert!(!overlay.is_edited());
+        assert_eq!(
+            summary(&overlay),
+            [(0x1130, vec![42, 43]), (0x1170, vec![44])]
+        );
+
+        overlay.remap_address(0x1130, 0x2130);
+        overlay.remove_record(0, 1);
+        overlay.remove_record(0, 7);
+        assert!(overlay.is_edited());
+        let edited = [(0x2130, vec![42]), (0x1170, vec![44])];
+        assert_eq!(summary(&overlay), edited);
+        assert_eq!(
+            summary(overlay.original()),
+            summary(&section.stack_maps().next().unwrap().unwrap())
+        );
+
+        let bytes = overlay.to_bytes().unwrap();
+        let serialized = LLVMStackMaps::new(&bytes);
+        let stack_map = serialized.stack_maps().next().unwrap().unwrap();
+        assert_eq!(stack_map.num_records(), 2);
+        assert_eq!(summary(&stack_map), edited);
+
+        overlay.restore_record(0, 1);
+        overlay.remap_address(0x1130, 0x1130);
+        overlay.remove_record(0, 7);
+        overlay.reset();
+        assert!(!overlay.is_edited());
+        assert_eq!(overlay.to_bytes().unwrap(), test_data::TWO_FUNCTIONS);
+    }
+}
@@ -0,0 +1,264 @@
+use alloc::vec::Vec;
+
+use crate::{addresses::AddressReporting, writer, Error, LiveOut, Location, StackMapVersion};
+
+// The owned model can be decorated with arbitrary metadata: `F` is attached to
+// each function and `R` to each record. Both default to `()`, and can be
+// replaced at any point through `map_function_metadata` and
+// `map_record_metadata`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
+pub struct StackMap<F = (), R = ()> {
+    pub version: StackMapVersion,
+    pub constants: Vec<u64>,
+    pub functions: Vec<Function<F, R>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
+pub struct Function<F = (), R = ()> {
+    pub address: u64,
+    pub stack_size: u64,
+    pub records: Vec<Record<R>>,
+    pub metadata: F,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", derive(schemars::JsonSchema))]
+pub struct Record<R = ()> {
+    pub patch_point_id: u64,
+    pub instruction_offset: u32,
+    pub locations: Vec<Location>,
+    pub live_outs: Vec<LiveOut>,
+    pub metadata: R,
+}
+
+impl<F: Default, R: Default> StackMap<F, R> {
+    pub fn from_parsed(stack_map: &crate::StackMap) -> Result<Self, Error> {
+        let constants = stack_map
+            .constants()
+            .map(|constant| constant.as_u64())
+            .collect();
+
+        let functions = stack_map
+            .functions()
+            .into_iter()
+            .map(|function| Function::from_parsed(&function?))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            version: stack_map.version(),
+            constants,
+            functions,
+        })
+    }
+}
+
+impl<F, R> StackMap<F, R> {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Appends the encoded stack map to `output`, so that the stack maps of a
+    // section can be written one after the other.
+    pub fn write_to(&self, output: &mut Vec<u8>) -> Result<(), Error> {
+        writer::write_stack_map(self, output)
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.records.len())
+            .sum()
+    }
+
+    pub fn map_function_metadata<G>(
+        self,
+        mut f: impl FnMut(&Function<F, R>) -> G,
+    ) -> StackMap<G, R> {
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|function| {
+                let metadata = f(&function);
+                Function {
+                    address: function.address,
+                    stack_size: function.stack_size,
+                    records: function.records,
+                    metadata,
+                }
+            })
+            .collect();
+
+        StackMap {
+            version: self.version,
+            constants: self.constants,
+            functions,
+        }
+    }
+
+    pub fn map_record_metadata<S>(
+        self,
+        mut f: impl FnMut(&Function<F, R>, &Record<R>) -> S,
+    ) -> StackMap<F, S> {
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|function| {
+                let metadata: Vec<_> = function
+                    .records
+                    .iter()
+                    .map(|record| f(&function, record))
+                    .collect();
+                let records = function
+                    .records
+                    .into_iter()
+                    .zip(metadata)
+                    .map(|(record, metadata)| Record {
+                        patch_point_id: record.patch_point_id,
+                        instruction_offset: record.instruction_offset,
+                        locations: record.locations,
+                        live_outs: record.live_outs,
+                        metadata,
+                    })
+                    .collect();
+
+                Function {
+                    address: function.address,
+                    stack_size: function.stack_size,
+                    records,
+                    metadata: function.metadata,
+                }
+            })
+            .collect();
+
+        StackMap {
+            version: self.version,
+            constants: self.constants,
+            functions,
+        }
+    }
+}
+
+impl<F, R> StackMap<F, R> {
+    /// Replaces the function addresses with how `reporting` reports them.
+    /// Records stay at their offset in their function.
+    pub fn report_addresses(&mut self, reporting: &AddressReporting) {
+        for function in &mut self.functions {
+            function.address = reporting.function(function.address);
+        }
+    }
+}
+
+impl<F: Default, R: Default> Function<F, R> {
+    pub fn from_parsed(function: &crate::Function) -> Result<Self, Error> {
+        let records = function
+            .records()
+            .into_iter()
+            .map(|record| Record::from_parsed(&record?))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            address: function.address(),
+            stack_size: function.stack_size() as u64,
+            records,
+            metadata: F::default(),
+        })
+    }
+}
+
+impl<R: Default> Record<R> {
+    pub fn from_parsed(record: &crate::Record) -> Result<Self, Error> {
+        Ok(Self {
+            patch_point_id: record.patch_point_id(),
+            instruction_offset: record.instruction_offset() as u32,
+            locations: record.locations_vec()?,
+            live_outs: record.live_outs_vec()?,
+            metadata: R::default(),
+        })
+    }
+}
+
+/// Returns the JSON Schema of a list of stack maps as serialized with
+/// `serde_json`, with metadata of type `F` for functions and `R` for records.
+#[cfg(feature = "json")]
+pub fn json_schema<F: schemars::JsonSchema, R: schemars::JsonSchema>(
+) -> schemars::schema::RootSchema {
+    schemars::schema_for!(Vec<StackMap<F, R>>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LLVMStackMaps};
+    use fallible_iterator::FallibleIterator;
+
+    fn parse_two_functions() -> StackMap {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        StackMap::from_parsed(&stack_map).unwrap()
+    }
+
+    #[test]
+    fn from_parsed() {
+        let stack_map = parse_two_functions();
+        assert_eq!(stack_map.version, 3);
+        assert_eq!(stack_map.constants, vec![1234567890123]);
+        assert_eq!(stack_map.functions.len(), 2);
+        assert_eq!(stack_map.num_records(), 3);
+
+        let function = &stack_map.functions[0];
+        assert_eq!(function.address, 0x1130);
+        assert_eq!(function.stack_size, 40);
+        assert_eq!(function.records[0].patch_point_id, 42);
+        assert_eq!(function.records[0].locations.len(), 4);
+    }
+
+    #[test]
+    fn to_bytes() {
+        let stack_map = parse_two_functions();
+        assert_eq!(stack_map.to_bytes().unwrap(), test_data::TWO_FUNCTIONS);
+    }
+
+    #[test]
+    fn metadata() {
+        let stack_map = parse_two_functions()
+            .map_function_metadata(|function| format!("fn_{:x}", function.address))
+            .map_record_metadata(|function, record| {
+                (function.metadata.clone(), record.locations.len())
+            });
+
+        assert_eq!(stack_map.functions[1].metadata, "fn_1170");
+        assert_eq!(
+            stack_map.functions[0].records[0].metadata,
+            ("fn_1130".to_owned(), 4)
+        );
+        assert_eq!(
+            stack_map.functions[0].records[1].metadata,
+            ("fn_1130".to_owned(), 1)
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let json = serde_json::to_value(vec![parse_two_functions()]).unwrap();
+        let record = &json[0]["functions"][0]["records"][0];
+        assert_eq!(record["patch_point_id"], 42);
+        assert_eq!(
+            record["locations"][0]["kind"],
+            serde_json::json!({"Direct": {"register": 6, "offset": -32}})
+        );
+        assert!(record["locations"][0].get("raw").is_none());
+
+        let schema = serde_json::to_value(json_schema::<(), ()>()).unwrap();
+        assert_eq!(schema["type"], "array");
+        assert!(schema["definitions"]["LocationKind"].is_object());
+    }
+}
@@ -1,6 +1,10 @@
-use crate::{Error, Function, LiveOut, Location, LocationKind, Record, StackMap};
+use crate::{Error, FunctionHeader, LiveOut, Location, LocationKind, Origin, Record, StackMap};
 
-use std::{mem::size_of, slice};
+use alloc::vec::Vec;
+use core::{
+    convert::{TryFrom, TryInto},
+    mem::size_of,
+};
 
 use nom::{
     bytes::complete::take,
@@ -10,16 +14,29 @@ use nom::{
 
 type IResult<I, O> = nom::IResult<I, O, crate::Error>;
 
-const STACK_SIZE_RECORD_SIZE: usize = size_of::<u64>() * 3;
-const CONSTANT_SIZE: usize = size_of::<u64>();
-const LOCATION_SIZE: usize = size_of::<u8>() * 2 + size_of::<u16>() * 3 + size_of::<i32>();
-const LIVE_OUT_SIZE: usize = size_of::<u16>() + size_of::<u8>() * 2;
-const ALIGNMENT_BYTES: usize = 8;
+// Version, reserved fields and the numbers of functions, constants and records
+pub(crate) const HEADER_SIZE: usize = size_of::<u8>() * 2 + size_of::<u16>() + size_of::<u32>() * 3;
+pub(crate) const STACK_SIZE_RECORD_SIZE: usize = size_of::<u64>() * 3;
+pub(crate) const CONSTANT_SIZE: usize = size_of::<u64>();
+pub(crate) const LOCATION_SIZE: usize =
+    size_of::<u8>() * 2 + size_of::<u16>() * 3 + size_of::<i32>();
+pub(crate) const LIVE_OUT_SIZE: usize = size_of::<u16>() + size_of::<u8>() * 2;
+pub(crate) const ALIGNMENT_BYTES: usize = 8;
+// A record without locations and live-outs: header, live-out count, padding
+const MIN_RECORD_SIZE: usize = 24;
+// Location kind of constants stored in the constants pool
+pub(crate) const CONSTANT_INDEX_KIND: u8 = 5;
+// The instruction offset follows the 64-bit patch point ID in every record
+pub(crate) const RECORD_OFFSET_FIELD: usize = size_of::<u64>();
+// ID, instruction offset, reserved field and number of locations
+pub(crate) const RECORD_HEADER_SIZE: usize =
+    size_of::<u64>() + size_of::<u32>() + size_of::<u16>() * 2;
 
 impl<'a, T> nom::error::ParseError<(&'a [u8], T)> for crate::Error {
     fn from_error_kind(input: (&'a [u8], T), kind: nom::error::ErrorKind) -> Self {
         Self::ParserError {
-            input: input.0[..8].into(),
+            input: input.0[..input.0.len().min(8)].into(),
+            offset: input.0.len(),
             kind,
         }
     }
@@ -32,7 +49,8 @@ impl<'a, T> nom::error::ParseError<(&'a [u8], T)> for crate::Error {
 impl<'a> nom::error::ParseError<&'a [u8]> for crate::Error {
     fn from_error_kind(input: &'a [u8], kind: nom::error::ErrorKind) -> Self {
         Self::ParserError {
-            input: input[..8].into(),
+            input: input[..input.len().min(8)].into(),
+            offset: input.len(),
             kind,
         }
     }
@@ -42,7 +60,7 @@ impl<'a> nom::error::ParseError<&'a [u8]> for crate::Error {
     }
 }
 
-fn parse_header(input: &[u8]) -> IResult<&[u8], crate::StackMapVersion> {
+pub(crate) fn parse_header(input: &[u8]) -> IResult<&[u8], crate::StackMapVersion> {
     let (rest, (version, zeroed_1, zeroed_2)) = tuple((le_u8, le_u8, le_u16))(input)?;
 
     if zeroed_1 != 0 || zeroed_2 != 0 {
@@ -52,20 +70,32 @@ fn parse_header(input: &[u8]) -> IResult<&[u8], crate::StackMapVersion> {
     Ok((rest, version))
 }
 
-const fn padding_size(parsed_bytes: usize, alignment_bytes: usize) -> usize {
+// Counts come straight from the input, so their size in bytes can overflow
+// `usize` on 32-bit hosts before `take` gets to reject them.
+fn checked_size(count: u64, element_size: usize) -> Result<usize, nom::Err<Error>> {
+    usize::try_from(count)
+        .ok()
+        .and_then(|count| count.checked_mul(element_size))
+        .ok_or(nom::Err::Failure(Error::SizeOverflow {
+            count,
+            element_size,
+        }))
+}
+
+pub(crate) const fn padding_size(parsed_bytes: usize, alignment_bytes: usize) -> usize {
     (alignment_bytes - (parsed_bytes % alignment_bytes)) % alignment_bytes
 }
 
 pub(crate) fn parse_record<'a>(
-    input_and_constants: (&'a [u8], &'a [u64]),
-) -> IResult<(&'a [u8], &'a [u64]), Record<'a>> {
+    input_and_constants: (&'a [u8], &'a [u8]),
+) -> IResult<(&'a [u8], &'a [u8]), Record<'a>> {
     // The `constants` are just passed on without being changed
     let (input, constants) = input_and_constants;
 
     let (rest, (patch_point_id, instruction_offset, _, num_locations)) =
         tuple((le_u64, le_u32, le_u16, le_u16))(input)?;
 
-    let locations_bytes = num_locations as usize * LOCATION_SIZE;
+    let locations_bytes = checked_size(num_locations as u64, LOCATION_SIZE)?;
     let (rest, locations) = take(locations_bytes)(rest)?;
     let parsed_bytes = input.len() - rest.len();
     let (rest, _) = take(padding_size(parsed_bytes, ALIGNMENT_BYTES))(rest)?;
@@ -73,14 +103,17 @@ pub(crate) fn parse_record<'a>(
     let (rest, _) = le_u16(rest)?;
     let (rest, num_live_outs) = le_u16(rest)?;
 
-    let live_outs_bytes = num_live_outs as usize * LIVE_OUT_SIZE;
+    let live_outs_bytes = checked_size(num_live_outs as u64, LIVE_OUT_SIZE)?;
     let (rest, live_outs) = take(live_outs_bytes)(rest)?;
     let parsed_bytes = input.len() - rest.len();
     let (rest, _) = take(padding_size(parsed_bytes, ALIGNMENT_BYTES))(rest)?;
 
+    let raw = &input[..input.len() - rest.len()];
     Ok((
         (rest, constants),
         Record {
+            raw,
+            offset: 0,
             patch_point_id,
             instruction_offset,
             num_locations,
@@ -92,7 +125,10 @@ pub(crate) fn parse_record<'a>(
     ))
 }
 
-pub(crate) fn parse_stack_map(input: &[u8]) -> IResult<&[u8], StackMap> {
+// Records are not parsed here, since they have variable length and are only
+// needed when iterating over functions. The returned `StackMap` (and the rest
+// of the input) starts at the first record.
+pub(crate) fn parse_stack_map(input: &[u8]) -> IResult<&[u8], StackMap<'_>> {
     let (rest, version) = parse_header(input)?;
     if version != 3 {
         return Err(nom::Err::Failure(Error::UnsupportedVersion));
@@ -101,62 +137,91 @@ pub(crate) fn parse_stack_map(input: &[u8]) -> IResult<&[u8], StackMap> {
     let (rest, (num_functions, num_constants, num_records)) =
         tuple((le_u32, le_u32, le_u32))(rest)?;
 
-    let (rest, functions) = take(num_functions as usize * STACK_SIZE_RECORD_SIZE)(rest)?;
-
-    let (rest, constants_bytes) = take(num_constants as usize * CONSTANT_SIZE)(rest)?;
-    let constants = unsafe {
-        slice::from_raw_parts(
-            constants_bytes.as_ptr().cast::<u64>(),
-            num_constants as usize,
-        )
-    };
-
-    let mut record_slices = Vec::with_capacity(num_records as usize);
-    let mut rest = rest;
-    for _ in 0..num_records {
-        let ((new_rest, _), _) = parse_record((rest, constants))?;
+    let functions_bytes = checked_size(num_functions as u64, STACK_SIZE_RECORD_SIZE)?;
+    let (rest, functions) = take(functions_bytes)(rest)?;
 
-        let record_size = rest.len() - new_rest.len();
-        let (record_slice, _) = rest.split_at(record_size);
-
-        record_slices.push(record_slice);
-        rest = new_rest;
-    }
+    let constants_bytes = checked_size(num_constants as u64, CONSTANT_SIZE)?;
+    let (rest, constants) = take(constants_bytes)(rest)?;
 
     Ok((
         rest,
         StackMap {
+            offset: 0,
             version,
             num_functions,
+            num_records,
             functions,
-            record_slices,
             constants,
+            records: rest,
         },
     ))
 }
 
-type InputRecordsConstantsTuple<'a> = (&'a [u8], Vec<&'a [u8]>, &'a [u64]);
-pub(crate) fn parse_function(
-    input_and_records_and_constants: InputRecordsConstantsTuple,
-) -> IResult<InputRecordsConstantsTuple, Function> {
-    let (input, mut records, constants) = input_and_records_and_constants;
-    let (rest_input, (address, stack_size, record_count)) = tuple((le_u64, le_u64, le_u64))(input)?;
-    let rest_records = records.split_off(record_count as usize);
+// Checks the header and counts of the stack map at the start of `input`
+// without parsing its records, which are only required to fit in the rest of
+// the input at their minimum size.
+pub(crate) fn probe_stack_map(input: &[u8]) -> IResult<&[u8], ()> {
+    let (rest, stack_map) = parse_stack_map(input)?;
+    let (rest, _) = take(checked_size(stack_map.num_records as u64, MIN_RECORD_SIZE)?)(rest)?;
+    Ok((rest, ()))
+}
+
+pub(crate) fn skip_records(input: &[u8], num_records: u32) -> IResult<&[u8], ()> {
+    let mut rest = input;
+    for _ in 0..num_records {
+        let ((new_rest, _), _) = parse_record((rest, &[]))?;
+        rest = new_rest;
+    }
+
+    Ok((rest, ()))
+}
+
+pub(crate) fn slice_records(input: &[u8], num_records: u64) -> IResult<&[u8], Vec<&[u8]>> {
+    // The count is not trusted for the allocation, since it could be huge
+    let capacity = (num_records as usize).min(input.len() / MIN_RECORD_SIZE);
+    let mut record_slices = Vec::with_capacity(capacity);
+    let mut rest = input;
+    for _ in 0..num_records {
+        let ((new_rest, _), _) = parse_record((rest, &[]))?;
+
+        let record_size = rest.len() - new_rest.len();
+        let (record_slice, _) = rest.split_at(record_size);
+
+        record_slices.push(record_slice);
+        rest = new_rest;
+    }
+
+    Ok((rest, record_slices))
+}
+
+pub(crate) fn parse_function_header(input: &[u8]) -> IResult<&[u8], FunctionHeader> {
+    let (rest, (address, stack_size, record_count)) = tuple((le_u64, le_u64, le_u64))(input)?;
 
     Ok((
-        (rest_input, rest_records, constants),
-        Function {
+        rest,
+        FunctionHeader {
             address,
             stack_size,
-            records,
-            constants,
+            record_count,
         },
     ))
 }
 
+// Constants are kept as raw bytes because the section is not guaranteed to be
+// 8-byte aligned in memory.
+fn constant_at(constants: &[u8], index: i32) -> Option<u64> {
+    if index < 0 {
+        return None;
+    }
+
+    let start = (index as usize).checked_mul(CONSTANT_SIZE)?;
+    let bytes = constants.get(start..start.checked_add(CONSTANT_SIZE)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
 pub(crate) fn parse_location<'a>(
-    input_and_constants: (&'a [u8], &'a [u64]),
-) -> IResult<(&'a [u8], &'a [u64]), Location> {
+    input_and_constants: (&'a [u8], &'a [u8]),
+) -> IResult<(&'a [u8], &'a [u8]), Location> {
     let (input, constants) = input_and_constants;
 
     let (rest, (loc_kind, zeroed_1, size, dwarf_reg_num, zeroed_2, offset_or_small_const)) =
@@ -177,14 +242,14 @@ pub(crate) fn parse_location<'a>(
             offset: offset_or_small_const as isize,
         },
         4 => LocationKind::Constant(offset_or_small_const as u64),
-        5 => {
-            if offset_or_small_const < 0 || offset_or_small_const as usize >= constants.len() {
+        CONSTANT_INDEX_KIND => match constant_at(constants, offset_or_small_const) {
+            Some(constant) => LocationKind::Constant(constant),
+            None => {
                 return Err(nom::Err::Failure(crate::Error::InvalidConstantIndex {
                     index: offset_or_small_const,
                 }));
             }
-            LocationKind::Constant(constants[offset_or_small_const as usize])
-        }
+        },
         invalid_kind => {
             return Err(nom::Err::Failure(crate::Error::InvalidLocationKind {
                 invalid_kind,
@@ -192,7 +257,22 @@ pub(crate) fn parse_location<'a>(
         }
     };
 
-    Ok(((rest, constants), Location { kind, size }))
+    let constant_index = if loc_kind == CONSTANT_INDEX_KIND {
+        Some(offset_or_small_const as u32)
+    } else {
+        None
+    };
+    Ok((
+        (rest, constants),
+        Location {
+            kind,
+            size,
+            origin: Some(Origin {
+                offset: 0,
+                constant_index,
+            }),
+        },
+    ))
 }
 
 pub(crate) fn parse_live_out(input: &[u8]) -> IResult<&[u8], LiveOut> {
@@ -206,3 +286,40 @@ pub(crate) fn parse_live_out(input: &[u8]) -> IResult<&[u8], LiveOut> {
         },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::Finish;
+
+    #[test]
+    fn checked_sizes() {
+        assert_eq!(checked_size(3, LOCATION_SIZE).unwrap(), 36);
+        assert!(matches!(
+            checked_size(u64::MAX, STACK_SIZE_RECORD_SIZE),
+            Err(nom::Err::Failure(Error::SizeOverflow {
+                count: u64::MAX,
+                element_size: 24,
+            }))
+        ));
+    }
+
+    // Hand-written inputs with hostile counts, which must be rejected without
+    // panicking or attempting a huge allocation
+    #[test]
+    fn hostile_counts() {
+        // 0xffffffff functions, constants and records with no data after them
+        let header: &[u8] = &[
+            0x03, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff,
+        ];
+        assert!(parse_stack_map(header).finish().is_err());
+
+        assert!(slice_records(&[], u32::MAX as u64).finish().is_err());
+        assert!(slice_records(&[0; 24], u32::MAX as u64).finish().is_err());
+
+        assert_eq!(constant_at(&[0; 8], i32::MAX), None);
+        assert_eq!(constant_at(&[0; 8], -1), None);
+        assert_eq!(constant_at(&[1, 0, 0, 0, 0, 0, 0, 0], 0), Some(1));
+    }
+}
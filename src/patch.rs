@@ -0,0 +1,238 @@
+// Planning of runtime code patches at patchpoints. A patch overwrites the
+// start of the shadow of its site, padded with NOPs up to the next
+// instruction boundary, and is only safe if it stays within the shadow and
+// does not touch the patch of another site.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+
+use crate::{index::PcIndex, Error, LLVMStackMaps, Record};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchSite {
+    pub pc: u64,
+    pub patch_point_ids: Vec<u64>,
+    pub shadow_size: u64,
+}
+
+/// Collects a site for each instrumented PC of `section`. Stack maps do not
+/// record shadow sizes, so `shadow_size` provides them per record, and sites
+/// shared by several records get the smallest of their shadows.
+pub fn patch_sites(
+    section: &LLVMStackMaps,
+    shadow_size: impl Fn(&Record) -> u64,
+) -> Result<Vec<PatchSite>, Error> {
+    let index = PcIndex::new(section)?;
+    Ok(index
+        .iter()
+        .map(|(pc, records)| PatchSite {
+            pc,
+            patch_point_ids: records.iter().map(Record::patch_point_id).collect(),
+            shadow_size: records.iter().map(&shadow_size).min().unwrap_or(0),
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchConflict {
+    // The patch, including its padding, does not fit in the shadow
+    ShadowTooSmall { shadow_size: u64, required: u64 },
+    NotAnInstructionBoundary,
+    // The patch overlaps the one of the site at `pc`
+    Overlap { pc: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchPlan {
+    pub pc: u64,
+    pub patch_point_ids: Vec<u64>,
+    // Bytes to overwrite, that is the patch followed by `nop_padding` bytes
+    // of NOPs
+    pub overwrite: Range<u64>,
+    pub nop_padding: u64,
+    pub conflicts: Vec<PatchConflict>,
+}
+
+impl PatchPlan {
+    pub fn is_safe(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Plans a patch of `patch_size` bytes at each of `sites`, sorted by PC. When
+/// the `instruction_boundaries` of the code are known, e.g. from a
+/// disassembler, patches are padded so that no instruction is left partially
+/// overwritten. Without them, the shadow is assumed to hold single-byte NOPs.
+pub fn plan_patches(
+    sites: &[PatchSite],
+    patch_size: u64,
+    instruction_boundaries: Option<&BTreeSet<u64>>,
+) -> Vec<PatchPlan> {
+    // Sorted by PC, so that only earlier plans can reach into a later one
+    let sites: BTreeMap<u64, &PatchSite> = sites.iter().map(|site| (site.pc, site)).collect();
+    let mut plans: Vec<PatchPlan> = Vec::with_capacity(sites.len());
+    // Indices of the earlier plans that still overwrite the current PC
+    let mut overlapping: Vec<usize> = Vec::new();
+
+    for (&pc, site) in &sites {
+        let patch_end = pc.saturating_add(patch_size);
+        let end = instruction_boundaries
+            .and_then(|boundaries| boundaries.range(patch_end..).next().copied())
+            .unwrap_or(patch_end);
+
+        let mut conflicts = Vec::new();
+        let required = end - pc;
+        if required > site.shadow_size {
+            conflicts.push(PatchConflict::ShadowTooSmall {
+                shadow_size: site.shadow_size,
+                required,
+            });
+        }
+        if instruction_boundaries.is_some_and(|boundaries| !boundaries.contains(&pc)) {
+            conflicts.push(PatchConflict::NotAnInstructionBoundary);
+        }
+
+        overlapping.retain(|&previous| plans[previous].overwrite.end > pc);
+        for &previous in &overlapping {
+            let previous_pc = plans[previous].pc;
+            plans[previous]
+                .conflicts
+                .push(PatchConflict::Overlap { pc });
+            conflicts.push(PatchConflict::Overlap { pc: previous_pc });
+        }
+
+        overlapping.push(plans.len());
+        plans.push(PatchPlan {
+            pc,
+            patch_point_ids: site.patch_point_ids.clone(),
+            overwrite: pc..end,
+            nop_padding: end - patch_end,
+            conflicts,
+        });
+    }
+
+    plans
+}
+
+/// Returns the shortest sequence of the NOPs recommended by Intel for x86-64
+/// that is `len` bytes long, to fill the padding of a patch.
+pub fn x86_64_nops(len: usize) -> Vec<u8> {
+    const NOPS: [&[u8]; 9] = [
+        &[0x90],
+        &[0x66, 0x90],
+        &[0x0f, 0x1f, 0x00],
+        &[0x0f, 0x1f, 0x40, 0x00],
+        &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    ];
+
+    let mut nops = Vec::with_capacity(len);
+    while nops.len() < len {
+        let nop_len = (len - nops.len()).min(NOPS.len());
+        nops.extend_from_slice(NOPS[nop_len - 1]);
+    }
+    nops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn plans() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let shadow_size = |record: &Record| match record.patch_point_id() {
+            42 => 16,
+            _ => 8,
+        };
+        let sites = patch_sites(&section, shadow_size).unwrap();
+        assert_eq!(sites.len(), 3);
+        assert_eq!(sites[0].shadow_size, 16);
+
+        let plans = plan_patches(&sites, 5, None);
+        assert!(plans.iter().all(PatchPlan::is_safe));
+        assert_eq!(plans[1].overwrite, 0x115b..0x1160);
+
+        // A 13-byte patch at 0x1150 runs into the site at 0x115b
+        let plans = plan_patches(&sites, 13, None);
+        assert_eq!(plans[0].conflicts, [PatchConflict::Overlap { pc: 0x115b }]);
+        assert_eq!(
+            plans[1].conflicts,
+            [
+                PatchConflict::ShadowTooSmall {
+                    shadow_size: 8,
+                    required: 13
+                },
+                PatchConflict::Overlap { pc: 0x1150 },
+            ]
+        );
+    }
+
+    #[test]
+    fn overlaps() {
+        let site = |pc, shadow_size| PatchSite {
+            pc,
+            patch_point_ids: vec![pc],
+            shadow_size,
+        };
+        // 10-byte patches at sites 4 bytes apart overlap both neighbours, and
+        // the first also overlaps the last
+        let sites = [site(0x1108, 16), site(0x1100, 16), site(0x1104, 16)];
+        let overlaps = |plan: &PatchPlan| -> Vec<u64> {
+            plan.conflicts
+                .iter()
+                .filter_map(|conflict| match conflict {
+                    PatchConflict::Overlap { pc } => Some(*pc),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let plans = plan_patches(&sites, 10, None);
+        assert_eq!(overlaps(&plans[0]), [0x1104, 0x1108]);
+        assert_eq!(overlaps(&plans[1]), [0x1100, 0x1108]);
+        assert_eq!(overlaps(&plans[2]), [0x1100, 0x1104]);
+
+        let plans = plan_patches(&sites, 4, None);
+        assert!(plans.iter().all(PatchPlan::is_safe));
+    }
+
+    #[test]
+    fn instruction_boundaries() {
+        let sites = [PatchSite {
+            pc: 0x1150,
+            patch_point_ids: vec![42],
+            shadow_size: 16,
+        }];
+        let boundaries = [0x1150, 0x1153, 0x1157, 0x1160].iter().copied().collect();
+        let plans = plan_patches(&sites, 5, Some(&boundaries));
+        assert_eq!(plans[0].overwrite, 0x1150..0x1157);
+        assert_eq!(plans[0].nop_padding, 2);
+        assert!(plans[0].is_safe());
+
+        let plans = plan_patches(&sites, 8, Some(&boundaries));
+        assert_eq!(plans[0].nop_padding, 8);
+        assert!(plans[0].is_safe());
+
+        let plans = plan_patches(&sites, 17, Some(&boundaries));
+        assert!(matches!(
+            plans[0].conflicts[..],
+            [PatchConflict::ShadowTooSmall { required: 17, .. }]
+        ));
+    }
+
+    #[test]
+    fn nops() {
+        assert!(x86_64_nops(0).is_empty());
+        assert_eq!(x86_64_nops(2), [0x66, 0x90]);
+        let nops = x86_64_nops(11);
+        assert_eq!(nops.len(), 11);
+        assert_eq!(&nops[9..], [0x66, 0x90]);
+    }
+}
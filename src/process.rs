@@ -0,0 +1,181 @@
+// Discovery of the modules of a running process from `/proc/<pid>/maps`, to
+// build an `AddressSpaceIndex` without knowing where each binary was loaded.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::{
+    index::{AddressSpaceIndex, Module},
+    loader::{self, LoadError, StackMapsSource},
+    Error, LLVMStackMaps,
+};
+
+#[derive(Debug, Snafu)]
+pub enum ProcessError {
+    #[snafu(display("Could not read {}: {}", path.display(), source))]
+    ReadFile { path: PathBuf, source: io::Error },
+    #[snafu(display("Malformed maps entry on line {}", line))]
+    MalformedMaps { line: usize },
+    #[snafu(display("Could not load {}: {}", path.display(), source))]
+    LoadModule { path: PathBuf, source: LoadError },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    pub executable: bool,
+    pub offset: u64,
+    pub path: Option<String>,
+}
+
+/// Parses the contents of a `/proc/<pid>/maps` file.
+pub fn parse_maps(text: &str) -> Result<Vec<Mapping>, ProcessError> {
+    let mut mappings = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let malformed = MalformedMaps { line: line_idx + 1 };
+        // The path is the only field that can contain spaces
+        let mut fields = line.splitn(6, char::is_whitespace);
+        let mut next_field = || fields.next().context(malformed);
+        let (start, end) = next_field()?.split_once('-').context(malformed)?;
+        let perms = next_field()?;
+        let offset = next_field()?;
+        next_field()?; // Device
+        next_field()?; // Inode
+        let path = fields.next().map(str::trim).filter(|path| !path.is_empty());
+
+        let hex = |field: &str| u64::from_str_radix(field, 16).ok().context(malformed);
+        mappings.push(Mapping {
+            start: hex(start)?,
+            end: hex(end)?,
+            executable: perms.contains('x'),
+            offset: hex(offset)?,
+            path: path.map(str::to_owned),
+        });
+    }
+
+    Ok(mappings)
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    pub path: PathBuf,
+    pub stack_maps_data: Vec<u8>,
+    pub load_bias: u64,
+}
+
+// The modules of a process that contain stack maps, with their data loaded so
+// that they can be indexed together.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessModules {
+    pub modules: Vec<LoadedModule>,
+}
+
+impl ProcessModules {
+    pub fn from_pid(pid: u32) -> Result<Self, ProcessError> {
+        let path = PathBuf::from(format!("/proc/{}/maps", pid));
+        let text = fs::read_to_string(&path).context(ReadFile { path })?;
+        Self::from_mappings(&parse_maps(&text)?)
+    }
+
+    /// Loads the stack maps of every file with an executable mapping, taking
+    /// the load bias from its first one. Pseudo-files such as `[vdso]`,
+    /// deleted files, and files without stack maps are skipped.
+    pub fn from_mappings(mappings: &[Mapping]) -> Result<Self, ProcessError> {
+        let mut seen = BTreeSet::new();
+        let mut modules = Vec::new();
+        for mapping in mappings.iter().filter(|mapping| mapping.executable) {
+            let path = match &mapping.path {
+                Some(path) if path.starts_with('/') && !path.ends_with(" (deleted)") => path,
+                _ => continue,
+            };
+            if !seen.insert(path) {
+                continue;
+            }
+
+            if let Some(module) = load_module(Path::new(path), mapping)? {
+                modules.push(module);
+            }
+        }
+
+        Ok(Self { modules })
+    }
+
+    pub fn index(&self) -> Result<AddressSpaceIndex<'_>, Error> {
+        AddressSpaceIndex::new(self.modules.iter().map(|module| Module {
+            name: module.path.display().to_string(),
+            stack_maps: LLVMStackMaps::new(&module.stack_maps_data),
+            load_bias: module.load_bias,
+        }))
+    }
+}
+
+fn load_module(path: &Path, mapping: &Mapping) -> Result<Option<LoadedModule>, ProcessError> {
+    let file_data = fs::read(path).context(ReadFile { path })?;
+    let stack_maps_data =
+        match loader::load_stack_maps_data(&file_data, &StackMapsSource::default()) {
+            Err(LoadError::SectionNotFound { .. }) => return Ok(None),
+            result => result.context(LoadModule { path })?.into_owned(),
+        };
+    let load_bias = loader::load_bias(&file_data, mapping.offset, mapping.start)
+        .context(LoadModule { path })?;
+
+    Ok(Some(LoadedModule {
+        path: path.into(),
+        stack_maps_data,
+        load_bias,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_entries() {
+        let text = "\
+555555554000-555555556000 r--p 00000000 fd:01 1234                       /usr/bin/my app
+555555556000-55555555a000 r-xp 00002000 fd:01 1234                       /usr/bin/my app
+7ffff7fc1000-7ffff7fc3000 r-xp 00000000 00:00 0                          [vdso]
+7ffffffde000-7ffffffff000 rw-p 00000000 00:00 0
+";
+        let mappings = parse_maps(text).unwrap();
+        assert_eq!(mappings.len(), 4);
+        assert_eq!(
+            mappings[1],
+            Mapping {
+                start: 0x5555_5555_6000,
+                end: 0x5555_5555_a000,
+                executable: true,
+                offset: 0x2000,
+                path: Some("/usr/bin/my app".to_owned()),
+            }
+        );
+        assert_eq!(mappings[3].path, None);
+
+        assert!(matches!(
+            parse_maps("555555554000 r--p 00000000 fd:01 1234"),
+            Err(ProcessError::MalformedMaps { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn skipped_mappings() {
+        let text = "\
+7ffff7fc1000-7ffff7fc3000 r-xp 00000000 00:00 0                          [vdso]
+7ffff7fc5000-7ffff7fc6000 r-xp 00000000 00:01 42                         /memfd:jit (deleted)
+";
+        let modules = ProcessModules::from_mappings(&parse_maps(text).unwrap()).unwrap();
+        assert!(modules.modules.is_empty());
+        assert!(modules.index().unwrap().is_empty());
+    }
+}
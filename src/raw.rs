@@ -0,0 +1,93 @@
+// The nom parsers the rest of the crate is built on, for callers that frame
+// stack maps themselves or embed them in their own formats. All of them work on
+// little-endian input and fail with `crate::Error`, so they compose with the
+// usual nom combinators.
+
+use crate::{parser, FunctionHeader, LiveOut, Location, Record, StackMap, StackMapVersion};
+
+pub type IResult<'a, O> = nom::IResult<&'a [u8], O, crate::Error>;
+
+pub const STACK_SIZE_RECORD_SIZE: usize = parser::STACK_SIZE_RECORD_SIZE;
+pub const CONSTANT_SIZE: usize = parser::CONSTANT_SIZE;
+pub const LOCATION_SIZE: usize = parser::LOCATION_SIZE;
+pub const LIVE_OUT_SIZE: usize = parser::LIVE_OUT_SIZE;
+pub const ALIGNMENT_BYTES: usize = parser::ALIGNMENT_BYTES;
+
+/// Number of padding bytes needed after `parsed_bytes` bytes to reach the
+/// next multiple of `alignment_bytes`.
+pub const fn padding_size(parsed_bytes: usize, alignment_bytes: usize) -> usize {
+    parser::padding_size(parsed_bytes, alignment_bytes)
+}
+
+/// Parses the 4-byte header of a stack map, checking that its reserved
+/// fields are zero but accepting any version.
+pub fn header(input: &[u8]) -> IResult<'_, StackMapVersion> {
+    parser::parse_header(input)
+}
+
+/// Parses a version 3 stack map up to its records, which are left in the
+/// returned input since their length is only known once they are parsed.
+pub fn stack_map(input: &[u8]) -> IResult<'_, StackMap<'_>> {
+    parser::parse_stack_map(input)
+}
+
+/// Parses one entry of the stack size records, i.e. a function header.
+pub fn stack_size_record(input: &[u8]) -> IResult<'_, FunctionHeader> {
+    parser::parse_function_header(input)
+}
+
+/// Returns a parser for one record, padding included. Large constants are
+/// looked up in `constants`, the raw constants of the stack map, when the
+/// locations of the record are read.
+pub fn record<'a>(constants: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<'a, Record<'a>> {
+    move |input| parser::parse_record((input, constants)).map(|((rest, _), record)| (rest, record))
+}
+
+/// Returns a parser for one location, resolving large constants in
+/// `constants`.
+pub fn location<'a>(constants: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<'a, Location> {
+    move |input| {
+        parser::parse_location((input, constants)).map(|((rest, _), location)| (rest, location))
+    }
+}
+
+/// Parses one live-out, without the padding that follows the live-outs of a
+/// record.
+pub fn live_out(input: &[u8]) -> IResult<'_, LiveOut> {
+    parser::parse_live_out(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LocationKind};
+    use fallible_iterator::FallibleIterator;
+    use nom::{multi::count, Finish};
+
+    #[test]
+    fn composed_parsers() {
+        let data = test_data::TWO_FUNCTIONS;
+        assert_eq!(header(data).unwrap().1, 3);
+
+        let (records, stack_map) = stack_map(data).unwrap();
+        let (_, functions) = count(stack_size_record, 2)(&data[16..]).unwrap();
+        assert_eq!(functions[1].address(), 0x1170);
+
+        // The constants sit between the stack size records and the records
+        let constants = &data[16 + 2 * STACK_SIZE_RECORD_SIZE..][..CONSTANT_SIZE];
+        let (rest, records) = count(record(constants), stack_map.num_records())(records)
+            .finish()
+            .unwrap();
+        assert!(rest.iter().all(|&byte| byte == 0));
+        assert_eq!(records[2].patch_point_id(), 44);
+
+        let mut locations = records[0].locations();
+        let first = locations.next().unwrap().unwrap();
+        let (_, parsed) = location(constants)(&records[0].raw_bytes()[16..]).unwrap();
+        assert_eq!(parsed, first);
+        assert!(matches!(parsed.kind(), LocationKind::Direct { .. }));
+
+        assert!(header(&[3, 1, 0, 0]).finish().is_err());
+        assert_eq!(live_out(&[7, 0, 0, 8]).unwrap().1, LiveOut::new(7, 8));
+    }
+}
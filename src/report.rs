@@ -0,0 +1,371 @@
+// Human-oriented summaries of a stack maps section, rendered as standalone
+// documents that can be shared without running the parser.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{
+    addresses::AddressReporting, symbols::FunctionSymbols, Error, LLVMStackMaps, Location,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordSummary {
+    pub patch_point_id: u64,
+    pub instruction_offset: u32,
+    pub locations: Vec<Location>,
+    pub num_live_outs: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSummary {
+    pub stack_map_index: usize,
+    pub address: u64,
+    pub name: Option<String>,
+    pub stack_size: u64,
+    pub records: Vec<RecordSummary>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub functions: Vec<FunctionSummary>,
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Pipes would end the table cell, even inside code spans
+fn escape_markdown(text: &str) -> String {
+    text.replace('|', "\\|").replace('`', "'")
+}
+
+// Stack sizes are grouped in power-of-two buckets, the first one only holding
+// functions without a frame.
+fn stack_size_bucket(stack_size: u64) -> u32 {
+    if stack_size == 0 {
+        0
+    } else {
+        64 - (stack_size - 1).leading_zeros() + 1
+    }
+}
+
+fn bucket_label(bucket: u32) -> String {
+    match bucket {
+        0 => "0".to_owned(),
+        1 => "1".to_owned(),
+        2 => "2".to_owned(),
+        _ => format!("{}-{}", (1u64 << (bucket - 2)) + 1, 1u64 << (bucket - 1)),
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse;margin-bottom:1em}\
+td,th{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+th{cursor:pointer;background:#eee}\
+.bar{background:#4a7;height:1em;display:inline-block}";
+
+// Sorts the function table by the clicked column, using the `data-value` of
+// each cell so that numbers are not compared as text, and expands the records
+// of a function when following its link.
+const HTML_SCRIPT: &str = "document.querySelectorAll('th[data-column]').forEach(function(th){\
+th.addEventListener('click',function(){\
+var table=th.closest('table'),body=table.tBodies[0],column=+th.dataset.column;\
+var ascending=th.dataset.order!=='asc';th.dataset.order=ascending?'asc':'desc';\
+var rows=Array.from(body.rows);rows.sort(function(a,b){\
+var x=a.cells[column].dataset.value,y=b.cells[column].dataset.value;\
+var nx=Number(x),ny=Number(y);var c=isNaN(nx)||isNaN(ny)?x.localeCompare(y):nx-ny;\
+return ascending?c:-c;});rows.forEach(function(row){body.appendChild(row);});});});\
+function openTarget(){var target=document.getElementById(location.hash.slice(1));\
+if(target)target.open=true;}window.addEventListener('hashchange',openTarget);openTarget();";
+
+impl Report {
+    pub fn new(section: &LLVMStackMaps, symbols: &FunctionSymbols) -> Result<Self, Error> {
+        let mut functions = Vec::new();
+
+        let mut stack_maps_iter = section.stack_maps().enumerate();
+        while let Some((stack_map_index, stack_map)) = stack_maps_iter.next()? {
+            let mut functions_iter = stack_map.functions();
+            while let Some(function) = functions_iter.next()? {
+                let records = function
+                    .records()
+                    .map(|record| {
+                        Ok(RecordSummary {
+                            patch_point_id: record.patch_point_id(),
+                            instruction_offset: record.instruction_offset() as u32,
+                            locations: record.locations_vec()?,
+                            num_live_outs: record.num_live_outs(),
+                        })
+                    })
+                    .collect()?;
+
+                functions.push(FunctionSummary {
+                    stack_map_index,
+                    address: function.address(),
+                    name: symbols.name(function.address()).map(str::to_owned),
+                    stack_size: function.stack_size() as u64,
+                    records,
+                });
+            }
+        }
+
+        Ok(Self { functions })
+    }
+
+    // Symbols are looked up before, by linked address
+    pub fn report_addresses(&mut self, reporting: &AddressReporting) {
+        for function in &mut self.functions {
+            function.address = reporting.function(function.address);
+        }
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.records.len())
+            .sum()
+    }
+
+    // Number of functions per stack size bucket, for the buckets between the
+    // smallest and the largest stack size.
+    fn stack_size_histogram(&self) -> Vec<(String, usize)> {
+        let buckets: Vec<u32> = self
+            .functions
+            .iter()
+            .map(|function| stack_size_bucket(function.stack_size))
+            .collect();
+        let (min, max) = match (buckets.iter().min(), buckets.iter().max()) {
+            (Some(&min), Some(&max)) => (min, max),
+            _ => return Vec::new(),
+        };
+
+        (min..=max)
+            .map(|bucket| {
+                let count = buckets.iter().filter(|&&other| other == bucket).count();
+                (bucket_label(bucket), count)
+            })
+            .collect()
+    }
+
+    /// Writes a self-contained HTML page with a sortable function table, the
+    /// records of every function and a histogram of the stack sizes.
+    pub fn write_html(&self, title: &str, output: &mut impl Write) -> io::Result<()> {
+        let mut html = String::new();
+        let title = escape_html(title);
+
+        // Writing to a String cannot fail
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Stack maps of {title}</title>\n<style>{style}</style>\n</head>\n<body>\n\
+             <h1>Stack maps of {title}</h1>\n<p>{functions} functions, {records} records</p>\n",
+            title = title,
+            style = HTML_STYLE,
+            functions = self.functions.len(),
+            records = self.num_records(),
+        );
+
+        html.push_str(
+            "<h2>Stack sizes</h2>\n<table>\n<tr><th>Bytes</th><th>Functions</th><th></th></tr>\n",
+        );
+        let histogram = self.stack_size_histogram();
+        let largest = histogram.iter().map(|&(_, count)| count).max().unwrap_or(0);
+        for (label, count) in &histogram {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td><span class=\"bar\" style=\"width:{}px\"></span></td></tr>",
+                label,
+                count,
+                count * 300 / largest.max(1)
+            );
+        }
+        html.push_str("</table>\n");
+
+        html.push_str(
+            "<h2>Functions</h2>\n<table>\n<thead><tr>\
+             <th data-column=\"0\">Stack map</th><th data-column=\"1\">Address</th>\
+             <th data-column=\"2\">Symbol</th><th data-column=\"3\">Stack size</th>\
+             <th data-column=\"4\">Records</th></tr></thead>\n<tbody>\n",
+        );
+        for (index, function) in self.functions.iter().enumerate() {
+            let name = escape_html(function.name.as_deref().unwrap_or("<unknown>"));
+            let _ = writeln!(
+                html,
+                "<tr><td data-value=\"{map}\">{map}</td>\
+                 <td data-value=\"{address}\"><a href=\"#function-{index}\">{address:#x}</a></td>\
+                 <td data-value=\"{name}\">{name}</td>\
+                 <td data-value=\"{stack_size}\">{stack_size}</td>\
+                 <td data-value=\"{records}\">{records}</td></tr>",
+                map = function.stack_map_index,
+                address = function.address,
+                index = index,
+                name = name,
+                stack_size = function.stack_size,
+                records = function.records.len(),
+            );
+        }
+        html.push_str("</tbody>\n</table>\n<h2>Records</h2>\n");
+
+        for (index, function) in self.functions.iter().enumerate() {
+            let name = escape_html(function.name.as_deref().unwrap_or("<unknown>"));
+            let _ = write!(
+                html,
+                "<details id=\"function-{}\">\n<summary>{:#x} {} ({} records)</summary>\n\
+                 <table>\n<tr><th>ID</th><th>Offset</th><th>Locations</th><th>Live-outs</th></tr>\n",
+                index,
+                function.address,
+                name,
+                function.records.len()
+            );
+            for record in &function.records {
+                let locations: Vec<String> = record
+                    .locations
+                    .iter()
+                    .map(|location| escape_html(&location.to_string()))
+                    .collect();
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{:#x}</td><td>{:#x}</td><td>{}</td><td>{}</td></tr>",
+                    record.patch_point_id,
+                    record.instruction_offset,
+                    locations.join(", "),
+                    record.num_live_outs
+                );
+            }
+            html.push_str("</table>\n</details>\n");
+        }
+
+        let _ = write!(html, "<script>{}</script>\n</body>\n</html>\n", HTML_SCRIPT);
+        output.write_all(html.as_bytes())
+    }
+
+    /// Writes the stack size histogram and the function table as Markdown
+    /// tables, e.g. to paste them into an issue.
+    pub fn write_markdown(&self, title: &str, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "### Stack maps of `{}`", escape_markdown(title))?;
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{} functions, {} records",
+            self.functions.len(),
+            self.num_records()
+        )?;
+        writeln!(output)?;
+
+        writeln!(output, "| Stack size (bytes) | Functions |")?;
+        writeln!(output, "| --- | ---: |")?;
+        for (label, count) in self.stack_size_histogram() {
+            writeln!(output, "| {} | {} |", label, count)?;
+        }
+        writeln!(output)?;
+
+        writeln!(
+            output,
+            "| Stack map | Address | Symbol | Stack size | Records |"
+        )?;
+        writeln!(output, "| ---: | --- | --- | ---: | ---: |")?;
+        for function in &self.functions {
+            let name = match &function.name {
+                Some(name) => format!("`{}`", escape_markdown(name)),
+                None => "_unknown_".to_owned(),
+            };
+            writeln!(
+                output,
+                "| {} | `{:#x}` | {} | {} | {} |",
+                function.stack_map_index,
+                function.address,
+                name,
+                function.stack_size,
+                function.records.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn stack_size_buckets() {
+        assert_eq!(bucket_label(stack_size_bucket(0)), "0");
+        assert_eq!(bucket_label(stack_size_bucket(1)), "1");
+        assert_eq!(bucket_label(stack_size_bucket(2)), "2");
+        assert_eq!(bucket_label(stack_size_bucket(3)), "3-4");
+        assert_eq!(bucket_label(stack_size_bucket(8)), "5-8");
+        assert_eq!(bucket_label(stack_size_bucket(40)), "33-64");
+        assert_eq!(bucket_label(stack_size_bucket(64)), "33-64");
+    }
+
+    #[test]
+    fn html_report() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let symbols = std::iter::once((
+            0x1130,
+            crate::symbols::FunctionSymbol {
+                name: "operator<".to_owned(),
+                size: 0x40,
+            },
+        ))
+        .collect();
+        let report = Report::new(&section, &symbols).unwrap();
+        assert_eq!(report.functions.len(), 2);
+        assert_eq!(report.num_records(), 3);
+        assert_eq!(report.stack_size_histogram().len(), 4);
+
+        let mut html = Vec::new();
+        report.write_html("a.out", &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("2 functions, 3 records"));
+        assert!(html.contains("operator&lt;"));
+        assert!(html.contains(
+            "direct(r6, -32, 8), register(r14, 8), constant(7, 8), constant(1234567890123, 8)"
+        ));
+    }
+
+    #[test]
+    fn markdown_report() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let symbols = std::iter::once((
+            0x1130,
+            crate::symbols::FunctionSymbol {
+                name: "operator|".to_owned(),
+                size: 0x40,
+            },
+        ))
+        .collect();
+        let report = Report::new(&section, &symbols).unwrap();
+
+        let mut markdown = Vec::new();
+        report.write_markdown("a.out", &mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.contains("| 33-64 | 1 |"));
+        assert!(markdown.contains("| 0 | `0x1130` | `operator\\|` | 40 | 2 |"));
+        assert!(markdown.contains("| 0 | `0x1170` | _unknown_ | 8 | 1 |"));
+
+        let mut report = report;
+        let mut reporting = AddressReporting::new(Default::default());
+        reporting.bias = 0x5555_0000_0000;
+        report.report_addresses(&reporting);
+        let mut markdown = Vec::new();
+        report.write_markdown("a.out", &mut markdown).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.contains("| 0 | `0x555500001130` | `operator\\|` | 40 | 2 |"));
+    }
+}
@@ -0,0 +1,87 @@
+// Checks that serializing the parsed stack maps of a section reproduces it
+// byte for byte, reserved fields included, so that edited sections only differ
+// from the original where they were edited. Reserved fields are not kept by
+// the parser, so nonzero bytes there make the round trip diverge.
+
+use alloc::vec::Vec;
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{coverage::section_coverage, owned::StackMap, Error, LLVMStackMaps};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub offset: usize,
+    // `None` past the end of the section
+    pub original: Option<u8>,
+    pub serialized: Option<u8>,
+}
+
+/// Parses and serializes each stack map of `data`, found as `verify` does
+/// with `coverage::section_coverage`, and returns the first offset at which
+/// the result differs from `data`, if any. Zero padding between stack maps is
+/// reproduced as is, and bytes that cannot be parsed are left out.
+pub fn check_roundtrip(data: &[u8]) -> Result<Option<Divergence>, Error> {
+    for range in section_coverage(data).stack_maps {
+        let original = &data[range.clone()];
+        let mut serialized = Vec::with_capacity(original.len());
+        for stack_map in LLVMStackMaps::new(original).stack_maps().iterator() {
+            StackMap::<(), ()>::from_parsed(&stack_map?)?.write_to(&mut serialized)?;
+        }
+
+        let offset = original
+            .iter()
+            .zip(&serialized)
+            .position(|(original, serialized)| original != serialized)
+            .unwrap_or_else(|| original.len().min(serialized.len()));
+        if offset < original.len() || offset < serialized.len() {
+            return Ok(Some(Divergence {
+                offset: range.start + offset,
+                original: original.get(offset).copied(),
+                serialized: serialized.get(offset).copied(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn divergences() {
+        assert_eq!(check_roundtrip(test_data::TWO_FUNCTIONS).unwrap(), None);
+
+        // The reserved field of the first record
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data[16 + 2 * 24 + 8 + 12] = 1;
+        assert_eq!(
+            check_roundtrip(&data).unwrap(),
+            Some(Divergence {
+                offset: 84,
+                original: Some(1),
+                serialized: Some(0),
+            })
+        );
+
+        // Padding is kept as is, and bytes that cannot be parsed are left to
+        // `verify`, which reports them
+        let mut data = test_data::TWO_FUNCTIONS.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(test_data::TWO_FUNCTIONS);
+        assert_eq!(check_roundtrip(&data).unwrap(), None);
+        data.extend_from_slice(&[3, 0, 0, 0]);
+        assert_eq!(check_roundtrip(&data).unwrap(), None);
+
+        // The reserved field of the first record of the second stack map
+        data[232 + 16 + 2 * 24 + 8 + 12] = 1;
+        assert_eq!(
+            check_roundtrip(&data)
+                .unwrap()
+                .map(|divergence| divergence.offset),
+            Some(232 + 84)
+        );
+    }
+}
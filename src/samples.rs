@@ -0,0 +1,264 @@
+// Attribution of profiler samples to the instrumented instructions of a
+// section, to measure how much time is spent at or right after safepoints.
+
+use std::{collections::BTreeMap, num::ParseIntError, ops::Range};
+
+use fallible_iterator::FallibleIterator;
+use snafu::{ResultExt, Snafu};
+
+use crate::{index::PcIndex, Error, LLVMStackMaps};
+
+#[derive(Debug, Snafu)]
+pub enum SamplesError {
+    #[snafu(display("Invalid address on line {}: {}", line, source))]
+    InvalidAddress { line: usize, source: ParseIntError },
+    #[snafu(display("Invalid sample count on line {}: {}", line, source))]
+    InvalidCount { line: usize, source: ParseIntError },
+}
+
+fn parse_hex(src: &str) -> Result<u64, ParseIntError> {
+    let digits = src
+        .strip_prefix("0x")
+        .or_else(|| src.strip_prefix("0X"))
+        .unwrap_or(src);
+    u64::from_str_radix(digits, 16)
+}
+
+// Number of samples at each address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleCounts {
+    counts: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl SampleCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, address: u64, count: u64) {
+        *self.counts.entry(address).or_default() += count;
+        self.total += count;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn at(&self, address: u64) -> u64 {
+        self.counts.get(&address).copied().unwrap_or(0)
+    }
+
+    pub fn in_range(&self, range: Range<u64>) -> u64 {
+        self.counts.range(range).map(|(_, count)| count).sum()
+    }
+
+    // Moves every sample by `-offset`, e.g. to undo the KASLR slide of the
+    // kernel the samples were taken on.
+    pub fn rebased(&self, offset: u64) -> Self {
+        let mut rebased = Self::new();
+        for (&address, &count) in &self.counts {
+            rebased.add(address.wrapping_sub(offset), count);
+        }
+        rebased
+    }
+
+    /// Reads the sampled instruction pointers from the output of
+    /// `perf script`, with the default fields, with or without `-g`, or with
+    /// `-F ip`. When the samples were recorded with callchains, the sampled
+    /// instruction pointer is the first entry of each callchain. Other lines
+    /// without an instruction pointer are skipped.
+    pub fn from_perf_script(text: &str) -> Self {
+        let mut samples = Self::new();
+        // Whether the last sample had no instruction pointer of its own, and
+        // so takes the first entry of the callchain below it
+        let mut in_callchain = false;
+        for line in text.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                in_callchain = false;
+                continue;
+            }
+
+            // The instruction pointer follows the event name, e.g.
+            // `cycles:u:`, if there is one. Both the command name and the
+            // callchain entries may be indented.
+            let ip = match tokens.iter().rposition(|token| token.ends_with(':')) {
+                Some(event) => {
+                    let ip = tokens.get(event + 1).and_then(|ip| parse_hex(ip).ok());
+                    in_callchain = ip.is_none();
+                    ip
+                }
+                None if in_callchain => {
+                    in_callchain = false;
+                    parse_hex(tokens[0]).ok()
+                }
+                None => match tokens.len() {
+                    1 => parse_hex(tokens[0]).ok(),
+                    // A caller further up a callchain
+                    _ => None,
+                },
+            };
+            if let Some(ip) = ip {
+                samples.add(ip, 1);
+            }
+        }
+        samples
+    }
+
+    /// Reads lines of a hexadecimal address followed by an optional decimal
+    /// sample count, which defaults to one. Empty lines and lines starting
+    /// with `#` are ignored.
+    pub fn from_address_counts(text: &str) -> Result<Self, SamplesError> {
+        let mut samples = Self::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line_number = line_idx + 1;
+            let mut tokens = line.split_whitespace();
+            let address =
+                parse_hex(tokens.next().unwrap()).context(InvalidAddress { line: line_number })?;
+            let count = match tokens.next() {
+                Some(count) => count.parse().context(InvalidCount { line: line_number })?,
+                None => 1,
+            };
+            samples.add(address, count);
+        }
+        Ok(samples)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcSamples {
+    pub pc: u64,
+    // Of the first function with a record at the PC
+    pub function_address: u64,
+    pub patch_point_ids: Vec<u64>,
+    pub at_pc: u64,
+    // Samples in `[pc, pc + shadow_size)`, including those at the PC
+    pub in_shadow: u64,
+}
+
+/// Counts the samples at each instrumented PC of `section` and within the
+/// `shadow_size` bytes starting there. Stack maps do not record the shadow of
+/// patchpoints, so it has to be provided by the caller. Sample addresses must
+/// be relative to the same base as the function addresses.
+pub fn correlate(
+    section: &LLVMStackMaps,
+    samples: &SampleCounts,
+    shadow_size: u64,
+) -> Result<Vec<PcSamples>, Error> {
+    let index = PcIndex::new(section)?;
+    let mut function_addresses = BTreeMap::new();
+    let mut safepoints_iter = section.safepoints();
+    while let Some(safepoint) = safepoints_iter.next()? {
+        function_addresses
+            .entry(safepoint.pc())
+            .or_insert_with(|| safepoint.function_address());
+    }
+    Ok(index
+        .iter()
+        .map(|(pc, records)| PcSamples {
+            pc,
+            function_address: function_addresses[&pc],
+            patch_point_ids: records
+                .iter()
+                .map(|record| record.patch_point_id())
+                .collect(),
+            at_pc: samples.at(pc),
+            in_shadow: samples.in_range(pc..pc.saturating_add(shadow_size)),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn perf_script() {
+        // `perf script` of a `perf record -e cycles:u`
+        let text = "\
+# ========
+# captured on    : Thu Oct 16 10:12:04 2026
+# ========
+#
+          stress  1234 [000] 12345.678901:     250000 cycles:u:      55d0c0a01137 foo+0x7 (/tmp/stress)
+          stress  1234 [000] 12345.678950:     250000 cycles:u:      55d0c0a01177 bar+0x7 (/tmp/stress)
+          stress  1234 [001] 12345.679001:     250000 cycles:u:      55d0c0a01137 foo+0x7 (/tmp/stress)
+";
+        let samples = SampleCounts::from_perf_script(text);
+        assert_eq!(samples.total(), 3);
+        assert_eq!(samples.at(0x55d0c0a01137), 2);
+        assert_eq!(samples.at(0x55d0c0a01177), 1);
+
+        // The same samples, recorded with `perf record -g`
+        let text = "\
+# ========
+# captured on    : Thu Oct 16 10:12:04 2026
+# ========
+#
+stress  1234 [000] 12345.678901:     250000 cycles:u: 
+\t    55d0c0a01137 foo+0x7 (/tmp/stress)
+\t    55d0c0a01180 bar+0x10 (/tmp/stress)
+\t    7f3a2c029d8f __libc_start_call_main+0x7f (/usr/lib/x86_64-linux-gnu/libc.so.6)
+
+stress  1234 [000] 12345.678950:     250000 cycles:u: 
+\t    55d0c0a01177 bar+0x7 (/tmp/stress)
+\t    7f3a2c029d8f __libc_start_call_main+0x7f (/usr/lib/x86_64-linux-gnu/libc.so.6)
+
+stress  1234 [001] 12345.679001:     250000 cycles:u: 
+\t    55d0c0a01137 foo+0x7 (/tmp/stress)
+\t    55d0c0a01180 bar+0x10 (/tmp/stress)
+
+";
+        assert_eq!(SampleCounts::from_perf_script(text), samples);
+
+        // `perf script -F ip`
+        let text = "    55d0c0a01137\n    55d0c0a01177\n    55d0c0a01137\n";
+        assert_eq!(SampleCounts::from_perf_script(text), samples);
+    }
+
+    #[test]
+    fn address_counts() {
+        let samples = SampleCounts::from_address_counts("0x1137 10\n\n# comment\n1177\n").unwrap();
+        assert_eq!(samples.at(0x1137), 10);
+        assert_eq!(samples.at(0x1177), 1);
+
+        assert!(matches!(
+            SampleCounts::from_address_counts("0x1137\n0x1138 many\n"),
+            Err(SamplesError::InvalidCount { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn samples_per_pc() {
+        let mut samples = SampleCounts::new();
+        samples.add(0xffffffff81001150, 3);
+        samples.add(0xffffffff81001154, 2);
+        samples.add(0xffffffff8100115b, 1);
+        samples.add(0xffffffff81001170, 7);
+        let samples = samples.rebased(0xffffffff81000000);
+
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let pcs = correlate(&section, &samples, 8).unwrap();
+        let counts: Vec<_> = pcs
+            .iter()
+            .map(|pc| (pc.pc, pc.patch_point_ids.as_slice(), pc.at_pc, pc.in_shadow))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (0x1150, &[42][..], 3, 5),
+                (0x115b, &[43][..], 1, 1),
+                (0x1177, &[44][..], 0, 0),
+            ]
+        );
+        assert_eq!(pcs[1].function_address, 0x1130);
+        assert_eq!(pcs[2].function_address, 0x1170);
+    }
+}
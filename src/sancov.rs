@@ -0,0 +1,251 @@
+// Reachability of safepoints according to SanitizerCoverage. SanCov reports
+// executed basic blocks rather than instructions, so each record is attributed
+// to the instrumented block containing it, and counts as reached when any PC
+// of that block was executed. Blocks are only known from the PC table of the
+// binary, and only with block or edge coverage: with function coverage, a
+// safepoint is only known not to be reached when its function was not entered.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    num::ParseIntError,
+};
+
+use fallible_iterator::FallibleIterator;
+use snafu::{ResultExt, Snafu};
+
+use crate::{Error, LLVMStackMaps};
+
+const MAGIC_64: u64 = 0xC0BF_FFFF_FFFF_FF64;
+const MAGIC_32: u64 = 0xC0BF_FFFF_FFFF_FF32;
+
+// Flag of the PC table entries of function entry blocks
+pub const FUNCTION_ENTRY: u64 = 1;
+
+#[derive(Debug, Snafu)]
+pub enum SancovError {
+    #[snafu(display("Truncated .sancov file"))]
+    Truncated,
+    #[snafu(display("Invalid PC on line {}: {}", line, source))]
+    InvalidPc { line: usize, source: ParseIntError },
+}
+
+/// Reads the executed PCs from either a `.sancov` file written by the
+/// sanitizer runtime or a text list of hexadecimal PCs, one per line, such as
+/// the output of `sancov -print`.
+pub fn read_executed_pcs(data: &[u8]) -> Result<Vec<u64>, SancovError> {
+    let magic = data
+        .get(..8)
+        .map(|magic| u64::from_le_bytes(magic.try_into().unwrap()));
+    let width = match magic {
+        Some(MAGIC_64) => 8,
+        Some(MAGIC_32) => 4,
+        _ => return read_pc_list(&String::from_utf8_lossy(data)),
+    };
+
+    let pcs = &data[8..];
+    if pcs.len() % width != 0 {
+        return Truncated.fail();
+    }
+    Ok(pcs
+        .chunks_exact(width)
+        .map(|pc| match width {
+            8 => u64::from_le_bytes(pc.try_into().unwrap()),
+            _ => u32::from_le_bytes(pc.try_into().unwrap()) as u64,
+        })
+        .collect())
+}
+
+fn read_pc_list(text: &str) -> Result<Vec<u64>, SancovError> {
+    let mut pcs = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let digits = line.strip_prefix("0x").unwrap_or(line);
+        let pc = u64::from_str_radix(digits, 16).context(InvalidPc { line: line_idx + 1 })?;
+        pcs.push(pc);
+    }
+    Ok(pcs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcTableEntry {
+    pub pc: u64,
+    pub flags: u64,
+}
+
+impl PcTableEntry {
+    pub fn is_function_entry(&self) -> bool {
+        self.flags & FUNCTION_ENTRY != 0
+    }
+}
+
+/// Maps every executed PC to the start of its block in `blocks`.
+pub fn executed_blocks(blocks: &BTreeSet<u64>, executed: &[u64]) -> BTreeSet<u64> {
+    executed
+        .iter()
+        .filter_map(|&pc| blocks.range(..=pc).next_back().copied())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reach {
+    Reached,
+    NotReached,
+    // Without a PC table, with function coverage only, or when no block of
+    // its function precedes the safepoint
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafepointReach {
+    pub pc: u64,
+    pub function_address: u64,
+    pub patch_point_ids: Vec<u64>,
+    // Start of the block the safepoint is attributed to, if blocks are known
+    // and any block of its function precedes it
+    pub block: Option<u64>,
+    pub reach: Reach,
+}
+
+/// Tells for each instrumented PC of `section` whether it was reached, given
+/// the PC table of the binary and the `executed` PCs. An empty table leaves
+/// every safepoint unknown.
+pub fn safepoint_reach(
+    section: &LLVMStackMaps,
+    pc_table: &[PcTableEntry],
+    executed: &[u64],
+) -> Result<Vec<SafepointReach>, Error> {
+    let mut safepoints: BTreeMap<u64, (u64, Vec<u64>)> = BTreeMap::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let mut records_iter = function.records();
+            while let Some(record) = records_iter.next()? {
+                let pc = function
+                    .address()
+                    .wrapping_add(record.instruction_offset() as u64);
+                safepoints
+                    .entry(pc)
+                    .or_insert_with(|| (function.address(), Vec::new()))
+                    .1
+                    .push(record.patch_point_id());
+            }
+        }
+    }
+
+    let blocks: BTreeSet<u64> = pc_table.iter().map(|entry| entry.pc).collect();
+    let executed_blocks = executed_blocks(&blocks, executed);
+    // With function coverage, the table only holds function entries
+    let has_blocks = pc_table.iter().any(|entry| !entry.is_function_entry());
+
+    Ok(safepoints
+        .into_iter()
+        .map(|(pc, (function_address, patch_point_ids))| {
+            let block = blocks.range(function_address..=pc).next_back().copied();
+            let reach = match block {
+                None => Reach::Unknown,
+                Some(block) if executed_blocks.contains(&block) => {
+                    if has_blocks {
+                        Reach::Reached
+                    } else {
+                        Reach::Unknown
+                    }
+                }
+                Some(_) => Reach::NotReached,
+            };
+            SafepointReach {
+                pc,
+                function_address,
+                patch_point_ids,
+                block: block.filter(|_| has_blocks),
+                reach,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data;
+
+    #[test]
+    fn executed_pcs() {
+        let mut sancov = MAGIC_32.to_le_bytes().to_vec();
+        sancov.extend_from_slice(&0x1154u32.to_le_bytes());
+        sancov.extend_from_slice(&0x1160u32.to_le_bytes());
+        assert_eq!(read_executed_pcs(&sancov).unwrap(), [0x1154, 0x1160]);
+
+        sancov.pop();
+        assert!(matches!(
+            read_executed_pcs(&sancov),
+            Err(SancovError::Truncated)
+        ));
+
+        assert_eq!(
+            read_executed_pcs(b"0x1154\n\n1160\n").unwrap(),
+            [0x1154, 0x1160]
+        );
+    }
+
+    #[test]
+    fn reached_safepoints() {
+        let block = |pc| PcTableEntry { pc, flags: 0 };
+        let entry = |pc| PcTableEntry {
+            pc,
+            flags: FUNCTION_ENTRY,
+        };
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let reach = |pc_table: &[PcTableEntry], executed: &[u64]| -> Vec<_> {
+            safepoint_reach(&section, pc_table, executed)
+                .unwrap()
+                .into_iter()
+                .map(|safepoint| (safepoint.pc, safepoint.block, safepoint.reach))
+                .collect()
+        };
+
+        let pc_table = [entry(0x1130), block(0x1158), entry(0x1170)];
+        let executed = [0x1134, 0x1150, 0x1168];
+        assert_eq!(
+            executed_blocks(
+                &[0x1130, 0x1158, 0x1170].iter().copied().collect(),
+                &executed
+            ),
+            [0x1130, 0x1158].iter().copied().collect()
+        );
+        assert_eq!(
+            reach(&pc_table, &executed),
+            [
+                (0x1150, Some(0x1130), Reach::Reached),
+                (0x115b, Some(0x1158), Reach::Reached),
+                (0x1177, Some(0x1170), Reach::NotReached),
+            ]
+        );
+
+        // Function coverage only tells which functions were not entered
+        let pc_table = [entry(0x1130), entry(0x1170)];
+        assert_eq!(
+            reach(&pc_table, &executed),
+            [
+                (0x1150, None, Reach::Unknown),
+                (0x115b, None, Reach::Unknown),
+                (0x1177, None, Reach::NotReached),
+            ]
+        );
+
+        // Nothing is known without a PC table, nor before the first block
+        // of a function
+        assert!(reach(&[], &executed)
+            .iter()
+            .all(|&(_, block, reach)| block.is_none() && reach == Reach::Unknown));
+        assert_eq!(
+            reach(&[block(0x1158)], &executed)[0],
+            (0x1150, None, Reach::Unknown)
+        );
+    }
+}
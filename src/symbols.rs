@@ -0,0 +1,43 @@
+// Function symbols of a binary, keyed by address, which name the functions of
+// the stack maps in reports and checks.
+
+use std::{collections::BTreeMap, iter::FromIterator};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSymbol {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FunctionSymbols {
+    by_address: BTreeMap<u64, FunctionSymbol>,
+}
+
+impl FunctionSymbols {
+    pub fn get(&self, address: u64) -> Option<&FunctionSymbol> {
+        self.by_address.get(&address)
+    }
+
+    pub fn name(&self, address: u64) -> Option<&str> {
+        self.get(address).map(|symbol| symbol.name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &FunctionSymbol)> {
+        self.by_address
+            .iter()
+            .map(|(&address, symbol)| (address, symbol))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+}
+
+impl FromIterator<(u64, FunctionSymbol)> for FunctionSymbols {
+    fn from_iter<I: IntoIterator<Item = (u64, FunctionSymbol)>>(iter: I) -> Self {
+        Self {
+            by_address: iter.into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// A stable textual syntax for locations, shared by the text outputs, the
+// command line filters and the tests:
+//
+//     register(r14, 8)
+//     direct(r6, -32, 8)
+//     indirect(r7, 16, 8)
+//     constant(-1, 8)
+//
+// The last argument is the size of the location, and is left out for a bare
+// `LocationKind`. Registers are DWARF register numbers. Numbers are decimal
+// or `0x`-prefixed hexadecimal, and constants are printed as signed, since
+// small constants are sign-extended.
+
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    str::FromStr,
+};
+
+use crate::{DwarfRegNum, Location, LocationKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLocationError {
+    input: String,
+}
+
+impl fmt::Display for ParseLocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid location: {}", self.input)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseLocationError {}
+
+// Signed values, in hexadecimal with `hex`
+fn write_number(f: &mut fmt::Formatter<'_>, value: i64, hex: bool) -> fmt::Result {
+    match value {
+        _ if !hex => write!(f, "{}", value),
+        value if value < 0 => write!(f, "-{:#x}", value.unsigned_abs()),
+        value => write!(f, "{:#x}", value),
+    }
+}
+
+fn write_location(
+    f: &mut fmt::Formatter<'_>,
+    kind: &LocationKind,
+    size: Option<u16>,
+    hex: bool,
+) -> fmt::Result {
+    match *kind {
+        LocationKind::Register(register) => write!(f, "register(r{}", register)?,
+        LocationKind::Direct { register, offset } => {
+            write!(f, "direct(r{}, ", register)?;
+            write_number(f, offset as i64, hex)?;
+        }
+        LocationKind::Indirect { register, offset } => {
+            write!(f, "indirect(r{}, ", register)?;
+            write_number(f, offset as i64, hex)?;
+        }
+        LocationKind::Constant(value) => {
+            f.write_str("constant(")?;
+            write_number(f, value as i64, hex)?;
+        }
+    }
+    if let Some(size) = size {
+        f.write_str(", ")?;
+        write_number(f, size.into(), hex)?;
+    }
+    f.write_str(")")
+}
+
+impl fmt::Display for LocationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, self, None, false)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, self.kind(), Some(self.size), false)
+    }
+}
+
+/// Displays a location in the same syntax, with hexadecimal numbers.
+pub struct HexLocation<'a>(pub &'a Location);
+
+impl fmt::Display for HexLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, self.0.kind(), Some(self.0.size), true)
+    }
+}
+
+// Splits `name(arg, ...)` into the name and the trimmed arguments
+fn split_call(input: &str) -> Option<(&str, Vec<&str>)> {
+    let (name, rest) = input.trim().split_once('(')?;
+    let args = rest.strip_suffix(')')?;
+    Some((name.trim(), args.split(',').map(str::trim).collect()))
+}
+
+fn parse_i64(input: &str) -> Option<i64> {
+    let (negative, digits) = match input.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, input),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    if negative {
+        0i64.checked_sub_unsigned(value)
+    } else {
+        Some(value as i64)
+    }
+}
+
+fn parse_register(input: &str) -> Option<DwarfRegNum> {
+    input.strip_prefix('r')?.parse().ok()
+}
+
+fn parse_kind(name: &str, args: &[&str]) -> Option<LocationKind> {
+    let kind = match (name, args) {
+        ("register", [register]) => LocationKind::Register(parse_register(register)?),
+        ("direct", [register, offset]) => LocationKind::Direct {
+            register: parse_register(register)?,
+            offset: parse_i64(offset)?.try_into().ok()?,
+        },
+        ("indirect", [register, offset]) => LocationKind::Indirect {
+            register: parse_register(register)?,
+            offset: parse_i64(offset)?.try_into().ok()?,
+        },
+        ("constant", [value]) => LocationKind::Constant(parse_i64(value)? as u64),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+impl FromStr for LocationKind {
+    type Err = ParseLocationError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        split_call(input)
+            .and_then(|(name, args)| parse_kind(name, &args))
+            .ok_or_else(|| ParseLocationError {
+                input: input.to_owned(),
+            })
+    }
+}
+
+impl FromStr for Location {
+    type Err = ParseLocationError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let location = split_call(input).and_then(|(name, args)| {
+            let (size, args) = args.split_last()?;
+            let size = u16::try_from(parse_i64(size)?).ok()?;
+            Some(Location::new(parse_kind(name, args)?, size))
+        });
+        location.ok_or_else(|| ParseLocationError {
+            input: input.to_owned(),
+        })
+    }
+}
+
+// A condition on locations, written `key=value`, where the key is `kind`
+// (`register`, `direct`, `indirect` or `constant`), `register`, `size` or
+// `location`, which takes a whole location in the syntax above
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocationFilter {
+    Kind(String),
+    Register(DwarfRegNum),
+    Size(u16),
+    Location(Location),
+}
+
+impl LocationFilter {
+    pub fn matches(&self, location: &Location) -> bool {
+        match self {
+            LocationFilter::Kind(name) => kind_name(location.kind()) == name,
+            LocationFilter::Register(register) => match *location.kind() {
+                LocationKind::Register(other)
+                | LocationKind::Direct {
+                    register: other, ..
+                }
+                | LocationKind::Indirect {
+                    register: other, ..
+                } => other == *register,
+                LocationKind::Constant(_) => false,
+            },
+            LocationFilter::Size(size) => location.size == *size,
+            LocationFilter::Location(other) => location == other,
+        }
+    }
+}
+
+fn kind_name(kind: &LocationKind) -> &'static str {
+    match kind {
+        LocationKind::Register(_) => "register",
+        LocationKind::Direct { .. } => "direct",
+        LocationKind::Indirect { .. } => "indirect",
+        LocationKind::Constant(_) => "constant",
+    }
+}
+
+impl FromStr for LocationFilter {
+    type Err = ParseLocationError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let filter = input.split_once('=').and_then(|(key, value)| {
+            let value = value.trim();
+            let filter = match key.trim() {
+                "kind" => match value {
+                    "register" | "direct" | "indirect" | "constant" => {
+                        LocationFilter::Kind(value.to_owned())
+                    }
+                    _ => return None,
+                },
+                "register" => LocationFilter::Register(parse_register(value)?),
+                "size" => LocationFilter::Size(u16::try_from(parse_i64(value)?).ok()?),
+                "location" => LocationFilter::Location(value.parse().ok()?),
+                _ => return None,
+            };
+            Some(filter)
+        });
+        filter.ok_or_else(|| ParseLocationError {
+            input: input.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LLVMStackMaps};
+    use fallible_iterator::FallibleIterator;
+
+    #[test]
+    fn round_trip() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let function = stack_map.functions().next().unwrap().unwrap();
+        let record = function.records().next().unwrap().unwrap();
+        let locations: Vec<_> = record.locations().collect().unwrap();
+        let text: Vec<_> = locations
+            .iter()
+            .map(|location| location.to_string())
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "direct(r6, -32, 8)",
+                "register(r14, 8)",
+                "constant(7, 8)",
+                "constant(1234567890123, 8)"
+            ]
+        );
+        for (location, text) in locations.iter().zip(&text) {
+            assert_eq!(&text.parse::<Location>().unwrap(), location);
+        }
+
+        assert_eq!(
+            " indirect( r7 ,0x10 ) ".parse(),
+            Ok(LocationKind::Indirect {
+                register: 7,
+                offset: 16
+            })
+        );
+        let all_ones = LocationKind::Constant(u64::MAX);
+        assert_eq!(all_ones.to_string(), "constant(-1)");
+        assert_eq!(all_ones.to_string().parse(), Ok(all_ones));
+        let hex = HexLocation(&locations[0]).to_string();
+        assert_eq!(hex, "direct(r6, -0x20, 0x8)");
+        assert_eq!(hex.parse::<Location>().unwrap(), locations[0]);
+        assert!("direct(r6, -32)".parse::<Location>().is_err());
+        assert!("direct(6, -32, 8)".parse::<Location>().is_err());
+        assert!("register(r6, 8".parse::<Location>().is_err());
+        assert!("stack(r6, 8)".parse::<Location>().is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let location: Location = "indirect(r7, 16, 8)".parse().unwrap();
+        let matching = [
+            "kind=indirect",
+            "register=r7",
+            "size=8",
+            "location=indirect(r7, 0x10, 8)",
+        ];
+        for filter in &matching {
+            let filter: LocationFilter = filter.parse().unwrap();
+            assert!(filter.matches(&location), "{:?}", filter);
+        }
+        let other = [
+            "kind=direct",
+            "register=r6",
+            "size=4",
+            "location=register(r7, 8)",
+        ];
+        for filter in &other {
+            let filter: LocationFilter = filter.parse().unwrap();
+            assert!(!filter.matches(&location), "{:?}", filter);
+        }
+        assert!("kind=stack".parse::<LocationFilter>().is_err());
+        assert!("offset=16".parse::<LocationFilter>().is_err());
+        assert!("indirect".parse::<LocationFilter>().is_err());
+    }
+}
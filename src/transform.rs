@@ -0,0 +1,130 @@
+use fallible_iterator::FallibleIterator;
+
+use crate::{
+    owned::{Function, Record, StackMap},
+    Error, LLVMStackMaps, Location,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Keep,
+    Drop,
+}
+
+// Each callback can rewrite the element it is given in place and decide
+// whether to keep it. Functions are visited before their records, and records
+// before their locations, so dropping a function skips all its records.
+pub trait Transform<F = (), R = ()> {
+    fn function(&mut self, _function: &mut Function<F, R>) -> Action {
+        Action::Keep
+    }
+
+    fn record(&mut self, _function: &Function<F, R>, _record: &mut Record<R>) -> Action {
+        Action::Keep
+    }
+
+    fn location(&mut self, _record: &Record<R>, _location: &mut Location) -> Action {
+        Action::Keep
+    }
+}
+
+pub fn apply<F, R, T: Transform<F, R> + ?Sized>(stack_map: &mut StackMap<F, R>, transform: &mut T) {
+    stack_map.functions.retain_mut(|function| {
+        if transform.function(function) == Action::Drop {
+            return false;
+        }
+
+        let mut records = std::mem::take(&mut function.records);
+        records.retain_mut(|record| {
+            if transform.record(function, record) == Action::Drop {
+                return false;
+            }
+
+            let mut locations = std::mem::take(&mut record.locations);
+            locations.retain_mut(|location| transform.location(record, location) == Action::Keep);
+            record.locations = locations;
+            true
+        });
+        function.records = records;
+        true
+    });
+}
+
+// Applies `transform` to every stack map in the section and serializes the
+// results back to back, producing the contents of a new section.
+pub fn transform_section<T: Transform + ?Sized>(
+    section: &LLVMStackMaps,
+    transform: &mut T,
+) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let mut stack_maps_iter = section.stack_maps();
+    while let Some(stack_map) = stack_maps_iter.next()? {
+        let mut stack_map = StackMap::from_parsed(&stack_map)?;
+        apply(&mut stack_map, transform);
+        stack_map.write_to(&mut output)?;
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LocationKind};
+
+    struct StripConstants {
+        kept_function: u64,
+    }
+
+    impl Transform for StripConstants {
+        fn function(&mut self, function: &mut Function) -> Action {
+            if function.address == self.kept_function {
+                function.address = 0x1000;
+                Action::Keep
+            } else {
+                Action::Drop
+            }
+        }
+
+        fn record(&mut self, _function: &Function, record: &mut Record) -> Action {
+            if record.patch_point_id == 43 {
+                Action::Drop
+            } else {
+                Action::Keep
+            }
+        }
+
+        fn location(&mut self, _record: &Record, location: &mut Location) -> Action {
+            match location.kind() {
+                LocationKind::Constant(_) => Action::Drop,
+                _ => Action::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn transform_section_keep_drop_rewrite() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let mut transform = StripConstants {
+            kept_function: 0x1130,
+        };
+        let output = transform_section(&section, &mut transform).unwrap();
+
+        let section = LLVMStackMaps::new(&output);
+        let stack_maps: Vec<_> = section.stack_maps().collect().unwrap();
+        assert_eq!(stack_maps.len(), 1);
+        let stack_map = StackMap::<(), ()>::from_parsed(&stack_maps[0]).unwrap();
+
+        assert_eq!(stack_map.functions.len(), 1);
+        assert_eq!(stack_map.functions[0].address, 0x1000);
+        assert_eq!(stack_map.functions[0].records.len(), 1);
+
+        let record = &stack_map.functions[0].records[0];
+        assert_eq!(record.patch_point_id, 42);
+        assert_eq!(record.locations.len(), 2);
+        assert!(record
+            .locations
+            .iter()
+            .all(|location| !matches!(location.kind(), LocationKind::Constant(_))));
+    }
+}
@@ -0,0 +1,160 @@
+// Checks for data that parses correctly but is almost certainly wrong, such as
+// functions whose addresses were never relocated. These usually point at
+// broken post-link processing of the section rather than at the compiler.
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{
+    diagnostics::{DiagnosticsSink, Warning},
+    symbols::FunctionSymbols,
+    Error, StackMap,
+};
+
+/// Checks the function table of `stack_map` for zero, duplicate and
+/// overlapping addresses. Overlaps can only be detected for functions whose
+/// size is found in `symbols`. Problems are reported to `sink` as warnings,
+/// errors are only returned for data that cannot be parsed.
+pub fn check_functions(
+    stack_map: &StackMap,
+    symbols: &FunctionSymbols,
+    sink: &mut impl DiagnosticsSink,
+) -> Result<(), Error> {
+    let mut addresses = Vec::with_capacity(stack_map.num_functions());
+
+    let mut headers_iter = stack_map.function_headers().enumerate();
+    while let Some((function_index, header)) = headers_iter.next()? {
+        if header.address() == 0 {
+            sink.warning(Warning::ZeroAddress { function_index });
+        } else {
+            addresses.push((header.address(), function_index));
+        }
+    }
+
+    addresses.sort_unstable();
+    // A function can overlap any later one, not only the next: each one is
+    // checked against the function reaching the furthest before it
+    let mut furthest: Option<(u64, u64)> = None;
+    let mut previous: Option<(u64, usize)> = None;
+    for &(address, function_index) in &addresses {
+        if let Some((previous_address, first_index)) = previous {
+            if previous_address == address {
+                sink.warning(Warning::DuplicateAddress {
+                    address,
+                    first_index,
+                    second_index: function_index,
+                });
+                previous = Some((address, function_index));
+                continue;
+            }
+        }
+        previous = Some((address, function_index));
+
+        if let Some((first, first_end)) = furthest {
+            if first_end > address {
+                sink.warning(Warning::Overlap {
+                    first,
+                    first_end,
+                    second: address,
+                });
+            }
+        }
+        if let Some(symbol) = symbols.get(address) {
+            let end = address.saturating_add(symbol.size);
+            if furthest.map(|(_, furthest_end)| furthest_end) < Some(end) {
+                furthest = Some((address, end));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{owned, symbols::FunctionSymbol, test_data, LLVMStackMaps};
+
+    fn warnings(stack_map: &StackMap, symbols: &FunctionSymbols) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        check_functions(stack_map, symbols, &mut warnings).unwrap();
+        warnings
+    }
+
+    #[test]
+    fn zero_and_duplicate_addresses() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let symbols = FunctionSymbols::default();
+        assert!(warnings(&stack_map, &symbols).is_empty());
+
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        let mut duplicate = owned.functions[1].clone();
+        duplicate.address = owned.functions[0].address;
+        owned.functions.push(duplicate);
+        owned.functions[1].address = 0;
+        let data = owned.to_bytes().unwrap();
+        let section = LLVMStackMaps::new(&data);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+
+        assert_eq!(
+            warnings(&stack_map, &symbols),
+            [
+                Warning::ZeroAddress { function_index: 1 },
+                Warning::DuplicateAddress {
+                    address: 0x1130,
+                    first_index: 0,
+                    second_index: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_functions() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let symbol = |size| FunctionSymbol {
+            name: "f".to_owned(),
+            size,
+        };
+
+        let symbols = std::iter::once((0x1130, symbol(0x40))).collect();
+        assert!(warnings(&stack_map, &symbols).is_empty());
+
+        let symbols = std::iter::once((0x1130, symbol(0x48))).collect();
+        assert_eq!(
+            warnings(&stack_map, &symbols),
+            [Warning::Overlap {
+                first: 0x1130,
+                first_end: 0x1178,
+                second: 0x1170,
+            }]
+        );
+
+        // A long function overlapping two later ones, the first of which
+        // has no known size
+        let mut owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        let mut third = owned.functions[1].clone();
+        third.address = 0x1190;
+        owned.functions.push(third);
+        let data = owned.to_bytes().unwrap();
+        let section = LLVMStackMaps::new(&data);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        let symbols = std::iter::once((0x1130, symbol(0x100))).collect();
+        assert_eq!(
+            warnings(&stack_map, &symbols),
+            [
+                Warning::Overlap {
+                    first: 0x1130,
+                    first_end: 0x1230,
+                    second: 0x1170,
+                },
+                Warning::Overlap {
+                    first: 0x1130,
+                    first_end: 0x1230,
+                    second: 0x1190,
+                },
+            ]
+        );
+    }
+}
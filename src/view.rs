@@ -0,0 +1,358 @@
+// Version-agnostic access to stack maps. Each supported format version
+// implements these traits on its concrete types, so that code that only needs
+// to walk functions, records and locations can be written once against the
+// traits. The owned model implements them too.
+
+use fallible_iterator::FallibleIterator;
+
+use crate::{owned, Error, LiveOut, Location, StackMapVersion};
+
+pub trait StackMapView {
+    type Function<'me>: FunctionView
+    where
+        Self: 'me;
+    type Functions<'me>: FallibleIterator<Item = Self::Function<'me>, Error = Error>
+    where
+        Self: 'me;
+
+    fn version(&self) -> StackMapVersion;
+    fn num_functions(&self) -> usize;
+    fn functions(&self) -> Self::Functions<'_>;
+}
+
+pub trait FunctionView {
+    type Record<'me>: RecordView
+    where
+        Self: 'me;
+    type Records<'me>: FallibleIterator<Item = Self::Record<'me>, Error = Error>
+    where
+        Self: 'me;
+
+    fn address(&self) -> u64;
+    fn stack_size(&self) -> usize;
+    fn num_records(&self) -> usize;
+    fn records(&self) -> Self::Records<'_>;
+}
+
+pub trait RecordView {
+    type Locations<'me>: FallibleIterator<Item = Location, Error = Error>
+    where
+        Self: 'me;
+    type LiveOuts<'me>: FallibleIterator<Item = LiveOut, Error = Error>
+    where
+        Self: 'me;
+
+    fn patch_point_id(&self) -> u64;
+    fn instruction_offset(&self) -> usize;
+    fn num_locations(&self) -> usize;
+    fn locations(&self) -> Self::Locations<'_>;
+    fn num_live_outs(&self) -> usize;
+    fn live_outs(&self) -> Self::LiveOuts<'_>;
+}
+
+impl<T: FunctionView + ?Sized> FunctionView for &T {
+    type Record<'me>
+        = T::Record<'me>
+    where
+        Self: 'me;
+    type Records<'me>
+        = T::Records<'me>
+    where
+        Self: 'me;
+
+    fn address(&self) -> u64 {
+        (**self).address()
+    }
+
+    fn stack_size(&self) -> usize {
+        (**self).stack_size()
+    }
+
+    fn num_records(&self) -> usize {
+        (**self).num_records()
+    }
+
+    fn records(&self) -> Self::Records<'_> {
+        (**self).records()
+    }
+}
+
+impl<T: RecordView + ?Sized> RecordView for &T {
+    type Locations<'me>
+        = T::Locations<'me>
+    where
+        Self: 'me;
+    type LiveOuts<'me>
+        = T::LiveOuts<'me>
+    where
+        Self: 'me;
+
+    fn patch_point_id(&self) -> u64 {
+        (**self).patch_point_id()
+    }
+
+    fn instruction_offset(&self) -> usize {
+        (**self).instruction_offset()
+    }
+
+    fn num_locations(&self) -> usize {
+        (**self).num_locations()
+    }
+
+    fn locations(&self) -> Self::Locations<'_> {
+        (**self).locations()
+    }
+
+    fn num_live_outs(&self) -> usize {
+        (**self).num_live_outs()
+    }
+
+    fn live_outs(&self) -> Self::LiveOuts<'_> {
+        (**self).live_outs()
+    }
+}
+
+impl<'input> StackMapView for crate::StackMap<'input> {
+    type Function<'me>
+        = crate::Function<'input>
+    where
+        Self: 'me;
+    type Functions<'me>
+        = crate::FunctionsIter<'input>
+    where
+        Self: 'me;
+
+    fn version(&self) -> StackMapVersion {
+        self.version()
+    }
+
+    fn num_functions(&self) -> usize {
+        self.num_functions()
+    }
+
+    fn functions(&self) -> Self::Functions<'_> {
+        self.functions()
+    }
+}
+
+impl<'input> FunctionView for crate::Function<'input> {
+    type Record<'me>
+        = crate::Record<'input>
+    where
+        Self: 'me;
+    type Records<'me>
+        = crate::RecordsIter<'me, 'input>
+    where
+        Self: 'me;
+
+    fn address(&self) -> u64 {
+        self.address()
+    }
+
+    fn stack_size(&self) -> usize {
+        self.stack_size()
+    }
+
+    fn num_records(&self) -> usize {
+        self.num_records()
+    }
+
+    fn records(&self) -> Self::Records<'_> {
+        self.records()
+    }
+}
+
+impl<'input> RecordView for crate::Record<'input> {
+    type Locations<'me>
+        = crate::LocationsIter<'input>
+    where
+        Self: 'me;
+    type LiveOuts<'me>
+        = crate::LiveOutsIter<'input>
+    where
+        Self: 'me;
+
+    fn patch_point_id(&self) -> u64 {
+        self.patch_point_id()
+    }
+
+    fn instruction_offset(&self) -> usize {
+        self.instruction_offset()
+    }
+
+    fn num_locations(&self) -> usize {
+        self.num_locations()
+    }
+
+    fn locations(&self) -> Self::Locations<'_> {
+        self.locations()
+    }
+
+    fn num_live_outs(&self) -> usize {
+        self.num_live_outs()
+    }
+
+    fn live_outs(&self) -> Self::LiveOuts<'_> {
+        self.live_outs()
+    }
+}
+
+pub struct SliceIter<'a, T> {
+    iter: core::slice::Iter<'a, T>,
+}
+
+impl<'a, T> SliceIter<'a, T> {
+    pub(crate) fn new(slice: &'a [T]) -> Self {
+        Self { iter: slice.iter() }
+    }
+}
+
+impl<'a, T> FallibleIterator for SliceIter<'a, T> {
+    type Item = &'a T;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Error> {
+        Ok(self.iter.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+pub struct ClonedSliceIter<'a, T> {
+    iter: core::slice::Iter<'a, T>,
+}
+
+impl<'a, T: Clone> FallibleIterator for ClonedSliceIter<'a, T> {
+    type Item = T;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Error> {
+        Ok(self.iter.next().cloned())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<F, R> StackMapView for owned::StackMap<F, R> {
+    type Function<'me>
+        = &'me owned::Function<F, R>
+    where
+        Self: 'me;
+    type Functions<'me>
+        = SliceIter<'me, owned::Function<F, R>>
+    where
+        Self: 'me;
+
+    fn version(&self) -> StackMapVersion {
+        self.version
+    }
+
+    fn num_functions(&self) -> usize {
+        self.functions.len()
+    }
+
+    fn functions(&self) -> Self::Functions<'_> {
+        SliceIter::new(&self.functions)
+    }
+}
+
+impl<F, R> FunctionView for owned::Function<F, R> {
+    type Record<'me>
+        = &'me owned::Record<R>
+    where
+        Self: 'me;
+    type Records<'me>
+        = SliceIter<'me, owned::Record<R>>
+    where
+        Self: 'me;
+
+    fn address(&self) -> u64 {
+        self.address
+    }
+
+    fn stack_size(&self) -> usize {
+        self.stack_size as usize
+    }
+
+    fn num_records(&self) -> usize {
+        self.records.len()
+    }
+
+    fn records(&self) -> Self::Records<'_> {
+        SliceIter::new(&self.records)
+    }
+}
+
+impl<R> RecordView for owned::Record<R> {
+    type Locations<'me>
+        = ClonedSliceIter<'me, Location>
+    where
+        Self: 'me;
+    type LiveOuts<'me>
+        = ClonedSliceIter<'me, LiveOut>
+    where
+        Self: 'me;
+
+    fn patch_point_id(&self) -> u64 {
+        self.patch_point_id
+    }
+
+    fn instruction_offset(&self) -> usize {
+        self.instruction_offset as usize
+    }
+
+    fn num_locations(&self) -> usize {
+        self.locations.len()
+    }
+
+    fn locations(&self) -> Self::Locations<'_> {
+        ClonedSliceIter {
+            iter: self.locations.iter(),
+        }
+    }
+
+    fn num_live_outs(&self) -> usize {
+        self.live_outs.len()
+    }
+
+    fn live_outs(&self) -> Self::LiveOuts<'_> {
+        ClonedSliceIter {
+            iter: self.live_outs.iter(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, LLVMStackMaps};
+
+    // Written once against the traits, used with both the parsed and the
+    // owned stack maps
+    fn count_locations<S: StackMapView>(stack_map: &S) -> Result<usize, Error> {
+        let mut count = 0;
+        let mut functions_iter = stack_map.functions();
+        while let Some(function) = functions_iter.next()? {
+            let mut records_iter = function.records();
+            while let Some(record) = records_iter.next()? {
+                count += record.locations().count()?;
+            }
+        }
+
+        Ok(count)
+    }
+
+    #[test]
+    fn parsed_and_owned_views() {
+        let section = LLVMStackMaps::new(test_data::TWO_FUNCTIONS);
+        let stack_map = section.stack_maps().next().unwrap().unwrap();
+        assert_eq!(count_locations(&stack_map).unwrap(), 6);
+
+        let owned = owned::StackMap::<(), ()>::from_parsed(&stack_map).unwrap();
+        assert_eq!(count_locations(&owned).unwrap(), 6);
+    }
+}
@@ -0,0 +1,136 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::{
+    owned,
+    parser::{padding_size, ALIGNMENT_BYTES},
+    Error, LiveOut, Location, LocationKind,
+};
+
+pub(crate) fn write_stack_map<F, R>(
+    stack_map: &owned::StackMap<F, R>,
+    output: &mut Vec<u8>,
+) -> Result<(), Error> {
+    // Large constants that are not in the pool yet are appended to it, so the
+    // pool is only known once all the records have been encoded.
+    let mut constants = stack_map.constants.clone();
+    let mut records = Vec::new();
+    for function in &stack_map.functions {
+        for record in &function.records {
+            write_record(record, &mut constants, &mut records)?;
+        }
+    }
+
+    output.extend_from_slice(&[stack_map.version, 0, 0, 0]);
+    write_count(stack_map.functions.len(), output)?;
+    write_count(constants.len(), output)?;
+    write_count(stack_map.num_records(), output)?;
+
+    for function in &stack_map.functions {
+        output.extend_from_slice(&function.address.to_le_bytes());
+        output.extend_from_slice(&function.stack_size.to_le_bytes());
+        output.extend_from_slice(&(function.records.len() as u64).to_le_bytes());
+    }
+
+    for constant in &constants {
+        output.extend_from_slice(&constant.to_le_bytes());
+    }
+
+    output.extend_from_slice(&records);
+    Ok(())
+}
+
+fn write_count(count: usize, output: &mut Vec<u8>) -> Result<(), Error> {
+    let count = u32::try_from(count).map_err(|_| Error::UnencodableCount { count })?;
+    output.extend_from_slice(&count.to_le_bytes());
+    Ok(())
+}
+
+fn write_padding(start: usize, output: &mut Vec<u8>) {
+    let padding = padding_size(output.len() - start, ALIGNMENT_BYTES);
+    output.resize(output.len() + padding, 0);
+}
+
+fn write_record<R>(
+    record: &owned::Record<R>,
+    constants: &mut Vec<u64>,
+    output: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let start = output.len();
+
+    let num_locations =
+        u16::try_from(record.locations.len()).map_err(|_| Error::UnencodableCount {
+            count: record.locations.len(),
+        })?;
+    let num_live_outs =
+        u16::try_from(record.live_outs.len()).map_err(|_| Error::UnencodableCount {
+            count: record.live_outs.len(),
+        })?;
+
+    output.extend_from_slice(&record.patch_point_id.to_le_bytes());
+    output.extend_from_slice(&record.instruction_offset.to_le_bytes());
+    output.extend_from_slice(&0u16.to_le_bytes());
+    output.extend_from_slice(&num_locations.to_le_bytes());
+    for location in &record.locations {
+        write_location(location, constants, output)?;
+    }
+    write_padding(start, output);
+
+    output.extend_from_slice(&0u16.to_le_bytes());
+    output.extend_from_slice(&num_live_outs.to_le_bytes());
+    for live_out in &record.live_outs {
+        write_live_out(live_out, output);
+    }
+    write_padding(start, output);
+
+    Ok(())
+}
+
+fn write_location(
+    location: &Location,
+    constants: &mut Vec<u64>,
+    output: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let encode_offset = |offset: isize| {
+        i32::try_from(offset).map_err(|_| Error::UnencodableOffset {
+            offset: offset as i64,
+        })
+    };
+
+    let (kind, register, offset_or_small_const): (u8, u16, i32) = match *location.kind() {
+        LocationKind::Register(register) => (1, register, 0),
+        LocationKind::Direct { register, offset } => (2, register, encode_offset(offset)?),
+        LocationKind::Indirect { register, offset } => (3, register, encode_offset(offset)?),
+        // Same choice as LLVM: constants that fit in 32 bits (sign-extended) are
+        // stored inline, the others go through the constants pool
+        LocationKind::Constant(constant) => match i32::try_from(constant as i64) {
+            Ok(small_constant) => (4, 0, small_constant),
+            Err(_) => {
+                let index = match constants.iter().position(|&other| other == constant) {
+                    Some(index) => index,
+                    None => {
+                        constants.push(constant);
+                        constants.len() - 1
+                    }
+                };
+                let index =
+                    i32::try_from(index).map_err(|_| Error::UnencodableCount { count: index })?;
+                (5, 0, index)
+            }
+        },
+    };
+
+    output.push(kind);
+    output.push(0);
+    output.extend_from_slice(&location.size.to_le_bytes());
+    output.extend_from_slice(&register.to_le_bytes());
+    output.extend_from_slice(&0u16.to_le_bytes());
+    output.extend_from_slice(&offset_or_small_const.to_le_bytes());
+    Ok(())
+}
+
+fn write_live_out(live_out: &LiveOut, output: &mut Vec<u8>) {
+    output.extend_from_slice(&live_out.dwarf_reg_num.to_le_bytes());
+    output.push(0);
+    output.push(live_out.size);
+}